@@ -0,0 +1,334 @@
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::entries::{address_key, Email};
+
+/// The prefix addressing a group alias instead of a mailbox, e.g. `@oncall-db`.
+pub(crate) const ALIAS_PREFIX: char = '@';
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub(crate) enum AliasError {
+    #[error("Unknown alias `@{alias}`, known aliases: {}", known_list(.known))]
+    Unknown { alias: String, known: Vec<String> },
+
+    #[error("The alias `@{0}` includes itself: {1}")]
+    Cycle(String, String),
+}
+
+fn known_list(known: &[String]) -> String {
+    if known.is_empty() {
+        return "none, see `--aliases-file`".to_owned();
+    }
+
+    known
+        .iter()
+        .map(|alias| format!("`@{alias}`"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Group aliases and their members, loaded from `--aliases-file`. A member is either an address,
+/// or another alias prefixed with `@`.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct AliasBook {
+    aliases: BTreeMap<String, Vec<String>>,
+}
+
+/// The members an alias of an E-mail field was replaced with, listed in the run output for auditing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Expansion {
+    pub(crate) field: &'static str,
+    pub(crate) alias: String,
+    pub(crate) members: Vec<String>,
+}
+
+impl fmt::Display for Expansion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`@{}` in `{}` expanded to {}",
+            self.alias,
+            self.field,
+            self.members.join(", ")
+        )
+    }
+}
+
+/// Loads the aliases of a TOML file, or a JSON file for a `.json` extension, mapping each alias to its members,
+/// e.g. `oncall-db = ["dba@example.com", "@managers"]`.
+/// ## Error
+/// Fails if the file can't be read or parsed, or if an alias includes an unknown alias or itself.
+pub(crate) fn load<P: AsRef<Path>>(path: P) -> Result<AliasBook> {
+    let path = path.as_ref();
+
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read aliases file \"{}\"", path.display()))?;
+
+    let aliases: BTreeMap<String, Vec<String>> = match path.extension().and_then(|ext| ext.to_str())
+    {
+        Some(ext) if ext.eq_ignore_ascii_case("json") => serde_json::from_str(&contents)
+            .with_context(|| format!("Unable to parse aliases file \"{}\"", path.display()))?,
+        _ => toml::from_str(&contents)
+            .with_context(|| format!("Unable to parse aliases file \"{}\"", path.display()))?,
+    };
+
+    let book = AliasBook::new(aliases);
+    book.validate()
+        .with_context(|| format!("Invalid aliases file \"{}\"", path.display()))?;
+
+    Ok(book)
+}
+
+impl AliasBook {
+    /// The aliases may be written with or without their `@` prefix.
+    pub(crate) fn new(aliases: BTreeMap<String, Vec<String>>) -> Self {
+        Self {
+            aliases: aliases
+                .into_iter()
+                .map(|(alias, members)| {
+                    (alias.trim_start_matches(ALIAS_PREFIX).to_owned(), members)
+                })
+                .collect(),
+        }
+    }
+
+    /// Checks that every alias resolves, without cycles or unknown aliases.
+    fn validate(&self) -> Result<(), AliasError> {
+        for alias in self.aliases.keys() {
+            self.resolve(alias)?;
+        }
+
+        Ok(())
+    }
+
+    /// The distinct addresses of an alias, in order, with its nested aliases resolved.
+    /// ## Error
+    /// Fails if the alias, or one of its nested aliases, is unknown or includes itself.
+    pub(crate) fn resolve(&self, alias: &str) -> Result<Vec<String>, AliasError> {
+        let mut members = Vec::new();
+        let mut seen = HashSet::new();
+        let mut chain = Vec::new();
+
+        self.collect(alias, &mut chain, &mut seen, &mut members)?;
+
+        Ok(members)
+    }
+
+    fn collect(
+        &self,
+        alias: &str,
+        chain: &mut Vec<String>,
+        seen: &mut HashSet<String>,
+        members: &mut Vec<String>,
+    ) -> Result<(), AliasError> {
+        if chain.iter().any(|parent| parent == alias) {
+            let cycle = chain
+                .iter()
+                .skip_while(|parent| *parent != alias)
+                .chain(std::iter::once(&alias.to_owned()))
+                .map(|alias| format!("@{alias}"))
+                .collect::<Vec<_>>()
+                .join(" -> ");
+
+            return Err(AliasError::Cycle(alias.to_owned(), cycle));
+        }
+
+        let Some(alias_members) = self.aliases.get(alias) else {
+            return Err(AliasError::Unknown {
+                alias: alias.to_owned(),
+                known: self.aliases.keys().cloned().collect(),
+            });
+        };
+
+        chain.push(alias.to_owned());
+
+        for member in alias_members {
+            match member.strip_prefix(ALIAS_PREFIX) {
+                Some(nested) => self.collect(nested, chain, seen, members)?,
+                None => {
+                    if seen.insert(address_key(member)) {
+                        members.push(member.clone());
+                    }
+                }
+            }
+        }
+
+        chain.pop();
+
+        Ok(())
+    }
+
+    /// Replaces the aliases of the `to`, `cc` and `bcc` fields of the E-mail by their members, returning
+    /// every expansion. Duplicates with the other recipients are left to [`Email::dedup_recipients`].
+    /// ## Error
+    /// Fails on the first unknown alias, leaving the E-mail unchanged.
+    pub(crate) fn expand(&self, email: &mut Email) -> Result<Vec<Expansion>, AliasError> {
+        let mut expansions = Vec::new();
+
+        let mut expand_field = |field, addresses: &[String]| {
+            let mut expanded = Vec::with_capacity(addresses.len());
+
+            for address in addresses {
+                let Some(alias) = address.trim().strip_prefix(ALIAS_PREFIX) else {
+                    expanded.push(address.clone());
+                    continue;
+                };
+
+                let members = self.resolve(alias)?;
+                expanded.extend(members.iter().cloned());
+                expansions.push(Expansion {
+                    field,
+                    alias: alias.to_owned(),
+                    members,
+                });
+            }
+
+            Ok(expanded)
+        };
+
+        let to = expand_field("to", &email.to)?;
+        let cc = expand_field("cc", &email.cc)?;
+        let bcc = expand_field("bcc", &email.bcc)?;
+
+        email.to = to;
+        email.cc = cc;
+        email.bcc = bcc;
+
+        Ok(expansions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(aliases: &[(&str, &[&str])]) -> AliasBook {
+        AliasBook::new(
+            aliases
+                .iter()
+                .map(|(alias, members)| {
+                    (
+                        alias.to_string(),
+                        members.iter().map(|member| member.to_string()).collect(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_expand_aliases() {
+        let book = book(&[
+            ("oncall-db", &["dba@x.com", "Lead <lead@x.com>"]),
+            ("managers", &["boss@x.com"]),
+        ]);
+
+        let mut email = Email {
+            to: vec!["@oncall-db".to_owned(), "ops@x.com".to_owned()],
+            bcc: vec!["@managers".to_owned()],
+            ..Default::default()
+        };
+
+        let expansions = book.expand(&mut email).unwrap();
+
+        assert_eq!(
+            email.to,
+            vec!["dba@x.com", "Lead <lead@x.com>", "ops@x.com"]
+        );
+        assert!(email.cc.is_empty());
+        assert_eq!(email.bcc, vec!["boss@x.com"]);
+        assert_eq!(
+            expansions
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec![
+                "`@oncall-db` in `to` expanded to dba@x.com, Lead <lead@x.com>",
+                "`@managers` in `bcc` expanded to boss@x.com",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_aliases() {
+        let book = book(&[
+            ("@everyone", &["@managers", "@oncall-db", "dev@x.com"]),
+            ("oncall-db", &["dba@x.com", "@managers"]),
+            ("managers", &["boss@x.com", "DBA@x.com"]),
+        ]);
+        book.validate().unwrap();
+
+        assert_eq!(
+            book.resolve("everyone").unwrap(),
+            vec!["boss@x.com", "DBA@x.com", "dev@x.com"]
+        );
+        assert_eq!(
+            book.resolve("oncall-db").unwrap(),
+            vec!["dba@x.com", "boss@x.com"]
+        );
+    }
+
+    #[test]
+    fn test_alias_cycle() {
+        let book = book(&[("a", &["a@x.com", "@b"]), ("b", &["@c"]), ("c", &["@a"])]);
+
+        assert_eq!(
+            book.resolve("b").unwrap_err().to_string(),
+            "The alias `@b` includes itself: @b -> @c -> @a -> @b"
+        );
+        assert!(book.validate().is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("aliases.json");
+        fs::write(&path, r#"{ "self": ["@self"] }"#).unwrap();
+
+        assert_eq!(
+            format!("{:#}", load(&path).unwrap_err()),
+            format!(
+                "Invalid aliases file \"{}\": The alias `@self` includes itself: @self -> @self",
+                path.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_unknown_alias() {
+        let book = book(&[("managers", &["boss@x.com"]), ("oncall-db", &["dba@x.com"])]);
+
+        let to = vec!["ops@x.com".to_owned(), "@oncal-db".to_owned()];
+        let mut email = Email {
+            to: to.clone(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            book.expand(&mut email).unwrap_err().to_string(),
+            "Unknown alias `@oncal-db`, known aliases: `@managers`, `@oncall-db`"
+        );
+        assert_eq!(email.to, to);
+
+        assert_eq!(
+            AliasBook::default()
+                .expand(&mut email)
+                .unwrap_err()
+                .to_string(),
+            "Unknown alias `@oncal-db`, known aliases: none, see `--aliases-file`"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("aliases.toml");
+        fs::write(&path, "team = [\"@missing\"]\n").unwrap();
+
+        assert!(format!("{:#}", load(&path).unwrap_err())
+            .ends_with("Unknown alias `@missing`, known aliases: `@team`"));
+
+        fs::write(&path, "team = [\"dev@x.com\", \"qa@x.com\"]\n").unwrap();
+        assert_eq!(
+            load(&path).unwrap().resolve("team").unwrap(),
+            vec!["dev@x.com", "qa@x.com"]
+        );
+    }
+}
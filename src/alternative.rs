@@ -0,0 +1,420 @@
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::rc::Rc;
+
+use crate::entries::ComposedEmail;
+use crate::render::{self, TemplateEngine};
+use crate::templates::AlternativeConfig;
+
+/// The characters a client shows as preview, filled up with padding after a shorter preview text,
+/// so the following content of the E-mail doesn't leak into the preview.
+pub(crate) const PREVIEW_LENGTH: usize = 150;
+
+/// An invisible unit of padding, a zero-width non-joiner and a non-breaking space, which clients don't collapse.
+const PREVIEW_PADDING: &str = "&zwnj;&nbsp;";
+
+/// Hides the preheader in every client, including Outlook (`mso-hide`).
+const PREHEADER_STYLE: &str =
+    "display:none;font-size:1px;line-height:1px;max-height:0;max-width:0;\
+opacity:0;overflow:hidden;mso-hide:all;";
+
+lazy_static! {
+    static ref BODY_TAG_PATTERN: Regex = Regex::new(r"(?i)<body[^>]*>").unwrap();
+    static ref HIDDEN_PATTERN: Regex = Regex::new(
+        r"(?is)<!--.*?-->|<(head|style|script|title)\b[^>]*>.*?</(head|style|script|title)\s*>"
+    )
+    .unwrap();
+    static ref BREAK_PATTERN: Regex =
+        Regex::new(r"(?i)<br\s*/?>|</(p|div|h[1-6]|tr|table|blockquote)\s*>").unwrap();
+    static ref LINK_PATTERN: Regex =
+        Regex::new(r#"(?is)<a\b[^>]*?\bhref\s*=\s*(?:"([^"]*)"|'([^']*)')[^>]*>(.*?)</a\s*>"#)
+            .unwrap();
+    static ref CELL_PATTERN: Regex = Regex::new(r"(?i)</t[dh]\s*>\s*(<t[dh]\b)").unwrap();
+    static ref LIST_PATTERN: Regex = Regex::new(r"(?i)<(/?)(ul|ol|li)\b[^>]*>").unwrap();
+    static ref TAG_PATTERN: Regex = Regex::new(r"<[^>]*>").unwrap();
+    static ref WHITESPACE_PATTERN: Regex = Regex::new(r"\s+").unwrap();
+    static ref BLANK_LINES_PATTERN: Regex = Regex::new(r"\n{3,}").unwrap();
+}
+
+/// Escapes the text for the HTML content and attribute values.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The hidden preheader of a preview text, padded up to [`PREVIEW_LENGTH`] characters.
+pub(crate) fn preheader(preview_text: &str) -> String {
+    let padding = PREVIEW_LENGTH.saturating_sub(preview_text.chars().count());
+
+    format!(
+        r#"<div style="{PREHEADER_STYLE}">{}{}</div>"#,
+        escape_html(preview_text),
+        PREVIEW_PADDING.repeat(padding)
+    )
+}
+
+/// Injects the preheader of a preview text at the top of the HTML body, or before the HTML without a `<body>`.
+pub(crate) fn inject_preheader(html: &str, preview_text: &str) -> String {
+    let preheader = preheader(preview_text);
+
+    match BODY_TAG_PATTERN.find(html) {
+        Some(body) => format!("{}{preheader}{}", &html[..body.end()], &html[body.end()..]),
+        None => format!("{preheader}{html}"),
+    }
+}
+
+/// Decodes the entities HTML editors commonly write, and the numeric ones.
+pub(crate) fn decode_entities(text: &str) -> String {
+    lazy_static! {
+        static ref ENTITY_PATTERN: Regex =
+            Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-z]+);").unwrap();
+    }
+
+    ENTITY_PATTERN
+        .replace_all(text, |caps: &regex::Captures| {
+            let entity = &caps[1];
+
+            let decoded = match entity {
+                "nbsp" => Some(' '),
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ => entity
+                    .strip_prefix("#x")
+                    .or_else(|| entity.strip_prefix("#X"))
+                    .map(|hex| u32::from_str_radix(hex, 16).ok())
+                    .unwrap_or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                    .and_then(char::from_u32),
+            };
+
+            decoded.map_or_else(|| caps[0].to_owned(), String::from)
+        })
+        .into_owned()
+}
+
+/// Marks the indentation of nested list items, kept when the whitespace of a line is collapsed.
+const INDENT_MARK: char = '\u{E000}';
+
+/// Replaces a link by its text followed by its URL, unless the text is the URL or the link is an anchor.
+fn link_to_text(caps: &regex::Captures) -> String {
+    let url = caps
+        .get(1)
+        .or_else(|| caps.get(2))
+        .map_or("", |url| url.as_str());
+    let text = &caps[3];
+
+    let visible = decode_entities(TAG_PATTERN.replace_all(text, "").trim());
+    let url_text = decode_entities(url.trim());
+
+    if url_text.is_empty()
+        || url_text.starts_with('#')
+        || visible == url_text
+        || url_text.strip_prefix("mailto:") == Some(visible.as_str())
+    {
+        text.to_owned()
+    } else {
+        format!("{text} ({url})")
+    }
+}
+
+/// Puts every list item on a line of its own, marked with `- `, or its number within an ordered list, and indented
+/// by the depth of its list.
+fn mark_list_items(html: &str) -> String {
+    // The last number of every open list, `None` for an unordered one
+    let mut lists: Vec<Option<usize>> = Vec::new();
+    let mut text = String::with_capacity(html.len());
+    let mut copied_up_to = 0;
+
+    let start_line = |text: &mut String| {
+        if !text.trim_end_matches(' ').ends_with('\n') && !text.trim().is_empty() {
+            text.push('\n');
+        }
+    };
+
+    for tag in LIST_PATTERN.captures_iter(html) {
+        let whole = tag.get(0).expect("The whole match");
+        text.push_str(&html[copied_up_to..whole.start()]);
+        copied_up_to = whole.end();
+
+        let closing = !tag[1].is_empty();
+        match (tag[2].to_lowercase().as_str(), closing) {
+            ("ul", false) => lists.push(None),
+            ("ol", false) => lists.push(Some(0)),
+            ("ul" | "ol", true) => {
+                lists.pop();
+                start_line(&mut text);
+            }
+            ("li", false) => {
+                start_line(&mut text);
+                let depth = lists.len().saturating_sub(1);
+                text.extend(std::iter::repeat_n(INDENT_MARK, depth));
+
+                match lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        text.push_str(&format!("{number}. "));
+                    }
+                    _ => text.push_str("- "),
+                }
+            }
+            _ => {}
+        }
+    }
+    text.push_str(&html[copied_up_to..]);
+
+    text
+}
+
+/// A plain-text version of the HTML: its visible text, a line per block, a line per list item, the cells of a
+/// table row separated by ` | `, and links followed by their URL, e.g. `Dashboard (https://x.com)`.
+/// As in the HTML, the line breaks of the source are only whitespace.
+pub(crate) fn html_to_text(html: &str) -> String {
+    let text = HIDDEN_PATTERN.replace_all(html, "");
+    let text = WHITESPACE_PATTERN.replace_all(&text, " ");
+    let text = LINK_PATTERN.replace_all(&text, link_to_text);
+    let text = CELL_PATTERN.replace_all(&text, " | $1");
+    let text = BREAK_PATTERN.replace_all(&text, "\n");
+    let text = mark_list_items(&text);
+    let text = TAG_PATTERN.replace_all(&text, "");
+
+    let lines: Vec<String> = text
+        .lines()
+        .map(|line| {
+            decode_entities(line)
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .replace(INDENT_MARK, "  ")
+        })
+        .collect();
+
+    BLANK_LINES_PATTERN
+        .replace_all(lines.join("\n").trim(), "\n\n")
+        .into_owned()
+}
+
+/// Applies the alternative settings of an E-mail to its rendered HTML: generates the plain-text alternative when
+/// the E-mail has none, unless `generate_text` is turned off, then injects the preview text, rendered with the engine of the
+/// template against the context, as a hidden preheader and as the first line of the plain-text alternative.
+/// The preview text of a raw E-mail is used as is.
+/// ## Error
+/// Fails if the preview text can't be rendered.
+pub(crate) fn apply(
+    email: &mut ComposedEmail,
+    html: Rc<String>,
+    engine: Option<TemplateEngine>,
+    config: &AlternativeConfig,
+) -> Result<Rc<String>> {
+    let header = &mut email.header;
+
+    // The `text_body` of a raw E-mail takes the place of its `alternative_content`
+    let text = match header.text_body.as_mut() {
+        Some(text_body) => text_body,
+        None => &mut header.alternative_content,
+    };
+
+    if config.generate_text.unwrap_or(true) && text.trim().is_empty() {
+        *text = html_to_text(&html);
+    }
+
+    let Some(preview_text) = &config.preview_text else {
+        return Ok(html);
+    };
+
+    let preview_text = match engine {
+        Some(engine) => render::render_inline(
+            preview_text,
+            &serde_json::Value::Object(email.context.clone()),
+            engine,
+            false,
+        )
+        .with_context(|| format!("Unable to render the preview text \"{preview_text}\""))?,
+        None => preview_text.clone(),
+    };
+    let preview_text = preview_text.trim();
+
+    if preview_text.is_empty() {
+        return Ok(html);
+    }
+
+    *text = if text.is_empty() {
+        preview_text.to_owned()
+    } else {
+        format!("{preview_text}\n\n{text}")
+    };
+
+    Ok(Rc::new(inject_preheader(&html, preview_text)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entries::Email;
+
+    #[test]
+    fn test_preheader_padding() {
+        let short = preheader("3 new alerts");
+        assert!(short.starts_with(&format!(
+            r#"<div style="{PREHEADER_STYLE}">3 new alerts&zwnj;&nbsp;"#
+        )));
+        assert_eq!(
+            short.matches(PREVIEW_PADDING).count(),
+            PREVIEW_LENGTH - "3 new alerts".len()
+        );
+
+        let long = "x".repeat(PREVIEW_LENGTH + 10);
+        assert_eq!(preheader(&long).matches(PREVIEW_PADDING).count(), 0);
+
+        assert!(preheader("Tom & <Jerry>").contains("Tom &amp; &lt;Jerry&gt;"));
+    }
+
+    #[test]
+    fn test_apply_preview_text() {
+        let mut email = ComposedEmail {
+            header: Email {
+                alternative_content: "See the table".to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        email
+            .context
+            .insert("count".to_owned(), serde_json::json!(3));
+
+        let config = AlternativeConfig {
+            preview_text: Some("{{ count }} new alerts".to_owned()),
+            generate_text: None,
+        };
+
+        let html = apply(
+            &mut email,
+            Rc::new(r#"<html><BODY class="main"><p>Alerts</p></BODY></html>"#.to_owned()),
+            Some(TemplateEngine::Tera),
+            &config,
+        )
+        .unwrap();
+
+        assert!(html.starts_with(&format!(
+            r#"<html><BODY class="main"><div style="{PREHEADER_STYLE}">3 new alerts&zwnj;"#
+        )));
+        assert!(html.ends_with("</div><p>Alerts</p></BODY></html>"));
+        assert_eq!(
+            email.header.alternative_content.lines().next(),
+            Some("3 new alerts")
+        );
+        assert_eq!(
+            email.header.alternative_content,
+            "3 new alerts\n\nSee the table"
+        );
+
+        // Without a body, the preheader leads the HTML
+        assert!(inject_preheader("<p>Alerts</p>", "Preview").starts_with("<div style="));
+    }
+
+    #[test]
+    fn test_html_to_text_links() {
+        let html = r#"<p>See the <a href="https://x.com/dash?a=1&amp;b=2">dashboard</a>,
+            <a href="https://x.com">https://x.com</a>, <a href='#top'>top</a>
+            or <a href="mailto:ops@x.com">ops@x.com</a></p>"#;
+
+        assert_eq!(
+            html_to_text(html),
+            "See the dashboard (https://x.com/dash?a=1&b=2), https://x.com, top or ops@x.com"
+        );
+    }
+
+    #[test]
+    fn test_html_to_text_tables() {
+        let html = "<table><tr><th>Job</th><th>Status</th></tr>
+            <tr><td>backup</td> <td><b>failed</b></td></tr>
+            <tr><td>sync</td><td>ok</td></tr></table><p>2 jobs</p>";
+
+        assert_eq!(
+            html_to_text(html),
+            "Job | Status\nbackup | failed\nsync | ok\n\n2 jobs"
+        );
+    }
+
+    #[test]
+    fn test_html_to_text_nested_lists() {
+        let html =
+            "<p>Hosts:</p><ul><li>eu<ol><li>db-1</li><li>db-2<ul><li>disk</li></ul></li></ol></li>
+            <li>us</li></ul><p>Done</p>";
+
+        assert_eq!(
+            html_to_text(html),
+            "Hosts:\n- eu\n  1. db-1\n  2. db-2\n    - disk\n- us\nDone"
+        );
+    }
+
+    #[test]
+    fn test_generate_text() {
+        let html = r#"<html><head><title>Alerts</title><style>p { color: red; }</style></head>
+            <body><!-- header --><h1>Daily&nbsp;alerts</h1>
+            <p>Disk   <b>full</b> on <i>db-1</i><br>since 10:00 &amp; rising</p>
+            <ul><li>db-1</li><li>db-2</li></ul></body></html>"#;
+
+        assert_eq!(
+            html_to_text(html),
+            "Daily alerts\nDisk full on db-1\nsince 10:00 & rising\n- db-1\n- db-2"
+        );
+
+        let mut raw = ComposedEmail {
+            header: Email {
+                html_body: Some(html.to_owned()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let config = AlternativeConfig {
+            preview_text: Some("{{ not rendered }}".to_owned()),
+            generate_text: Some(true),
+        };
+
+        apply(&mut raw, Rc::new(html.to_owned()), None, &config).unwrap();
+        assert_eq!(
+            raw.header.alternative_content.lines().next(),
+            Some("{{ not rendered }}")
+        );
+        assert!(raw.header.alternative_content.ends_with("- db-1\n- db-2"));
+
+        // An alternative of the entry is never replaced
+        let mut written = ComposedEmail {
+            header: Email {
+                alternative_content: "Written".to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let config = AlternativeConfig {
+            preview_text: None,
+            generate_text: Some(true),
+        };
+        apply(&mut written, Rc::new(html.to_owned()), None, &config).unwrap();
+        assert_eq!(written.header.alternative_content, "Written");
+
+        // Generated unless turned off
+        let mut blank = ComposedEmail::default();
+        apply(
+            &mut blank,
+            Rc::new(html.to_owned()),
+            None,
+            &Default::default(),
+        )
+        .unwrap();
+        assert!(blank.header.alternative_content.starts_with("Daily alerts"));
+
+        let mut blank = ComposedEmail::default();
+        let config = AlternativeConfig {
+            preview_text: None,
+            generate_text: Some(false),
+        };
+        apply(&mut blank, Rc::new(html.to_owned()), None, &config).unwrap();
+        assert!(blank.header.alternative_content.is_empty());
+    }
+}
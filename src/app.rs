@@ -1,27 +1,531 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
 use crate::errors::ErrorReport;
+use crate::exit::ExitCode;
+use crate::paths;
+use crate::retention::Pruned;
+
+/// The directory of the failure reports within the outbox, by default. It's not part of the outbox entries.
+pub(crate) const ERRORS_DIR: &str = "errors";
+
+/// The directory of the failure reports: `error_report_dir` relative to the binary directory, or the `errors`
+/// directory of the outbox.
+/// ## Error
+/// Fails if `error_report_dir` is another directory within the outbox, as its reports would be read as entries.
+pub(crate) fn error_report_dir(
+    outbox_path: &Path,
+    current_exe_dir: &Path,
+    error_report_dir: Option<&Path>,
+) -> anyhow::Result<PathBuf> {
+    let default_path = outbox_path.join(ERRORS_DIR);
+    let Some(error_report_dir) = error_report_dir else {
+        return Ok(default_path);
+    };
+    let report_path = current_exe_dir.join(error_report_dir);
+
+    // Compared once resolved, as `..` or a symbolic link may lead into the outbox
+    let resolved_report_path = paths::resolved(&report_path);
+    if resolved_report_path != paths::resolved(&default_path)
+        && resolved_report_path.starts_with(paths::resolved(outbox_path))
+    {
+        anyhow::bail!(
+            "The error reports directory \"{}\" is within the outbox \"{}\", other than its `{ERRORS_DIR}` directory",
+            report_path.display(),
+            outbox_path.display()
+        );
+    }
+
+    Ok(report_path)
+}
 
+#[derive(Default)]
 pub struct AppState {
-    error_reports: Option<Vec<ErrorReport>>,
+    error_reports: Vec<(FailureCategory, ErrorReport)>,
+    sent_emails: usize,
+    degraded_emails: usize,
+    failed_emails: usize,
+    pruned: Vec<Pruned>,
+    stop: Option<Stop>,
+}
+
+/// Why a run stopped before attempting every E-mail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stop {
+    /// By the circuit breaker, or a mail relay that is unreachable or refused the credentials
+    Aborted,
+
+    /// By `--max-run-time`, resumed by the next run
+    Resumable,
+
+    /// By Ctrl+C or SIGTERM
+    Interrupted,
+}
+
+/// What failed, to count the reported failures of a run by category.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FailureCategory {
+    /// An entry file that failed to parse or was rejected
+    Entry,
+    Render,
+    Build,
+    Send,
+
+    /// An entry file of a sent E-mail that couldn't be removed
+    Removal,
+
+    /// An image that couldn't be embedded into a sent E-mail, reported as warnings
+    Image,
+}
+
+impl fmt::Display for FailureCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureCategory::Entry => write!(f, "entry"),
+            FailureCategory::Render => write!(f, "render"),
+            FailureCategory::Build => write!(f, "build"),
+            FailureCategory::Send => write!(f, "send"),
+            FailureCategory::Removal => write!(f, "removal"),
+            FailureCategory::Image => write!(f, "image"),
+        }
+    }
+}
+
+/// A failure report along with its category, as written into the JSON reports.
+#[derive(Serialize)]
+struct CategorizedReport<'a> {
+    category: FailureCategory,
+    #[serde(flatten)]
+    report: &'a ErrorReport,
+}
+
+/// Writes a JSON report into the file.
+fn write_json_file(
+    path: &Path,
+    write_json: impl FnOnce(&mut BufWriter<fs::File>) -> serde_json::Result<()>,
+) -> anyhow::Result<()> {
+    let file = fs::File::create(path)
+        .with_context(|| format!("Unable to create the error report \"{}\"", path.display()))?;
+
+    let mut writer = BufWriter::new(file);
+    write_json(&mut writer)
+        .map_err(anyhow::Error::from)
+        .and_then(|()| Ok(writer.flush()?))
+        .with_context(|| format!("Unable to write the error report \"{}\"", path.display()))
 }
 
 impl AppState {
-    pub fn add_error_report(&mut self, error_report: ErrorReport) {
-        match self.error_reports {
-            Some(ref mut errors) => errors.push(error_report),
-            None => self.error_reports = Some(vec![error_report]),
+    /// Collects the report of a failure, for the summary of the run. Any report with errors fails the run.
+    pub(crate) fn add_error_report(
+        &mut self,
+        category: FailureCategory,
+        error_report: ErrorReport,
+    ) {
+        self.error_reports.push((category, error_report));
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn error_reports(&self) -> &[(FailureCategory, ErrorReport)] {
+        &self.error_reports
+    }
+
+    /// The reported failures counted by category, followed by every report with the causes of its errors.
+    /// `None` without any report.
+    pub(crate) fn failure_summary(&self) -> Option<String> {
+        if self.error_reports.is_empty() {
+            return None;
+        }
+
+        let mut counts: BTreeMap<FailureCategory, usize> = BTreeMap::new();
+        for (category, _) in &self.error_reports {
+            *counts.entry(*category).or_default() += 1;
+        }
+        let counts: Vec<String> = counts
+            .iter()
+            .map(|(category, count)| format!("{count} {category}"))
+            .collect();
+
+        let mut summary = format!(
+            "{} failure(s) reported: {}\n",
+            self.error_reports.len(),
+            counts.join(", ")
+        );
+        for (category, report) in &self.error_reports {
+            summary.push_str(&format!("[{category}] {report:#}"));
+        }
+
+        Some(summary)
+    }
+
+    /// Writes the reports as a JSON array, each with its category, context, entries, and errors with their
+    /// timestamp and causes. An empty array without any report.
+    pub(crate) fn write_json<W: Write>(&self, writer: W) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(writer, &self.categorized_reports())
+    }
+
+    /// Writes the report of the run as JSON: `{ "reports": [...], "retention": [...] }`, the reports as in
+    /// [`AppState::write_json`], along with each directory pruned by the retention rules, with the count and size of
+    /// the files it removed.
+    pub(crate) fn write_run_json<W: Write>(&self, writer: W) -> serde_json::Result<()> {
+        #[derive(Serialize)]
+        struct PrunedDir<'a> {
+            dir: &'a Path,
+            removed_files: usize,
+            freed_bytes: u64,
+            dry_run: bool,
+        }
+
+        #[derive(Serialize)]
+        struct RunReport<'a> {
+            reports: Vec<CategorizedReport<'a>>,
+            retention: Vec<PrunedDir<'a>>,
         }
+
+        let retention = self
+            .pruned
+            .iter()
+            .map(|pruned| PrunedDir {
+                dir: &pruned.dir,
+                removed_files: pruned.removed.len(),
+                freed_bytes: pruned.freed_bytes,
+                dry_run: pruned.dry_run,
+            })
+            .collect();
+
+        serde_json::to_writer_pretty(
+            writer,
+            &RunReport {
+                reports: self.categorized_reports(),
+                retention,
+            },
+        )
+    }
+
+    fn categorized_reports(&self) -> Vec<CategorizedReport<'_>> {
+        self.error_reports
+            .iter()
+            .map(|(category, report)| CategorizedReport {
+                category: *category,
+                report,
+            })
+            .collect()
+    }
+
+    /// Writes the report of the run as JSON into `dir`, named after the time of the run, e.g.
+    /// `20230101T100000000Z.json`, see [`AppState::write_run_json`]. Returns the file written, `None` without any
+    /// failure report nor file removed by the retention rules.
+    /// ## Error
+    /// Fails if the directory can't be created or the file written.
+    pub(crate) fn write_error_reports(
+        &self,
+        dir: &Path,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        let pruned_any = self.pruned.iter().any(|pruned| !pruned.removed.is_empty());
+        if self.error_reports.is_empty() && !pruned_any {
+            return Ok(None);
+        }
+
+        fs::create_dir_all(dir).with_context(|| {
+            format!(
+                "Unable to create the error reports directory \"{}\"",
+                dir.display()
+            )
+        })?;
+        let path = dir.join(format!("{}.json", now.format("%Y%m%dT%H%M%S%3fZ")));
+        write_json_file(&path, |writer| self.write_run_json(writer))?;
+
+        Ok(Some(path))
+    }
+
+    /// Writes the reports as a JSON array into the file, see [`AppState::write_json`].
+    /// ## Error
+    /// Fails if the file can't be written.
+    pub(crate) fn write_error_output(&self, path: &Path) -> anyhow::Result<()> {
+        write_json_file(path, |writer| self.write_json(writer))
+    }
+
+    pub(crate) fn record_sent(&mut self) {
+        self.sent_emails += 1;
     }
 
-    pub fn error_reports(&self) -> Option<&[ErrorReport]> {
-        self.error_reports.as_deref()
+    /// A sent E-mail degraded to plain text, as its template failed to render. Also recorded as sent.
+    pub(crate) fn record_degraded(&mut self) {
+        self.degraded_emails += 1;
+    }
+
+    /// An E-mail that failed at any stage, whether its entries are kept or moved aside.
+    pub(crate) fn record_failed(&mut self) {
+        self.failed_emails += 1;
+    }
+
+    /// A directory pruned by the retention rules, even of nothing.
+    pub(crate) fn record_pruned(&mut self, pruned: Pruned) {
+        self.pruned.push(pruned);
+    }
+
+    /// The first stop wins, an interrupted run is not aborted by a later check.
+    pub(crate) fn stop(&mut self, stop: Stop) {
+        self.stop.get_or_insert(stop);
+    }
+
+    #[inline]
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.stop.is_some()
+    }
+
+    #[inline]
+    pub(crate) fn sent_emails(&self) -> usize {
+        self.sent_emails
+    }
+
+    #[inline]
+    pub(crate) fn degraded_emails(&self) -> usize {
+        self.degraded_emails
+    }
+
+    #[inline]
+    pub(crate) fn failed_emails(&self) -> usize {
+        self.failed_emails
+    }
+
+    /// The exit code of the run summary. A run with nothing to send succeeds, unless `distinct_idle`.
+    pub(crate) fn exit_code(&self, distinct_idle: bool) -> ExitCode {
+        match self.stop {
+            Some(Stop::Interrupted) => return ExitCode::Interrupted,
+            Some(Stop::Aborted) => return ExitCode::Aborted,
+            Some(Stop::Resumable) => return ExitCode::Resumable,
+            None => {}
+        }
+
+        let failed = self.failed_emails > 0
+            || self
+                .error_reports
+                .iter()
+                .any(|(_, report)| !report.errors().is_empty());
+
+        match (self.sent_emails, failed) {
+            (0, false) if distinct_idle => ExitCode::Idle,
+            (_, false) => ExitCode::Success,
+            (0, true) => ExitCode::AllFailed,
+            _ => ExitCode::PartialFailure,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
 
     #[test]
     fn somekind() {
         assert_eq!(1, 1)
     }
+
+    fn run(sent: usize, failed: usize, stop: Option<Stop>) -> AppState {
+        let mut state = AppState::default();
+        (0..sent).for_each(|_| state.record_sent());
+        (0..failed).for_each(|_| state.record_failed());
+        if let Some(stop) = stop {
+            state.stop(stop);
+        }
+        state
+    }
+
+    #[test]
+    fn test_error_report_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox_path = dir.path().join("outbox");
+        fs::create_dir_all(&outbox_path).unwrap();
+        let exe_dir = dir.path().join("bin");
+
+        assert_eq!(
+            error_report_dir(&outbox_path, &exe_dir, None).unwrap(),
+            outbox_path.join(ERRORS_DIR)
+        );
+
+        // Relative to the binary directory
+        assert_eq!(
+            error_report_dir(&outbox_path, &exe_dir, Some(Path::new("reports"))).unwrap(),
+            exe_dir.join("reports")
+        );
+        assert!(
+            error_report_dir(&outbox_path, &exe_dir, Some(&outbox_path.join(ERRORS_DIR))).is_ok()
+        );
+
+        // Read back as entries by the next run
+        assert!(
+            error_report_dir(&outbox_path, &exe_dir, Some(&outbox_path.join("reports"))).is_err()
+        );
+        assert!(
+            error_report_dir(&outbox_path, &exe_dir, Some(Path::new("../outbox/reports"))).is_err()
+        );
+    }
+
+    #[test]
+    fn test_exit_code_of_run() {
+        assert_eq!(run(3, 0, None).exit_code(false), ExitCode::Success);
+        assert_eq!(run(3, 0, None).exit_code(true), ExitCode::Success);
+        assert_eq!(run(0, 0, None).exit_code(false), ExitCode::Success);
+        assert_eq!(run(0, 0, None).exit_code(true), ExitCode::Idle);
+        assert_eq!(run(2, 1, None).exit_code(false), ExitCode::PartialFailure);
+        assert_eq!(run(0, 2, None).exit_code(true), ExitCode::AllFailed);
+
+        let aborted = run(1, 5, Some(Stop::Aborted));
+        assert_eq!(aborted.exit_code(false), ExitCode::Aborted);
+        assert_eq!(aborted.exit_code(false).code(), 5);
+
+        let resumable = run(4, 0, Some(Stop::Resumable));
+        assert_eq!(resumable.exit_code(false).code(), 7);
+
+        let mut interrupted = run(0, 0, Some(Stop::Interrupted));
+        interrupted.stop(Stop::Aborted);
+        assert_eq!(interrupted.exit_code(true), ExitCode::Interrupted);
+        assert_eq!(interrupted.exit_code(true).code(), 130);
+    }
+
+    #[test]
+    fn test_failure_summary() {
+        use crate::errors::ErrorWrapper;
+        use anyhow::anyhow;
+
+        let mut state = run(2, 0, None);
+        assert_eq!(state.failure_summary(), None);
+
+        // A removal failure fails a run that sent every E-mail
+        state.add_error_report(
+            FailureCategory::Removal,
+            ErrorReport::new()
+                .set_context("outbox/a.json".to_owned())
+                .add_error(ErrorWrapper(anyhow!("Permission denied"))),
+        );
+        assert_eq!(state.exit_code(false), ExitCode::PartialFailure);
+
+        for email in ["0000abcd", "0000abce"] {
+            state.record_failed();
+            state.add_error_report(
+                FailureCategory::Render,
+                ErrorReport::new()
+                    .set_context(format!("E-mail `{email}` failed to render"))
+                    .add_error(ErrorWrapper(
+                        anyhow!("Variable `name` not found").context("Failed to render"),
+                    )),
+            );
+        }
+        assert_eq!(state.error_reports().len(), 3);
+
+        assert_eq!(
+            state.failure_summary().unwrap(),
+            "3 failure(s) reported: 2 render, 1 removal\n\
+            [removal] outbox/a.json:\n  - Permission denied\n\
+            [render] E-mail `0000abcd` failed to render:\n  - Failed to render\n    \
+            caused by: Variable `name` not found\n\
+            [render] E-mail `0000abce` failed to render:\n  - Failed to render\n    \
+            caused by: Variable `name` not found\n"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let now = "2023-01-01T10:00:00Z".parse().unwrap();
+        let path = state
+            .write_error_reports(&dir.path().join(ERRORS_DIR), now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(path, dir.path().join("errors/20230101T100000000Z.json"));
+
+        let written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        let categories: Vec<&str> = written["reports"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|report| report["category"].as_str().unwrap())
+            .collect();
+        assert_eq!(categories, ["removal", "render", "render"]);
+        assert_eq!(written["reports"][0]["context"], "outbox/a.json");
+        assert_eq!(
+            written["reports"][1]["errors"][0]["causes"][0],
+            "Variable `name` not found"
+        );
+
+        assert_eq!(written["retention"], serde_json::json!([]));
+
+        // `--error-output` is the array of the reports alone
+        let mut output = Vec::new();
+        state.write_json(&mut output).unwrap();
+        let output: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(output, written["reports"]);
+
+        // A run that only pruned files is still reported
+        let mut state = run(1, 0, None);
+        state.record_pruned(Pruned {
+            dir: PathBuf::from("sent_mail"),
+            removed: vec![
+                PathBuf::from("sent_mail/a.eml"),
+                PathBuf::from("sent_mail/b.eml"),
+            ],
+            freed_bytes: 300,
+            dry_run: false,
+        });
+        let path = state
+            .write_error_reports(&dir.path().join("pruned"), now)
+            .unwrap()
+            .unwrap();
+        let written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(
+            written,
+            serde_json::json!({
+                "reports": [],
+                "retention": [
+                    { "dir": "sent_mail", "removed_files": 2, "freed_bytes": 300, "dry_run": false }
+                ]
+            })
+        );
+        assert_eq!(state.exit_code(false), ExitCode::Success);
+
+        let mut output = Vec::new();
+        state.write_json(&mut output).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&output).unwrap(),
+            serde_json::json!([])
+        );
+
+        // Unlike a directory pruned of nothing
+        let mut state = run(1, 0, None);
+        state.record_pruned(Pruned {
+            dir: PathBuf::from("sent_mail"),
+            removed: Vec::new(),
+            freed_bytes: 0,
+            dry_run: false,
+        });
+        assert_eq!(
+            state
+                .write_error_reports(&dir.path().join("unpruned"), now)
+                .unwrap(),
+            None
+        );
+
+        // Unparsable entries fail a run with nothing to send
+        let mut state = run(0, 0, None);
+        state.add_error_report(
+            FailureCategory::Entry,
+            ErrorReport::new().add_error(ErrorWrapper(anyhow!("expected value"))),
+        );
+        assert_eq!(state.exit_code(true), ExitCode::AllFailed);
+
+        assert_eq!(
+            AppState::default()
+                .write_error_reports(&dir.path().join("empty"), now)
+                .unwrap(),
+            None
+        );
+        assert!(!dir.path().join("empty").exists());
+    }
 }
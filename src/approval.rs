@@ -0,0 +1,393 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::archive::archive_stem;
+use crate::entries::ComposedEmail;
+
+/// The directory of the E-mails parked until an operator approves them, next to the outbox.
+pub(crate) const APPROVAL_DIR: &str = "approval";
+
+const MANIFEST_FILE: &str = "manifest.json";
+const PREVIEW_FILE: &str = "preview.html";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ApprovalState {
+    Pending,
+    Approved,
+}
+
+impl fmt::Display for ApprovalState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApprovalState::Pending => write!(f, "pending"),
+            ApprovalState::Approved => write!(f, "approved"),
+        }
+    }
+}
+
+/// A parked E-mail, written next to its `preview.html`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Manifest {
+    pub(crate) email_id: u32,
+    pub(crate) page: Option<usize>,
+    pub(crate) template: String,
+    pub(crate) subject: String,
+
+    /// The recipients (to, cc and bcc) of the E-mail
+    pub(crate) recipients: Vec<String>,
+
+    /// The outbox entries of the E-mail, dead-lettered when it's denied
+    pub(crate) entries: Vec<PathBuf>,
+
+    /// The content the approval was given for. A changed E-mail is parked again
+    pub(crate) content_hash: String,
+    pub(crate) state: ApprovalState,
+    pub(crate) parked_at: DateTime<Utc>,
+    pub(crate) approved_at: Option<DateTime<Utc>>,
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:08x} ({}): `{}` to {}",
+            self.email_id,
+            self.state,
+            self.subject,
+            self.recipients.join(", ")
+        )
+    }
+}
+
+impl Manifest {
+    fn new(email: &ComposedEmail, entries: Vec<PathBuf>, now: DateTime<Utc>) -> Self {
+        let header = &email.header;
+
+        Self {
+            email_id: email.id,
+            page: email.page.map(|page| page.number),
+            template: header.template.clone(),
+            subject: header.subject.clone(),
+            recipients: [&header.to, &header.cc, &header.bcc]
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect(),
+            entries,
+            content_hash: email.content_hash(),
+            state: ApprovalState::Pending,
+            parked_at: now,
+            approved_at: None,
+        }
+    }
+
+    /// Whether the approval was given within `expiry` of `now`.
+    fn is_approved(&self, now: DateTime<Utc>, expiry: Duration) -> bool {
+        self.state == ApprovalState::Approved
+            && self
+                .approved_at
+                .is_some_and(|approved_at| now - approved_at < expiry)
+    }
+
+    fn load(parked_dir: &Path) -> Result<Option<Self>> {
+        let path = parked_dir.join(MANIFEST_FILE);
+
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read approval manifest \"{}\"", path.display()))?;
+
+        serde_json::from_str(&contents)
+            .map(Some)
+            .with_context(|| format!("Unable to parse approval manifest \"{}\"", path.display()))
+    }
+
+    fn save(&self, parked_dir: &Path) -> Result<()> {
+        let path = parked_dir.join(MANIFEST_FILE);
+        let contents =
+            serde_json::to_string_pretty(self).expect("Approval manifest is always valid JSON");
+
+        fs::write(&path, contents)
+            .with_context(|| format!("Unable to write approval manifest \"{}\"", path.display()))
+    }
+}
+
+/// Whether an approval-gated E-mail can be sent, or was parked for an operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Gate {
+    Approved,
+    Parked,
+}
+
+/// Lets an approved E-mail through, or parks it with a preview of its rendered HTML.
+/// An approval older than `expiry`, or given for a different content, is pending again.
+pub(crate) fn gate(
+    approval_dir: &Path,
+    email: &ComposedEmail,
+    html: &str,
+    entries: Vec<PathBuf>,
+    now: DateTime<Utc>,
+    expiry: Duration,
+) -> Result<Gate> {
+    let parked_dir = approval_dir.join(archive_stem(email));
+    let content_hash = email.content_hash();
+
+    match Manifest::load(&parked_dir)? {
+        Some(manifest) if manifest.content_hash == content_hash => {
+            if manifest.is_approved(now, expiry) {
+                return Ok(Gate::Approved);
+            }
+
+            if manifest.state == ApprovalState::Approved {
+                // Expired, back into pending
+                let manifest = Manifest {
+                    state: ApprovalState::Pending,
+                    approved_at: None,
+                    ..manifest
+                };
+                manifest.save(&parked_dir)?;
+            }
+        }
+        _ => {
+            fs::create_dir_all(&parked_dir).with_context(|| {
+                format!(
+                    "Unable to create approval directory \"{}\"",
+                    parked_dir.display()
+                )
+            })?;
+
+            let preview_path = parked_dir.join(PREVIEW_FILE);
+            fs::write(&preview_path, html).with_context(|| {
+                format!(
+                    "Unable to write approval preview \"{}\"",
+                    preview_path.display()
+                )
+            })?;
+
+            Manifest::new(email, entries, now).save(&parked_dir)?;
+        }
+    }
+
+    Ok(Gate::Parked)
+}
+
+/// The parked E-mails, with their directory.
+pub(crate) fn parked(approval_dir: &Path) -> Result<Vec<(PathBuf, Manifest)>> {
+    if !approval_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut parked = Vec::new();
+
+    let dir_entries = fs::read_dir(approval_dir).with_context(|| {
+        format!(
+            "Unable to read approval directory \"{}\"",
+            approval_dir.display()
+        )
+    })?;
+
+    for dir_entry in dir_entries {
+        let path = dir_entry?.path();
+
+        if let Some(manifest) = Manifest::load(&path)? {
+            parked.push((path, manifest));
+        }
+    }
+
+    parked.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(parked)
+}
+
+/// Whether a parked E-mail is selected by an E-mail ID, as printed in hex. All are selected without one.
+fn is_selected(manifest: &Manifest, email_id: Option<&str>) -> Result<bool> {
+    let Some(email_id) = email_id else {
+        return Ok(true);
+    };
+
+    let email_id = u32::from_str_radix(email_id.trim(), 16)
+        .with_context(|| format!("Invalid E-mail ID `{email_id}`"))?;
+
+    Ok(manifest.email_id == email_id)
+}
+
+/// Approves the parked E-mails of an E-mail ID (every page of it), or all of them without one.
+/// Returns the approved E-mails.
+pub(crate) fn approve(
+    approval_dir: &Path,
+    email_id: Option<&str>,
+    now: DateTime<Utc>,
+) -> Result<Vec<Manifest>> {
+    let mut approved = Vec::new();
+
+    for (parked_dir, manifest) in parked(approval_dir)? {
+        if !is_selected(&manifest, email_id)? {
+            continue;
+        }
+
+        let manifest = Manifest {
+            state: ApprovalState::Approved,
+            approved_at: Some(now),
+            ..manifest
+        };
+        manifest.save(&parked_dir)?;
+        approved.push(manifest);
+    }
+
+    Ok(approved)
+}
+
+/// Removes the parked E-mails of an E-mail ID, returning them so their entries can be dead-lettered.
+pub(crate) fn deny(approval_dir: &Path, email_id: &str) -> Result<Vec<Manifest>> {
+    let mut denied = Vec::new();
+
+    for (parked_dir, manifest) in parked(approval_dir)? {
+        if !is_selected(&manifest, Some(email_id))? {
+            continue;
+        }
+
+        remove_parked(&parked_dir)?;
+        denied.push(manifest);
+    }
+
+    Ok(denied)
+}
+
+/// Removes a parked E-mail once it was sent.
+pub(crate) fn release(approval_dir: &Path, email: &ComposedEmail) -> Result<()> {
+    let parked_dir = approval_dir.join(archive_stem(email));
+
+    if parked_dir.is_dir() {
+        remove_parked(&parked_dir)?;
+    }
+
+    Ok(())
+}
+
+fn remove_parked(parked_dir: &Path) -> Result<()> {
+    fs::remove_dir_all(parked_dir).with_context(|| {
+        format!(
+            "Unable to remove approval directory \"{}\"",
+            parked_dir.display()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entries::Email;
+
+    fn email(subject: &str) -> ComposedEmail {
+        ComposedEmail {
+            id: 0xabc,
+            header: Email {
+                to: vec!["customers@x.com".to_owned()],
+                subject: subject.to_owned(),
+                template: "announcement".to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parked_until_approved() {
+        let dir = tempfile::tempdir().unwrap();
+        let approval_dir = dir.path().join(APPROVAL_DIR);
+        let email = email("New pricing");
+        let entries = vec![dir.path().join("outbox").join("entry.json")];
+        let now = Utc::now();
+        let expiry = Duration::hours(24);
+
+        let gate_at = |now| {
+            gate(
+                &approval_dir,
+                &email,
+                "<p>Hi</p>",
+                entries.clone(),
+                now,
+                expiry,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(gate_at(now), Gate::Parked);
+        assert!(approval_dir.join("00000abc").join(PREVIEW_FILE).is_file());
+
+        // Parking again keeps the original manifest
+        assert_eq!(gate_at(now + Duration::minutes(5)), Gate::Parked);
+        let (_, manifest) = parked(&approval_dir).unwrap().remove(0);
+        assert_eq!(manifest.state, ApprovalState::Pending);
+        assert_eq!(manifest.parked_at, now);
+        assert_eq!(manifest.entries, entries);
+
+        assert!(approve(&approval_dir, Some("fff"), now).unwrap().is_empty());
+        let approved = approve(&approval_dir, Some("00000ABC"), now).unwrap();
+        assert_eq!(approved.len(), 1);
+
+        // Survives a restart: the state is only read back from the manifest
+        assert_eq!(gate_at(now + Duration::minutes(1)), Gate::Approved);
+
+        release(&approval_dir, &email).unwrap();
+        assert!(parked(&approval_dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_approval_expires_back_into_pending() {
+        let dir = tempfile::tempdir().unwrap();
+        let parked_email = email("New pricing");
+        let now = Utc::now();
+        let expiry = Duration::hours(24);
+
+        gate(dir.path(), &parked_email, "", Vec::new(), now, expiry).unwrap();
+        approve(dir.path(), None, now).unwrap();
+
+        let later = now + Duration::hours(25);
+        let outcome = gate(dir.path(), &parked_email, "", Vec::new(), later, expiry).unwrap();
+        assert_eq!(outcome, Gate::Parked);
+
+        let (_, manifest) = parked(dir.path()).unwrap().remove(0);
+        assert_eq!(manifest.state, ApprovalState::Pending);
+        assert_eq!(manifest.approved_at, None);
+
+        // An approval is given for the content it previewed
+        approve(dir.path(), None, later).unwrap();
+        let changed = email("New pricing, corrected");
+        let outcome = gate(dir.path(), &changed, "", Vec::new(), later, expiry).unwrap();
+        assert_eq!(outcome, Gate::Parked);
+    }
+
+    #[test]
+    fn test_deny_returns_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries = vec![
+            PathBuf::from("outbox/a.json"),
+            PathBuf::from("outbox/b.json"),
+        ];
+        let now = Utc::now();
+
+        gate(
+            dir.path(),
+            &email("New pricing"),
+            "",
+            entries.clone(),
+            now,
+            Duration::hours(24),
+        )
+        .unwrap();
+
+        assert!(deny(dir.path(), "zz").is_err());
+
+        let denied = deny(dir.path(), "abc").unwrap();
+        assert_eq!(denied.len(), 1);
+        assert_eq!(denied[0].entries, entries);
+        assert!(parked(dir.path()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{Context, Result};
+use similar::TextDiff;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::entries::ComposedEmail;
+
+const HTML_EXT: &str = "html";
+const TEXT_EXT: &str = "txt";
+pub(crate) const DIFF_EXT: &str = "diff";
+
+/// The file stem of a composed E-mail within the archive: its E-mail ID, and page number when paged.
+pub(crate) fn archive_stem(email: &ComposedEmail) -> String {
+    match email.page {
+        Some(page) => format!("{:08x}-{}", email.id, page.number),
+        None => format!("{:08x}", email.id),
+    }
+}
+
+/// Stores the rendered HTML and the text alternative of a sent E-mail, replacing its previous render.
+pub(crate) fn store(archive_dir: &Path, email: &ComposedEmail, html: &str) -> Result<()> {
+    fs::create_dir_all(archive_dir).with_context(|| {
+        format!(
+            "Unable to create archive directory \"{}\"",
+            archive_dir.display()
+        )
+    })?;
+
+    let stem = archive_stem(email);
+    let write = |extension: &str, contents: &str| {
+        let path = archive_dir.join(&stem).with_extension(extension);
+        fs::write(&path, contents)
+            .with_context(|| format!("Unable to write archived render \"{}\"", path.display()))
+    };
+
+    write(HTML_EXT, html)?;
+    write(TEXT_EXT, &email.header.alternative_content)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DiffOutcome {
+    Changed,
+    Unchanged,
+    /// No render of the E-mail was archived yet
+    New,
+}
+
+#[derive(Debug)]
+pub(crate) struct EmailDiff {
+    pub(crate) stem: String,
+    pub(crate) outcome: DiffOutcome,
+
+    /// Unified diff of the HTML and the text alternative, when changed
+    pub(crate) diff: Option<String>,
+}
+
+/// Compares the current render of an E-mail against its archived render.
+/// With `ignore_whitespace`, lines are compared with their whitespace collapsed and blank lines are skipped.
+pub(crate) fn diff_email(
+    archive_dir: &Path,
+    email: &ComposedEmail,
+    html: &str,
+    ignore_whitespace: bool,
+) -> Result<EmailDiff> {
+    let stem = archive_stem(email);
+    let html_path = archive_dir.join(&stem).with_extension(HTML_EXT);
+
+    if !html_path.is_file() {
+        return Ok(EmailDiff {
+            stem,
+            outcome: DiffOutcome::New,
+            diff: None,
+        });
+    }
+
+    let read = |path: &Path| {
+        fs::read_to_string(path)
+            .with_context(|| format!("Unable to read archived render \"{}\"", path.display()))
+    };
+
+    let archived_html = read(&html_path)?;
+
+    // Renders archived without a text alternative compare as an empty one
+    let text_path = html_path.with_extension(TEXT_EXT);
+    let archived_text = if text_path.is_file() {
+        read(&text_path)?
+    } else {
+        String::new()
+    };
+
+    let diffs: Vec<String> = [
+        (HTML_EXT, archived_html.as_str(), html),
+        (
+            TEXT_EXT,
+            archived_text.as_str(),
+            email.header.alternative_content.as_str(),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(extension, old, new)| {
+        unified_diff(&format!("{stem}.{extension}"), old, new, ignore_whitespace)
+    })
+    .collect();
+
+    Ok(if diffs.is_empty() {
+        EmailDiff {
+            stem,
+            outcome: DiffOutcome::Unchanged,
+            diff: None,
+        }
+    } else {
+        EmailDiff {
+            stem,
+            outcome: DiffOutcome::Changed,
+            diff: Some(diffs.concat()),
+        }
+    })
+}
+
+/// Returns the unified diff between the two texts, or `None` when they're equal.
+fn unified_diff(name: &str, old: &str, new: &str, ignore_whitespace: bool) -> Option<String> {
+    let (old, new) = if ignore_whitespace {
+        (collapse_whitespace(old), collapse_whitespace(new))
+    } else {
+        (old.to_owned(), new.to_owned())
+    };
+
+    if old == new {
+        return None;
+    }
+
+    Some(
+        TextDiff::from_lines(&old, &new)
+            .unified_diff()
+            .header(&format!("sent/{name}"), &format!("next/{name}"))
+            .to_string(),
+    )
+}
+
+/// Trims every line, collapses its inner whitespace runs and drops blank lines.
+fn collapse_whitespace(text: &str) -> String {
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .map(|line| line + "\n")
+        .collect()
+}
+
+/// How many E-mails changed since they were last sent.
+#[derive(Debug, Default)]
+pub(crate) struct DiffSummary {
+    pub(crate) changed: usize,
+    pub(crate) unchanged: usize,
+    pub(crate) new: usize,
+}
+
+impl DiffSummary {
+    pub(crate) fn add(&mut self, outcome: DiffOutcome) {
+        match outcome {
+            DiffOutcome::Changed => self.changed += 1,
+            DiffOutcome::Unchanged => self.unchanged += 1,
+            DiffOutcome::New => self.new += 1,
+        }
+    }
+}
+
+impl fmt::Display for DiffSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} changed, {} unchanged, {} new",
+            self.changed, self.unchanged, self.new
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_against_archived_render() {
+        let archive = tempfile::tempdir().unwrap();
+        let email = ComposedEmail {
+            id: 0xabc,
+            ..Default::default()
+        };
+
+        let sent = "<h1>Daily report</h1>\n<p>3 jobs failed</p>\n<footer>Ops</footer>\n";
+        let outcome = diff_email(archive.path(), &email, sent, false).unwrap();
+        assert_eq!(outcome.outcome, DiffOutcome::New);
+
+        store(archive.path(), &email, sent).unwrap();
+        assert!(archive.path().join("00000abc.html").is_file());
+
+        let outcome = diff_email(archive.path(), &email, sent, false).unwrap();
+        assert_eq!(outcome.outcome, DiffOutcome::Unchanged);
+
+        // The template changed the body block
+        let next = "<h1>Daily report</h1>\n<p><b>3</b> jobs failed</p>\n<footer>Ops</footer>\n";
+        let outcome = diff_email(archive.path(), &email, next, false).unwrap();
+        assert_eq!(outcome.outcome, DiffOutcome::Changed);
+
+        let diff = outcome.diff.unwrap();
+        assert!(diff.contains("--- sent/00000abc.html"));
+        assert!(diff.contains("-<p>3 jobs failed</p>"));
+        assert!(diff.contains("+<p><b>3</b> jobs failed</p>"));
+        assert!(!diff.contains("-<h1>"));
+    }
+
+    #[test]
+    fn test_diff_ignore_whitespace() {
+        let archive = tempfile::tempdir().unwrap();
+        let email = ComposedEmail::default();
+
+        store(archive.path(), &email, "<p>\n  Hello   world\n</p>\n").unwrap();
+
+        let reformatted = "<p>\n\n\tHello world\n</p>";
+        let outcome = diff_email(archive.path(), &email, reformatted, true).unwrap();
+        assert_eq!(outcome.outcome, DiffOutcome::Unchanged);
+
+        let outcome = diff_email(archive.path(), &email, reformatted, false).unwrap();
+        assert_eq!(outcome.outcome, DiffOutcome::Changed);
+    }
+}
@@ -0,0 +1,301 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::sent_log::LogRecord;
+
+/// The append-only index of the audit archive, one JSON record per sent message.
+pub(crate) const INDEX_FILE: &str = "index.jsonl";
+
+const EML_EXT: &str = "eml";
+
+/// A message stored in the audit archive of `--archive-cas`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct IndexRecord {
+    pub(crate) sent_at: DateTime<Utc>,
+    pub(crate) email_id: u32,
+
+    /// The SHA-256 of the formatted message, naming its `.eml` file
+    pub(crate) sha256: String,
+    pub(crate) recipients: Vec<String>,
+}
+
+impl LogRecord for IndexRecord {
+    #[inline]
+    fn sent_at(&self) -> DateTime<Utc> {
+        self.sent_at
+    }
+}
+
+/// The lowercase hex SHA-256 of the bytes.
+fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The file of a message within the audit archive.
+fn eml_path(archive_dir: &Path, sha256: &str) -> PathBuf {
+    archive_dir.join(sha256).with_extension(EML_EXT)
+}
+
+/// Stores a sent message as `<sha256>.eml` and appends it to the index, which is synced to disk before returning,
+/// so the entries of the message are only removed once it is audited. The message is written to a temporary file
+/// first, and renamed into place once complete.
+/// ## Error
+/// Fails if the message or the index can't be written.
+pub(crate) fn store(
+    archive_dir: &Path,
+    email_id: u32,
+    formatted: &[u8],
+    recipients: &[String],
+) -> Result<IndexRecord> {
+    fs::create_dir_all(archive_dir).with_context(|| {
+        format!(
+            "Unable to create audit archive directory \"{}\"",
+            archive_dir.display()
+        )
+    })?;
+
+    let sha256 = sha256_hex(formatted);
+    let path = eml_path(archive_dir, &sha256);
+
+    // Identical messages share their file
+    if !path.is_file() {
+        let temp_path = path.with_extension(format!("{EML_EXT}.tmp"));
+
+        let write = || -> std::io::Result<()> {
+            let mut file = File::create(&temp_path)?;
+            file.write_all(formatted)?;
+            file.sync_all()?;
+            fs::rename(&temp_path, &path)
+        };
+
+        if let Err(e) = write() {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e).with_context(|| {
+                format!("Unable to write audited message \"{}\"", path.display())
+            });
+        }
+    }
+
+    let record = IndexRecord {
+        sent_at: Utc::now(),
+        email_id,
+        sha256,
+        recipients: recipients.to_vec(),
+    };
+
+    let index_path = archive_dir.join(INDEX_FILE);
+    let line = serde_json::to_string(&record).expect("Log record is always valid JSON");
+
+    let mut index = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&index_path)
+        .with_context(|| {
+            format!(
+                "Unable to open audit archive index \"{}\"",
+                index_path.display()
+            )
+        })?;
+
+    writeln!(index, "{line}")
+        .and_then(|_| index.sync_all())
+        .with_context(|| {
+            format!(
+                "Unable to write audit archive index \"{}\"",
+                index_path.display()
+            )
+        })?;
+
+    Ok(record)
+}
+
+/// A problem found by [`verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Finding {
+    /// The stored message doesn't hash to its name: it was altered
+    Mismatch { file: String, sha256: String },
+
+    /// An indexed message has no stored file
+    Missing { email_id: u32, sha256: String },
+
+    /// A stored message is not indexed
+    Unindexed(String),
+
+    /// A line of the index can't be parsed
+    Malformed { line: usize },
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::Mismatch { file, sha256 } => {
+                write!(f, "MISMATCH  {file}: the contents hash to {sha256}")
+            }
+            Finding::Missing { email_id, sha256 } => write!(
+                f,
+                "MISSING   {sha256}.{EML_EXT}: indexed for E-mail `{email_id:08x}`"
+            ),
+            Finding::Unindexed(file) => write!(f, "UNINDEXED {file}: not in the index"),
+            Finding::Malformed { line } => {
+                write!(
+                    f,
+                    "MALFORMED {INDEX_FILE}:{line}: unable to parse the record"
+                )
+            }
+        }
+    }
+}
+
+/// Re-hashes every message of the audit archive and checks it against the index.
+/// ## Error
+/// Fails if the archive directory or one of its messages can't be read.
+pub(crate) fn verify(archive_dir: &Path) -> Result<Vec<Finding>> {
+    let index_path = archive_dir.join(INDEX_FILE);
+
+    let index = if index_path.is_file() {
+        fs::read_to_string(&index_path).with_context(|| {
+            format!(
+                "Unable to read audit archive index \"{}\"",
+                index_path.display()
+            )
+        })?
+    } else {
+        String::new()
+    };
+
+    let mut findings = Vec::new();
+    let mut indexed = HashSet::new();
+
+    for (number, line) in index.lines().enumerate() {
+        match serde_json::from_str::<IndexRecord>(line) {
+            Ok(record) => {
+                if indexed.insert(record.sha256.clone())
+                    && !eml_path(archive_dir, &record.sha256).is_file()
+                {
+                    findings.push(Finding::Missing {
+                        email_id: record.email_id,
+                        sha256: record.sha256,
+                    });
+                }
+            }
+            Err(_) => findings.push(Finding::Malformed { line: number + 1 }),
+        }
+    }
+
+    let mut stored: Vec<PathBuf> = fs::read_dir(archive_dir)
+        .with_context(|| {
+            format!(
+                "Unable to read audit archive directory \"{}\"",
+                archive_dir.display()
+            )
+        })?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == EML_EXT))
+        .collect();
+    stored.sort();
+
+    for path in stored {
+        let file = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let contents = fs::read(&path)
+            .with_context(|| format!("Unable to read audited message \"{}\"", path.display()))?;
+        let sha256 = sha256_hex(&contents);
+
+        if sha256 != name {
+            findings.push(Finding::Mismatch {
+                file: file.clone(),
+                sha256,
+            });
+        }
+
+        if !indexed.contains(&name) {
+            findings.push(Finding::Unindexed(file));
+        }
+    }
+
+    Ok(findings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive_dir = dir.path().join("audit");
+        let recipients = vec!["ops@x.com".to_owned()];
+
+        let first = store(&archive_dir, 1, b"Subject: first\r\n\r\nBody", &recipients).unwrap();
+        let second = store(&archive_dir, 2, b"Subject: second\r\n\r\nBody", &recipients).unwrap();
+
+        assert_eq!(first.sha256.len(), 64);
+        assert_ne!(first.sha256, second.sha256);
+        assert!(verify(&archive_dir).unwrap().is_empty());
+
+        // A resent message is indexed again, but stored once
+        store(&archive_dir, 1, b"Subject: first\r\n\r\nBody", &recipients).unwrap();
+        assert!(verify(&archive_dir).unwrap().is_empty());
+
+        let tampered = eml_path(&archive_dir, &second.sha256);
+        fs::write(&tampered, "Subject: second\r\n\r\nAltered").unwrap();
+
+        let findings = verify(&archive_dir).unwrap();
+        assert_eq!(
+            findings,
+            vec![Finding::Mismatch {
+                file: format!("{}.eml", second.sha256),
+                sha256: sha256_hex(b"Subject: second\r\n\r\nAltered"),
+            }]
+        );
+        assert!(findings[0].to_string().starts_with("MISMATCH  "));
+    }
+
+    #[test]
+    fn test_verify_archive_gaps() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive_dir = dir.path();
+
+        let record = store(archive_dir, 1, b"indexed", &[]).unwrap();
+        fs::remove_file(eml_path(archive_dir, &record.sha256)).unwrap();
+
+        let unindexed = sha256_hex(b"unindexed");
+        fs::write(eml_path(archive_dir, &unindexed), "unindexed").unwrap();
+
+        let index_path = archive_dir.join(INDEX_FILE);
+        fs::write(
+            &index_path,
+            fs::read_to_string(&index_path).unwrap() + "{ partially written\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            verify(archive_dir).unwrap(),
+            vec![
+                Finding::Missing {
+                    email_id: 1,
+                    sha256: record.sha256,
+                },
+                Finding::Malformed { line: 2 },
+                Finding::Unindexed(format!("{unindexed}.eml")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,627 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::entries::{self, ComposedEmail, Entry, ENTRY_EXT};
+use crate::send::{MessageBuilder, NullTransport, Transport};
+use crate::signing::Verifier;
+use crate::templates::TemplateConfig;
+
+/// The sender and recipient of the synthesized entries, on a reserved domain.
+const BENCH_ADDRESS: &str = "bench@example.invalid";
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub(crate) enum BenchError {
+    #[error("Unknown bench format `{0}`")]
+    UnknownFormat(String),
+
+    #[error("The batch ratio {0} is not between 0 and 1, excluded")]
+    InvalidBatchRatio(f64),
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BenchFormat {
+    #[default]
+    Table,
+    Json,
+}
+
+impl fmt::Display for BenchFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BenchFormat::Table => write!(f, "table"),
+            BenchFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for BenchFormat {
+    type Err = BenchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let res = match s.trim().to_lowercase().as_str() {
+            "table" => BenchFormat::Table,
+            "json" => BenchFormat::Json,
+            _ => return Err(BenchError::UnknownFormat(s.to_string())),
+        };
+
+        Ok(res)
+    }
+}
+
+/// The entries written into a temporary outbox by [`synthesize`].
+#[derive(Debug, Clone)]
+pub(crate) struct Synthesis {
+    pub(crate) entries: usize,
+    pub(crate) template: String,
+
+    /// The share of the entries accumulated into the E-mail of another entry, from 0 to 1, excluded
+    pub(crate) batch_ratio: f64,
+
+    /// The context of every entry, along with its accumulated `+bench` value
+    pub(crate) context: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Synthesis {
+    /// The E-mails the entries compose into, at least one.
+    pub(crate) fn emails(&self) -> usize {
+        ((self.entries as f64 * (1.0 - self.batch_ratio)).round() as usize)
+            .clamp(1, self.entries.max(1))
+    }
+}
+
+/// Writes the entries of the synthesis into the outbox, the way a producer would, each parsed and validated as
+/// an outbox entry first. Entry `n` belongs to E-mail `n % emails`, so the E-mails accumulate evenly.
+/// Returns the count of E-mails the entries compose into.
+/// ## Error
+/// Fails if the batch ratio is invalid, an entry is invalid, e.g. its template is unknown, or can't be written.
+pub(crate) fn synthesize(
+    outbox_path: &Path,
+    templates_path: &Path,
+    synthesis: &Synthesis,
+) -> Result<usize> {
+    if !(0.0..1.0).contains(&synthesis.batch_ratio) {
+        return Err(BenchError::InvalidBatchRatio(synthesis.batch_ratio).into());
+    }
+
+    fs::create_dir_all(outbox_path)
+        .with_context(|| format!("Unable to create outbox \"{}\"", outbox_path.display()))?;
+
+    let emails = synthesis.emails();
+    let utc = chrono::Utc::now().to_rfc3339();
+
+    for n in 0..synthesis.entries {
+        let mut context = synthesis.context.clone();
+        context.insert(
+            "+bench".to_owned(),
+            serde_json::json!({ "entry": n, "email": n % emails }),
+        );
+
+        let entry: Entry = serde_json::from_value(serde_json::json!({
+            "id": format!("bench-{n:08}"),
+            "utc": utc,
+            "notify_error": [],
+            "email": {
+                "system": "bench",
+                "subsystem": "bench",
+                "from": BENCH_ADDRESS,
+                "to": [BENCH_ADDRESS],
+                "cc": [],
+                "bcc": [],
+                "reply_to": [],
+                "subject": format!("Bench {:08}", n % emails),
+                "template": synthesis.template,
+                "alternative_content": "Bench",
+                "attachments": [],
+                "unique_by": ""
+            },
+            "context": context
+        }))
+        .context("Unable to synthesize a bench entry")?;
+        entries::validate_entry(&entry, templates_path)?;
+
+        let path = outbox_path.join(format!("bench-{n:08}{ENTRY_EXT}"));
+        fs::write(
+            &path,
+            serde_json::to_string(&entry).expect("An entry is always valid JSON"),
+        )
+        .with_context(|| format!("Unable to write entry \"{}\"", path.display()))?;
+    }
+
+    Ok(emails)
+}
+
+/// A stage of the pipeline, as timed by [`StageTimer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stage {
+    /// Reading and parsing the entry files
+    Load,
+
+    /// Accumulating the entries into E-mails, then paging them
+    Compose,
+
+    /// Rendering the templates
+    Render,
+
+    /// Building and formatting the messages
+    Build,
+
+    /// Handing the messages to the transport
+    Send,
+}
+
+impl Stage {
+    const ALL: [Stage; 5] = [
+        Stage::Load,
+        Stage::Compose,
+        Stage::Render,
+        Stage::Build,
+        Stage::Send,
+    ];
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Load => write!(f, "load"),
+            Stage::Compose => write!(f, "compose"),
+            Stage::Render => write!(f, "render"),
+            Stage::Build => write!(f, "build"),
+            Stage::Send => write!(f, "send"),
+        }
+    }
+}
+
+/// The time spent in every stage of the pipeline, summed over the E-mails.
+#[derive(Debug, Default)]
+pub(crate) struct StageTimer {
+    elapsed: [Duration; Stage::ALL.len()],
+}
+
+impl StageTimer {
+    /// Runs `f`, adding the time it took to the stage.
+    pub(crate) fn time<T>(&mut self, stage: Stage, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        self.elapsed[stage as usize] += started.elapsed();
+        result
+    }
+
+    #[inline]
+    pub(crate) fn elapsed(&self, stage: Stage) -> Duration {
+        self.elapsed[stage as usize]
+    }
+
+    #[inline]
+    pub(crate) fn total(&self) -> Duration {
+        self.elapsed.iter().sum()
+    }
+}
+
+/// The time spent in a stage.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct StageTiming {
+    pub(crate) stage: String,
+    pub(crate) seconds: f64,
+
+    /// The share of the pipeline time, in percent
+    pub(crate) percent: f64,
+}
+
+/// The throughput of a bench run.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct BenchReport {
+    pub(crate) entries: usize,
+    pub(crate) emails: usize,
+
+    /// The messages handed to the transport, one per page of an E-mail
+    pub(crate) messages: usize,
+    pub(crate) failed: usize,
+
+    /// The time spent in the pipeline, from loading the entries to sending the last message
+    pub(crate) seconds: f64,
+    pub(crate) entries_per_sec: f64,
+    pub(crate) emails_per_sec: f64,
+    pub(crate) stages: Vec<StageTiming>,
+
+    /// The peak resident memory of the process, when the OS reports it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) peak_memory_bytes: Option<u64>,
+}
+
+impl BenchReport {
+    pub(crate) fn new(
+        entries: usize,
+        emails: usize,
+        messages: usize,
+        failed: usize,
+        timer: &StageTimer,
+    ) -> Self {
+        let seconds = timer.total().as_secs_f64();
+        let per_sec = |count: usize| {
+            if seconds > 0.0 {
+                count as f64 / seconds
+            } else {
+                0.0
+            }
+        };
+
+        let stages = Stage::ALL
+            .iter()
+            .map(|stage| {
+                let stage_seconds = timer.elapsed(*stage).as_secs_f64();
+                StageTiming {
+                    stage: stage.to_string(),
+                    seconds: stage_seconds,
+                    percent: if seconds > 0.0 {
+                        stage_seconds / seconds * 100.0
+                    } else {
+                        0.0
+                    },
+                }
+            })
+            .collect();
+
+        Self {
+            entries,
+            emails,
+            messages,
+            failed,
+            seconds,
+            entries_per_sec: per_sec(entries),
+            emails_per_sec: per_sec(emails),
+            stages,
+            peak_memory_bytes: peak_memory(),
+        }
+    }
+}
+
+/// The peak resident memory of the process, read from the `VmHWM` line of `/proc/self/status`.
+/// None where there is no such file, e.g. on Windows.
+pub(crate) fn peak_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|value| value.trim().strip_suffix("kB"))
+        .and_then(|kilobytes| kilobytes.trim().parse::<u64>().ok())
+        .map(|kilobytes| kilobytes * 1024)
+}
+
+/// Drains the outbox through the pipeline of a run, without removing its entries: loads and composes them, pages
+/// the E-mails, renders them with `render`, builds their messages and sends them to a null transport discarding
+/// them. A failing E-mail is counted and skipped. Returns the report of the run.
+/// ## Error
+/// Fails if the null transport can't be established, which it always can.
+pub(crate) fn run(
+    outbox_path: &Path,
+    templates_path: &Path,
+    resources_path: &Path,
+    verifier: &Verifier,
+    mut render: impl FnMut(&mut ComposedEmail, &HashMap<String, TemplateConfig>) -> Result<Rc<String>>,
+) -> Result<BenchReport> {
+    let mut timer = StageTimer::default();
+    let mut transport = NullTransport;
+    transport.establish(None)?;
+
+    let loaded = timer.time(Stage::Load, || entries::load_entries(outbox_path, verifier));
+    let mut failed = loaded.err.len() + loaded.rejected.len();
+
+    let mut template_configs: HashMap<String, TemplateConfig> = HashMap::new();
+    let pages = timer.time(Stage::Compose, || {
+        let composed = entries::compose_emails(&entries::map_emails(&loaded.ok)).ok;
+        let emails = composed.len();
+        let mut pages = Vec::new();
+
+        for email in composed {
+            if email.header.is_raw() {
+                pages.push(email);
+                continue;
+            }
+
+            let template = email.header.template.clone();
+            if !template_configs.contains_key(&template) {
+                match TemplateConfig::load(templates_path.join(&template)) {
+                    Ok(config) => template_configs.insert(template.clone(), config),
+                    Err(e) => {
+                        log::error!("{:?}", e);
+                        failed += 1;
+                        continue;
+                    }
+                };
+            }
+
+            for mut page in entries::paginate(email, &template_configs[&template].page_size) {
+                entries::add_values_views(&mut page.context);
+                pages.push(page);
+            }
+        }
+
+        (emails, pages)
+    });
+    let (emails, pages) = pages;
+
+    let mut messages = 0;
+
+    for mut email in pages {
+        let html = match timer.time(Stage::Render, || render(&mut email, &template_configs)) {
+            Ok(html) => html,
+            Err(e) => {
+                log::error!("{:?}", e);
+                failed += 1;
+                continue;
+            }
+        };
+
+        let message = timer.time(Stage::Build, || {
+            let images_root = email.header.resources_path(templates_path, resources_path);
+            let message: lettre::Message = MessageBuilder::new()
+                .from(&email.header.from)
+                .to_addresses(&email.header.to.join(", "))
+                .cc_addresses(&email.header.cc.join(", "))
+                .bcc_addresses(&email.header.bcc.join(", "))
+                .reply_to_addresses(&email.header.reply_to.join(", "))
+                .subject(&email.header.subject)
+                .alternative_content(
+                    email
+                        .header
+                        .text_body
+                        .as_deref()
+                        .unwrap_or(&email.header.alternative_content),
+                )
+                .attachments(&email.header.attachments.join(", "))
+                .headers(&email.header.headers)
+                .content(&html, Some(&images_root))
+                .build()?
+                .try_into()?;
+
+            // Formatted as a relay transport would
+            message.formatted();
+            anyhow::Ok(message)
+        });
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                log::error!("{:?}", e);
+                failed += 1;
+                continue;
+            }
+        };
+
+        match timer.time(Stage::Send, || transport.send(message)) {
+            Ok(_) => messages += 1,
+            Err(e) => {
+                log::error!("{:?}", e);
+                failed += 1;
+            }
+        }
+    }
+
+    transport.close();
+
+    Ok(BenchReport::new(
+        loaded.ok.len(),
+        emails,
+        messages,
+        failed,
+        &timer,
+    ))
+}
+
+/// Formats the report as aligned tables, or as pretty-printed JSON for trend tracking.
+pub(crate) fn render(report: &BenchReport, format: BenchFormat) -> String {
+    match format {
+        BenchFormat::Json => {
+            serde_json::to_string_pretty(report).expect("A bench report is always valid JSON")
+                + "\n"
+        }
+        BenchFormat::Table => {
+            let peak_memory = match report.peak_memory_bytes {
+                Some(bytes) => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+                None => "unknown".to_owned(),
+            };
+
+            let mut output = String::new();
+            for (name, value) in [
+                ("entries", report.entries.to_string()),
+                ("emails", report.emails.to_string()),
+                ("messages", report.messages.to_string()),
+                ("failed", report.failed.to_string()),
+                ("seconds", format!("{:.3}", report.seconds)),
+                ("entries/sec", format!("{:.1}", report.entries_per_sec)),
+                ("emails/sec", format!("{:.1}", report.emails_per_sec)),
+                ("peak memory", peak_memory),
+            ] {
+                output.push_str(&format!("{name:<12}  {value:>12}\n"));
+            }
+
+            output.push_str(&format!(
+                "\n{:<12}  {:>12}  {:>7}\n",
+                "stage", "seconds", "%"
+            ));
+            for timing in &report.stages {
+                output.push_str(&format!(
+                    "{:<12}  {:>12.3}  {:>7.1}\n",
+                    timing.stage, timing.seconds, timing.percent
+                ));
+            }
+
+            output
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{
+        self, AbsolutePath, ContextData, DetectionMethod, TemplateData, TemplateExtension,
+    };
+
+    #[test]
+    fn test_synthesized_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let templates_path = dir.path().join("templates");
+        fs::create_dir_all(templates_path.join("welcome")).unwrap();
+        fs::write(
+            templates_path.join("welcome").join("template.html"),
+            "<p>Welcome</p>",
+        )
+        .unwrap();
+
+        let synthesis = Synthesis {
+            entries: 10,
+            template: "welcome".to_owned(),
+            batch_ratio: 0.2,
+            context: Default::default(),
+        };
+        let outbox = dir.path().join("outbox");
+        assert_eq!(synthesize(&outbox, &templates_path, &synthesis).unwrap(), 8);
+
+        // Composed the way a run composes the outbox
+        let loaded = entries::load_entries(&outbox, &Default::default());
+        assert_eq!(loaded.ok.len(), 10);
+        assert!(loaded.err.is_empty());
+        let composed = entries::compose_emails(&entries::map_emails(&loaded.ok)).ok;
+        assert_eq!(composed.len(), 8);
+
+        // An unknown template or ratio is refused before anything is written
+        let unknown = Synthesis {
+            template: "missing".to_owned(),
+            ..synthesis.clone()
+        };
+        assert!(synthesize(&dir.path().join("other"), &templates_path, &unknown).is_err());
+
+        let ratio = Synthesis {
+            batch_ratio: 1.0,
+            ..synthesis
+        };
+        let error = synthesize(&dir.path().join("other"), &templates_path, &ratio).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&BenchError::InvalidBatchRatio(1.0))
+        );
+    }
+
+    #[test]
+    fn test_tiny_benchmark() {
+        let dir = tempfile::tempdir().unwrap();
+        let templates_path = dir.path().join("templates");
+        let template_dir = templates_path.join("welcome");
+        fs::create_dir_all(&template_dir).unwrap();
+        fs::write(
+            template_dir.join("template.html"),
+            "<!--TEMPLATE tera--><h1>Welcome {{ name }}</h1>{% for row in bench_values %}<p>{{ row.entry }}</p>{% endfor %}",
+        )
+        .unwrap();
+        fs::write(
+            template_dir.join("template.toml"),
+            "[page_size]\nbench = 2\n",
+        )
+        .unwrap();
+
+        let mut context = serde_json::Map::new();
+        context.insert("name".to_owned(), serde_json::json!("Ada"));
+        let synthesis = Synthesis {
+            entries: 20,
+            template: "welcome".to_owned(),
+            batch_ratio: 0.5,
+            context,
+        };
+        let outbox = dir.path().join("outbox");
+        assert_eq!(
+            synthesize(&outbox, &templates_path, &synthesis).unwrap(),
+            10
+        );
+
+        let mut rendered = Vec::new();
+        let report = run(
+            &outbox,
+            &templates_path,
+            &dir.path().join("resources"),
+            &Verifier::default(),
+            |email, _| {
+                let file_path: AbsolutePath = templates_path
+                    .join(&email.header.template)
+                    .join("template.html")
+                    .into();
+                let template_data = TemplateData {
+                    contents: Rc::new(fs::read_to_string(&*file_path).unwrap()),
+                    file_path: Some(&file_path),
+                    extensions: None,
+                };
+                let context_data = ContextData {
+                    context: serde_json::Value::Object(email.context.clone()),
+                    file_path: None,
+                    trusted: false,
+                    local_time: Default::default(),
+                };
+                let html = render::render(
+                    &template_data,
+                    &context_data,
+                    DetectionMethod::Auto,
+                    TemplateExtension::Auto,
+                )?
+                .0;
+                rendered.push(html.to_string());
+                Ok(html)
+            },
+        )
+        .unwrap();
+
+        assert_eq!(report.entries, 20);
+        assert_eq!(report.emails, 10);
+        // Each E-mail of 2 entries fits a page
+        assert_eq!(report.messages, 10);
+        assert_eq!(report.failed, 0);
+        assert!(report.seconds > 0.0);
+        assert!(report.entries_per_sec > report.emails_per_sec);
+        assert!(report
+            .stages
+            .iter()
+            .all(|timing| timing.seconds >= 0.0 && timing.percent <= 100.0));
+        assert!(
+            rendered[0].starts_with("<h1>Welcome Ada</h1>"),
+            "{}",
+            rendered[0]
+        );
+
+        if cfg!(target_os = "linux") {
+            assert!(report.peak_memory_bytes.is_some_and(|bytes| bytes > 0));
+        }
+
+        // The outbox is only read
+        assert_eq!(fs::read_dir(&outbox).unwrap().count(), 20);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut timer = StageTimer::default();
+        for stage in Stage::ALL {
+            timer.time(stage, || std::thread::sleep(Duration::from_millis(2)));
+        }
+
+        let report = BenchReport::new(100, 50, 50, 0, &timer);
+        assert!(report.seconds >= 0.01);
+        assert_eq!(report.entries_per_sec, 100.0 / report.seconds);
+        assert_eq!(report.stages.len(), 5);
+        let percent: f64 = report.stages.iter().map(|timing| timing.percent).sum();
+        assert!((percent - 100.0).abs() < 0.001, "{percent}");
+
+        let table = render(&report, BenchFormat::Table);
+        assert!(table.contains("entries/sec"), "{table}");
+        assert!(table.contains("render"), "{table}");
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render(&report, BenchFormat::Json)).unwrap();
+        assert_eq!(json["entries"], 100);
+        assert_eq!(json["stages"][2]["stage"], "render");
+    }
+}
@@ -0,0 +1,148 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::sent_log::{self, LogRecord};
+
+/// The bounce token map, one JSON record per line, kept next to the sent-log.
+pub(crate) const BOUNCE_LOG_FILE: &str = "bounce_tokens.jsonl";
+
+/// How long a bounce token can be resolved after its E-mail was sent.
+/// Bounces may be returned days later, as relays retry before giving up.
+pub(crate) const BOUNCE_TOKEN_RETENTION: Duration = Duration::days(14);
+
+/// The characters of a bounce token. Lowercase only, as some relays change the case of local parts.
+const TOKEN_ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+const TOKEN_LENGTH: usize = 16;
+
+/// The E-mail a bounce token was sent with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct BounceRecord {
+    pub(crate) token: String,
+    pub(crate) email_id: u32,
+
+    /// The recipients (to, cc and bcc) of the E-mail
+    pub(crate) recipients: Vec<String>,
+    pub(crate) sent_at: DateTime<Utc>,
+}
+
+impl LogRecord for BounceRecord {
+    #[inline]
+    fn sent_at(&self) -> DateTime<Utc> {
+        self.sent_at
+    }
+}
+
+/// Generates a random token, distinct from the `known` ones.
+pub(crate) fn generate_token(known: &HashSet<String>) -> String {
+    loop {
+        let token: String = (0..TOKEN_LENGTH)
+            .map(|_| TOKEN_ALPHABET[fastrand::usize(..TOKEN_ALPHABET.len())] as char)
+            .collect();
+
+        if !known.contains(&token) {
+            return token;
+        }
+    }
+}
+
+/// The VERP envelope sender of a bounce address, e.g. `bounces+<token>@example.com` for `bounces@example.com`.
+/// ## Error
+/// Fails if the bounce address has no `@`.
+pub(crate) fn verp_address(bounce_address: &str, token: &str) -> Result<String> {
+    let (local_part, domain) = bounce_address
+        .rsplit_once('@')
+        .filter(|(local_part, domain)| !local_part.is_empty() && !domain.is_empty())
+        .ok_or_else(|| anyhow!("Invalid bounce address `{bounce_address}`"))?;
+
+    Ok(format!("{local_part}+{token}@{domain}"))
+}
+
+/// Finds the E-mail of a bounce token, among those still retained at `now`.
+pub(crate) fn resolve<P: AsRef<Path>>(
+    path: P,
+    token: &str,
+    now: DateTime<Utc>,
+) -> Result<Option<BounceRecord>> {
+    let token = token.trim().to_lowercase();
+
+    Ok(
+        sent_log::load_since::<BounceRecord, _>(path, now - BOUNCE_TOKEN_RETENTION)?
+            .into_iter()
+            .find(|record| record.token == token),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(token: &str, sent_at: DateTime<Utc>) -> BounceRecord {
+        BounceRecord {
+            token: token.to_owned(),
+            email_id: 0xabc,
+            recipients: vec!["a@x.com".to_owned(), "b@x.com".to_owned()],
+            sent_at,
+        }
+    }
+
+    #[test]
+    fn test_generated_tokens_are_unique() {
+        let mut known = HashSet::new();
+
+        for _ in 0..10_000 {
+            let token = generate_token(&known);
+            assert_eq!(token.len(), TOKEN_LENGTH);
+            assert!(token.bytes().all(|c| TOKEN_ALPHABET.contains(&c)));
+            assert!(known.insert(token));
+        }
+
+        assert_eq!(
+            verp_address("bounces@x.com", "abc123").unwrap(),
+            "bounces+abc123@x.com"
+        );
+        assert!("bounces+abc123@x.com".parse::<lettre::Address>().is_ok());
+        assert!(verp_address("bounces", "abc123").is_err());
+    }
+
+    #[test]
+    fn test_tokens_persist_and_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(BOUNCE_LOG_FILE);
+        let now = Utc::now();
+
+        // Written by an earlier run
+        sent_log::append(&path, &record("expired", now - Duration::days(20))).unwrap();
+        sent_log::append(&path, &record("k3y", now - Duration::days(2))).unwrap();
+
+        let resolved = resolve(&path, "K3Y", now).unwrap().unwrap();
+        assert_eq!(resolved, record("k3y", now - Duration::days(2)));
+        assert!(resolve(&path, "expired", now).unwrap().is_none());
+        assert!(resolve(&path, "unknown", now).unwrap().is_none());
+
+        sent_log::compact::<BounceRecord, _>(&path, now - BOUNCE_TOKEN_RETENTION).unwrap();
+        let retained = sent_log::load_since::<BounceRecord, _>(&path, now - Duration::days(365));
+        assert_eq!(
+            retained.unwrap(),
+            vec![record("k3y", now - Duration::days(2))]
+        );
+    }
+
+    #[test]
+    fn test_resolve_output() {
+        let sent_at = "2023-01-01T10:20:30Z".parse().unwrap();
+        let output = serde_json::to_value(record("k3y", sent_at)).unwrap();
+
+        assert_eq!(
+            output,
+            serde_json::json!({
+                "token": "k3y",
+                "email_id": 2748,
+                "recipients": ["a@x.com", "b@x.com"],
+                "sent_at": "2023-01-01T10:20:30Z"
+            })
+        );
+    }
+}
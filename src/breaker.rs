@@ -0,0 +1,436 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::send::{MessageBuilder, SubjectTag};
+use crate::templates::Ownership;
+
+/// The failures listed by the alert, the others are only counted.
+const ALERT_LISTED_FAILURES: usize = 20;
+
+/// The content failures tolerated among the first E-mails of a run. A limit that isn't set is disabled.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct BreakerLimits {
+    /// The first attempted E-mails of a run the failures are counted over
+    pub(crate) window: usize,
+
+    /// Maximum E-mails failing to render or build within the window
+    pub(crate) max_failures: Option<usize>,
+
+    /// Maximum percentage of the window failing to render or build
+    pub(crate) max_failure_percent: Option<f64>,
+}
+
+impl BreakerLimits {
+    #[inline]
+    fn is_enabled(&self) -> bool {
+        self.window > 0 && (self.max_failures.is_some() || self.max_failure_percent.is_some())
+    }
+}
+
+/// The stage an E-mail failed at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stage {
+    Render,
+    Build,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Render => write!(f, "render"),
+            Stage::Build => write!(f, "build"),
+        }
+    }
+}
+
+/// An E-mail that failed to render or build.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ContentFailure {
+    /// The E-mail, as named by its archive stem
+    pub(crate) email: String,
+    pub(crate) stage: Stage,
+    pub(crate) reason: String,
+
+    /// The owner of the template of the E-mail, if declared
+    pub(crate) ownership: Ownership,
+}
+
+impl fmt::Display for ContentFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "E-mail `{}` failed to {}: {}",
+            self.email, self.stage, self.reason
+        )?;
+
+        if !self.ownership.is_empty() {
+            write!(f, " ({})", self.ownership)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Aborts a run once its first E-mails fail systematically, e.g. after a broken template deploy.
+/// Connection failures are not counted: they already abort the run.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    limits: BreakerLimits,
+
+    /// The window, no larger than the E-mails of the run
+    window: usize,
+    attempted: usize,
+    failures: Vec<ContentFailure>,
+
+    /// The escalation addresses of the templates of the failed E-mails, or their `notify_error` addresses
+    notify: BTreeSet<String>,
+    from: Option<String>,
+    tripped: bool,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(limits: BreakerLimits, total_emails: usize) -> Self {
+        Self {
+            limits,
+            window: limits.window.min(total_emails),
+            attempted: 0,
+            failures: Vec::new(),
+            notify: BTreeSet::new(),
+            from: None,
+            tripped: false,
+        }
+    }
+
+    /// Counts an E-mail about to be attempted.
+    #[inline]
+    pub(crate) fn record_attempt(&mut self) {
+        self.attempted += 1;
+    }
+
+    /// Counts the failure of the E-mail last attempted. Returns whether the breaker tripped.
+    /// The escalation address of its template is notified instead of the `notify_error` addresses of its entries.
+    pub(crate) fn record_failure<'a>(
+        &mut self,
+        failure: ContentFailure,
+        from: &str,
+        notify_error: impl IntoIterator<Item = &'a String>,
+    ) -> bool {
+        if !self.limits.is_enabled() || self.attempted > self.window {
+            return self.tripped;
+        }
+
+        match &failure.ownership.escalation_email {
+            Some(escalation_email) => {
+                self.notify.insert(escalation_email.clone());
+            }
+            None => self.notify.extend(notify_error.into_iter().cloned()),
+        }
+        self.failures.push(failure);
+        self.from.get_or_insert_with(|| from.to_owned());
+
+        let failed = self.failures.len();
+        let exceeds_count = self.limits.max_failures.is_some_and(|max| failed > max);
+        // Decided as soon as the failures exceed the percentage of the whole window
+        let exceeds_percent = self
+            .limits
+            .max_failure_percent
+            .is_some_and(|max| failed as f64 * 100.0 > max * self.window as f64);
+
+        self.tripped = exceeds_count || exceeds_percent;
+        self.tripped
+    }
+
+    /// Whether the failure of the E-mail last attempted would be counted, within the window of an enabled breaker.
+    #[inline]
+    pub(crate) fn is_counting(&self) -> bool {
+        self.limits.is_enabled() && self.attempted <= self.window
+    }
+
+    #[inline]
+    pub(crate) fn is_tripped(&self) -> bool {
+        self.tripped
+    }
+
+    /// The single alert replacing the notifications of every failed E-mail, to the escalation addresses of their
+    /// templates or their `notify_error` addresses. `None` when the breaker didn't trip, or no address is to be notified.
+    pub(crate) fn alert(&self) -> Option<Alert> {
+        let from = self.from.clone().filter(|_| self.tripped)?;
+
+        if self.notify.is_empty() {
+            return None;
+        }
+
+        let mut body = format!(
+            "The run was aborted: {self}. The remaining E-mails are kept in the outbox.\n\n"
+        );
+
+        for failure in self.failures.iter().take(ALERT_LISTED_FAILURES) {
+            body.push_str(&format!("- {failure}\n"));
+        }
+
+        if self.failures.len() > ALERT_LISTED_FAILURES {
+            body.push_str(&format!(
+                "- and {} more\n",
+                self.failures.len() - ALERT_LISTED_FAILURES
+            ));
+        }
+
+        Some(Alert {
+            from,
+            to: self.notify.iter().cloned().collect(),
+            subject: format!("Run aborted: {} E-mail(s) failed", self.failures.len()),
+            body,
+        })
+    }
+}
+
+impl fmt::Display for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of the first {} attempted E-mail(s) failed to render or build",
+            self.failures.len(),
+            self.attempted
+        )
+    }
+}
+
+/// The notification of a tripped circuit breaker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Alert {
+    pub(crate) from: String,
+    pub(crate) to: Vec<String>,
+    pub(crate) subject: String,
+    pub(crate) body: String,
+}
+
+impl Alert {
+    pub(crate) fn message(&self, subject_tag: &SubjectTag) -> anyhow::Result<lettre::Message> {
+        let to = self.to.join(", ");
+
+        let message = MessageBuilder::new()
+            .from(&self.from)
+            .to_addresses(&to)
+            .subject(&self.subject)
+            .subject_tag(subject_tag)
+            .alternative_content(&self.body)
+            .build()?
+            .try_into()?;
+
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{self, ContextData, DetectionMethod, TemplateData, TemplateExtension};
+    use crate::send::{FileTransport, Transport};
+    use std::fs;
+    use std::rc::Rc;
+
+    const BROKEN_TEMPLATE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/breaker/broken_template.html"
+    );
+
+    fn limits(max_failures: Option<usize>, max_failure_percent: Option<f64>) -> BreakerLimits {
+        BreakerLimits {
+            window: 10,
+            max_failures,
+            max_failure_percent,
+        }
+    }
+
+    fn failure(email: &str) -> ContentFailure {
+        ContentFailure {
+            email: email.to_owned(),
+            stage: Stage::Build,
+            reason: "Invalid address".to_owned(),
+            ownership: Ownership::default(),
+        }
+    }
+
+    #[test]
+    fn test_broken_template_trips_breaker() {
+        let template_data = TemplateData {
+            contents: Rc::new(fs::read_to_string(BROKEN_TEMPLATE).unwrap()),
+            file_path: None,
+            extensions: None,
+        };
+        let notify = vec!["ops@x.com".to_owned()];
+
+        let total_emails = 3000;
+        let mut breaker = CircuitBreaker::new(limits(None, Some(50.0)), total_emails);
+        let mut attempted = 0;
+
+        for i in 0..total_emails {
+            attempted += 1;
+            breaker.record_attempt();
+
+            let context_data = ContextData {
+                context: serde_json::json!({ "job": i, "status": "failed" }),
+                file_path: None,
+                trusted: false,
+                local_time: Default::default(),
+            };
+            let rendered = render::render(
+                &template_data,
+                &context_data,
+                DetectionMethod::Auto,
+                TemplateExtension::Auto,
+            );
+
+            if let Err(e) = rendered {
+                let failure = ContentFailure {
+                    email: format!("{i:08x}"),
+                    stage: Stage::Render,
+                    reason: format!("{e:#}"),
+                    ownership: Ownership::default(),
+                };
+
+                if breaker.record_failure(failure, "mailer@x.com", &notify) {
+                    break;
+                }
+            }
+        }
+
+        // More than half of the window of 10
+        assert!(breaker.is_tripped());
+        assert_eq!(attempted, 6);
+        assert_eq!(
+            breaker.to_string(),
+            "6 of the first 6 attempted E-mail(s) failed to render or build"
+        );
+
+        let alert = breaker.alert().unwrap();
+        assert_eq!(alert.to, notify);
+        assert_eq!(alert.subject, "Run aborted: 6 E-mail(s) failed");
+        assert!(alert
+            .body
+            .contains("- E-mail `00000005` failed to render: "));
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut transport = FileTransport::new(dir.path().join("sent"));
+        transport.establish(None).unwrap();
+        let tag = SubjectTag::new(Some("[{env}]"), None, Some("staging"));
+        transport.send(alert.message(&tag).unwrap()).unwrap();
+
+        let sent: Vec<_> = fs::read_dir(dir.path().join("sent")).unwrap().collect();
+        assert_eq!(sent.len(), 1, "A single aggregated alert");
+        let eml = fs::read_to_string(sent[0].as_ref().unwrap().path()).unwrap();
+        assert!(eml.contains("Subject: [staging] Run aborted: 6 E-mail(s) failed"));
+    }
+
+    #[test]
+    fn test_breaker_thresholds() {
+        let mut breaker = CircuitBreaker::new(limits(Some(2), None), 100);
+        let notify = vec!["b@x.com".to_owned(), "a@x.com".to_owned()];
+
+        for i in 0..3 {
+            breaker.record_attempt();
+            let tripped = breaker.record_failure(failure(&i.to_string()), "m@x.com", &notify);
+            assert_eq!(tripped, i == 2);
+        }
+        assert_eq!(breaker.alert().unwrap().to, vec!["a@x.com", "b@x.com"]);
+
+        // Failures past the window are not counted
+        let mut breaker = CircuitBreaker::new(limits(Some(0), None), 100);
+        for _ in 0..10 {
+            breaker.record_attempt();
+        }
+        assert!(breaker.is_counting());
+        breaker.record_attempt();
+        assert!(!breaker.is_counting());
+        assert!(!breaker.record_failure(failure("late"), "m@x.com", &notify));
+
+        // The window shrinks to a smaller run
+        let mut breaker = CircuitBreaker::new(limits(None, Some(50.0)), 3);
+        breaker.record_attempt();
+        assert!(!breaker.record_failure(failure("a"), "m@x.com", &notify));
+        breaker.record_attempt();
+        assert!(breaker.record_failure(failure("b"), "m@x.com", &notify));
+
+        // Disabled without a limit, and no alert without an address to notify
+        let mut breaker = CircuitBreaker::new(limits(None, None), 100);
+        breaker.record_attempt();
+        assert!(!breaker.is_counting());
+        assert!(!breaker.record_failure(failure("a"), "m@x.com", &notify));
+
+        let mut breaker = CircuitBreaker::new(limits(Some(0), None), 100);
+        breaker.record_attempt();
+        assert!(breaker.record_failure(failure("a"), "m@x.com", &Vec::new()));
+        assert!(breaker.alert().is_none());
+    }
+
+    #[test]
+    fn test_owned_template_failure_escalates() {
+        let template_dir = tempfile::tempdir().unwrap();
+        fs::write(
+            template_dir.path().join(crate::templates::TEMPLATE_CONFIG_FILE),
+            "owner = \"team-db\"\nslack_channel = \"#db-oncall\"\nescalation_email = \"db-oncall@x.com\"\n",
+        )
+        .unwrap();
+        let ownership = crate::templates::TemplateConfig::load(template_dir.path())
+            .unwrap()
+            .ownership;
+
+        let template_data = TemplateData {
+            contents: Rc::new(fs::read_to_string(BROKEN_TEMPLATE).unwrap()),
+            file_path: None,
+            extensions: None,
+        };
+        let context_data = ContextData {
+            context: serde_json::json!({ "job": 1, "status": "failed" }),
+            file_path: None,
+            trusted: false,
+            local_time: Default::default(),
+        };
+        let Err(e) = render::render(
+            &template_data,
+            &context_data,
+            DetectionMethod::Auto,
+            TemplateExtension::Auto,
+        ) else {
+            panic!("The broken template rendered");
+        };
+
+        let owned = ContentFailure {
+            email: "00000001".to_owned(),
+            stage: Stage::Render,
+            reason: format!("{e:#}"),
+            ownership,
+        };
+        let report = owned.to_string();
+        assert!(
+            report.ends_with("(owner: team-db, slack: #db-oncall, escalation: db-oncall@x.com)"),
+            "{report}"
+        );
+
+        // The escalation address replaces the `notify_error` of the entries, the others are still notified
+        let notify = vec!["ops@x.com".to_owned()];
+        let mut breaker = CircuitBreaker::new(limits(Some(1), None), 100);
+        breaker.record_attempt();
+        breaker.record_failure(owned, "mailer@x.com", &notify);
+        breaker.record_attempt();
+        breaker.record_failure(failure("00000002"), "mailer@x.com", &notify);
+
+        let alert = breaker.alert().unwrap();
+        assert_eq!(alert.to, vec!["db-oncall@x.com", "ops@x.com"]);
+        assert!(alert.body.contains(&report));
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut transport = FileTransport::new(dir.path().join("sent"));
+        transport.establish(None).unwrap();
+        transport
+            .send(alert.message(&SubjectTag::default()).unwrap())
+            .unwrap();
+
+        let sent = fs::read_dir(dir.path().join("sent"))
+            .unwrap()
+            .next()
+            .unwrap();
+        let eml = fs::read_to_string(sent.unwrap().path()).unwrap();
+        assert!(eml.contains("To: db-oncall@x.com, ops@x.com"), "{eml}");
+    }
+}
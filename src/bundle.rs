@@ -0,0 +1,346 @@
+use anyhow::{Context, Result};
+use path_slash::PathExt;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{AesMode, CompressionMethod, ZipWriter};
+
+use crate::paths;
+
+/// Packs many attached files into a single zip file, e.g. `{ "name": "logs.zip", "threshold": 5 }`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BundleConfig {
+    /// The file name of the zip file attached instead
+    pub(crate) name: String,
+
+    /// The attached files are bundled when there are more than this many
+    pub(crate) threshold: usize,
+
+    /// Encrypts the bundled files with AES-256 and this password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) password: Option<String>,
+}
+
+/// A zip file of attached files, built in memory.
+#[derive(Debug)]
+pub(crate) struct Bundle {
+    pub(crate) data: Vec<u8>,
+
+    /// The names of the bundled files within the zip file, in order
+    pub(crate) members: Vec<String>,
+
+    /// The attached files that couldn't be read, left to be reported when attached on their own
+    pub(crate) unreadable: Vec<String>,
+}
+
+/// The deepest directory holding every path.
+fn common_dir(paths: &[PathBuf]) -> PathBuf {
+    let mut common: Vec<_> = paths
+        .first()
+        .and_then(|path| path.parent())
+        .map(|parent| parent.components().collect())
+        .unwrap_or_default();
+
+    for path in paths.iter().skip(1) {
+        let shared = path
+            .parent()
+            .map(|parent| {
+                parent
+                    .components()
+                    .zip(&common)
+                    .take_while(|(component, common)| component == *common)
+                    .count()
+            })
+            .unwrap_or_default();
+        common.truncate(shared);
+    }
+
+    common.iter().collect()
+}
+
+/// Bundles the attached files when there are more than the threshold, naming each by its path relative to the
+/// directory they share, e.g. `db-1/app.log` and `db-2/app.log`. A file attached twice is bundled once.
+/// None when the files are below the threshold.
+/// ## Error
+/// Fails if the zip file can't be written.
+pub(crate) fn bundle(attachments: &[String], config: &BundleConfig) -> Result<Option<Bundle>> {
+    if attachments.len() <= config.threshold {
+        return Ok(None);
+    }
+
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+    let mut unreadable = Vec::new();
+
+    for attachment in attachments {
+        let path = Path::new(attachment);
+
+        match fs::canonicalize(paths::long_path(path))
+            .and_then(|canonical| Ok((fs::read(&canonical)?, canonical)))
+        {
+            Ok((data, canonical)) => {
+                let canonical = paths::simplified(canonical);
+                if seen.insert(canonical.clone()) {
+                    files.push((canonical, data));
+                }
+            }
+            Err(_) => unreadable.push(attachment.clone()),
+        }
+    }
+
+    let common = common_dir(
+        &files
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>(),
+    );
+
+    let mut options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    if let Some(password) = &config.password {
+        options = options.with_aes_encryption(AesMode::Aes256, password);
+    }
+
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let mut members = Vec::new();
+
+    for (path, data) in &files {
+        let member = path
+            .strip_prefix(&common)
+            .unwrap_or(path)
+            .to_slash_lossy()
+            .into_owned();
+
+        writer
+            .start_file(member.as_str(), options)
+            .and_then(|()| Ok(writer.write_all(data)?))
+            .with_context(|| {
+                format!(
+                    "Unable to bundle \"{}\" into `{}`",
+                    path.display(),
+                    config.name
+                )
+            })?;
+        members.push(member);
+    }
+
+    let data = writer
+        .finish()
+        .with_context(|| format!("Unable to write the bundle `{}`", config.name))?
+        .into_inner();
+
+    Ok(Some(Bundle {
+        data,
+        members,
+        unreadable,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entries::{ComposedEmail, Email};
+    use crate::large_files::{self, Uploader};
+    use crate::mime_tree;
+    use crate::send::MessageBuilder;
+    use chrono::{DateTime, Duration, Utc};
+    use std::cell::RefCell;
+    use std::io::Read;
+    use zip::ZipArchive;
+
+    fn config(threshold: usize, password: Option<&str>) -> BundleConfig {
+        BundleConfig {
+            name: "logs.zip".to_owned(),
+            threshold,
+            password: password.map(str::to_owned),
+        }
+    }
+
+    /// A log file per host, two of them sharing a file name.
+    fn log_files(dir: &Path) -> Vec<String> {
+        [
+            "db-1/app.log",
+            "db-2/app.log",
+            "db-2/slow.log",
+            "web/access.log",
+        ]
+        .iter()
+        .map(|name| {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, format!("log of {name}\n")).unwrap();
+            path.display().to_string()
+        })
+        .collect()
+    }
+
+    fn unzip(data: &[u8], password: Option<&str>) -> Vec<(String, String)> {
+        let mut archive = ZipArchive::new(Cursor::new(data)).unwrap();
+
+        (0..archive.len())
+            .map(|index| {
+                let mut file = match password {
+                    Some(password) => archive.by_index_decrypt(index, password.as_bytes()),
+                    None => archive.by_index(index),
+                }
+                .unwrap();
+                let mut contents = String::new();
+                file.read_to_string(&mut contents).unwrap();
+                (file.name().to_owned(), contents)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_below_threshold_is_attached_as_is() {
+        let dir = tempfile::tempdir().unwrap();
+        let attachments = log_files(dir.path());
+
+        assert!(bundle(&attachments, &config(4, None)).unwrap().is_none());
+        assert!(bundle(&attachments, &config(3, None)).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_bundled_members() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut attachments = log_files(dir.path());
+        attachments.push(attachments[0].clone());
+        attachments.push(dir.path().join("missing.log").display().to_string());
+
+        let bundle = bundle(&attachments, &config(2, None)).unwrap().unwrap();
+
+        assert_eq!(
+            bundle.members,
+            vec![
+                "db-1/app.log",
+                "db-2/app.log",
+                "db-2/slow.log",
+                "web/access.log"
+            ]
+        );
+        assert_eq!(bundle.unreadable, vec![attachments[5].clone()]);
+        assert_eq!(
+            unzip(&bundle.data, None),
+            vec![
+                (
+                    "db-1/app.log".to_owned(),
+                    "log of db-1/app.log\n".to_owned()
+                ),
+                (
+                    "db-2/app.log".to_owned(),
+                    "log of db-2/app.log\n".to_owned()
+                ),
+                (
+                    "db-2/slow.log".to_owned(),
+                    "log of db-2/slow.log\n".to_owned()
+                ),
+                (
+                    "web/access.log".to_owned(),
+                    "log of web/access.log\n".to_owned()
+                ),
+            ]
+        );
+
+        // The bundle is attached as a single part
+        let zip_path = dir.path().join("logs.zip");
+        fs::write(&zip_path, &bundle.data).unwrap();
+        let message: lettre::Message = MessageBuilder::new()
+            .from("sender@x.com")
+            .to_addresses("a@x.com")
+            .subject("Logs")
+            .attachments(&zip_path.display().to_string())
+            .build()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let tree = mime_tree::structure(&message.formatted()).to_string();
+        assert_eq!(tree.matches("disposition=attachment").count(), 1, "{tree}");
+        assert!(tree.contains("application/zip"), "{tree}");
+    }
+
+    #[test]
+    fn test_encrypted_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        let attachments = log_files(dir.path());
+
+        let bundle = bundle(&attachments, &config(1, Some("s3cret")))
+            .unwrap()
+            .unwrap();
+
+        let mut archive = ZipArchive::new(Cursor::new(bundle.data.as_slice())).unwrap();
+        assert!(archive.by_index(0).is_err(), "A password is required");
+        assert!(archive.by_index_decrypt(0, b"wrong").is_err());
+        assert_eq!(
+            unzip(&bundle.data, Some("s3cret"))[3],
+            (
+                "web/access.log".to_owned(),
+                "log of web/access.log\n".to_owned()
+            )
+        );
+    }
+
+    #[derive(Default)]
+    struct StubUploader {
+        uploaded: RefCell<Vec<String>>,
+    }
+
+    impl Uploader for StubUploader {
+        fn upload(
+            &self,
+            _path: &Path,
+            key: &str,
+            _now: DateTime<Utc>,
+            _expiry: Duration,
+        ) -> Result<String> {
+            self.uploaded.borrow_mut().push(key.to_owned());
+            Ok(format!("https://files.x.com/{key}"))
+        }
+    }
+
+    #[test]
+    fn test_size_limit_applies_to_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // Each file is below the threshold of linked attachments, the bundle is not
+        let attachments: Vec<String> = (0..6)
+            .map(|i| {
+                let path = dir.path().join(format!("dump-{i}.bin"));
+                let data: Vec<u8> = (0..600).map(|_| fastrand::u8(..)).collect();
+                fs::write(&path, data).unwrap();
+                path.display().to_string()
+            })
+            .collect();
+
+        let bundle = bundle(&attachments, &config(5, None)).unwrap().unwrap();
+        assert!(bundle.data.len() > 1024);
+
+        let zip_path = dir.path().join("bundle").join("logs.zip");
+        fs::create_dir_all(zip_path.parent().unwrap()).unwrap();
+        fs::write(&zip_path, &bundle.data).unwrap();
+
+        let mut email = ComposedEmail {
+            header: Email {
+                attachments: vec![zip_path.display().to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let uploader = StubUploader::default();
+
+        large_files::link_large_attachments(
+            &mut email,
+            1024,
+            Duration::hours(24),
+            Some(&uploader),
+            Utc::now(),
+        )
+        .unwrap();
+
+        assert!(email.header.attachments.is_empty());
+        assert!(uploader.uploaded.borrow()[0].ends_with("/logs.zip"));
+        assert_eq!(email.context["_meta"]["large_files"][0]["name"], "logs.zip");
+    }
+}
@@ -0,0 +1,238 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use crate::entries::ComposedEmail;
+use crate::sent_log::SentRecord;
+
+/// The progress of a backlog stopped by `--max-run-time`, kept next to the sent-log until a run drains it.
+pub(crate) const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+/// Where the runs of a logical backlog stand: a run stopped by `--max-run-time` writes it, the next runs
+/// resume from it, and the first run that goes through every E-mail removes it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Checkpoint {
+    /// When the first run of the backlog started, carried over by the runs resuming it
+    pub(crate) backlog_started_at: DateTime<Utc>,
+    pub(crate) stopped_at: DateTime<Utc>,
+
+    /// The E-mails sent by the runs of the backlog, by their archive stem
+    pub(crate) completed: Vec<String>,
+
+    /// The E-mails left for the next run
+    pub(crate) remaining: usize,
+}
+
+impl Checkpoint {
+    /// Loads the checkpoint of a stopped backlog, if any.
+    /// ## Error
+    /// Fails if the checkpoint can't be read or parsed.
+    pub(crate) fn load<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        let path = path.as_ref();
+
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read checkpoint \"{}\"", path.display()))?;
+
+        serde_json::from_str(&contents)
+            .map(Some)
+            .with_context(|| format!("Unable to parse checkpoint \"{}\"", path.display()))
+    }
+
+    pub(crate) fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let contents = serde_json::to_string_pretty(self).expect("Checkpoint is always valid JSON");
+
+        fs::write(path, contents)
+            .with_context(|| format!("Unable to write checkpoint \"{}\"", path.display()))
+    }
+
+    /// Removes the checkpoint once its backlog is drained. A missing checkpoint is fine.
+    pub(crate) fn clear<P: AsRef<Path>>(path: P) -> Result<()> {
+        let path = path.as_ref();
+
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e)
+                .with_context(|| format!("Unable to remove checkpoint \"{}\"", path.display())),
+            _ => Ok(()),
+        }
+    }
+
+    /// The checkpoint of a run stopped at `stopped_at`, resuming `previous` if given.
+    pub(crate) fn resume(
+        previous: Option<Self>,
+        run_started_at: DateTime<Utc>,
+        stopped_at: DateTime<Utc>,
+        sent: Vec<String>,
+        remaining: usize,
+    ) -> Self {
+        let (backlog_started_at, mut completed) = previous.map_or_else(
+            || (run_started_at, Vec::new()),
+            |previous| (previous.backlog_started_at, previous.completed),
+        );
+        completed.extend(sent);
+
+        Self {
+            backlog_started_at,
+            stopped_at,
+            completed,
+            remaining,
+        }
+    }
+}
+
+/// Drops the E-mails the runs of the backlog already sent, recognized by the content hash of their sent-log record
+/// since the backlog started, so they are neither rendered nor sent again. These are the earlier pages of an
+/// E-mail whose entries are kept until its last page is sent. Returns the E-mails to send and the skipped count.
+pub(crate) fn skip_completed(
+    emails: Vec<ComposedEmail>,
+    sent_records: &[SentRecord],
+) -> (Vec<ComposedEmail>, usize) {
+    let sent: HashSet<(u32, &str)> = sent_records
+        .iter()
+        .map(|record| (record.email_id, record.content_hash.as_str()))
+        .collect();
+
+    let total = emails.len();
+    let pending: Vec<ComposedEmail> = emails
+        .into_iter()
+        .filter(|email| !sent.contains(&(email.id, email.content_hash().as_str())))
+        .collect();
+    let skipped = total - pending.len();
+
+    (pending, skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::archive_stem;
+    use crate::entries::Page;
+    use crate::schedule::Deadline;
+    use std::time::{Duration, Instant};
+
+    fn paged_emails() -> Vec<ComposedEmail> {
+        let page = |id, number, count| {
+            let mut email = ComposedEmail {
+                id,
+                page: Some(Page { number, count }),
+                ..Default::default()
+            };
+            email
+                .context
+                .insert("page".to_owned(), serde_json::json!(number));
+            email
+        };
+
+        vec![
+            page(1, 1, 1),
+            page(2, 1, 3),
+            page(2, 2, 3),
+            page(2, 3, 3),
+            page(3, 1, 2),
+            page(3, 2, 2),
+        ]
+    }
+
+    /// Sends the E-mails until the deadline on a simulated clock, every send taking 4 seconds.
+    fn run(emails: &[ComposedEmail], max_run_time: u64) -> (Vec<ComposedEmail>, usize) {
+        let started = Instant::now();
+        let deadline = Deadline::new(started, Some(Duration::from_secs(max_run_time)));
+
+        let mut now = started;
+        let mut sent = Vec::new();
+        for email in emails {
+            if deadline.stops_before(now, email.page) {
+                break;
+            }
+            now += Duration::from_secs(4);
+            sent.push(email.clone());
+        }
+
+        let remaining = emails.len() - sent.len();
+        (sent, remaining)
+    }
+
+    fn records(sent: &[ComposedEmail], sent_at: DateTime<Utc>) -> Vec<SentRecord> {
+        sent.iter()
+            .map(|email| SentRecord {
+                sent_at,
+                email_id: email.id,
+                template: email.header.template.clone(),
+                content_hash: email.content_hash(),
+                reply_token: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_resume_stopped_backlog() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CHECKPOINT_FILE);
+        let started = Utc::now();
+        let emails = paged_emails();
+
+        // The deadline passes after the first page of E-mail 2, whose pages are finished
+        let (sent, remaining) = run(&emails, 5);
+        assert_eq!(sent.len(), 4);
+        assert_eq!(remaining, 2);
+
+        let stems: Vec<String> = sent.iter().map(archive_stem).collect();
+        Checkpoint::resume(None, started, started, stems.clone(), remaining)
+            .save(&path)
+            .unwrap();
+        let mut sent_log = records(&sent, started);
+
+        // The next run resumes the backlog, without the E-mails sent by the first
+        let checkpoint = Checkpoint::load(&path).unwrap().unwrap();
+        assert_eq!(checkpoint.completed, stems);
+
+        let (pending, skipped) = skip_completed(emails.clone(), &sent_log);
+        assert_eq!(skipped, 4);
+        assert_eq!(
+            pending.iter().map(archive_stem).collect::<Vec<_>>(),
+            vec!["00000003-1", "00000003-2"]
+        );
+
+        let later = started + chrono::Duration::minutes(10);
+        let (sent, remaining) = run(&pending, 600);
+        assert_eq!(remaining, 0);
+        sent_log.extend(records(&sent, later));
+
+        let resumed = Checkpoint::resume(
+            Some(checkpoint),
+            later,
+            later,
+            sent.iter().map(archive_stem).collect(),
+            remaining,
+        );
+        assert_eq!(resumed.backlog_started_at, started);
+        assert_eq!(resumed.completed.len(), emails.len());
+
+        // Nothing is left once the backlog is drained, and the checkpoint is removed
+        assert!(skip_completed(emails, &sent_log).0.is_empty());
+        Checkpoint::clear(&path).unwrap();
+        assert_eq!(Checkpoint::load(&path).unwrap(), None);
+        Checkpoint::clear(&path).unwrap();
+    }
+
+    #[test]
+    fn test_changed_email_is_not_skipped() {
+        let emails = paged_emails();
+        let sent_log = records(&emails[..1], Utc::now());
+
+        let mut changed = emails[0].clone();
+        changed
+            .context
+            .insert("late".to_owned(), serde_json::json!(true));
+
+        let (pending, skipped) = skip_completed(vec![changed], &sent_log);
+        assert_eq!((pending.len(), skipped), (1, 0));
+    }
+}
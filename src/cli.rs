@@ -0,0 +1,792 @@
+#[cfg(feature = "ingest-imap")]
+use clap::Args;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use crate::bench::BenchFormat;
+use crate::breaker::BreakerLimits;
+use crate::config::{self, ConfigFormat};
+use crate::exit;
+use crate::guards::{GuardLimit, GuardLimits, GuardPolicy};
+use crate::identity::{IdentityConfig, IdentityConflict, IdentityFields};
+use crate::logging::LogTarget;
+use crate::schedule::{self, SplayMode};
+use crate::schema::SchemaFormat;
+use crate::send::{
+    AttachmentEncoding, Authentication, LongHeaderPolicy, SmtpConnectionBuilder,
+    SmtpConnectionInfo, SubjectTag, TransportKind,
+};
+use crate::signing::UnsignedPolicy;
+use crate::stats::StatsFormat;
+
+/// Send dynamic and sophisticated E-mails using Smart Templates
+#[derive(Parser, Debug)]
+#[command(version, about, after_help = exit::EXIT_CODES_HELP)]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+
+    /// A TOML file of the `server`, `port`, `auth` and `timeout` of the mail relay, and the `outbox` and `templates`
+    /// directories. Relative to the binary directory, `config.toml` when it exists. Arguments and environment
+    /// variables take precedence over it
+    #[arg(long = "config", value_name = "PATH")]
+    pub(crate) config_file: Option<PathBuf>,
+
+    /// Where E-mails are sent: `smtp` (the mail relay of `--server`, `--port` and `--auth`),
+    /// `file` (into `--transport-dir`), `sendmail` or `null` (discarded)
+    #[arg(long, value_name = "TRANSPORT", default_value_t = TransportKind::Smtp)]
+    pub(crate) transport: TransportKind,
+
+    /// The mail relay host
+    #[arg(
+        long,
+        visible_alias = "relay",
+        env = "SERVER",
+        value_name = "HOST",
+        default_value = config::DEFAULT_SERVER
+    )]
+    pub(crate) server: String,
+
+    /// The mail relay port
+    #[arg(long, env = "PORT", value_name = "PORT", default_value = config::DEFAULT_PORT)]
+    pub(crate) port: u16,
+
+    /// How to connect to the mail relay: `noauth` (plain SMTP, without credentials), `tls` (implicit TLS)
+    /// or `starttls` (upgraded with a required STARTTLS). `USERNAME` and `PASSWORD` are sent over `tls` and `starttls`
+    #[arg(long, env = "AUTH", value_name = "METHOD", default_value = config::DEFAULT_AUTH)]
+    pub(crate) auth: Authentication,
+
+    /// The mail relay username, sent over `tls` and `starttls` when `--password` is set as well.
+    /// Not required along with `--password`, as Windows always sets `USERNAME` to the logged in user
+    #[arg(long, env = "USERNAME", value_name = "NAME")]
+    pub(crate) username: Option<String>,
+
+    /// The mail relay password. Prefer the `PASSWORD` environment variable, command lines are visible to other users
+    #[arg(
+        long,
+        env = "PASSWORD",
+        value_name = "PASSWORD",
+        hide_env_values = true
+    )]
+    pub(crate) password: Option<String>,
+
+    /// Seconds to wait on the mail relay before a connection or a command fails
+    #[arg(
+        long,
+        visible_alias = "timeout-secs",
+        env = "SMTP_TIMEOUT",
+        value_name = "SECONDS",
+        default_value_t = 60
+    )]
+    pub(crate) timeout: u64,
+
+    /// Don't check that the mail relay answers, and accepts the credentials, before rendering the E-mails,
+    /// e.g. for an offline run
+    #[arg(long)]
+    pub(crate) skip_connection_test: bool,
+
+    /// A PEM private key to DKIM-sign the E-mails sent to the mail relay with: PKCS#1 RSA or PKCS#8 Ed25519
+    #[arg(
+        long,
+        env = "DKIM_KEY",
+        value_name = "PATH",
+        requires_all = ["dkim_selector", "dkim_domain"]
+    )]
+    pub(crate) dkim_key: Option<PathBuf>,
+
+    /// The DKIM selector of `--dkim-key`, publishing its public key at `<selector>._domainkey.<domain>`
+    #[arg(
+        long,
+        env = "DKIM_SELECTOR",
+        value_name = "SELECTOR",
+        requires = "dkim_key"
+    )]
+    pub(crate) dkim_selector: Option<String>,
+
+    /// The signing domain of `--dkim-key`
+    #[arg(
+        long,
+        env = "DKIM_DOMAIN",
+        value_name = "DOMAIN",
+        requires = "dkim_key"
+    )]
+    pub(crate) dkim_domain: Option<String>,
+
+    /// The outbox directory, `outbox` in the binary directory by default
+    #[arg(long, visible_alias = "outbox-dir", env = "OUTBOX", value_name = "DIR")]
+    pub(crate) outbox: Option<PathBuf>,
+
+    /// The templates directory, `templates` in the binary directory by default
+    #[arg(
+        long,
+        visible_alias = "templates-dir",
+        env = "TEMPLATES",
+        value_name = "DIR"
+    )]
+    pub(crate) templates: Option<PathBuf>,
+
+    /// The directory `--transport file` writes every E-mail into, as an `.eml` file
+    #[arg(long, value_name = "DIR", default_value = "sent_mail")]
+    pub(crate) transport_dir: PathBuf,
+
+    /// The `sendmail` compatible command of `--transport sendmail`
+    #[arg(long, value_name = "PATH", default_value = "sendmail")]
+    pub(crate) sendmail_command: PathBuf,
+
+    /// Transfer encoding of attached files and inline images: `auto`, `base64` or `8bit`.
+    /// `8bit` is only used when the relay advertises `8BITMIME`, otherwise `base64`
+    #[arg(long, value_name = "ENCODING", default_value_t = AttachmentEncoding::Auto)]
+    pub(crate) attachment_encoding: AttachmentEncoding,
+
+    /// A TOML file of `Name = "value"` headers to append to every E-mail.
+    /// Headers of the same name set by an entry take precedence
+    #[arg(long, value_name = "PATH")]
+    pub(crate) include_headers_file: Option<PathBuf>,
+
+    /// A TOML file, or a JSON file for a `.json` extension, of group aliases and their members,
+    /// e.g. `oncall-db = ["dba@example.com", "@managers"]`. Relative to the binary directory.
+    /// Entries address an alias as `@oncall-db` in `to`, `cc` or `bcc`
+    #[arg(long, value_name = "PATH")]
+    pub(crate) aliases_file: Option<PathBuf>,
+
+    /// A TOML file, or a JSON file for a `.json` extension, of recipient domains and their TLS policy:
+    /// `require`, `testing` or `allow-plaintext`, e.g. `"example.com" = "require"`. Relative to the binary directory.
+    /// E-mails to a `require` domain fail over a `noauth` mail relay connection, `testing` ones are warned about
+    #[arg(long, value_name = "PATH")]
+    pub(crate) tls_policy_file: Option<PathBuf>,
+
+    /// A TOML file, or a JSON file for a `.json` extension, of directories and their retention limits:
+    /// `max_age_days`, `max_total_bytes` and `max_files`, e.g. `[sent_mail]` followed by `max_files = 1000`.
+    /// Relative to the binary directory, as the directories are. They are pruned oldest-first at the start of
+    /// every run, and on every poll of `ingest-imap`
+    #[arg(long, value_name = "PATH")]
+    pub(crate) retention_file: Option<PathBuf>,
+
+    /// Print what `--retention-file` would remove without removing anything
+    #[arg(long, requires = "retention_file")]
+    pub(crate) retention_dry_run: bool,
+
+    /// A TOML file, or a JSON file for a `.json` extension, of the producer key IDs and their hex-encoded Ed25519
+    /// public keys, e.g. `backup-prod = "3d4017c3..."`, verifying the `signature` of the entries.
+    /// Relative to the binary directory
+    #[arg(long, value_name = "PATH")]
+    pub(crate) producer_keys: Option<PathBuf>,
+
+    /// What to do with the entries without a `signature`: `accept` or `reject`, moving them to the outbox `failed`
+    /// directory. Entries with a bad signature are always rejected
+    #[arg(long, value_name = "POLICY", default_value_t = UnsignedPolicy::Accept)]
+    pub(crate) unsigned_entries: UnsignedPolicy,
+
+    /// What to do with a header word too long to be folded within the 998 characters of a line, e.g. an unbroken
+    /// subject: `reject` fails the E-mail, `truncate` cuts the word to fit
+    #[arg(long, value_name = "POLICY", default_value_t = LongHeaderPolicy::Reject)]
+    pub(crate) long_header_policy: LongHeaderPolicy,
+
+    /// The domain of the generated `Message-ID` headers, `<uuid@domain>`, the domain of the `from` address by default
+    #[arg(long, env = "MESSAGE_ID_DOMAIN", value_name = "DOMAIN")]
+    pub(crate) message_id_domain: Option<String>,
+
+    /// Where to log, in addition to the console: `console`, `eventlog` or `syslog`.
+    /// `eventlog` and `syslog` require the matching cargo feature
+    #[arg(long, value_name = "TARGET", default_value_t = LogTarget::Console)]
+    pub(crate) log_target: LogTarget,
+
+    /// The UDP address of the syslog daemon, used with `--log-target syslog`
+    #[arg(long, value_name = "ADDRESS", default_value = "127.0.0.1:514")]
+    pub(crate) syslog_address: String,
+
+    /// Export the traces of every run to this OTLP/HTTP collector, e.g. `http://localhost:4318/v1/traces`
+    #[cfg(feature = "otel")]
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", value_name = "URL")]
+    pub(crate) otlp_endpoint: Option<String>,
+
+    /// Write the effective configuration of the run to this file, as JSON for a `.json` file and TOML otherwise,
+    /// so the run can be reproduced. Secrets are redacted
+    #[arg(long, value_name = "PATH")]
+    pub(crate) config_snapshot: Option<PathBuf>,
+
+    /// Only compose the outbox entries and output the composed E-mails as canonical JSON, with sorted keys,
+    /// byte-identical for identical entries. Nothing is rendered or sent, and the entries are kept
+    #[arg(long)]
+    pub(crate) compose_only: bool,
+
+    /// Write the `--compose-only` output to a file instead of stdout
+    #[arg(long, value_name = "PATH", requires = "compose_only")]
+    pub(crate) compose_output: Option<PathBuf>,
+
+    /// Render and build every E-mail without connecting to the mail relay, and print each message as it would be
+    /// sent. The entries are kept, and the run fails if any E-mail failed to render or build
+    #[arg(long)]
+    pub(crate) dry_run: bool,
+
+    /// Write the `--dry-run` messages to this directory as `<email id>.eml`, instead of stdout
+    #[arg(long, value_name = "DIR", requires = "dry_run")]
+    pub(crate) dry_run_dir: Option<PathBuf>,
+
+    /// Send with a VERP envelope sender derived from this address, e.g. `bounces+<token>@example.com`,
+    /// and keep the E-mail of every token for `resolve-bounce`
+    #[arg(long, value_name = "ADDRESS")]
+    pub(crate) bounce_address: Option<String>,
+
+    /// The deployment environment, e.g. `staging`, replacing `{env}` in `--subject-prefix` and `--subject-suffix`
+    #[arg(long, env = "OSA_ENV", value_name = "NAME")]
+    pub(crate) environment: Option<String>,
+
+    /// Prefix the subject of every E-mail, including the ones the mailer sends itself, e.g. `[{env}]`.
+    /// Leave unset in production
+    #[arg(long, env = "SUBJECT_PREFIX", value_name = "TEXT")]
+    pub(crate) subject_prefix: Option<String>,
+
+    /// Suffix the subject of every E-mail, as `--subject-prefix`
+    #[arg(long, env = "SUBJECT_SUFFIX", value_name = "TEXT")]
+    pub(crate) subject_suffix: Option<String>,
+
+    /// Keep the rendered HTML and text alternative of every sent E-mail in this directory, for `diff`
+    #[arg(long, value_name = "DIR")]
+    pub(crate) archive_dir: Option<PathBuf>,
+
+    /// Move the entries of sent E-mails into a `sent` directory next to the outbox, under the same subdirectories
+    /// and with a timestamp suffix, instead of removing them
+    #[arg(long, env = "ARCHIVE_SENT")]
+    pub(crate) archive_sent: bool,
+
+    /// Keep every sent message, as formatted for the mail relay, in this directory as `<sha256>.eml`, indexed in
+    /// an append-only `index.jsonl`, for `verify-archive`
+    #[arg(long, value_name = "DIR")]
+    pub(crate) archive_cas: Option<PathBuf>,
+
+    /// Leave the entry files that fail to parse in the outbox, instead of moving them into its `failed` directory
+    #[arg(long)]
+    pub(crate) keep_unparsable: bool,
+
+    /// Move the entries of an E-mail into the dead-letter directory once it failed to send this many times, instead
+    /// of retrying them on every run
+    #[arg(long, env = "MAX_SEND_ATTEMPTS", value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub(crate) max_send_attempts: Option<u32>,
+
+    /// The dead-letter directory the entries that can't be delivered are moved into, `<outbox>/failed` by default.
+    /// Outside the outbox, as `requeue-failed` puts them back
+    #[arg(long, env = "FAILED_DIR", value_name = "DIR")]
+    pub(crate) failed_dir: Option<PathBuf>,
+
+    /// The directory the failures of a run are written into as JSON, `<outbox>/errors` by default.
+    /// Relative to the binary directory, outside the outbox
+    #[arg(long, env = "ERROR_REPORT_DIR", value_name = "DIR")]
+    pub(crate) error_report_dir: Option<PathBuf>,
+
+    /// Also write the failures of the run as a JSON array into this file, `[]` without any, e.g. for an orchestrator
+    #[arg(long, env = "ERROR_OUTPUT", value_name = "PATH")]
+    pub(crate) error_output: Option<PathBuf>,
+
+    /// Before sending, check that every attached file and embedded image of every E-mail exists and is readable.
+    /// Any missing asset is reported and nothing is sent
+    #[arg(long)]
+    pub(crate) verify_assets: bool,
+
+    /// Print the MIME structure of every message before sending it: an indented tree of its content types,
+    /// dispositions, file names, content IDs and body sizes, without the bodies
+    #[arg(long)]
+    pub(crate) dump_mime_structure: bool,
+
+    /// At the start of the run, report the images referenced by the templates but missing from their directory,
+    /// and the images of the template directories never referenced, as `lint` and `config check` do
+    #[arg(long)]
+    pub(crate) check_template_assets: bool,
+
+    /// How long an `approve` lasts. E-mails not sent by then are pending approval again
+    #[arg(long, value_name = "SECS", default_value_t = 86400)]
+    pub(crate) approval_expiry: u64,
+
+    /// The fields of the entry `email` telling its E-mail apart, separated by commas, e.g. `from,to,subject`.
+    /// The entries differing only by other fields are batched together. The whole `email` when not set
+    #[arg(long, env = "IDENTITY_FIELDS", value_name = "FIELDS")]
+    pub(crate) identity_fields: Option<IdentityFields>,
+
+    /// What happens when the entries of an E-mail differ by fields outside `--identity-fields`:
+    /// `first_wins` (the first entry is sent, with a warning) or `error` (the E-mail fails)
+    #[arg(long, value_name = "POLICY", default_value_t = IdentityConflict::FirstWins)]
+    pub(crate) identity_conflict: IdentityConflict,
+
+    /// Hold back an E-mail until no new entries arrived for it during this many seconds,
+    /// so late entries join the batch. Held E-mails are sent by a later run
+    #[arg(long, value_name = "SECS")]
+    pub(crate) batch_window: Option<u64>,
+
+    /// The longest an E-mail is held back by `--batch-window` since its first entry
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 600,
+        requires = "batch_window"
+    )]
+    pub(crate) batch_max_hold: u64,
+
+    /// Wait up to this many seconds before sending, so hosts started by the same cron schedule
+    /// don't all reach the mail relay at once
+    #[arg(long, value_name = "SECS", help_heading = "Scheduling")]
+    pub(crate) splay: Option<u64>,
+
+    /// How the `--splay` wait is chosen: `host` (fixed for each hostname) or `random`
+    #[arg(long, value_name = "MODE", default_value_t = SplayMode::Host, help_heading = "Scheduling")]
+    pub(crate) splay_mode: SplayMode,
+
+    /// Milliseconds to wait between two E-mails
+    #[arg(
+        long,
+        value_name = "MILLIS",
+        default_value_t = 0,
+        help_heading = "Scheduling"
+    )]
+    pub(crate) send_delay: u64,
+
+    /// Spread every `--send-delay` randomly by up to this fraction of itself, from `0` to `1`
+    #[arg(
+        long,
+        value_name = "FACTOR",
+        default_value_t = 0.0,
+        help_heading = "Scheduling"
+    )]
+    pub(crate) jitter: f64,
+
+    /// Retry an E-mail this many times when the mail relay defers it (4xx) or the connection drops, waiting
+    /// `--retry-delay` before the first retry and `--retry-multiplier` times longer before every next one.
+    /// Rejected E-mails (5xx) are never retried
+    #[arg(
+        long,
+        visible_alias = "max-retries",
+        value_name = "COUNT",
+        default_value_t = 2,
+        help_heading = "Scheduling"
+    )]
+    pub(crate) send_retries: u32,
+
+    /// Milliseconds to wait before the first retry of `--send-retries`, up to a minute
+    #[arg(
+        long,
+        visible_alias = "retry-delay-ms",
+        value_name = "MILLIS",
+        default_value_t = 1000,
+        help_heading = "Scheduling"
+    )]
+    pub(crate) retry_delay: u64,
+
+    /// Multiply the wait of `--retry-delay` by this factor for every next retry, at least `1`
+    #[arg(
+        long,
+        value_name = "FACTOR",
+        default_value_t = 2.0,
+        help_heading = "Scheduling"
+    )]
+    pub(crate) retry_multiplier: f64,
+
+    /// Stop starting new E-mails once the run took this long, e.g. `600` seconds or `10m`, so a run fits its
+    /// maintenance window. The E-mail being sent and its remaining pages are finished, the others are kept,
+    /// a checkpoint is written, and the exit code is 7. The next run resumes the backlog
+    #[arg(
+        long,
+        alias = "max-runtime",
+        value_name = "DURATION",
+        value_parser = schedule::parse_duration,
+        help_heading = "Scheduling"
+    )]
+    pub(crate) max_run_time: Option<std::time::Duration>,
+
+    /// Exit with 6 instead of 0 when there was nothing to send, so wrappers can tell an idle run from one that sent
+    #[arg(long, help_heading = "Scheduling")]
+    pub(crate) exit_idle: bool,
+
+    /// Maximum recipients (to, cc and bcc) of a single E-mail
+    #[arg(long, value_name = "COUNT", help_heading = "Anomaly guards")]
+    pub(crate) max_recipients: Option<usize>,
+
+    /// What to do with an E-mail exceeding `--max-recipients`: `block` or `warn`
+    #[arg(long, value_name = "POLICY", default_value_t = GuardPolicy::Block, help_heading = "Anomaly guards")]
+    pub(crate) max_recipients_policy: GuardPolicy,
+
+    /// Maximum E-mails sent per run for the same template
+    #[arg(long, value_name = "COUNT", help_heading = "Anomaly guards")]
+    pub(crate) max_emails_per_template: Option<usize>,
+
+    /// What to do with an E-mail exceeding `--max-emails-per-template`: `block` or `warn`
+    #[arg(long, value_name = "POLICY", default_value_t = GuardPolicy::Block, help_heading = "Anomaly guards")]
+    pub(crate) max_emails_per_template_policy: GuardPolicy,
+
+    /// Maximum sends of identical E-mail content within an hour, counted with the sent-log
+    #[arg(long, value_name = "COUNT", help_heading = "Anomaly guards")]
+    pub(crate) max_identical_per_hour: Option<usize>,
+
+    /// What to do with an E-mail exceeding `--max-identical-per-hour`: `block` or `warn`
+    #[arg(long, value_name = "POLICY", default_value_t = GuardPolicy::Block, help_heading = "Anomaly guards")]
+    pub(crate) max_identical_per_hour_policy: GuardPolicy,
+
+    /// The first attempted E-mails of a run the circuit breaker counts the render and build failures over
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = 20,
+        help_heading = "Circuit breaker"
+    )]
+    pub(crate) breaker_window: usize,
+
+    /// Abort the run when more E-mails of the window fail to render or build. The remaining E-mails are kept,
+    /// a single alert is sent to the `notify_error` addresses of the failed ones, and the exit code is 5
+    #[arg(long, value_name = "COUNT", help_heading = "Circuit breaker")]
+    pub(crate) breaker_max_failures: Option<usize>,
+
+    /// Abort the run when a larger percentage of the window fails to render or build, as `--breaker-max-failures`
+    #[arg(long, value_name = "PERCENT", help_heading = "Circuit breaker")]
+    pub(crate) breaker_max_failure_percent: Option<f64>,
+
+    /// The S3-compatible endpoint the attached files of `large_attachment_policy = "link"` templates are
+    /// uploaded to, e.g. `https://s3.eu-west-1.amazonaws.com`
+    #[cfg(feature = "s3-links")]
+    #[arg(
+        long,
+        env = "S3_ENDPOINT",
+        value_name = "URL",
+        requires = "s3_bucket",
+        help_heading = "Large attachments"
+    )]
+    pub(crate) s3_endpoint: Option<String>,
+
+    /// The bucket of `--s3-endpoint`, addressed path-style
+    #[cfg(feature = "s3-links")]
+    #[arg(
+        long,
+        env = "S3_BUCKET",
+        value_name = "BUCKET",
+        help_heading = "Large attachments"
+    )]
+    pub(crate) s3_bucket: Option<String>,
+
+    #[cfg(feature = "s3-links")]
+    #[arg(
+        long,
+        env = "S3_REGION",
+        value_name = "REGION",
+        default_value = "us-east-1",
+        help_heading = "Large attachments"
+    )]
+    pub(crate) s3_region: String,
+
+    #[cfg(feature = "s3-links")]
+    #[arg(
+        long,
+        env = "S3_ACCESS_KEY_ID",
+        value_name = "KEY",
+        help_heading = "Large attachments"
+    )]
+    pub(crate) s3_access_key_id: Option<String>,
+
+    #[cfg(feature = "s3-links")]
+    #[arg(
+        long,
+        env = "S3_SECRET_ACCESS_KEY",
+        value_name = "SECRET",
+        hide_env_values = true,
+        help_heading = "Large attachments"
+    )]
+    pub(crate) s3_secret_access_key: Option<String>,
+
+    /// Embeds the `http://` and `https://` images of the templates, fetching them when sending
+    #[cfg(feature = "remote-images")]
+    #[arg(long, env = "REMOTE_IMAGES", help_heading = "Remote images")]
+    pub(crate) remote_images: bool,
+
+    /// How long a remote image may take to fetch, in seconds
+    #[cfg(feature = "remote-images")]
+    #[arg(
+        long,
+        env = "REMOTE_IMAGE_TIMEOUT",
+        value_name = "SECONDS",
+        default_value_t = 10,
+        help_heading = "Remote images"
+    )]
+    pub(crate) remote_image_timeout: u64,
+
+    /// The largest remote image fetched, in bytes
+    #[cfg(feature = "remote-images")]
+    #[arg(
+        long,
+        env = "REMOTE_IMAGE_MAX_SIZE",
+        value_name = "BYTES",
+        default_value_t = 5 * 1024 * 1024,
+        help_heading = "Remote images"
+    )]
+    pub(crate) remote_image_max_size: u64,
+}
+
+impl Cli {
+    /// The mail relay connection of `--server`, `--port`, `--auth` and `--timeout`.
+    pub(crate) fn relay(&self) -> SmtpConnectionInfo<'_> {
+        SmtpConnectionBuilder::new()
+            .relay(&self.server)
+            .port(self.port)
+            .auth(self.auth)
+            .timeout(std::time::Duration::from_secs(self.timeout))
+            .build()
+    }
+
+    /// The `--username` and `--password` of the mail relay, when both are set.
+    pub(crate) fn relay_credentials(&self) -> Option<(String, String)> {
+        self.username.clone().zip(self.password.clone())
+    }
+
+    /// The anomaly guards configured with the `--max-*` arguments.
+    pub(crate) fn guard_limits(&self) -> GuardLimits {
+        let limit = |max: Option<usize>, policy| max.map(|max| GuardLimit { max, policy });
+
+        GuardLimits {
+            max_recipients: limit(self.max_recipients, self.max_recipients_policy),
+            max_emails_per_template: limit(
+                self.max_emails_per_template,
+                self.max_emails_per_template_policy,
+            ),
+            max_identical_per_hour: limit(
+                self.max_identical_per_hour,
+                self.max_identical_per_hour_policy,
+            ),
+        }
+    }
+
+    /// The circuit breaker configured with the `--breaker-*` arguments.
+    pub(crate) fn breaker_limits(&self) -> BreakerLimits {
+        BreakerLimits {
+            window: self.breaker_window,
+            max_failures: self.breaker_max_failures,
+            max_failure_percent: self.breaker_max_failure_percent,
+        }
+    }
+
+    /// The identity of the E-mails configured with `--identity-fields` and `--identity-conflict`,
+    /// unless their template overrides it.
+    pub(crate) fn identity(&self) -> IdentityConfig {
+        IdentityConfig {
+            fields: self.identity_fields.clone(),
+            conflict: Some(self.identity_conflict),
+        }
+    }
+
+    /// The subject tag configured with `--subject-prefix` and `--subject-suffix`.
+    pub(crate) fn subject_tag(&self) -> SubjectTag {
+        SubjectTag::new(
+            self.subject_prefix.as_deref(),
+            self.subject_suffix.as_deref(),
+            self.environment.as_deref(),
+        )
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+    /// Move entries from the outbox `failed` directory back into the outbox, if they now pass validation
+    RequeueFailed {
+        /// Only requeue entries of the given template
+        #[arg(long, value_name = "TEMPLATE")]
+        filter_template: Option<String>,
+
+        /// Print the decision for each entry without moving anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Render the composed E-mails of the outbox and diff them against their last sent render, without sending
+    Diff {
+        /// The archive directory of the sent renders, see `--archive-dir`
+        #[arg(long, value_name = "DIR")]
+        against: PathBuf,
+
+        /// Where the `.diff` file of every changed E-mail is written
+        #[arg(long, value_name = "DIR", default_value = "diff")]
+        output: PathBuf,
+
+        /// Collapse whitespace and skip blank lines, so reformatting alone is not a change
+        #[arg(long)]
+        ignore_whitespace: bool,
+    },
+
+    /// Re-hash the messages of the `--archive-cas` audit archive, reporting the altered ones and the gaps of its index
+    VerifyArchive {
+        /// The audit archive directory, `--archive-cas` by default
+        #[arg(value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
+
+    /// Print the E-mail a bounce token of `--bounce-address` was sent with, as JSON
+    ResolveBounce {
+        /// The token of the VERP envelope sender, between `+` and `@`
+        token: String,
+    },
+
+    /// Approve E-mails parked by a template with `requires_approval`, then send the outbox
+    Approve {
+        /// The E-mail ID, as listed by `approve --list`
+        #[arg(required_unless_present_any = ["all", "list"])]
+        email_id: Option<String>,
+
+        /// Approve every parked E-mail
+        #[arg(long, conflicts_with = "email_id")]
+        all: bool,
+
+        /// List the parked E-mails without approving or sending anything
+        #[arg(long, conflicts_with_all = ["email_id", "all"])]
+        list: bool,
+    },
+
+    /// Reject an E-mail parked for approval, moving its entries to the outbox `failed` directory
+    Deny {
+        /// The E-mail ID, as listed by `approve --list`
+        email_id: String,
+
+        /// Why the E-mail was denied, kept with its entries
+        #[arg(long, default_value = "Denied by an operator")]
+        reason: String,
+    },
+
+    /// Render the composed E-mails of the outbox and check them against the content policy of their template,
+    /// without sending
+    Lint {
+        /// Also validate every entry file of the outbox against the entry JSON Schema of the `schema` command
+        #[arg(long)]
+        schema: bool,
+    },
+
+    /// Print the JSON Schema of the entry files, or their field reference as Markdown, for producers
+    Schema {
+        /// The output format: `json-schema` or `markdown`
+        #[arg(long, value_name = "FORMAT", default_value_t = SchemaFormat::JsonSchema)]
+        format: SchemaFormat,
+    },
+
+    /// Print the volume of the mail sent during a month: messages, formatted bytes, attachment bytes and recipients
+    Stats {
+        /// A row per template, instead of a single one for all of them
+        #[arg(long)]
+        by_template: bool,
+
+        /// The month, as `YYYY-MM`, the current one by default
+        #[arg(long, value_name = "YYYY-MM")]
+        month: Option<String>,
+
+        /// The output format: `table` or `csv`
+        #[arg(long, value_name = "FORMAT", default_value_t = StatsFormat::Table)]
+        format: StatsFormat,
+    },
+
+    /// Drain a synthesized outbox through the compose, render and build pipeline into a transport discarding every
+    /// message, then print the throughput, the time of each stage and the peak memory. Nothing is sent
+    Bench {
+        /// The count of entries synthesized into a temporary outbox
+        #[arg(
+            long,
+            value_name = "COUNT",
+            default_value_t = 1000,
+            conflicts_with = "outbox"
+        )]
+        entries: usize,
+
+        /// The template of the synthesized entries
+        #[arg(long, value_name = "TEMPLATE", required_unless_present = "outbox")]
+        template: Option<String>,
+
+        /// The share of the synthesized entries accumulated into the E-mail of another entry, from 0 to 1, excluded
+        #[arg(
+            long,
+            value_name = "RATIO",
+            default_value_t = 0.0,
+            conflicts_with = "outbox"
+        )]
+        batch_ratio: f64,
+
+        /// A JSON file of the context of every synthesized entry, `{}` by default
+        #[arg(long, value_name = "PATH", conflicts_with = "outbox")]
+        context: Option<PathBuf>,
+
+        /// Drain an existing outbox instead, only reading its entries
+        #[arg(long, value_name = "DIR", conflicts_with = "template")]
+        outbox: Option<PathBuf>,
+
+        /// The output format: `table` or `json`
+        #[arg(long, value_name = "FORMAT", default_value_t = BenchFormat::Table)]
+        format: BenchFormat,
+    },
+
+    /// Create a template directory whose `template.html` extends the shared `base.html` layout,
+    /// creating the layout in the `shared` template directory when missing
+    NewTemplate {
+        /// The name of the template directory, as set in the `template` of the entries
+        name: String,
+    },
+
+    /// Print or check the effective configuration: the arguments, environment and defaults a run would use
+    #[command(subcommand)]
+    Config(ConfigCommand),
+
+    /// Poll an IMAP mailbox and write the entry of every incoming message (a JSON attachment or a plain text
+    /// body) into the outbox, for producers that can only send E-mails
+    #[cfg(feature = "ingest-imap")]
+    IngestImap(IngestImapArgs),
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum ConfigCommand {
+    /// Print the effective configuration, with the source of each value: `default`, `env` or `cli`
+    Show {
+        /// The output format: `toml` or `json`
+        #[arg(long, value_name = "FORMAT", default_value_t = ConfigFormat::Toml)]
+        format: ConfigFormat,
+
+        /// Also redact the hosts and addresses, for a configuration shared outside the team
+        #[arg(long)]
+        redact: bool,
+
+        /// Print the passwords, secrets and tokens instead of redacting them
+        #[arg(long, conflicts_with = "redact")]
+        unsafe_show_secrets: bool,
+    },
+
+    /// Validate the effective configuration without running
+    Check,
+}
+
+/// The mailbox polled by `ingest-imap`. The credentials are read from `IMAP_USERNAME` and `IMAP_PASSWORD`,
+/// or the mail relay `USERNAME` and `PASSWORD` when unset
+#[cfg(feature = "ingest-imap")]
+#[derive(Args, Debug)]
+pub(crate) struct IngestImapArgs {
+    /// The IMAP server
+    #[arg(long, env = "IMAP_SERVER", value_name = "HOST")]
+    pub(crate) host: String,
+
+    /// The IMAP server port, 143 by default or 993 with `--auth tls`
+    #[arg(long, env = "IMAP_PORT", value_name = "PORT")]
+    pub(crate) port: Option<u16>,
+
+    /// How to connect: `noauth`, `tls` or `starttls`, as with the mail relay `AUTH`
+    #[arg(long, env = "IMAP_AUTH", value_name = "METHOD", default_value = "tls")]
+    pub(crate) auth: String,
+
+    /// The folder polled for new entries
+    #[arg(long, value_name = "FOLDER", default_value = "INBOX")]
+    pub(crate) folder: String,
+
+    /// Where messages are moved once their entry was written into the outbox
+    #[arg(long, value_name = "FOLDER", default_value = "Processed")]
+    pub(crate) processed_folder: String,
+
+    /// Where messages that aren't valid entries are moved
+    #[arg(long, value_name = "FOLDER", default_value = "Rejected")]
+    pub(crate) rejected_folder: String,
+
+    /// Seconds between two polls of the mailbox
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    pub(crate) poll_interval: u64,
+
+    /// Poll the mailbox a single time and exit
+    #[arg(long)]
+    pub(crate) once: bool,
+
+    /// Reply to the sender of a rejected message with the error, from this address, through the mail relay
+    #[arg(long, value_name = "ADDRESS")]
+    pub(crate) reply_from: Option<String>,
+}
@@ -0,0 +1,676 @@
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::cli::Cli;
+use crate::send::Authentication;
+
+/// The configuration file next to the binary, unless `--config` is set.
+pub(crate) const CONFIG_FILE: &str = "config.toml";
+
+/// The mail relay defaults, when neither `--server`, `--port` and `--auth` nor `SERVER`, `PORT` and `AUTH` are set.
+pub(crate) const DEFAULT_SERVER: &str = "localhost";
+pub(crate) const DEFAULT_PORT: &str = "25";
+pub(crate) const DEFAULT_AUTH: &str = "noauth";
+
+const REDACTED: &str = "<redacted>";
+
+/// The settings only read from the environment, with their default.
+const ENVIRONMENT: &[(&str, Option<&str>)] = &[("IMAP_USERNAME", None), ("IMAP_PASSWORD", None)];
+
+/// Settings identifying a host or a person, hidden by `--redact`.
+const IDENTIFYING: &[&str] = &[
+    "IMAP_USERNAME",
+    "bounce-address",
+    "otlp-endpoint",
+    "s3-access-key-id",
+    "s3-bucket",
+    "s3-endpoint",
+    "server",
+    "syslog-address",
+    "username",
+];
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum ConfigError {
+    #[error("Unknown configuration format \"{0}\"")]
+    UnknownFormat(String),
+
+    #[error("Unable to read the configuration file \"{path}\": {error}")]
+    Unreadable { path: PathBuf, error: String },
+
+    #[error("Malformed configuration file \"{path}\" at line {line}, column {column}: {message}")]
+    Malformed {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+}
+
+/// The settings of the configuration file, e.g. `server = "relay.example.com"`. The arguments and the environment
+/// take precedence over them, and they take precedence over the defaults.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// The mail relay host, as `--server`
+    pub(crate) server: Option<String>,
+
+    /// The mail relay port, as `--port`
+    pub(crate) port: Option<u16>,
+
+    /// How to connect to the mail relay, as `--auth`
+    pub(crate) auth: Option<Authentication>,
+
+    /// Seconds to wait on the mail relay, as `--timeout`
+    pub(crate) timeout: Option<u64>,
+
+    /// The outbox directory, as `--outbox`. Relative to the configuration file
+    pub(crate) outbox: Option<PathBuf>,
+
+    /// The templates directory, as `--templates`. Relative to the configuration file
+    pub(crate) templates: Option<PathBuf>,
+}
+
+impl Config {
+    /// Loads a configuration file. None if there is no such file.
+    /// ## Error
+    /// Fails if the file can't be read, or isn't valid TOML of the known settings.
+    pub(crate) fn load(path: &Path) -> Result<Option<Self>, ConfigError> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(path).map_err(|e| ConfigError::Unreadable {
+            path: path.to_owned(),
+            error: e.to_string(),
+        })?;
+
+        let mut config: Self = toml::from_str(&contents).map_err(|e| {
+            let offset = e.span().map(|span| span.start).unwrap_or_default();
+            let before = &contents[..offset.min(contents.len())];
+
+            ConfigError::Malformed {
+                path: path.to_owned(),
+                line: before.matches('\n').count() + 1,
+                column: before.chars().rev().take_while(|&c| c != '\n').count() + 1,
+                message: e.message().to_owned(),
+            }
+        })?;
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for dir_setting in [&mut config.outbox, &mut config.templates]
+            .into_iter()
+            .flatten()
+        {
+            *dir_setting = dir.join(&*dir_setting);
+        }
+
+        Ok(Some(config))
+    }
+
+    /// The value of a setting, named by its argument.
+    fn value(&self, name: &str) -> Option<String> {
+        match name {
+            "server" => self.server.clone(),
+            "port" => self.port.map(|port| port.to_string()),
+            "auth" => self.auth.map(|auth| auth.to_string()),
+            "timeout" => self.timeout.map(|timeout| timeout.to_string()),
+            "outbox" => self.outbox.as_ref().map(|dir| dir.display().to_string()),
+            "templates" => self.templates.as_ref().map(|dir| dir.display().to_string()),
+            _ => None,
+        }
+    }
+
+    /// Sets the arguments given neither on the command line nor in the environment to the values of the file.
+    pub(crate) fn apply(&self, cli: &mut Cli, matches: &ArgMatches) {
+        let unset = |id: &str| {
+            !matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+
+        if let Some(server) = self.server.clone().filter(|_| unset("server")) {
+            cli.server = server;
+        }
+        if let Some(port) = self.port.filter(|_| unset("port")) {
+            cli.port = port;
+        }
+        if let Some(auth) = self.auth.filter(|_| unset("auth")) {
+            cli.auth = auth;
+        }
+        if let Some(timeout) = self.timeout.filter(|_| unset("timeout")) {
+            cli.timeout = timeout;
+        }
+        if let Some(outbox) = self.outbox.clone().filter(|_| unset("outbox")) {
+            cli.outbox = Some(outbox);
+        }
+        if let Some(templates) = self.templates.clone().filter(|_| unset("templates")) {
+            cli.templates = Some(templates);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum ConfigFormat {
+    #[default]
+    Toml,
+    Json,
+}
+
+impl fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigFormat::Toml => write!(f, "toml"),
+            ConfigFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let res = match s.trim().to_lowercase().as_str() {
+            "toml" => ConfigFormat::Toml,
+            "json" => ConfigFormat::Json,
+            _ => return Err(ConfigError::UnknownFormat(s.to_string())),
+        };
+
+        Ok(res)
+    }
+}
+
+/// Where the effective value of a setting comes from.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Source {
+    Default,
+    File,
+    Env,
+    Cli,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File => write!(f, "file"),
+            Source::Env => write!(f, "env"),
+            Source::Cli => write!(f, "cli"),
+        }
+    }
+}
+
+/// Which values are hidden from the printed configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Redaction {
+    /// Every value is printed, secrets included
+    Nothing,
+
+    /// Passwords, secrets and tokens are hidden
+    Secrets,
+
+    /// Hosts and addresses are hidden as well, for a configuration shared outside the team
+    Identifying,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Setting {
+    pub(crate) name: String,
+
+    /// Unset when the setting has no default
+    pub(crate) value: Option<String>,
+    pub(crate) source: Source,
+}
+
+impl Setting {
+    fn is_secret(&self) -> bool {
+        let name = self.name.to_lowercase();
+        ["password", "secret", "token"]
+            .iter()
+            .any(|secret| name.contains(secret))
+    }
+
+    fn is_identifying(&self) -> bool {
+        IDENTIFYING.contains(&self.name.as_str())
+    }
+}
+
+/// The configuration a run uses, merged from its arguments, environment and defaults.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EffectiveConfig {
+    /// The arguments, named by their long flag
+    pub(crate) arguments: Vec<Setting>,
+
+    /// The settings only read from the environment
+    pub(crate) env: Vec<Setting>,
+}
+
+impl EffectiveConfig {
+    /// Collects the top-level arguments of `command` as parsed into `matches`, or set by the configuration `file`,
+    /// along with the environment settings as returned by `env`.
+    pub(crate) fn new(
+        command: &clap::Command,
+        matches: &ArgMatches,
+        file: &Config,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Self {
+        let arguments = command
+            .get_arguments()
+            .filter(|arg| !matches!(arg.get_id().as_str(), "help" | "version"))
+            .map(|arg| {
+                let id = arg.get_id().as_str();
+                let value = matches.get_raw(id).map(|values| {
+                    values
+                        .map(|value| value.to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                });
+                let source = match matches.value_source(id) {
+                    Some(ValueSource::CommandLine) => Source::Cli,
+                    Some(ValueSource::EnvVariable) => Source::Env,
+                    _ => Source::Default,
+                };
+
+                if let Some(value) = file.value(id).filter(|_| source == Source::Default) {
+                    return Setting {
+                        name: arg.get_long().unwrap_or(id).to_owned(),
+                        value: Some(value),
+                        source: Source::File,
+                    };
+                }
+
+                Setting {
+                    name: arg.get_long().unwrap_or(id).to_owned(),
+                    value,
+                    source,
+                }
+            })
+            .collect();
+
+        let env_settings = ENVIRONMENT
+            .iter()
+            .map(|&(name, default)| match env(name) {
+                Some(value) => Setting {
+                    name: name.to_owned(),
+                    value: Some(value),
+                    source: Source::Env,
+                },
+                None => Setting {
+                    name: name.to_owned(),
+                    value: default.map(str::to_owned),
+                    source: Source::Default,
+                },
+            })
+            .collect();
+
+        Self {
+            arguments,
+            env: env_settings,
+        }
+    }
+
+    /// Where the named arguments come from, e.g. `(server: cli, port: env)`, printed at startup.
+    pub(crate) fn sources(&self, names: &[&str]) -> String {
+        let sources = names
+            .iter()
+            .filter_map(|name| self.arguments.iter().find(|setting| setting.name == *name))
+            .map(|setting| format!("{}: {}", setting.name, setting.source))
+            .collect::<Vec<_>>();
+
+        format!("({})", sources.join(", "))
+    }
+
+    /// Hides the values the redaction applies to. Unset values stay unset.
+    pub(crate) fn redact(&mut self, redaction: Redaction) {
+        for setting in self.arguments.iter_mut().chain(&mut self.env) {
+            let redacted = match redaction {
+                Redaction::Nothing => false,
+                Redaction::Secrets => setting.is_secret(),
+                Redaction::Identifying => setting.is_secret() || setting.is_identifying(),
+            };
+
+            if redacted && setting.value.is_some() {
+                setting.value = Some(REDACTED.to_owned());
+            }
+        }
+    }
+
+    /// The configuration as TOML, each value annotated with its source.
+    /// The environment settings are listed under `[env]`.
+    pub(crate) fn to_toml(&self) -> String {
+        let settings = |settings: &[Setting]| {
+            settings
+                .iter()
+                .map(|setting| match &setting.value {
+                    Some(value) => format!(
+                        "{} = {} # {}\n",
+                        setting.name,
+                        toml::Value::String(value.clone()),
+                        setting.source
+                    ),
+                    None => format!("# {} is not set\n", setting.name),
+                })
+                .collect::<String>()
+        };
+
+        format!(
+            "{}\n[env]\n{}",
+            settings(&self.arguments),
+            settings(&self.env)
+        )
+    }
+
+    pub(crate) fn render(&self, format: ConfigFormat) -> String {
+        match format {
+            ConfigFormat::Toml => self.to_toml(),
+            ConfigFormat::Json => {
+                serde_json::to_string_pretty(self).expect("Configuration is always valid JSON")
+            }
+        }
+    }
+
+    /// Writes the configuration for a later reproduction of the run, as JSON for a `.json` file
+    /// and TOML otherwise.
+    pub(crate) fn write_snapshot(&self, path: &Path) -> Result<()> {
+        let format = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Toml,
+        };
+
+        fs::write(path, self.render(format)).with_context(|| {
+            format!(
+                "Unable to write configuration snapshot \"{}\"",
+                path.display()
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Cli;
+    use clap::CommandFactory;
+
+    fn effective_config(args: &[&str], env: &[(&str, &str)]) -> EffectiveConfig {
+        let command = Cli::command();
+        let matches = command
+            .clone()
+            .try_get_matches_from(std::iter::once("osa_mailer").chain(args.iter().copied()))
+            .unwrap();
+
+        EffectiveConfig::new(&command, &matches, &Config::default(), |name| {
+            env.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    fn setting<'a>(settings: &'a [Setting], name: &str) -> &'a Setting {
+        settings
+            .iter()
+            .find(|setting| setting.name == name)
+            .unwrap_or_else(|| panic!("Missing setting `{name}`"))
+    }
+
+    #[test]
+    fn test_provenance() {
+        let config = effective_config(
+            &[
+                "--transport",
+                "file",
+                "--max-run-time",
+                "60",
+                "--environment",
+                "staging",
+                "--server",
+                "relay.x.com",
+            ],
+            &[("IMAP_USERNAME", "mailer"), ("IMAP_PASSWORD", "hunter2")],
+        );
+
+        let transport = setting(&config.arguments, "transport");
+        assert_eq!(transport.value.as_deref(), Some("file"));
+        assert_eq!(transport.source, Source::Cli);
+
+        let transport_dir = setting(&config.arguments, "transport-dir");
+        assert_eq!(transport_dir.value.as_deref(), Some("sent_mail"));
+        assert_eq!(transport_dir.source, Source::Default);
+
+        assert_eq!(setting(&config.arguments, "bounce-address").value, None);
+
+        let server = setting(&config.arguments, "server");
+        assert_eq!(server.value.as_deref(), Some("relay.x.com"));
+        assert_eq!(server.source, Source::Cli);
+        let port = setting(&config.arguments, "port");
+        assert_eq!(port.value.as_deref(), Some(DEFAULT_PORT));
+        assert_eq!(port.source, Source::Default);
+        let username = setting(&config.env, "IMAP_USERNAME");
+        assert_eq!(username.value.as_deref(), Some("mailer"));
+        assert_eq!(username.source, Source::Env);
+        assert_eq!(
+            config.sources(&["server", "port", "unknown"]),
+            "(server: cli, port: default)"
+        );
+
+        let toml = config.to_toml();
+        assert!(toml.contains("max-run-time = \"60\" # cli\n"), "{toml}");
+        assert!(toml.contains("transport-dir = \"sent_mail\" # default\n"));
+        assert!(toml.contains("# bounce-address is not set\n"));
+        assert!(
+            toml.contains("\n[env]\nIMAP_USERNAME = \"mailer\" # env\n"),
+            "{toml}"
+        );
+
+        // The TOML output is valid, so it can be compared or loaded by other tools
+        let parsed: toml::Table = toml.parse().unwrap();
+        assert_eq!(parsed["port"].as_str(), Some(DEFAULT_PORT));
+        assert_eq!(parsed["environment"].as_str(), Some("staging"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&config.render(ConfigFormat::Json)).unwrap();
+        assert!(json["arguments"].as_array().unwrap().contains(
+            &serde_json::json!({ "name": "max-run-time", "value": "60", "source": "cli" })
+        ));
+    }
+
+    #[test]
+    fn test_relay_arguments() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from([
+            "osa_mailer",
+            "--server",
+            "relay.x.com",
+            "--port",
+            "2525",
+            "--auth",
+            "STARTTLS",
+            "--timeout",
+            "5",
+        ])
+        .unwrap();
+
+        let relay = cli.relay();
+        assert_eq!(relay.relay(), "relay.x.com");
+        assert_eq!(*relay.port(), 2525);
+        assert_eq!(*relay.auth(), crate::send::Authentication::Starttls);
+        assert_eq!(*relay.timeout(), std::time::Duration::from_secs(5));
+
+        assert_eq!(cli.relay_credentials(), None);
+
+        let error = Cli::try_parse_from(["osa_mailer", "--auth", "ssl"]).unwrap_err();
+        assert_eq!(error.kind(), clap::error::ErrorKind::ValueValidation);
+
+        // The names of the former environment-only configuration
+        let cli = Cli::try_parse_from([
+            "osa_mailer",
+            "--relay",
+            "relay.x.com",
+            "--username",
+            "mailer",
+            "--password",
+            "hunter2",
+            "--outbox-dir",
+            "/var/spool/outbox",
+            "--templates-dir",
+            "/etc/osa/templates",
+        ])
+        .unwrap();
+        assert_eq!(cli.relay().relay(), "relay.x.com");
+        assert_eq!(*cli.relay().port(), 25);
+        assert_eq!(*cli.relay().auth(), crate::send::Authentication::NoAuth);
+        assert_eq!(
+            cli.relay_credentials(),
+            Some(("mailer".to_owned(), "hunter2".to_owned()))
+        );
+        assert_eq!(cli.outbox.as_deref(), Some(Path::new("/var/spool/outbox")));
+        assert_eq!(
+            cli.templates.as_deref(),
+            Some(Path::new("/etc/osa/templates"))
+        );
+
+        // Without a password, e.g. the `USERNAME` Windows sets, there are no credentials
+        let cli = Cli::try_parse_from(["osa_mailer", "--username", "mailer"]).unwrap();
+        assert_eq!(cli.relay_credentials(), None);
+    }
+
+    #[test]
+    fn test_config_file() {
+        use clap::FromArgMatches;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE);
+        assert_eq!(Config::load(&path).unwrap(), None);
+
+        fs::write(
+            &path,
+            "server = \"relay.x.com\"\nport = 587\nauth = \"starttls\"\noutbox = \"spool/outbox\"\n",
+        )
+        .unwrap();
+        let config = Config::load(&path).unwrap().unwrap();
+        assert_eq!(config.auth, Some(Authentication::Starttls));
+        assert_eq!(config.outbox, Some(dir.path().join("spool/outbox")));
+
+        // The arguments take precedence over the file, which takes precedence over the defaults
+        let command = Cli::command();
+        let matches = command
+            .clone()
+            .try_get_matches_from(["osa_mailer", "--port", "2525"])
+            .unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        config.apply(&mut cli, &matches);
+
+        let relay = cli.relay();
+        assert_eq!(relay.relay(), "relay.x.com");
+        assert_eq!(*relay.port(), 2525);
+        assert_eq!(*relay.auth(), Authentication::Starttls);
+        assert_eq!(*relay.timeout(), std::time::Duration::from_secs(60));
+        assert_eq!(cli.outbox, Some(dir.path().join("spool/outbox")));
+        assert_eq!(cli.templates, None);
+
+        let effective = EffectiveConfig::new(&command, &matches, &config, |_| None);
+        assert_eq!(
+            effective.sources(&["server", "port", "auth", "timeout", "outbox"]),
+            "(server: file, port: cli, auth: file, timeout: default, outbox: file)"
+        );
+        assert_eq!(
+            setting(&effective.arguments, "server").value.as_deref(),
+            Some("relay.x.com")
+        );
+    }
+
+    #[test]
+    fn test_malformed_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE);
+
+        fs::write(&path, "server = \"relay.x.com\"\nport = \"many\"\n").unwrap();
+        let error = Config::load(&path).unwrap_err();
+        assert!(
+            matches!(
+                error,
+                ConfigError::Malformed {
+                    line: 2,
+                    column: 8,
+                    ..
+                }
+            ),
+            "{error}"
+        );
+
+        fs::write(&path, "auth = \"ssl\"\n").unwrap();
+        let error = Config::load(&path).unwrap_err().to_string();
+        assert!(error.contains("at line 1, column 8"), "{error}");
+        assert!(error.contains("ssl"), "{error}");
+
+        fs::write(&path, "\nrelay = \"relay.x.com\"\n").unwrap();
+        let error = Config::load(&path).unwrap_err().to_string();
+        assert!(error.contains("at line 2, column 1"), "{error}");
+    }
+
+    #[test]
+    fn test_redaction() {
+        let env = [("IMAP_USERNAME", "mailer"), ("IMAP_PASSWORD", "hunter2")];
+        let args = [
+            "--bounce-address",
+            "bounces@x.com",
+            "--server",
+            "relay.x.com",
+            "--username",
+            "relay-user",
+            "--password",
+            "s3cr3t",
+        ];
+
+        let mut config = effective_config(&args, &env);
+        config.redact(Redaction::Nothing);
+        assert_eq!(
+            setting(&config.env, "IMAP_PASSWORD").value.as_deref(),
+            Some("hunter2")
+        );
+
+        let mut config = effective_config(&args, &env);
+        config.redact(Redaction::Secrets);
+        let toml = config.to_toml();
+        assert!(
+            toml.contains("IMAP_PASSWORD = \"<redacted>\" # env\n"),
+            "{toml}"
+        );
+        assert!(!toml.contains("hunter2"));
+        assert!(toml.contains("password = \"<redacted>\" # cli\n"), "{toml}");
+        assert!(toml.contains("server = \"relay.x.com\" # cli\n"));
+
+        let mut config = effective_config(&args, &env);
+        config.redact(Redaction::Identifying);
+        let toml = config.to_toml();
+        for hidden in [
+            "hunter2",
+            "s3cr3t",
+            "relay.x.com",
+            "relay-user",
+            "mailer\"",
+            "bounces@x.com",
+        ] {
+            assert!(!toml.contains(hidden), "{toml}");
+        }
+        assert!(toml.contains("bounce-address = \"<redacted>\" # cli\n"));
+        // Unset values are not turned into redacted ones
+        assert!(toml.contains("# max-run-time is not set\n"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        config.write_snapshot(&path).unwrap();
+        let snapshot = fs::read_to_string(&path).unwrap();
+        assert_eq!(snapshot, config.render(ConfigFormat::Json));
+        assert!(!snapshot.contains("hunter2"));
+    }
+}
@@ -0,0 +1,192 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::alternative;
+
+lazy_static! {
+    /// The entities HTML escaping produces, named or numeric.
+    static ref ESCAPE_PATTERN: Regex =
+        Regex::new(r"&(amp|lt|gt|quot|apos|#[0-9]{1,7}|#[xX][0-9a-fA-F]{1,6});").unwrap();
+}
+
+/// How the engine treats the HTML of the string values of the context, set in `template.toml` as
+/// `context_html_policy`.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ContextHtmlPolicy {
+    /// The values are escaped by the engine, as written by the producer
+    #[default]
+    Escape,
+
+    /// The values are rendered without escaping, as trusted HTML.
+    /// Dangerous: any producer value can inject markup, links or scripts into the E-mail
+    Trust,
+
+    /// The values the producer already escaped are decoded once, then escaped by the engine
+    Detect,
+}
+
+/// Whether the value looks escaped by its producer: it has an entity, every `&` starts one,
+/// and it has no raw `<` or `>`. A value mixing entities with raw characters is literal text, and kept.
+pub(crate) fn is_pre_escaped(value: &str) -> bool {
+    if value.contains(['<', '>']) {
+        return false;
+    }
+
+    let entities = ESCAPE_PATTERN.find_iter(value).count();
+    entities > 0 && value.matches('&').count() == entities
+}
+
+/// Decodes the pre-escaped string values, within arrays and objects. Returns the count of decoded values.
+fn decode_pre_escaped(value: &mut serde_json::Value) -> usize {
+    match value {
+        serde_json::Value::String(text) if is_pre_escaped(text) => {
+            *text = alternative::decode_entities(text);
+            1
+        }
+        serde_json::Value::Array(items) => items.iter_mut().map(decode_pre_escaped).sum(),
+        serde_json::Value::Object(object) => object.values_mut().map(decode_pre_escaped).sum(),
+        _ => 0,
+    }
+}
+
+/// The context a template renders under the policy: with `detect`, a copy whose pre-escaped values are decoded
+/// once, leaving `_meta` to the mailer. The context of the E-mail is kept as written, so every rendering of it,
+/// in this run or the next, decodes the same values once.
+pub(crate) fn prepare(
+    context: &serde_json::Map<String, serde_json::Value>,
+    policy: ContextHtmlPolicy,
+) -> serde_json::Value {
+    let mut context = context.clone();
+
+    if policy == ContextHtmlPolicy::Detect {
+        let decoded: usize = context
+            .iter_mut()
+            .filter(|(key, _)| *key != "_meta")
+            .map(|(_, value)| decode_pre_escaped(value))
+            .sum();
+
+        if decoded > 0 {
+            log::debug!("Decoded {decoded} pre-escaped context value(s)");
+        }
+    }
+
+    serde_json::Value::Object(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{self, ContextData, DetectionMethod, TemplateData, TemplateExtension};
+    use serde_json::json;
+    use std::rc::Rc;
+
+    fn render(template: &str, context: &serde_json::Value, policy: ContextHtmlPolicy) -> String {
+        let template_data = TemplateData {
+            contents: Rc::new(template.to_owned()),
+            file_path: None,
+            extensions: None,
+        };
+        let context_data = ContextData {
+            context: prepare(context.as_object().unwrap(), policy),
+            file_path: None,
+            trusted: policy == ContextHtmlPolicy::Trust,
+            local_time: Default::default(),
+        };
+
+        render::render(
+            &template_data,
+            &context_data,
+            DetectionMethod::Auto,
+            TemplateExtension::Auto,
+        )
+        .unwrap_or_else(|_| panic!("The template renders"))
+        .0
+        .to_string()
+    }
+
+    #[test]
+    fn test_detection_heuristics() {
+        // Escaped by the producer
+        assert!(is_pre_escaped("Tom &amp; Jerry"));
+        assert!(is_pre_escaped("&lt;b&gt;bold&lt;/b&gt;"));
+        assert!(is_pre_escaped("It&#39;s &#x27;quoted&#x27;"));
+
+        // Plain text, or literal entity text alongside raw characters
+        assert!(!is_pre_escaped("Tom & Jerry"));
+        assert!(!is_pre_escaped("Write & as &amp;"));
+        assert!(!is_pre_escaped("Use <b> or &lt;b&gt;"));
+        assert!(!is_pre_escaped("&nbsp;&amp;"));
+        assert!(!is_pre_escaped("R&D;"));
+        assert!(!is_pre_escaped(""));
+    }
+
+    #[test]
+    fn test_detect_policy() {
+        let template = "<!--TEMPLATE tera-->{{ title }}|{% for row in rows %}{{ row.name }};{% endfor %}|{{ _meta.url }}";
+        let context = json!({
+            "title": "Tom &amp; Jerry &lt;3",
+            "rows": [{ "name": "R&amp;D" }, { "name": "Write & as &amp;" }, { "name": 7 }],
+            "_meta": { "url": "https://x.com/?a=1&amp;b=2" },
+        });
+
+        assert_eq!(
+            render(template, &context, ContextHtmlPolicy::Escape),
+            "Tom &amp;amp; Jerry &amp;lt;3|R&amp;amp;D;Write &amp; as &amp;amp;;7;|https:&#x2F;&#x2F;x.com&#x2F;?a=1&amp;amp;b=2"
+        );
+        assert_eq!(
+            render(template, &context, ContextHtmlPolicy::Detect),
+            "Tom &amp; Jerry &lt;3|R&amp;D;Write &amp; as &amp;amp;;7;|https:&#x2F;&#x2F;x.com&#x2F;?a=1&amp;amp;b=2"
+        );
+    }
+
+    #[test]
+    fn test_detect_is_idempotent() {
+        let template = "<!--TEMPLATE handlebars-->{{ title }}";
+        let context = json!({ "title": "&amp;lt;b&amp;gt; double &amp;amp; escaped" });
+
+        // Decoded once per rendering, as the context keeps the values of the producer
+        let first = render(template, &context, ContextHtmlPolicy::Detect);
+        let rerun = render(template, &context, ContextHtmlPolicy::Detect);
+        assert_eq!(first, rerun);
+        assert_eq!(first, "&amp;lt;b&amp;gt; double &amp;amp; escaped");
+
+        let prepared = prepare(context.as_object().unwrap(), ContextHtmlPolicy::Detect);
+        assert_eq!(prepared["title"], "&lt;b&gt; double &amp; escaped");
+        assert_eq!(
+            context["title"],
+            "&amp;lt;b&amp;gt; double &amp;amp; escaped"
+        );
+
+        // A decoded value with raw characters is not decoded again
+        let single = json!({ "title": "&lt;b&gt;" });
+        let prepared = prepare(single.as_object().unwrap(), ContextHtmlPolicy::Detect);
+        assert_eq!(prepared["title"], "<b>");
+        assert_eq!(
+            prepare(prepared.as_object().unwrap(), ContextHtmlPolicy::Detect),
+            prepared
+        );
+    }
+
+    #[test]
+    fn test_trust_policy() {
+        let context = json!({ "title": "<b>Tom &amp; Jerry</b>" });
+
+        for template in [
+            "<!--TEMPLATE tera-->{{ title }}",
+            "<!--TEMPLATE handlebars-->{{ title }}",
+        ] {
+            assert_eq!(
+                render(template, &context, ContextHtmlPolicy::Trust),
+                "<b>Tom &amp; Jerry</b>",
+                "{template}"
+            );
+            assert_ne!(
+                render(template, &context, ContextHtmlPolicy::Escape),
+                "<b>Tom &amp; Jerry</b>",
+                "{template}"
+            );
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// The prefix of the classes marking the elements with inline colors of a generated dark variant.
+const DARK_CLASS_PREFIX: &str = "osa-dark-";
+
+/// The meta tags telling Apple Mail and other modern clients the HTML handles both color schemes itself,
+/// so they don't invert its colors.
+const COLOR_SCHEME_META: &str = r#"<meta name="color-scheme" content="light dark"><meta name="supported-color-schemes" content="light dark">"#;
+
+const COLOR_SCHEME_ROOT: &str =
+    ":root { color-scheme: light dark; supported-color-schemes: light dark; }";
+
+lazy_static! {
+    static ref HEAD_TAG_PATTERN: Regex = Regex::new(r"(?i)<head\b[^>]*>").unwrap();
+    static ref HTML_TAG_PATTERN: Regex = Regex::new(r"(?i)<html\b[^>]*>").unwrap();
+    static ref COLOR_SCHEME_META_PATTERN: Regex =
+        Regex::new(r#"(?i)<meta\s[^>]*name\s*=\s*["']?color-scheme\b"#).unwrap();
+    static ref STYLE_BLOCK_PATTERN: Regex =
+        Regex::new(r"(?is)<style\b[^>]*>(.*?)</style\s*>").unwrap();
+    static ref START_TAG_PATTERN: Regex = Regex::new(r"(?i)<[a-z][a-z0-9]*\b[^>]*>").unwrap();
+    static ref STYLE_ATTRIBUTE_PATTERN: Regex =
+        Regex::new(r#"(?i)\sstyle\s*=\s*"([^"]*)""#).unwrap();
+    static ref CLASS_ATTRIBUTE_PATTERN: Regex =
+        Regex::new(r#"(?i)(\sclass\s*=\s*")([^"]*)""#).unwrap();
+    static ref COLOR_PATTERN: Regex = Regex::new(r"(?i)#[0-9a-f]{3,8}\b|\b[a-z]+\b").unwrap();
+}
+
+/// How the HTML of a template supports the dark mode of modern clients, set in `template.toml` as `dark_mode`.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DarkMode {
+    /// The HTML is sent as rendered, and clients may invert its colors
+    #[default]
+    Off,
+
+    /// The color scheme meta tags are injected, the template brings its own
+    /// `@media (prefers-color-scheme: dark)` rules
+    Meta,
+
+    /// As `meta`, with a dark variant generated by mapping the colors of `dark_colors`
+    Auto,
+}
+
+/// The colors of the declarations, replaced by their dark counterpart, each as `!important` so it wins over
+/// the inline styles. None when no color of the declarations is mapped.
+fn dark_declarations(declarations: &str, colors: &BTreeMap<String, String>) -> Option<String> {
+    let mapped: Vec<String> = declarations
+        .split(';')
+        .filter_map(|declaration| declaration.split_once(':'))
+        .filter_map(|(property, value)| {
+            let mut changed = false;
+            let value = COLOR_PATTERN.replace_all(value.trim(), |caps: &regex::Captures| {
+                match colors.get(&caps[0].to_ascii_lowercase()) {
+                    Some(dark) => {
+                        changed = true;
+                        dark.clone()
+                    }
+                    None => caps[0].to_owned(),
+                }
+            });
+            let value = value.trim_end_matches("!important").trim();
+
+            changed.then(|| format!("{}: {value} !important;", property.trim()))
+        })
+        .collect();
+
+    (!mapped.is_empty()).then(|| mapped.join(" "))
+}
+
+/// The rules outside of at-rules, such as `@media`, as their selector and declarations.
+fn top_level_rules(css: &str) -> Vec<(&str, &str)> {
+    let mut rules = Vec::new();
+    let mut rest = css;
+
+    while let Some(open) = rest.find('{') {
+        let selector = rest[..open].trim();
+
+        // The closing brace of the rule, or of the at-rule with its nested rules
+        let mut depth = 0;
+        let close = rest[open..].char_indices().find_map(|(i, c)| {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            }
+            (depth == 0).then_some(open + i)
+        });
+        let Some(close) = close else {
+            break;
+        };
+
+        if !selector.starts_with('@') {
+            rules.push((selector, &rest[open + 1..close]));
+        }
+        rest = &rest[close + 1..];
+    }
+
+    rules
+}
+
+/// Generates a crude dark variant of the HTML: the elements with a mapped color in their inline style get a class,
+/// and the rules of the style blocks with a mapped color are repeated with the dark colors, under a
+/// `@media (prefers-color-scheme: dark)` block. Returns the marked HTML and the rules of the block.
+fn dark_variant(html: &str, colors: &BTreeMap<String, String>) -> (String, Vec<String>) {
+    let mut rules: Vec<String> = STYLE_BLOCK_PATTERN
+        .captures_iter(html)
+        .flat_map(|caps| {
+            top_level_rules(caps.get(1).map_or("", |css| css.as_str()))
+                .into_iter()
+                .filter_map(|(selector, declarations)| {
+                    dark_declarations(declarations, colors)
+                        .map(|dark| format!("{selector} {{ {dark} }}"))
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    let mut marked_count = 0;
+    let marked = START_TAG_PATTERN.replace_all(html, |caps: &regex::Captures| {
+        let tag = &caps[0];
+
+        let Some(dark) = STYLE_ATTRIBUTE_PATTERN
+            .captures(tag)
+            .and_then(|style| dark_declarations(&style[1], colors))
+        else {
+            return tag.to_owned();
+        };
+
+        let class = format!("{DARK_CLASS_PREFIX}{marked_count}");
+        marked_count += 1;
+        rules.push(format!(".{class} {{ {dark} }}"));
+
+        match CLASS_ATTRIBUTE_PATTERN.captures(tag) {
+            Some(existing) => CLASS_ATTRIBUTE_PATTERN
+                .replace(tag, format!("{}{} {class}\"", &existing[1], &existing[2]))
+                .into_owned(),
+            None => {
+                let end = tag.len() - if tag.ends_with("/>") { 2 } else { 1 };
+                format!("{} class=\"{class}\"{}", &tag[..end], &tag[end..])
+            }
+        }
+    });
+
+    (marked.into_owned(), rules)
+}
+
+/// Applies the dark mode support of a template to its rendered HTML: injects the color scheme meta tags into the
+/// `<head>`, created when missing, and with `auto` the dark variant of the `dark_colors`, mapping lowercase light
+/// colors to dark ones, e.g. `"#ffffff" = "#1e1e1e"`. The style blocks of the template are kept as written,
+/// so its own `@media (prefers-color-scheme: dark)` rules still apply.
+pub(crate) fn apply(html: &str, mode: DarkMode, colors: &BTreeMap<String, String>) -> String {
+    if mode == DarkMode::Off {
+        return html.to_owned();
+    }
+
+    let (html, rules) = match mode {
+        DarkMode::Auto => dark_variant(html, colors),
+        _ => (html.to_owned(), Vec::new()),
+    };
+
+    let mut head = String::new();
+    if !COLOR_SCHEME_META_PATTERN.is_match(&html) {
+        head.push_str(COLOR_SCHEME_META);
+    }
+    head.push_str("<style>");
+    head.push_str(COLOR_SCHEME_ROOT);
+    if !rules.is_empty() {
+        head.push_str(" @media (prefers-color-scheme: dark) { ");
+        head.push_str(&rules.join(" "));
+        head.push_str(" }");
+    }
+    head.push_str("</style>");
+
+    if let Some(tag) = HEAD_TAG_PATTERN.find(&html) {
+        format!("{}{head}{}", &html[..tag.end()], &html[tag.end()..])
+    } else if let Some(tag) = HTML_TAG_PATTERN.find(&html) {
+        format!(
+            "{}<head>{head}</head>{}",
+            &html[..tag.end()],
+            &html[tag.end()..]
+        )
+    } else {
+        format!("{head}{html}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alternative;
+    use std::fs;
+
+    fn colors() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("#ffffff".to_owned(), "#1e1e1e".to_owned()),
+            ("#333333".to_owned(), "#e0e0e0".to_owned()),
+        ])
+    }
+
+    #[test]
+    fn test_meta_tags() {
+        let html = r#"<html><head><title>Alerts</title></head><body>Alerts</body></html>"#;
+
+        let injected = apply(html, DarkMode::Meta, &colors());
+        assert!(injected.starts_with(&format!(
+            "<html><head>{COLOR_SCHEME_META}<style>{COLOR_SCHEME_ROOT}</style><title>"
+        )));
+
+        // A head is created when missing, and the meta tags of the template are kept
+        assert!(apply(
+            "<html><body>Alerts</body></html>",
+            DarkMode::Meta,
+            &colors()
+        )
+        .starts_with(&format!("<html><head>{COLOR_SCHEME_META}")));
+        assert!(apply("<p>Alerts</p>", DarkMode::Meta, &colors()).starts_with(COLOR_SCHEME_META));
+        assert_eq!(
+            apply(&injected, DarkMode::Meta, &colors())
+                .matches("name=\"color-scheme\"")
+                .count(),
+            1
+        );
+
+        assert_eq!(apply(html, DarkMode::Off, &colors()), html);
+    }
+
+    #[test]
+    fn test_dark_variant_of_fixture() {
+        let html = fs::read_to_string("tests/fixtures/dark_mode/template.html").unwrap();
+        let dark = apply(&html, DarkMode::Auto, &colors());
+
+        if std::env::var_os("UPDATE_FIXTURES").is_some() {
+            fs::write("tests/fixtures/dark_mode/expected.html", &dark).unwrap();
+        }
+        let expected = fs::read_to_string("tests/fixtures/dark_mode/expected.html").unwrap();
+        assert_eq!(dark, expected);
+
+        // The rules of the template, including its dark ones, are kept through the preheader injection
+        let with_preheader = alternative::inject_preheader(&dark, "3 new alerts");
+        let own_rules = STYLE_BLOCK_PATTERN
+            .captures_iter(&html)
+            .map(|caps| caps[0].to_owned())
+            .collect::<Vec<_>>();
+        assert!(own_rules
+            .iter()
+            .any(|rules| rules.contains("@media (prefers-color-scheme: dark)")));
+        for rules in own_rules {
+            assert!(with_preheader.contains(&rules), "{rules}");
+        }
+    }
+
+    #[test]
+    fn test_color_mapping() {
+        assert_eq!(
+            dark_declarations(
+                "color: #333333; padding: 4px; border: 1px solid #FFFFFF !important",
+                &colors()
+            ),
+            Some("color: #e0e0e0 !important; border: 1px solid #1e1e1e !important;".to_owned())
+        );
+        assert_eq!(
+            dark_declarations("color: #ffffff00; margin: 0", &colors()),
+            None
+        );
+
+        let (marked, rules) = dark_variant(
+            r#"<td class="cell" style="background-color:#ffffff">1</td><img src="a.png" style="border:#333333"/>"#,
+            &colors(),
+        );
+        assert_eq!(
+            marked,
+            r#"<td class="cell osa-dark-0" style="background-color:#ffffff">1</td><img src="a.png" style="border:#333333" class="osa-dark-1"/>"#
+        );
+        assert_eq!(
+            rules,
+            vec![
+                ".osa-dark-0 { background-color: #1e1e1e !important; }",
+                ".osa-dark-1 { border: #e0e0e0 !important; }"
+            ]
+        );
+    }
+}
@@ -0,0 +1,698 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::entries;
+use crate::paths;
+
+/// The dead-letter directory within the outbox, holding entries that could not be delivered.
+pub(crate) const FAILED_DIR: &str = "failed";
+
+/// The sidecar file extension appended to an entry path, e.g. `entry.json.state`
+const STATE_EXT: &str = "state";
+
+/// Delivery bookkeeping of an entry, kept in a sidecar file next to the entry file.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub(crate) struct EntryState {
+    /// Failed delivery attempts since the entry was (re)queued
+    pub(crate) attempts: u32,
+    pub(crate) last_error: Option<String>,
+    pub(crate) failed_at: Option<DateTime<Utc>>,
+    pub(crate) requeued_at: Option<DateTime<Utc>>,
+
+    /// The pages of its paged E-mail already sent, by number, so a later run doesn't send them again
+    pub(crate) delivered_pages: BTreeSet<usize>,
+
+    /// When its failure was notified to its `notify_error` addresses, so the later runs don't notify it again
+    pub(crate) notified_at: Option<DateTime<Utc>>,
+}
+
+/// Returns the sidecar file path of the given entry file.
+pub(crate) fn state_path<P: AsRef<Path>>(entry_path: P) -> PathBuf {
+    let mut path = entry_path.as_ref().as_os_str().to_owned();
+    path.push(".");
+    path.push(STATE_EXT);
+    path.into()
+}
+
+impl EntryState {
+    /// Loads the sidecar of the given entry file. A missing sidecar is an empty state.
+    pub(crate) fn load<P: AsRef<Path>>(entry_path: P) -> Result<Self> {
+        let path = state_path(entry_path);
+
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read entry state \"{}\"", path.display()))?;
+
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Unable to parse entry state \"{}\"", path.display()))
+    }
+
+    /// Writes the sidecar of the given entry file.
+    pub(crate) fn save<P: AsRef<Path>>(&self, entry_path: P) -> Result<()> {
+        let path = state_path(entry_path);
+        let contents =
+            serde_json::to_string_pretty(self).expect("Entry state is always valid JSON");
+
+        fs::write(&path, contents)
+            .with_context(|| format!("Unable to write entry state \"{}\"", path.display()))
+    }
+}
+
+/// Where the entries that can't be delivered are moved, and after how many failed attempts.
+#[derive(Debug, Clone)]
+pub(crate) struct Quarantine {
+    outbox_path: PathBuf,
+    failed_path: PathBuf,
+
+    /// The failed attempts after which an entry failing transiently is moved aside, None to keep it forever
+    max_attempts: Option<u32>,
+}
+
+impl Quarantine {
+    /// The dead-letter directory is the `failed` directory of the outbox, unless `failed_path` is given.
+    /// ## Error
+    /// Fails if `failed_path` is another directory within the outbox, as its entries would be sent again.
+    pub(crate) fn new(
+        outbox_path: &Path,
+        failed_path: Option<&Path>,
+        max_attempts: Option<u32>,
+    ) -> Result<Self> {
+        let default_path = outbox_path.join(FAILED_DIR);
+        let failed_path = failed_path.map_or_else(|| default_path.clone(), Path::to_owned);
+
+        // Compared once resolved, as `..` or a symbolic link may lead into the outbox
+        let resolved_failed_path = paths::resolved(&failed_path);
+        if resolved_failed_path != paths::resolved(&default_path)
+            && resolved_failed_path.starts_with(paths::resolved(outbox_path))
+        {
+            anyhow::bail!(
+                "The dead-letter directory \"{}\" is within the outbox \"{}\", other than its `{FAILED_DIR}` directory",
+                failed_path.display(),
+                outbox_path.display()
+            );
+        }
+
+        Ok(Self {
+            outbox_path: outbox_path.to_owned(),
+            failed_path,
+            max_attempts,
+        })
+    }
+
+    /// Moves an entry of the outbox into the dead-letter directory, noting the reason in its sidecar.
+    /// Returns the quarantined path.
+    pub(crate) fn quarantine(&self, entry_path: &Path, reason: &str) -> Result<PathBuf> {
+        quarantine_entry(&self.outbox_path, &self.failed_path, entry_path, reason)
+    }
+
+    /// Counts a failed delivery attempt of an entry, moving it into the dead-letter directory once it reaches the
+    /// maximum attempts. Returns the quarantined path, or None for an entry kept in the outbox for another try.
+    pub(crate) fn record_attempt(
+        &self,
+        entry_path: &Path,
+        reason: &str,
+    ) -> Result<Option<PathBuf>> {
+        let attempts = EntryState::load(entry_path)?.attempts + 1;
+
+        if self
+            .max_attempts
+            .is_some_and(|max_attempts| attempts >= max_attempts)
+        {
+            self.quarantine(entry_path, reason).map(Some)
+        } else {
+            record_attempt(entry_path, reason).map(|_| None)
+        }
+    }
+}
+
+/// What happened to a single dead-lettered entry file during a requeue.
+#[derive(Debug, PartialEq)]
+pub(crate) enum RequeueOutcome {
+    Requeued,
+    StillInvalid(String),
+    Skipped,
+
+    /// The outbox already holds an entry at the path of the requeued one, which is left in place
+    Conflict(PathBuf),
+}
+
+#[derive(Debug)]
+pub(crate) struct RequeueDecision {
+    pub(crate) path: PathBuf,
+    pub(crate) outcome: RequeueOutcome,
+}
+
+impl std::fmt::Display for RequeueDecision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.outcome {
+            RequeueOutcome::Requeued => write!(f, "requeued: \"{}\"", self.path.display()),
+            RequeueOutcome::StillInvalid(reason) => {
+                write!(f, "still-invalid: \"{}\". {reason}", self.path.display())
+            }
+            RequeueOutcome::Skipped => write!(f, "skipped: \"{}\"", self.path.display()),
+            RequeueOutcome::Conflict(target) => write!(
+                f,
+                "conflict: \"{}\". The outbox already holds \"{}\"",
+                self.path.display(),
+                target.display()
+            ),
+        }
+    }
+}
+
+/// Moves the entries of the dead-letter directory back into the outbox, if they now pass validation.
+/// Requeued entries have their attempts counter reset and a `requeued_at` marker set in their sidecar.
+/// Entries that are still invalid stay put, with the reason noted in their sidecar.
+///
+/// When `filter_template` is given, only entries of that template are requeued.
+/// With `dry_run`, decisions are returned without touching any file.
+pub(crate) fn requeue_failed(
+    quarantine: &Quarantine,
+    templates_path: &Path,
+    filter_template: Option<&str>,
+    dry_run: bool,
+) -> Result<Vec<RequeueDecision>> {
+    let outbox_path = &quarantine.outbox_path;
+    let failed_path = &quarantine.failed_path;
+    let mut decisions = Vec::new();
+
+    if !failed_path.is_dir() {
+        return Ok(decisions);
+    }
+
+    for dir_entry in WalkDir::new(failed_path)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(entries::is_entry)
+    {
+        let path = dir_entry.path();
+        let target = outbox_path.join(
+            path.strip_prefix(failed_path)
+                .expect("Walked from within the failed directory"),
+        );
+
+        let outcome = match entries::validate_entry_file(path, templates_path) {
+            Ok(entry) => {
+                if filter_template.is_some_and(|template| entry.template() != template) {
+                    RequeueOutcome::Skipped
+                } else if target.exists() {
+                    // Never overwritten, it may be a newer entry waiting to be sent
+                    RequeueOutcome::Conflict(target.clone())
+                } else {
+                    RequeueOutcome::Requeued
+                }
+            }
+            Err(e) => RequeueOutcome::StillInvalid(e.to_string()),
+        };
+
+        if !dry_run {
+            match &outcome {
+                RequeueOutcome::Requeued => requeue_entry(path, &target)?,
+                RequeueOutcome::StillInvalid(reason) => {
+                    let mut state = EntryState::load(path)?;
+                    state.last_error = Some(reason.clone());
+                    state.save(path)?;
+                }
+                RequeueOutcome::Skipped | RequeueOutcome::Conflict(_) => {}
+            }
+        }
+
+        decisions.push(RequeueDecision {
+            path: path.to_owned(),
+            outcome,
+        });
+    }
+
+    Ok(decisions)
+}
+
+/// Moves an entry and its sidecar to `target`, resetting its delivery bookkeeping.
+fn requeue_entry(source: &Path, target: &Path) -> Result<()> {
+    let mut state = EntryState::load(source)?;
+    state.attempts = 0;
+    state.last_error = None;
+    state.requeued_at = Some(Utc::now());
+    state.notified_at = None;
+
+    move_entry(source, target, &state)?;
+
+    // A requeued entry is parsed again
+    let _ = fs::remove_file(entries::parse_error_path(source));
+    Ok(())
+}
+
+/// Counts a failed delivery attempt of an entry that stays in the outbox for another try.
+pub(crate) fn record_attempt(entry_path: &Path, reason: &str) -> Result<()> {
+    let mut state = EntryState::load(entry_path)?;
+    state.attempts += 1;
+    state.last_error = Some(reason.to_owned());
+    state.failed_at = Some(Utc::now());
+    state.save(entry_path)
+}
+
+/// Notes in the sidecar of an entry that a page of its paged E-mail was sent.
+pub(crate) fn record_delivered_page(entry_path: &Path, page: usize) -> Result<()> {
+    let mut state = EntryState::load(entry_path)?;
+    state.delivered_pages.insert(page);
+    state.save(entry_path)
+}
+
+/// Notes in the sidecar of an entry that its failure was notified. An entry moved away since, e.g. into the
+/// dead-letter directory along with its sidecar, is left as it is.
+pub(crate) fn record_notified(entry_path: &Path) -> Result<()> {
+    if !entry_path.is_file() {
+        return Ok(());
+    }
+
+    let mut state = EntryState::load(entry_path)?;
+    state.notified_at = Some(Utc::now());
+    state.save(entry_path)
+}
+
+/// Whether the failure of an entry was already notified. An entry with a sidecar that can't be read is notified again.
+pub(crate) fn is_notified(entry_path: &Path) -> bool {
+    EntryState::load(entry_path).is_ok_and(|state| state.notified_at.is_some())
+}
+
+/// The pages of a paged E-mail already sent, those noted in the sidecars of every one of its entries.
+/// An entry added to the E-mail since, changing its pages, has none of them noted, so every page is sent again.
+/// ## Error
+/// Fails if a sidecar can't be read or parsed.
+pub(crate) fn delivered_pages<'a>(
+    entry_paths: impl IntoIterator<Item = &'a Path>,
+) -> Result<BTreeSet<usize>> {
+    let mut delivered: Option<BTreeSet<usize>> = None;
+
+    for entry_path in entry_paths {
+        let pages = EntryState::load(entry_path)?.delivered_pages;
+        delivered = Some(match delivered {
+            Some(delivered) => delivered.intersection(&pages).copied().collect(),
+            None => pages,
+        });
+    }
+
+    Ok(delivered.unwrap_or_default())
+}
+
+/// Moves an entry of the outbox into the `failed_path` directory, noting the reason in its sidecar.
+/// Returns the quarantined path.
+fn quarantine_entry(
+    outbox_path: &Path,
+    failed_path: &Path,
+    entry_path: &Path,
+    reason: &str,
+) -> Result<PathBuf> {
+    let relative_path = entry_path.strip_prefix(outbox_path).with_context(|| {
+        format!(
+            "The entry \"{}\" is not within the outbox \"{}\"",
+            entry_path.display(),
+            outbox_path.display()
+        )
+    })?;
+
+    let mut state = EntryState::load(entry_path)?;
+    state.attempts += 1;
+    state.last_error = Some(reason.to_owned());
+    state.failed_at = Some(Utc::now());
+
+    let target = failed_path.join(relative_path);
+    move_entry(entry_path, &target, &state)?;
+
+    Ok(target)
+}
+
+/// Moves an entry to `target`, writing the given state into the sidecar at its new location.
+fn move_entry(source: &Path, target: &Path, state: &EntryState) -> Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Unable to create directory \"{}\"", parent.display()))?;
+    }
+
+    fs::rename(source, target).with_context(|| {
+        format!(
+            "Unable to move \"{}\" to \"{}\"",
+            source.display(),
+            target.display()
+        )
+    })?;
+
+    state.save(target)?;
+
+    let source_state = state_path(source);
+    if source_state.is_file() {
+        fs::remove_file(&source_state).with_context(|| {
+            format!(
+                "Unable to remove entry state \"{}\"",
+                source_state.display()
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID_ENTRY: &str = r#"{
+        "id": "1",
+        "utc": "2023-01-01T00:00:00+00:00",
+        "notify_error": [],
+        "email": {
+            "system": "sys",
+            "subsystem": "sub",
+            "from": "a@x.com",
+            "to": ["b@x.com"],
+            "cc": [],
+            "bcc": [],
+            "reply_to": [],
+            "subject": "Hi",
+            "template": "ops_department",
+            "alternative_content": "",
+            "attachments": [],
+            "unique_by": ""
+        },
+        "context": {}
+    }"#;
+
+    fn default_quarantine(outbox: &Path) -> Quarantine {
+        Quarantine::new(outbox, None, None).unwrap()
+    }
+
+    #[test]
+    fn test_requeue_failed() {
+        let root = tempfile::tempdir().unwrap();
+        let outbox = root.path().join("outbox");
+        let templates = root.path().join("templates");
+        let failed = outbox.join(FAILED_DIR);
+        fs::create_dir_all(&failed).unwrap();
+
+        // The template was fixed: it exists now
+        fs::create_dir_all(templates.join("ops_department")).unwrap();
+        fs::write(templates.join("ops_department/template.html"), "").unwrap();
+
+        let fixable = failed.join("fixable.json");
+        fs::write(&fixable, VALID_ENTRY).unwrap();
+        EntryState {
+            attempts: 3,
+            last_error: Some("Unable to load template file".to_owned()),
+            ..Default::default()
+        }
+        .save(&fixable)
+        .unwrap();
+
+        let malformed = failed.join("malformed.json");
+        fs::write(&malformed, "{ not json").unwrap();
+
+        // Dry-run decides without moving anything
+        let decisions =
+            requeue_failed(&default_quarantine(&outbox), &templates, None, true).unwrap();
+        assert_eq!(decisions.len(), 2);
+        assert!(fixable.is_file());
+
+        let decisions =
+            requeue_failed(&default_quarantine(&outbox), &templates, None, false).unwrap();
+
+        let outcome_of = |path: &Path| {
+            &decisions
+                .iter()
+                .find(|d| d.path == path)
+                .expect("A decision for every failed entry")
+                .outcome
+        };
+        assert_eq!(outcome_of(&fixable), &RequeueOutcome::Requeued);
+        assert!(matches!(
+            outcome_of(&malformed),
+            RequeueOutcome::StillInvalid(_)
+        ));
+
+        let requeued = outbox.join("fixable.json");
+        assert!(requeued.is_file());
+        assert!(!fixable.exists());
+        assert!(!state_path(&fixable).exists());
+
+        let state = EntryState::load(&requeued).unwrap();
+        assert_eq!(state.attempts, 0);
+        assert_eq!(state.last_error, None);
+        assert!(state.requeued_at.is_some());
+
+        assert!(malformed.is_file());
+        assert!(EntryState::load(&malformed).unwrap().last_error.is_some());
+    }
+
+    #[test]
+    fn test_requeue_failed_filter_template() {
+        let root = tempfile::tempdir().unwrap();
+        let outbox = root.path().join("outbox");
+        let templates = root.path().join("templates");
+        let failed = outbox.join(FAILED_DIR);
+        fs::create_dir_all(&failed).unwrap();
+        fs::create_dir_all(templates.join("ops_department")).unwrap();
+        fs::write(templates.join("ops_department/template.html"), "").unwrap();
+
+        let entry = failed.join("entry.json");
+        fs::write(&entry, VALID_ENTRY).unwrap();
+
+        let decisions = requeue_failed(
+            &default_quarantine(&outbox),
+            &templates,
+            Some("other"),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(decisions[0].outcome, RequeueOutcome::Skipped);
+        assert!(entry.is_file());
+    }
+
+    #[test]
+    fn test_requeue_failed_conflict() {
+        let root = tempfile::tempdir().unwrap();
+        let outbox = root.path().join("outbox");
+        let templates = root.path().join("templates");
+        let failed = outbox.join(FAILED_DIR);
+        fs::create_dir_all(&failed).unwrap();
+        fs::create_dir_all(templates.join("ops_department")).unwrap();
+        fs::write(templates.join("ops_department/template.html"), "").unwrap();
+
+        let entry = failed.join("entry.json");
+        fs::write(&entry, VALID_ENTRY).unwrap();
+        let newer = outbox.join("entry.json");
+        fs::write(&newer, "newer").unwrap();
+
+        let decisions =
+            requeue_failed(&default_quarantine(&outbox), &templates, None, false).unwrap();
+
+        assert_eq!(
+            decisions[0].outcome,
+            RequeueOutcome::Conflict(newer.clone())
+        );
+        assert!(decisions[0].to_string().starts_with("conflict: "));
+        assert!(entry.is_file());
+        assert_eq!(fs::read_to_string(&newer).unwrap(), "newer");
+    }
+
+    #[test]
+    fn test_requeue_failed_missing_template() {
+        let root = tempfile::tempdir().unwrap();
+        let outbox = root.path().join("outbox");
+        let failed = outbox.join(FAILED_DIR);
+        fs::create_dir_all(&failed).unwrap();
+
+        let entry = failed.join("entry.json");
+        fs::write(&entry, VALID_ENTRY).unwrap();
+
+        let decisions = requeue_failed(
+            &default_quarantine(&outbox),
+            &root.path().join("templates"),
+            None,
+            false,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            decisions[0].outcome,
+            RequeueOutcome::StillInvalid(_)
+        ));
+        assert!(entry.is_file());
+    }
+
+    #[test]
+    fn test_quarantine_entry() {
+        let root = tempfile::tempdir().unwrap();
+        let outbox = root.path().join("outbox");
+        fs::create_dir_all(outbox.join("nested")).unwrap();
+
+        let entry = outbox.join("nested/entry.json");
+        fs::write(&entry, VALID_ENTRY).unwrap();
+        record_attempt(&entry, "450 Mailbox busy").unwrap();
+
+        let quarantined = default_quarantine(&outbox)
+            .quarantine(&entry, "550 No such user")
+            .unwrap();
+
+        assert_eq!(
+            quarantined,
+            outbox.join(FAILED_DIR).join("nested/entry.json")
+        );
+        assert!(quarantined.is_file());
+        assert!(!entry.exists());
+        assert!(!state_path(&entry).exists());
+
+        let state = EntryState::load(&quarantined).unwrap();
+        assert_eq!(state.attempts, 2);
+        assert_eq!(state.last_error.as_deref(), Some("550 No such user"));
+        assert!(state.failed_at.is_some());
+    }
+
+    #[test]
+    fn test_record_attempt_max_attempts() {
+        let root = tempfile::tempdir().unwrap();
+        let outbox = root.path().join("outbox");
+        let failed = root.path().join("quarantine");
+        fs::create_dir_all(&outbox).unwrap();
+
+        let entry = outbox.join("entry.json");
+        fs::write(&entry, VALID_ENTRY).unwrap();
+        let quarantine = Quarantine::new(&outbox, Some(&failed), Some(3)).unwrap();
+
+        for attempt in 1..3 {
+            assert_eq!(
+                quarantine
+                    .record_attempt(&entry, "450 Mailbox busy")
+                    .unwrap(),
+                None
+            );
+            assert_eq!(EntryState::load(&entry).unwrap().attempts, attempt);
+        }
+
+        let quarantined = quarantine
+            .record_attempt(&entry, "451 Try again later")
+            .unwrap();
+
+        assert_eq!(quarantined, Some(failed.join("entry.json")));
+        assert!(!entry.exists());
+        assert!(!state_path(&entry).exists());
+
+        let state = EntryState::load(failed.join("entry.json")).unwrap();
+        assert_eq!(state.attempts, 3);
+        assert_eq!(state.last_error.as_deref(), Some("451 Try again later"));
+    }
+
+    #[test]
+    fn test_record_attempt_unlimited() {
+        let root = tempfile::tempdir().unwrap();
+        let entry = root.path().join("entry.json");
+        fs::write(&entry, VALID_ENTRY).unwrap();
+        let quarantine = default_quarantine(root.path());
+
+        for _ in 0..5 {
+            assert_eq!(
+                quarantine
+                    .record_attempt(&entry, "450 Mailbox busy")
+                    .unwrap(),
+                None
+            );
+        }
+        assert_eq!(EntryState::load(&entry).unwrap().attempts, 5);
+    }
+
+    #[test]
+    fn test_delivered_pages() {
+        let root = tempfile::tempdir().unwrap();
+        let first = root.path().join("first.json");
+        let second = root.path().join("second.json");
+        fs::write(&first, VALID_ENTRY).unwrap();
+        fs::write(&second, VALID_ENTRY).unwrap();
+        let paths = [first.as_path(), second.as_path()];
+
+        assert!(delivered_pages(paths).unwrap().is_empty());
+        assert!(delivered_pages([]).unwrap().is_empty());
+
+        for page in [1, 2] {
+            for path in paths {
+                record_delivered_page(path, page).unwrap();
+            }
+        }
+        assert_eq!(delivered_pages(paths).unwrap(), BTreeSet::from([1, 2]));
+
+        // Kept along with the other bookkeeping
+        record_attempt(&first, "451 Try again later").unwrap();
+        let state = EntryState::load(&first).unwrap();
+        assert_eq!(state.attempts, 1);
+        assert_eq!(state.delivered_pages, BTreeSet::from([1, 2]));
+
+        // A new entry of the E-mail has none of its pages sent
+        let third = root.path().join("third.json");
+        fs::write(&third, VALID_ENTRY).unwrap();
+        assert!(
+            delivered_pages([first.as_path(), second.as_path(), third.as_path()])
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_notified() {
+        let root = tempfile::tempdir().unwrap();
+        let outbox = root.path().join("outbox");
+        fs::create_dir_all(&outbox).unwrap();
+        let entry = outbox.join("entry.json");
+        fs::write(&entry, VALID_ENTRY).unwrap();
+
+        assert!(!is_notified(&entry));
+        record_notified(&entry).unwrap();
+        assert!(is_notified(&entry));
+
+        // Carried into the dead-letter directory, and reset by a requeue
+        let quarantine = default_quarantine(&outbox);
+        let failed = quarantine.quarantine(&entry, "550 No such user").unwrap();
+        assert!(is_notified(&failed));
+        requeue_entry(&failed, &entry).unwrap();
+        assert!(!is_notified(&entry));
+
+        // Nothing is noted for an entry moved away
+        let moved = outbox.join("moved.json");
+        record_notified(&moved).unwrap();
+        assert!(!state_path(&moved).exists());
+    }
+
+    #[test]
+    fn test_quarantine_within_outbox() {
+        let outbox = Path::new("outbox");
+
+        assert!(Quarantine::new(outbox, Some(&outbox.join(FAILED_DIR)), None).is_ok());
+        assert!(Quarantine::new(outbox, Some(Path::new("failed")), None).is_ok());
+        assert!(Quarantine::new(outbox, Some(&outbox.join("dead")), None).is_err());
+
+        // Leading into the outbox once resolved
+        let root = tempfile::tempdir().unwrap();
+        let outbox = root.path().join("outbox");
+        fs::create_dir_all(&outbox).unwrap();
+
+        let dotted = root.path().join("dead/../outbox/dead");
+        assert!(Quarantine::new(&outbox, Some(&dotted), None).is_err());
+        let dotted = root.path().join("outbox/../dead");
+        assert!(Quarantine::new(&outbox, Some(&dotted), None).is_ok());
+        let dotted = root.path().join("outbox/./failed");
+        assert!(Quarantine::new(&outbox, Some(&dotted), None).is_ok());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&outbox, root.path().join("link")).unwrap();
+            let linked = root.path().join("link").join("dead");
+            assert!(Quarantine::new(&outbox, Some(&linked), None).is_err());
+        }
+    }
+}
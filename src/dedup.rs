@@ -0,0 +1,301 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+use crate::sent_log::{self, LogRecord};
+
+/// The fingerprints of the sent and suppressed E-mails of the templates with a dedup window, one JSON record per
+/// line, kept next to the sent-log.
+pub(crate) const DEDUP_LOG_FILE: &str = "dedup_log.jsonl";
+
+/// How long fingerprints are kept, which caps the dedup windows.
+pub(crate) const DEDUP_RETENTION: Duration = Duration::hours(24);
+
+/// The context key counting the duplicates suppressed since the previous E-mail of the template, within `_meta`.
+pub(crate) const SUPPRESSED_DUPLICATES_KEY: &str = "suppressed_duplicates";
+
+/// The suppression of near-identical E-mails of a template, set in `template.toml` under `[dedup]`,
+/// e.g. `window_minutes = 30` and `ignore = ["detected_at"]`.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub(crate) struct DedupConfig {
+    /// How long an E-mail suppresses the E-mails rendering the same, up to a day. Disabled when not set
+    pub(crate) window_minutes: Option<u64>,
+
+    /// The context paths masked out of the fingerprint, dotted for nested keys, e.g. `rows.detected_at`.
+    /// A path through an array masks the key in every element
+    pub(crate) ignore: Vec<String>,
+}
+
+impl DedupConfig {
+    /// The dedup window, capped by [`DEDUP_RETENTION`]. None when disabled.
+    pub(crate) fn window(&self) -> Option<Duration> {
+        self.window_minutes
+            .filter(|minutes| *minutes > 0)
+            .map(|minutes| Duration::minutes(minutes as i64).min(DEDUP_RETENTION))
+    }
+}
+
+/// Replaces the values of the context at the dotted path with `null`, so they don't change the rendered HTML.
+fn mask_path(value: &mut serde_json::Value, path: &[&str]) {
+    let Some((key, rest)) = path.split_first() else {
+        return;
+    };
+
+    match value {
+        serde_json::Value::Array(items) => {
+            items.iter_mut().for_each(|item| mask_path(item, path));
+        }
+        serde_json::Value::Object(object) => match object.get_mut(*key) {
+            Some(masked) if rest.is_empty() => *masked = serde_json::Value::Null,
+            Some(nested) => mask_path(nested, rest),
+            None => {}
+        },
+        _ => {}
+    }
+}
+
+/// The context with the ignored paths masked out, to render the fingerprint of an E-mail from.
+pub(crate) fn mask(
+    context: &serde_json::Map<String, serde_json::Value>,
+    ignore: &[String],
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut masked = serde_json::Value::Object(context.clone());
+
+    for path in ignore {
+        mask_path(&mut masked, &path.split('.').collect::<Vec<_>>());
+    }
+
+    match masked {
+        serde_json::Value::Object(masked) => masked,
+        _ => unreachable!("A masked object stays an object"),
+    }
+}
+
+/// The fingerprint of the HTML rendered from a masked context.
+pub(crate) fn fingerprint(html: &str) -> String {
+    Sha256::digest(html.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// An E-mail of a template with a dedup window, sent or suppressed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct DedupRecord {
+    pub(crate) recorded_at: DateTime<Utc>,
+    pub(crate) template: String,
+    pub(crate) fingerprint: String,
+    pub(crate) suppressed: bool,
+}
+
+impl LogRecord for DedupRecord {
+    #[inline]
+    fn sent_at(&self) -> DateTime<Utc> {
+        self.recorded_at
+    }
+}
+
+/// The fingerprints of the last day, persisted across runs.
+#[derive(Debug, Default)]
+pub(crate) struct DedupLog {
+    path: PathBuf,
+    records: Vec<DedupRecord>,
+}
+
+impl DedupLog {
+    /// Loads the dedup log, dropping the records past [`DEDUP_RETENTION`].
+    /// ## Error
+    /// Fails if the log exists but can't be read.
+    pub(crate) fn load<P: AsRef<Path>>(path: P, now: DateTime<Utc>) -> Result<Self> {
+        let path = path.as_ref();
+        let since = now - DEDUP_RETENTION;
+
+        if let Err(e) = sent_log::compact::<DedupRecord, _>(path, since) {
+            log::warn!("{:?}", e);
+        }
+
+        Ok(Self {
+            path: path.to_owned(),
+            records: sent_log::load_since(path, since)?,
+        })
+    }
+
+    /// Whether an E-mail of the template with the same fingerprint was sent at or after `since`.
+    pub(crate) fn is_duplicate(
+        &self,
+        template: &str,
+        fingerprint: &str,
+        since: DateTime<Utc>,
+    ) -> bool {
+        self.records.iter().any(|record| {
+            !record.suppressed
+                && record.recorded_at >= since
+                && record.template == template
+                && record.fingerprint == fingerprint
+        })
+    }
+
+    /// The E-mails of the template suppressed since the last one sent.
+    pub(crate) fn pending_suppressions(&self, template: &str) -> usize {
+        self.records
+            .iter()
+            .rev()
+            .filter(|record| record.template == template)
+            .take_while(|record| record.suppressed)
+            .count()
+    }
+
+    /// Appends a record to the log.
+    /// ## Error
+    /// Fails if the log can't be written, the record is still counted by this run.
+    pub(crate) fn record(&mut self, record: DedupRecord) -> Result<()> {
+        let appended = sent_log::append(&self.path, &record);
+        self.records.push(record);
+        appended
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{self, ContextData, DetectionMethod, TemplateData, TemplateExtension};
+    use serde_json::json;
+    use std::rc::Rc;
+
+    const TEMPLATE: &str = "<!--TEMPLATE tera-->\
+<p>{{ service }} is {{ status }} since {{ detected_at }}</p>\
+{% if _meta.suppressed_duplicates %}<p>{{ _meta.suppressed_duplicates }} duplicate(s) suppressed</p>{% endif %}";
+
+    fn render(context: &serde_json::Map<String, serde_json::Value>) -> String {
+        let template_data = TemplateData {
+            contents: Rc::new(TEMPLATE.to_owned()),
+            file_path: None,
+            extensions: None,
+        };
+        let context_data = ContextData {
+            context: serde_json::Value::Object(context.clone()),
+            file_path: None,
+            trusted: false,
+            local_time: Default::default(),
+        };
+
+        render::render(
+            &template_data,
+            &context_data,
+            DetectionMethod::Auto,
+            TemplateExtension::Auto,
+        )
+        .unwrap_or_else(|_| panic!("The template renders"))
+        .0
+        .to_string()
+    }
+
+    fn context(status: &str, detected_at: &str) -> serde_json::Map<String, serde_json::Value> {
+        json!({ "service": "billing", "status": status, "detected_at": detected_at, "_meta": {} })
+            .as_object()
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn test_mask_paths() {
+        let context = json!({
+            "detected_at": "10:00",
+            "rows": [{ "host": "db-1", "at": "10:01" }, { "host": "db-2", "at": "10:02" }],
+            "job": { "started_at": "09:00", "name": "backup" },
+        });
+
+        let masked = mask(
+            context.as_object().unwrap(),
+            &[
+                "detected_at".to_owned(),
+                "rows.at".to_owned(),
+                "job.started_at".to_owned(),
+                "missing.key".to_owned(),
+            ],
+        );
+
+        assert_eq!(
+            serde_json::Value::Object(masked),
+            json!({
+                "detected_at": null,
+                "rows": [{ "host": "db-1", "at": null }, { "host": "db-2", "at": null }],
+                "job": { "started_at": null, "name": "backup" },
+            })
+        );
+    }
+
+    #[test]
+    fn test_near_identical_emails_are_suppressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(DEDUP_LOG_FILE);
+        let config = DedupConfig {
+            window_minutes: Some(30),
+            ignore: vec!["detected_at".to_owned()],
+        };
+        let started = Utc::now();
+
+        // Three entries flapping within the window, then a distinct one
+        let emails = [
+            context("down", "10:00:01"),
+            context("down", "10:00:31"),
+            context("down", "10:01:01"),
+            context("up", "10:05:00"),
+        ];
+
+        let mut sent = Vec::new();
+        for (minute, mut email) in emails.into_iter().enumerate() {
+            let now = started + Duration::minutes(minute as i64);
+            let mut dedup_log = DedupLog::load(&path, now).unwrap();
+
+            let fingerprint = fingerprint(&render(&mask(&email, &config.ignore)));
+            let since = now - config.window().unwrap();
+
+            if dedup_log.is_duplicate("alerts", &fingerprint, since) {
+                dedup_log
+                    .record(DedupRecord {
+                        recorded_at: now,
+                        template: "alerts".to_owned(),
+                        fingerprint,
+                        suppressed: true,
+                    })
+                    .unwrap();
+                continue;
+            }
+
+            let suppressed = dedup_log.pending_suppressions("alerts");
+            if suppressed > 0 {
+                email["_meta"][SUPPRESSED_DUPLICATES_KEY] = json!(suppressed);
+            }
+            sent.push(render(&email));
+
+            dedup_log
+                .record(DedupRecord {
+                    recorded_at: now,
+                    template: "alerts".to_owned(),
+                    fingerprint,
+                    suppressed: false,
+                })
+                .unwrap();
+        }
+
+        assert_eq!(
+            sent,
+            vec![
+                "<p>billing is down since 10:00:01</p>",
+                "<p>billing is up since 10:05:00</p><p>2 duplicate(s) suppressed</p>",
+            ]
+        );
+
+        // The counter is reset by the send, and the fingerprints expire with the window
+        let later = started + Duration::hours(2);
+        let dedup_log = DedupLog::load(&path, later).unwrap();
+        assert_eq!(dedup_log.pending_suppressions("alerts"), 0);
+        let fingerprint = fingerprint(&render(&mask(&context("down", "12:00"), &config.ignore)));
+        assert!(!dedup_log.is_duplicate("alerts", &fingerprint, later - config.window().unwrap()));
+        assert!(dedup_log.is_duplicate("alerts", &fingerprint, started));
+    }
+}
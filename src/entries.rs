@@ -1,16 +1,84 @@
-use serde::{Deserialize, Serialize};
+use anyhow::Context;
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::borrow::Cow;
 use std::fs;
 use std::rc::Rc;
 use std::{
-    collections::HashMap,
-    path::{Path, PathBuf},
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Component, Path, PathBuf},
 };
 
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, Duration, FixedOffset, Utc};
 use walkdir::{DirEntry, WalkDir};
 
 use crc::{Algorithm, Crc, CRC_32_ISO_HDLC};
 
+use crate::app::ERRORS_DIR;
+use crate::bundle::BundleConfig;
+use crate::dead_letter::{self, Quarantine, FAILED_DIR};
+use crate::errors::{EntryError, EntryFormatError, ErrorReport};
+use crate::reply_token::ReplyTokenConfig;
+use crate::schema;
+use crate::send;
+use crate::signing::{EntrySignature, SignatureError, Verifier};
+use crate::templates::{find_template_file, AlternativeConfig, CharsetConfig};
+
+/// The file extension of entry files within the outbox.
+pub(crate) const ENTRY_EXT: &str = ".json";
+
+/// The directory next to the outbox the entries of sent E-mails are moved into, with `--archive-sent`.
+pub(crate) const SENT_DIR: &str = "sent";
+
+/// The file extension appended to the path of a quarantined entry that failed to parse, e.g. `entry.json.error.txt`.
+const PARSE_ERROR_EXT: &str = "error.txt";
+
+/// The file extensions of entry files written in YAML.
+const YAML_ENTRY_EXTS: [&str; 2] = [".yaml", ".yml"];
+
+/// The format an entry file is written in, told by its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EntryFormat {
+    Json,
+    Yaml,
+}
+
+impl EntryFormat {
+    /// The format of the entry file at `path`, or None if it is not an entry file.
+    pub(crate) fn of<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let name = path.as_ref().file_name()?.to_str()?.to_lowercase();
+
+        if name.ends_with(ENTRY_EXT) {
+            Some(Self::Json)
+        } else if YAML_ENTRY_EXTS.iter().any(|ext| name.ends_with(ext)) {
+            Some(Self::Yaml)
+        } else {
+            None
+        }
+    }
+
+    /// Deserializes content written in this format.
+    pub(crate) fn parse<T: DeserializeOwned>(self, content: &str) -> Result<T, EntryFormatError> {
+        Ok(match self {
+            Self::Json => serde_json::from_str(content)?,
+            Self::Yaml => serde_yaml::from_str(content)?,
+        })
+    }
+
+    /// Deserializes an entry written in this format, checking the rules of its `email` section.
+    pub(crate) fn parse_entry(self, content: &str) -> Result<Entry, EntryFormatError> {
+        fn validated<E: serde::de::Error>(entry: Entry) -> Result<Entry, E> {
+            entry.email.validate_body().map_err(E::custom)?;
+            Ok(entry)
+        }
+
+        Ok(match self {
+            Self::Json => serde_json::from_str(content).and_then(validated)?,
+            Self::Yaml => serde_yaml::from_str(content).and_then(validated)?,
+        })
+    }
+}
+
 // CRC_32_ISO_HDLC is compatible with Python 3
 const CRC32_ALGORITHM: Algorithm<u32> = CRC_32_ISO_HDLC;
 
@@ -50,37 +118,432 @@ struct AccumulatedValue {
     value: serde_json::Value,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+/// The E-mail an entry contributes to. Entries with identical E-mails are sent as a single E-mail.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone)]
 pub(crate) struct Email {
+    /// The producing system, e.g. `backup`
     pub(crate) system: String,
+
+    /// The part of the producing system, e.g. `scheduler`
     pub(crate) subsystem: String,
+
+    /// The sending address, e.g. `OSA Mailer <osa@example.com>`, or several separated by commas
     pub(crate) from: String,
+
+    /// The mailbox actually sending the E-mail, as the `Sender` header. Required when `from` lists several addresses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sender: Option<String>,
+
+    /// The recipients, as addresses, `Name <address>` or `@alias` groups
     pub(crate) to: Vec<String>,
+
+    /// The carbon copy recipients, as `to`
     pub(crate) cc: Vec<String>,
+
+    /// The blind carbon copy recipients, as `to`
     pub(crate) bcc: Vec<String>,
+
+    /// The addresses replies go to
     pub(crate) reply_to: Vec<String>,
+
+    /// The subject, rendered with the template engine against the context
     pub(crate) subject: String,
+
+    /// The template rendering the E-mail, unless the entry brings its own `html_body`
+    #[serde(default)]
     pub(crate) template: String,
+
+    /// The plain-text alternative of the HTML, empty for none
     pub(crate) alternative_content: String,
+
+    /// The paths of the attached files, rendered with the template engine against the context
     pub(crate) attachments: Vec<String>,
+
+    /// Packs the attached files into a single zip file when there are more than a threshold
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) bundle_attachments: Option<BundleConfig>,
+
+    /// Any text telling apart E-mails that are otherwise identical, so their entries are not batched together
     pub(crate) unique_by: String,
+
+    /// Custom headers of the E-mail, e.g. `X-Campaign-Id`. Override global headers of the same name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) headers: BTreeMap<String, String>,
+
+    /// Charsets of the text parts, overriding the template `[charset]` settings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) charset: Option<CharsetConfig>,
+
+    /// The preview text and the generation of the plain-text alternative, overriding the template `[alternative]` settings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) alternative: Option<AlternativeConfig>,
+
+    /// The token threading the replies into a ticketing system, overriding the template `[reply_token]` settings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reply_token: Option<ReplyTokenConfig>,
+
+    /// The IANA timezone the `localtime` filter displays the timestamps in, e.g. `America/New_York`,
+    /// overriding the template `[localtime]` setting. Injected as `_meta.timezone`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) display_timezone: Option<String>,
+
+    /// HTML already rendered by the producer, sent as is instead of rendering a `template`.
+    /// Part of the E-mail ID, so distinct bodies are never batched together.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) html_body: Option<String>,
+
+    /// The plain-text alternative of an `html_body`, instead of `alternative_content`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) text_body: Option<String>,
+
+    /// The directory of the images embedded by an `html_body`, within the `resources` directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) resources_dir: Option<String>,
+
+    /// The links of the `List-Unsubscribe` header, for bulk E-mails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) unsubscribe: Option<UnsubscribeConfig>,
+}
+
+/// How the recipients of a bulk E-mail unsubscribe, as the `List-Unsubscribe` header of RFC 2369, e.g.
+/// `{ "url": "https://example.com/unsubscribe?u=1", "one_click": true }`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub(crate) struct UnsubscribeConfig {
+    /// The unsubscribe page, an `https://` or `http://` URL
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) url: Option<String>,
+
+    /// The address unsubscribe requests are sent to, e.g. `unsubscribe@example.com`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) mailto: Option<String>,
+
+    /// Unsubscribe with a single POST to the `url`, as the `List-Unsubscribe-Post` header of RFC 8058.
+    /// Only sent along an `https://` URL
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) one_click: bool,
+}
+
+/// The directory next to the binary holding the images of `html_body` entries, see `Email::resources_dir`.
+pub(crate) const RESOURCES_DIR: &str = "resources";
+
+impl Email {
+    /// Whether the entry brings its own HTML, instead of a template.
+    #[inline]
+    pub(crate) fn is_raw(&self) -> bool {
+        self.html_body.is_some()
+    }
+
+    /// Checks that the E-mail has either a `template` or an `html_body`, and that its
+    /// `resources_dir` stays within the resources directory.
+    pub(crate) fn validate_body(&self) -> Result<(), EntryError> {
+        if self.template.is_empty() == self.html_body.is_none() {
+            return Err(EntryError::TemplateOrBody);
+        }
+
+        if let Some(resources_dir) = &self.resources_dir {
+            let is_contained = Path::new(resources_dir)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+            if !is_contained {
+                return Err(EntryError::InvalidResourcesDir(resources_dir.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The directory the embedded images of the E-mail are read from.
+    pub(crate) fn resources_path(&self, templates_path: &Path, resources_path: &Path) -> PathBuf {
+        if self.is_raw() {
+            resources_path.join(self.resources_dir.as_deref().unwrap_or_default())
+        } else {
+            templates_path.join(&self.template)
+        }
+    }
+
+    /// Parses every address of `from`, `sender`, `to`, `cc`, `bcc` and `reply_to`, reporting each invalid one, so they
+    /// are all known before sending. Addresses with template syntax are checked once rendered, when building.
+    pub(crate) fn validate_addresses(&self) -> ErrorReport {
+        let fields = [
+            ("from", std::slice::from_ref(&self.from)),
+            ("sender", self.sender.as_slice()),
+            ("to", &self.to),
+            ("cc", &self.cc),
+            ("bcc", &self.bcc),
+            ("reply_to", &self.reply_to),
+        ];
+
+        let mut report = ErrorReport::new();
+        for (field, values) in fields {
+            for address in values.iter().flat_map(|value| send::split(value)) {
+                if address.contains("{{") || address.contains("{%") {
+                    continue;
+                }
+
+                if let Err(e) = address.parse::<lettre::message::Mailbox>() {
+                    report = report.add_error(EntryError::InvalidAddress {
+                        field,
+                        address: address.to_owned(),
+                        reason: e.to_string(),
+                    });
+                }
+            }
+        }
+
+        report
+    }
+
+    /// Removes the recipients listed more than once, keeping the first of `to`, `cc` and `bcc`,
+    /// and duplicates within `reply_to`. Every removed address is reported as a warning.
+    pub(crate) fn dedup_recipients(&mut self) -> ErrorReport {
+        let mut duplicates = Vec::new();
+        let mut seen: HashMap<String, &'static str> = HashMap::new();
+
+        for (field, addresses) in [
+            ("to", &mut self.to),
+            ("cc", &mut self.cc),
+            ("bcc", &mut self.bcc),
+        ] {
+            addresses.retain(|address| match seen.get(&address_key(address)) {
+                Some(&kept_in) => {
+                    duplicates.push(EntryError::DuplicateRecipient {
+                        address: address.clone(),
+                        field,
+                        kept_in,
+                    });
+                    false
+                }
+                None => {
+                    seen.insert(address_key(address), field);
+                    true
+                }
+            });
+        }
+
+        let mut seen_reply_to = HashSet::new();
+        self.reply_to.retain(|address| {
+            if seen_reply_to.insert(address_key(address)) {
+                return true;
+            }
+
+            duplicates.push(EntryError::DuplicateRecipient {
+                address: address.clone(),
+                field: "reply_to",
+                kept_in: "reply_to",
+            });
+            false
+        });
+
+        duplicates
+            .into_iter()
+            .fold(ErrorReport::new(), ErrorReport::add_warning)
+    }
+}
+
+/// The comparable part of an address: the mailbox of `Name <mailbox>`, without case.
+pub(crate) fn address_key(address: &str) -> String {
+    let mailbox = match (address.rfind('<'), address.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &address[start + 1..end],
+        _ => address,
+    };
+
+    mailbox.trim().to_lowercase()
 }
 
 /// A Composed E-mail is one that has all of its context gathered and ordered.
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub(crate) struct ComposedEmail {
     pub(crate) id: u32,
     pub(crate) header: Email,
     pub(crate) context: serde_json::Map<String, serde_json::Value>,
+
+    /// Set when the accumulated context was too large for a single E-mail and was split into pages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) page: Option<Page>,
+
+    /// The position of its entry among the entries of the E-mail ID, when composed in the `single` mode.
+    /// None when composed of all of them
+    #[serde(skip)]
+    pub(crate) single_entry: Option<usize>,
+}
+
+impl ComposedEmail {
+    /// A checksum of the header and context, equal for E-mails with identical content.
+    pub(crate) fn content_hash(&self) -> String {
+        let content = serde_json::to_string(&(&self.header, &self.context))
+            .expect("Composed from JSON but cannot be serialized into JSON?");
+        string_crc32_iso_hdlc_checksum(&content)
+    }
+
+    fn canonical_value(&self) -> serde_json::Value {
+        canonicalize(
+            serde_json::to_value(self)
+                .expect("Composed from JSON but cannot be converted to JSON?"),
+        )
+    }
+
+    /// The E-mail as pretty JSON that is byte-identical for identical E-mails, whatever the order their
+    /// entries and keys were written in, so snapshots can be diffed. Object keys are sorted at every level,
+    /// integral floats are written as integers, and lines end with a single LF, including the last one.
+    ///
+    /// The E-mail ID, the content hash and the checksums of accumulated values are not computed from this form,
+    /// but from the values as written.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn to_canonical_json(&self) -> String {
+        pretty_json(&self.canonical_value())
+    }
+}
+
+/// The canonical JSON of [`ComposedEmail::to_canonical_json`] for a list of E-mails, ordered by E-mail ID
+/// and page, then by their canonical form.
+pub(crate) fn canonical_json(emails: &[ComposedEmail]) -> String {
+    let mut values: Vec<_> = emails
+        .iter()
+        .map(|email| {
+            let value = email.canonical_value();
+            let key = (
+                email.id,
+                email.page.map(|page| page.number),
+                value.to_string(),
+            );
+            (key, value)
+        })
+        .collect();
+    values.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let values: Vec<_> = values.into_iter().map(|(_, value)| value).collect();
+    pretty_json(&serde_json::Value::Array(values))
+}
+
+pub(crate) fn pretty_json(value: &serde_json::Value) -> String {
+    serde_json::to_string_pretty(value).expect("A JSON value is always valid JSON") + "\n"
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub(crate) struct Entry {
+/// Sorts the keys of every object and writes integral floats as integers, e.g. `2.0` and `-0.0` as `2` and `0`.
+pub(crate) fn canonicalize(value: serde_json::Value) -> serde_json::Value {
+    use serde_json::Value;
+
+    match value {
+        Value::Object(object) => {
+            let mut entries: Vec<_> = object.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, canonicalize(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(canonicalize).collect()),
+        Value::Number(number) => match number.as_f64() {
+            Some(float)
+                if number.is_f64() && float.fract() == 0.0 && float.abs() < i64::MAX as f64 =>
+            {
+                Value::from(float as i64)
+            }
+            _ => Value::Number(number),
+        },
+        value => value,
+    }
+}
+
+/// The position of a paged E-mail within its sequence of follow-up E-mails.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Page {
+    pub(crate) number: usize,
+    pub(crate) count: usize,
+}
+
+impl Page {
+    #[inline]
+    pub(crate) fn is_last(&self) -> bool {
+        self.number == self.count
+    }
+}
+
+/// An entry file of the outbox, written by a producer.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct Entry {
+    /// Any unique ID of the entry, e.g. a UUID
     id: String,
+
+    /// When the entry was written, as RFC 3339. Orders the entries of an E-mail
     utc: DateTime<FixedOffset>,
+
+    /// The addresses notified when the E-mail of the entry fails
     notify_error: Vec<String>,
     email: Email,
+
+    /// The values the template renders. The values of `+key` keys are accumulated across the entries of the E-mail,
+    /// see the Accumulation section of `schema --format markdown`
     context: serde_json::Map<String, serde_json::Value>,
+
+    /// The signature of the producer, verified against `--producer-keys`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<EntrySignature>,
+}
+
+impl Entry {
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn new(
+        id: String,
+        utc: DateTime<FixedOffset>,
+        notify_error: Vec<String>,
+        email: Email,
+        context: serde_json::Map<String, serde_json::Value>,
+    ) -> Self {
+        Self {
+            id,
+            utc,
+            notify_error,
+            email,
+            context,
+            signature: None,
+        }
+    }
+
+    /// The ID of the E-mail the entry contributes to, shared by the entries sent as a single E-mail.
+    pub fn email_id(&self) -> u32 {
+        let email_string = serde_json::to_string(&self.email)
+            .expect("Deserialized from JSON but cannot be serialized into JSON?");
+        crc32_iso_hdlc_checksum(email_string.as_bytes())
+    }
+
+    #[inline]
+    pub(crate) fn template(&self) -> &str {
+        &self.email.template
+    }
+
+    #[inline]
+    pub(crate) fn email(&self) -> &Email {
+        &self.email
+    }
+
+    /// Whether the context has a `+` key at any depth, batching the entries of its E-mail.
+    pub(crate) fn accumulates(&self) -> bool {
+        fn has_accumulation(object: &JsonObject) -> bool {
+            object.iter().any(|(key, value)| {
+                key.starts_with('+') || value.as_object().is_some_and(has_accumulation)
+            })
+        }
+
+        has_accumulation(&self.context)
+    }
+
+    /// The ID set by the producer.
+    #[inline]
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The addresses notified when the E-mail of the entry fails.
+    #[inline]
+    pub(crate) fn notify_error(&self) -> &[String] {
+        &self.notify_error
+    }
 }
 
 /// Contains metadata about the parsed entry and the deserialized entry itself
@@ -94,9 +557,14 @@ pub(crate) struct ParsedEntry {
 impl ParsedEntry {
     /// Calculate the E-Mail ID for the current entry.
     pub fn email_id(&self) -> u32 {
-        let email_string = serde_json::to_string(&self.entry.email)
+        self.entry.email_id()
+    }
+
+    /// A checksum of the whole entry, telling apart entries written under the same file name.
+    pub(crate) fn checksum(&self) -> String {
+        let entry_string = serde_json::to_string(&self.entry)
             .expect("Deserialized from JSON but cannot be serialized into JSON?");
-        crc32_iso_hdlc_checksum(email_string.as_bytes())
+        string_crc32_iso_hdlc_checksum(&entry_string)
     }
 }
 
@@ -105,26 +573,111 @@ pub(crate) struct UnparsedEntry {
     id: String,
     content: String,
     path: Option<PathBuf>,
+    format: EntryFormat,
 }
 
 #[derive(Debug)]
 pub(crate) struct EntryParseError {
     pub(crate) entry_content: UnparsedEntry,
-    pub(crate) error: serde_json::Error,
+    pub(crate) error: EntryFormatError,
+}
+
+impl EntryParseError {
+    /// The entry file, or the ID of an entry read without one.
+    pub(crate) fn entry(&self) -> String {
+        match &self.entry_content.path {
+            Some(path) => path.display().to_string(),
+            None => self.entry_content.id.clone(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn path(&self) -> Option<&Path> {
+        self.entry_content.path.as_deref()
+    }
+}
+
+/// An entry whose signature failed verification, or an unsigned entry the policy rejects.
+#[derive(Debug)]
+pub(crate) struct RejectedEntry {
+    pub(crate) id: String,
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) error: SignatureError,
+}
+
+/// The `_meta` object of a context, replacing a `_meta` value that is not an object.
+pub(crate) fn meta_object(context: &mut JsonObject) -> &mut JsonObject {
+    let meta = context
+        .entry("_meta")
+        .or_insert_with(|| serde_json::Value::Object(JsonObject::new()));
+
+    if !meta.is_object() {
+        *meta = serde_json::Value::Object(JsonObject::new());
+    }
+
+    meta.as_object_mut().expect("Replaced with an object")
 }
 
 fn parse_entities(
     unparsed_entries: &Vec<UnparsedEntry>,
+    verifier: &Verifier,
     parsed_entries: &mut Vec<Rc<ParsedEntry>>,
     parse_errors: &mut Vec<EntryParseError>,
+    rejected_entries: &mut Vec<RejectedEntry>,
 ) {
     for unparsed_entry in unparsed_entries {
-        match serde_json::from_str::<Entry>(&unparsed_entry.content) {
-            Ok(parsed_entry) => parsed_entries.push(Rc::new(ParsedEntry {
-                id: unparsed_entry.id.clone(),
-                path: unparsed_entry.path.clone(),
-                entry: parsed_entry,
-            })),
+        let parse_result = unparsed_entry.format.parse_entry(&unparsed_entry.content);
+
+        match parse_result {
+            Ok(mut parsed_entry) => {
+                // The signature covers the JSON of the entry, whichever format it is written in
+                let signed_content = match unparsed_entry.format {
+                    EntryFormat::Json => Cow::Borrowed(unparsed_entry.content.as_str()),
+                    EntryFormat::Yaml => Cow::Owned(
+                        unparsed_entry
+                            .format
+                            .parse::<serde_json::Value>(&unparsed_entry.content)
+                            .map(|value| value.to_string())
+                            .unwrap_or_default(),
+                    ),
+                };
+
+                let producer =
+                    match verifier.verify(parsed_entry.signature.as_ref(), &signed_content) {
+                        Ok(producer) => producer,
+                        Err(error) => {
+                            rejected_entries.push(RejectedEntry {
+                                id: unparsed_entry.id.clone(),
+                                path: unparsed_entry.path.clone(),
+                                error,
+                            });
+                            continue;
+                        }
+                    };
+
+                // Only a verified signature tells the producer, never the entry itself. No `_meta` is added otherwise
+                match producer {
+                    Some(producer) => {
+                        meta_object(&mut parsed_entry.context)
+                            .insert("producer".to_owned(), serde_json::Value::String(producer));
+                    }
+                    None => {
+                        if let Some(meta) = parsed_entry
+                            .context
+                            .get_mut("_meta")
+                            .and_then(serde_json::Value::as_object_mut)
+                        {
+                            meta.remove("producer");
+                        }
+                    }
+                }
+
+                parsed_entries.push(Rc::new(ParsedEntry {
+                    id: unparsed_entry.id.clone(),
+                    path: unparsed_entry.path.clone(),
+                    entry: parsed_entry,
+                }))
+            }
             Err(e) => parse_errors.push(EntryParseError {
                 entry_content: unparsed_entry.clone(),
                 error: e,
@@ -133,28 +686,42 @@ fn parse_entities(
     }
 }
 
-fn is_entry(entry: &DirEntry, extension: &str) -> bool {
-    entry
-        .file_name()
-        .to_str()
-        .map(|s| s.to_lowercase().ends_with(extension))
-        .unwrap_or(false)
+/// Whether the file is an entry file, written in JSON or YAML.
+pub(crate) fn is_entry(entry: &DirEntry) -> bool {
+    EntryFormat::of(entry.path()).is_some()
 }
 
 /// The results of parsing the entry files
 pub(crate) struct EntryParseResults {
     pub(crate) ok: Vec<Rc<ParsedEntry>>,
     pub(crate) err: Vec<EntryParseError>,
+    pub(crate) rejected: Vec<RejectedEntry>,
 }
 
-pub(crate) fn load_entries<P: AsRef<Path>>(dir: P, extension: &str) -> EntryParseResults {
-    let mut unparsed_entries = Vec::new();
-
-    for entry in WalkDir::new(dir)
+/// The entry files of the outbox, without the dead-lettered ones.
+pub(crate) fn entry_files<P: AsRef<Path>>(dir: P) -> impl Iterator<Item = DirEntry> {
+    WalkDir::new(dir)
         .into_iter()
+        // Dead-lettered entries and error reports are not part of the outbox
+        .filter_entry(|e| {
+            !(e.depth() == 1
+                && e.file_type().is_dir()
+                && (e.file_name() == FAILED_DIR || e.file_name() == ERRORS_DIR))
+        })
         .filter_map(|e| e.ok())
-        .filter(|e| is_entry(e, extension))
-    {
+        .filter(is_entry)
+}
+
+/// Loads the entry files of the outbox, verifying their signatures. The `_meta.producer` of the context is
+/// set to the verified producer key, and removed from the unsigned entries.
+/// Each entry file is parsed as JSON or YAML by its extension.
+pub(crate) fn load_entries<P: AsRef<Path>>(dir: P, verifier: &Verifier) -> EntryParseResults {
+    let mut unparsed_entries = Vec::new();
+
+    for entry in entry_files(dir) {
+        let Some(format) = EntryFormat::of(entry.path()) else {
+            continue;
+        };
         let entry_content = fs::read_to_string(entry.path());
 
         match entry_content {
@@ -163,6 +730,7 @@ pub(crate) fn load_entries<P: AsRef<Path>>(dir: P, extension: &str) -> EntryPars
                     id: entry.path().display().to_string(),
                     content: v,
                     path: Some(entry.path().to_owned()),
+                    format,
                 });
             }
             Err(_) => continue,
@@ -171,15 +739,170 @@ pub(crate) fn load_entries<P: AsRef<Path>>(dir: P, extension: &str) -> EntryPars
 
     let mut result = Vec::new();
     let mut errors = Vec::new();
+    let mut rejected = Vec::new();
 
-    parse_entities(&unparsed_entries, &mut result, &mut errors);
+    parse_entities(
+        &unparsed_entries,
+        verifier,
+        &mut result,
+        &mut errors,
+        &mut rejected,
+    );
 
     EntryParseResults {
         ok: result,
         err: errors,
+        rejected,
     }
 }
 
+/// Checks an entry against the entry schema, as `lint --schema` checks the entry files, and against the rules of
+/// its `email` section.
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) fn check_entry(entry: &Entry) -> Result<(), EntryError> {
+    let value = serde_json::to_value(entry).expect("An entry is always valid JSON");
+
+    let violations = schema::validate(&schema::entry_schema(), &value);
+    if !violations.is_empty() {
+        return Err(EntryError::SchemaViolations(violations));
+    }
+
+    entry.email.validate_body()
+}
+
+/// Validates a parsed entry against the rules an entry must pass before it can be sent.
+pub(crate) fn validate_entry(entry: &Entry, templates_path: &Path) -> Result<(), EntryError> {
+    entry.email.validate_body()?;
+
+    if entry.email.is_raw() {
+        return Ok(());
+    }
+
+    let template_file = find_template_file(&templates_path.join(&entry.email.template));
+
+    if !template_file.is_file() {
+        return Err(EntryError::MissingTemplate(entry.email.template.clone()));
+    }
+
+    Ok(())
+}
+
+/// Reads, parses and validates a single entry file.
+pub(crate) fn validate_entry_file(path: &Path, templates_path: &Path) -> Result<Entry, EntryError> {
+    let content = fs::read_to_string(path).map_err(|error| EntryError::ReadFailure {
+        path: path.display().to_string(),
+        error,
+    })?;
+
+    let format = EntryFormat::of(path).unwrap_or(EntryFormat::Json);
+    let entry = format
+        .parse::<Entry>(&content)
+        .map_err(|error| EntryError::ParsingFailure {
+            id: path.display().to_string(),
+            content: content.clone(),
+            error,
+        })?;
+
+    validate_entry(&entry, templates_path)?;
+
+    Ok(entry)
+}
+
+/// Returns the file path of the parse error of the given entry file.
+pub(crate) fn parse_error_path<P: AsRef<Path>>(entry_path: P) -> PathBuf {
+    let mut path = entry_path.as_ref().as_os_str().to_owned();
+    path.push(".");
+    path.push(PARSE_ERROR_EXT);
+    path.into()
+}
+
+/// Moves an entry file that failed to parse into the dead-letter directory, so it's not parsed again
+/// by every run, and writes the parse error along with the time of the move next to it, see [`parse_error_path`].
+/// Returns the quarantined path, or None for an entry without a file.
+/// ## Error
+/// Fails if the entry is not within the outbox, or can't be moved, or the parse error can't be written.
+pub(crate) fn quarantine_unparsed(
+    quarantine: &Quarantine,
+    parse_error: &EntryParseError,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Option<PathBuf>> {
+    let Some(entry_path) = &parse_error.entry_content.path else {
+        return Ok(None);
+    };
+
+    let reason = parse_error.error.to_string();
+    let target = quarantine.quarantine(entry_path, &reason)?;
+
+    let error_path = parse_error_path(&target);
+    fs::write(&error_path, format!("{}\n{reason}\n", now.to_rfc3339())).with_context(|| {
+        format!(
+            "Unable to write the parse error \"{}\"",
+            error_path.display()
+        )
+    })?;
+
+    Ok(Some(target))
+}
+
+/// Moves the entry file of a sent E-mail from the outbox into `archive_root`, under the same subdirectories, with
+/// the time of the move appended to its name, e.g. `backup/report.20230101T100000000Z.json`.
+/// The delivery state of the entry is removed. Returns the archived path.
+/// ## Error
+/// Fails if the entry is not within the outbox, or if it can't be renamed into the archive, e.g. on another file
+/// system.
+pub(crate) fn archive_entry(
+    entry_path: &Path,
+    archive_root: &Path,
+    outbox_path: &Path,
+    now: DateTime<Utc>,
+) -> anyhow::Result<PathBuf> {
+    let relative_path = entry_path.strip_prefix(outbox_path).with_context(|| {
+        format!(
+            "The entry \"{}\" is not within the outbox \"{}\"",
+            entry_path.display(),
+            outbox_path.display()
+        )
+    })?;
+
+    let target_dir = match relative_path.parent() {
+        Some(parent) => archive_root.join(parent),
+        None => archive_root.to_owned(),
+    };
+    fs::create_dir_all(&target_dir)
+        .with_context(|| format!("Unable to create directory \"{}\"", target_dir.display()))?;
+
+    let stem = entry_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let extension = entry_path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    let timestamp = now.format("%Y%m%dT%H%M%S%3fZ");
+
+    // Entries of the same name archived at the same time are told apart by a counter
+    let target = (0..)
+        .map(|n| match n {
+            0 => target_dir.join(format!("{stem}.{timestamp}{extension}")),
+            n => target_dir.join(format!("{stem}.{timestamp}-{n}{extension}")),
+        })
+        .find(|target| !target.exists())
+        .expect("An unbounded range always has a free name");
+
+    fs::rename(entry_path, &target).with_context(|| {
+        format!(
+            "Unable to move \"{}\" to \"{}\"",
+            entry_path.display(),
+            target.display()
+        )
+    })?;
+
+    let _ = fs::remove_file(dead_letter::state_path(entry_path));
+
+    Ok(target)
+}
+
 enum EmailComposeMethod {
     /// Treat each entry as a single E-mail
     Single,
@@ -188,19 +911,43 @@ enum EmailComposeMethod {
     Batch,
 }
 
-type EmailEntries = HashMap<u32, Vec<Rc<ParsedEntry>>>;
+pub(crate) type EmailEntries = HashMap<u32, Vec<Rc<ParsedEntry>>>;
+
+/// The entries an E-mail was composed of: its own entry in the `single` mode, otherwise every entry of its ID.
+pub(crate) fn entries_of<'a>(
+    email: &ComposedEmail,
+    email_entries: &'a EmailEntries,
+) -> impl Iterator<Item = &'a Rc<ParsedEntry>> + Clone {
+    let single_entry = email.single_entry;
+
+    email_entries
+        .get(&email.id)
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .filter(move |(index, _)| single_entry.is_none_or(|single| single == *index))
+        .map(|(_, entry)| entry)
+}
 
 /// Arrange all entries for each E-Mail ID in an ordered manure.
 pub(crate) fn map_emails(entries_pool: &Vec<Rc<ParsedEntry>>) -> EmailEntries {
+    map_emails_with(entries_pool, ParsedEntry::email_id)
+}
+
+/// Arranges the entries as [`map_emails`], by the E-mail ID `email_id` computes, e.g. over the identity fields.
+pub(crate) fn map_emails_with(
+    entries_pool: &Vec<Rc<ParsedEntry>>,
+    email_id: impl Fn(&ParsedEntry) -> u32,
+) -> EmailEntries {
     let mut email_entries: EmailEntries = HashMap::new();
 
     // Accumulate entries of the same E-mail
     for entry_metadata in entries_pool {
         // Calculate ID for each E-Mail entry
-        let email_id = entry_metadata.email_id();
+        let email_id = email_id(entry_metadata);
 
         // Retrieve entries vector for E-Mail ID (or create one if doesn't exists)
-        let entries = email_entries.entry(email_id).or_insert_with(Vec::new);
+        let entries = email_entries.entry(email_id).or_default();
 
         // Append new Entry to the E-Mail ID
         entries.push(entry_metadata.clone())
@@ -208,31 +955,77 @@ pub(crate) fn map_emails(entries_pool: &Vec<Rc<ParsedEntry>>) -> EmailEntries {
 
     // Order entries by their UTC time
     for (_, value) in email_entries.iter_mut() {
-        value.sort_by(|a, b| a.entry.utc.cmp(&b.entry.utc))
+        value.sort_by_key(|a| a.entry.utc)
     }
 
     email_entries
 }
 
+/// Holds back the E-mails whose entries may still be arriving, so late contributors join the batch.
+/// A held E-mail stays in the outbox and is picked up again by a later run.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BatchWindow {
+    /// The quiet period without new entries, after which an E-mail is due
+    pub(crate) window: Duration,
+
+    /// The longest an E-mail is held since its first entry, even when entries keep arriving
+    pub(crate) max_hold: Duration,
+}
+
+impl BatchWindow {
+    /// Whether the E-mail of the given entries (ordered by their UTC time) is due at `now`.
+    pub(crate) fn is_due(&self, entries: &[Rc<ParsedEntry>], now: DateTime<Utc>) -> bool {
+        let (Some(first), Some(last)) = (entries.first(), entries.last()) else {
+            return true;
+        };
+
+        now.signed_duration_since(last.entry.utc) >= self.window
+            || now.signed_duration_since(first.entry.utc) >= self.max_hold
+    }
+
+    /// Removes the E-mails that are not due at `now`, returning how many were held back.
+    pub(crate) fn hold_back(&self, email_entries: &mut EmailEntries, now: DateTime<Utc>) -> usize {
+        let count = email_entries.len();
+        email_entries.retain(|_, entries| self.is_due(entries, now));
+        count - email_entries.len()
+    }
+}
+
 type JsonObject = serde_json::Map<String, serde_json::Value>;
 
+/// The JSON pointer of a key within the object at `parent_pointer`, e.g. `/table/rows`.
+fn json_pointer(parent_pointer: &str, key: &str) -> String {
+    format!(
+        "{parent_pointer}/{}",
+        key.replace('~', "~0").replace('/', "~1")
+    )
+}
+
+/// Copies the source context into the target one, accumulating its `+key` values.
+/// ## Error
+/// The JSON pointer of a plain `key` next to its `+key` within the same source object, which of the two the
+/// E-mail should get is ambiguous.
 fn copy_and_accumulate(
     source: &JsonObject,
     target: &mut JsonObject,
     email_compose_method: &mut EmailComposeMethod,
-) {
+    pointer: &str,
+) -> Result<(), String> {
     // Scan all key/value elements in the source JSON object
     for (k, v) in source {
         // Detected an accumulation sign in key name
 
         if let Some(key_name) = k.strip_prefix('+') {
+            if source.contains_key(key_name) {
+                return Err(json_pointer(pointer, key_name));
+            }
+
             // When we detect the `+` key symbol, we automatically treat all entries as a batch meant for a single E-mail
             *email_compose_method = EmailComposeMethod::Batch;
 
             // Remove the prefixed version key from the target JSON object
             target.remove(k);
 
-            // FIXME: Return error if key without `+` prefix already exists within the same JSON Object (DuplicationError)
             let value_vec = target
                 .entry(key_name)
                 .or_insert_with(|| serde_json::Value::Array(Vec::new()));
@@ -250,20 +1043,46 @@ fn copy_and_accumulate(
                 .or_insert_with(|| serde_json::Value::Object(json_obj_borrowed.to_owned()));
 
             if let serde_json::Value::Object(ref mut iv) = nested_target {
-                copy_and_accumulate(json_obj_borrowed, iv, email_compose_method);
+                copy_and_accumulate(
+                    json_obj_borrowed,
+                    iv,
+                    email_compose_method,
+                    &json_pointer(pointer, k),
+                )?;
             }
         } else {
             target.entry(k).or_insert_with(|| v.clone());
         }
     }
+
+    Ok(())
+}
+
+/// An E-mail that could not be composed from its entries.
+#[derive(Debug)]
+pub(crate) struct ComposeFailure {
+    pub(crate) email_id: u32,
+    pub(crate) entry_paths: Vec<PathBuf>,
+    pub(crate) error: EntryError,
 }
 
-pub(crate) fn compose_emails(email_entries: &EmailEntries) -> Vec<ComposedEmail> {
+/// The results of composing the E-mails of the entries
+#[derive(Debug, Default)]
+pub(crate) struct ComposeResults {
+    pub(crate) ok: Vec<ComposedEmail>,
+    pub(crate) err: Vec<ComposeFailure>,
+}
+
+/// Composes the E-mails of the entries, accumulating the `+key` values of the batched ones.
+/// An E-mail with an entry having both `key` and `+key` in the same object is not composed, see
+/// [`EntryError::DuplicateAccumulationKey`].
+pub(crate) fn compose_emails(email_entries: &EmailEntries) -> ComposeResults {
     let mut composed_emails = Vec::new();
+    let mut failures = Vec::new();
 
-    for (id, entries_metadata) in email_entries {
+    'emails: for (id, entries_metadata) in email_entries {
         let first_entry = entries_metadata
-            .get(0)
+            .first()
             .expect("The vector was created empty when it was inserted to the map.");
 
         let email = first_entry.entry.email.clone();
@@ -274,24 +1093,44 @@ pub(crate) fn compose_emails(email_entries: &EmailEntries) -> Vec<ComposedEmail>
         // By default, we assume the `single` mode for Email composition. e.g. Every single entry is an E-mail to send.
         // This can be changed if the `+` key symbol is detected during context scan of the first E-mail entry, which then changes the mode to `batch`.
         let mut email_compose_method = EmailComposeMethod::Single;
+        let mut single_emails = Vec::new();
 
-        for entry_metadata in entries_metadata {
+        for (index, entry_metadata) in entries_metadata.iter().enumerate() {
             let entry_context = &entry_metadata.entry.context;
-            copy_and_accumulate(
+            if let Err(pointer) = copy_and_accumulate(
                 entry_context,
                 &mut accumulated_context,
                 &mut email_compose_method,
-            );
+                "",
+            ) {
+                let entry = match &entry_metadata.path {
+                    Some(path) => path.display().to_string(),
+                    None => entry_metadata.id.clone(),
+                };
+
+                failures.push(ComposeFailure {
+                    email_id: *id,
+                    entry_paths: entries_metadata
+                        .iter()
+                        .filter_map(|entry| entry.path.clone())
+                        .collect(),
+                    error: EntryError::DuplicateAccumulationKey { entry, pointer },
+                });
+                continue 'emails;
+            }
 
             if let EmailComposeMethod::Single = email_compose_method {
                 // Create a single E-mail for a single entry
-                composed_emails.push(ComposedEmail {
+                single_emails.push(ComposedEmail {
                     id: *id,
                     header: entry_metadata.entry.email.clone(),
                     context: entry_metadata.entry.context.clone(),
+                    page: None,
+                    single_entry: Some(index),
                 });
             };
         }
+        composed_emails.extend(single_emails);
 
         if let EmailComposeMethod::Batch = email_compose_method {
             // Create a single E-mail from the entries batch with their accumulated context
@@ -299,8 +1138,1243 @@ pub(crate) fn compose_emails(email_entries: &EmailEntries) -> Vec<ComposedEmail>
                 id: *id,
                 header: email,
                 context: accumulated_context,
+                page: None,
+                single_entry: None,
+            });
+        }
+    }
+
+    ComposeResults {
+        ok: composed_emails,
+        err: failures,
+    }
+}
+
+/// The suffix of the key holding the plain values of an accumulated array, e.g. `rows_values` for `rows`.
+pub(crate) const VALUES_SUFFIX: &str = "_values";
+
+/// Whether every item of the array is an accumulated value, `{ order, checksum, value }`.
+fn is_accumulated(array: &[serde_json::Value]) -> bool {
+    !array.is_empty()
+        && array.iter().all(|item| {
+            item.as_object().is_some_and(|object| {
+                object.len() == 3
+                    && ["order", "checksum", "value"]
+                        .iter()
+                        .all(|key| object.contains_key(*key))
+            })
+        })
+}
+
+/// Adds a `k_values` array of the plain values, in order, next to every accumulated array `k` of the context,
+/// at any depth, so templates can write `{{ row.hostname }}` instead of `{{ row.value.hostname }}`.
+/// Added before rendering, once the E-mail ID is computed, so entries never differ by them.
+///
+/// An existing `k_values` key is kept as it is, and reported as a warning.
+pub(crate) fn add_values_views(context: &mut JsonObject) -> ErrorReport {
+    let mut collisions = Vec::new();
+    add_nested_values_views(context, "", &mut collisions);
+
+    collisions
+        .into_iter()
+        .fold(ErrorReport::new(), ErrorReport::add_warning)
+}
+
+fn add_nested_values_views(
+    object: &mut JsonObject,
+    parent_path: &str,
+    collisions: &mut Vec<EntryError>,
+) {
+    let mut views = Vec::new();
+
+    for (key, value) in object.iter_mut() {
+        match value {
+            serde_json::Value::Object(nested) => {
+                add_nested_values_views(nested, &format!("{parent_path}{key}."), collisions);
+            }
+            serde_json::Value::Array(array) if is_accumulated(array) => {
+                let values: Vec<_> = array
+                    .iter()
+                    .filter_map(|item| item.get("value").cloned())
+                    .collect();
+                views.push((key.clone(), values));
+            }
+            _ => {}
+        }
+    }
+
+    for (key, values) in views {
+        let view_key = format!("{key}{VALUES_SUFFIX}");
+
+        if object.contains_key(&view_key) {
+            collisions.push(EntryError::ValuesKeyCollision(format!(
+                "{parent_path}{key}"
+            )));
+            continue;
+        }
+
+        object.insert(view_key, serde_json::Value::Array(values));
+    }
+}
+
+/// Finds the array at the dotted `path` (e.g. `table.entries`) within the JSON object.
+fn find_array<'a>(object: &'a JsonObject, path: &str) -> Option<&'a Vec<serde_json::Value>> {
+    let mut keys = path.split('.');
+    let last_key = keys.next_back()?;
+
+    let mut current = object;
+    for key in keys {
+        current = current.get(key)?.as_object()?;
+    }
+    current.get(last_key)?.as_array()
+}
+
+/// Mutable version of [`find_array`].
+fn find_array_mut<'a>(
+    object: &'a mut JsonObject,
+    path: &str,
+) -> Option<&'a mut Vec<serde_json::Value>> {
+    let mut keys = path.split('.');
+    let last_key = keys.next_back()?;
+
+    let mut current = object;
+    for key in keys {
+        current = current.get_mut(key)?.as_object_mut()?;
+    }
+    current.get_mut(last_key)?.as_array_mut()
+}
+
+/// Splits a composed E-mail into numbered follow-up E-mails when any of its accumulated arrays
+/// exceeds the page size configured for it (`page_sizes` maps a dotted key path to its page size).
+///
+/// Every page shares the header, with its subject suffixed by `(n/N)`, and a copy of all non-paged context keys.
+/// The page position is injected into the context as `_meta.page` and `_meta.page_count`.
+pub(crate) fn paginate(
+    email: ComposedEmail,
+    page_sizes: &HashMap<String, usize>,
+) -> Vec<ComposedEmail> {
+    let paged_keys: Vec<(&str, usize)> = page_sizes
+        .iter()
+        .filter(|(_, &page_size)| page_size > 0)
+        .filter_map(|(path, &page_size)| {
+            find_array(&email.context, path).map(|_| (path.as_str(), page_size))
+        })
+        .collect();
+
+    let page_count = paged_keys
+        .iter()
+        .filter_map(|(path, page_size)| {
+            find_array(&email.context, path).map(|array| array.len().div_ceil(*page_size))
+        })
+        .max()
+        .unwrap_or(1);
+
+    if page_count <= 1 {
+        return vec![email];
+    }
+
+    let mut pages = Vec::with_capacity(page_count);
+
+    for page_index in 0..page_count {
+        let mut context = email.context.clone();
+
+        for (path, page_size) in &paged_keys {
+            if let Some(array) = find_array_mut(&mut context, path) {
+                let start = (page_index * page_size).min(array.len());
+                let end = (start + page_size).min(array.len());
+                *array = array.drain(start..end).collect();
+            }
+        }
+
+        let page = Page {
+            number: page_index + 1,
+            count: page_count,
+        };
+
+        let meta = meta_object(&mut context);
+        meta.insert("page".to_owned(), serde_json::json!(page.number));
+        meta.insert("page_count".to_owned(), serde_json::json!(page.count));
+
+        let mut header = email.header.clone();
+        header.subject = format!("{} ({}/{})", header.subject, page.number, page.count);
+
+        pages.push(ComposedEmail {
+            id: email.id,
+            header,
+            context,
+            page: Some(page),
+            single_entry: email.single_entry,
+        });
+    }
+
+    pages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    /// Golden composition cases: composing `<case>.entries.json` must output `<case>.expected.json`.
+    const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/compose");
+
+    /// Composes the entries of a fixture case, loaded from an outbox as a run loads them, as canonical JSON.
+    fn compose_fixture(case: &str) -> String {
+        let path = Path::new(FIXTURES_DIR).join(format!("{case}.entries.json"));
+        let entries: Vec<serde_json::Value> =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+
+        let outbox = tempfile::tempdir().unwrap();
+        for (i, entry) in entries.iter().enumerate() {
+            fs::write(
+                outbox.path().join(format!("{i:04}{ENTRY_EXT}")),
+                entry.to_string(),
+            )
+            .unwrap();
+        }
+
+        let loaded = load_entries(outbox.path(), &Verifier::default());
+        assert!(loaded.err.is_empty() && loaded.rejected.is_empty());
+
+        canonical_json(&compose_emails(&map_emails(&loaded.ok)).ok)
+    }
+
+    #[test]
+    fn test_compose_golden_fixtures() {
+        let mut cases: Vec<String> = fs::read_dir(FIXTURES_DIR)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let file_name = e.file_name();
+                let case = file_name.to_str()?.strip_suffix(".entries.json")?;
+                Some(case.to_owned())
+            })
+            .collect();
+        cases.sort();
+        assert!(!cases.is_empty());
+
+        for case in cases {
+            let composed = compose_fixture(&case);
+            let expected_path = Path::new(FIXTURES_DIR).join(format!("{case}.expected.json"));
+
+            // A new or intentionally changed case is (re)generated with `UPDATE_FIXTURES=1 cargo test`
+            if std::env::var_os("UPDATE_FIXTURES").is_some() {
+                fs::write(&expected_path, &composed).unwrap();
+                continue;
+            }
+
+            let expected = fs::read_to_string(&expected_path)
+                .unwrap_or_else(|_| panic!("Missing golden file \"{}\"", expected_path.display()));
+            assert_eq!(
+                composed, expected,
+                "The composition of the `{case}` fixture changed"
+            );
+        }
+    }
+
+    /// Reverses the keys of every object, except within accumulated values as their checksum hashes them as written.
+    fn reverse_keys(value: &serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Object(object) => serde_json::Value::Object(
+                object
+                    .iter()
+                    .rev()
+                    .map(|(key, value)| {
+                        let value = if key.starts_with('+') {
+                            value.clone()
+                        } else {
+                            reverse_keys(value)
+                        };
+                        (key.clone(), value)
+                    })
+                    .collect(),
+            ),
+            serde_json::Value::Array(values) => {
+                serde_json::Value::Array(values.iter().map(reverse_keys).collect())
+            }
+            value => value.clone(),
+        }
+    }
+
+    #[test]
+    fn test_canonical_json_is_stable_for_permuted_entries() {
+        let compose = |entries: &serde_json::Value| {
+            let entries: Vec<Entry> = serde_json::from_value(entries.clone()).unwrap();
+            let entries_pool = entries
+                .into_iter()
+                .enumerate()
+                .map(|(i, entry)| {
+                    Rc::new(ParsedEntry {
+                        id: i.to_string(),
+                        path: None,
+                        entry,
+                    })
+                })
+                .collect();
+            canonical_json(&compose_emails(&map_emails(&entries_pool)).ok)
+        };
+
+        for case in ["batch_mode", "nested_accumulation", "single_mode"] {
+            let path = Path::new(FIXTURES_DIR).join(format!("{case}.entries.json"));
+            let mut entries: serde_json::Value =
+                serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+            entries[0]["context"]["ratio"] = json!(2.0);
+            let canonical = compose(&entries);
+
+            // The first entry is last, with its float written as an integer
+            let mut permuted = reverse_keys(&entries);
+            let permuted_entries = permuted.as_array_mut().unwrap();
+            permuted_entries.reverse();
+            permuted_entries.last_mut().unwrap()["context"]["ratio"] = json!(2);
+            assert_eq!(compose(&permuted), canonical, "{case}");
+
+            assert!(canonical.ends_with("}\n]\n") && !canonical.contains('\r'));
+            assert!(
+                canonical
+                    .lines()
+                    .any(|line| line.trim().trim_end_matches(',') == "\"ratio\": 2"),
+                "{canonical}"
+            );
+        }
+
+        let email = composed_email(json!({ "b": { "z": 1.5, "a": [{ "y": 1, "x": 2 }] }, "a": 1 }));
+        let canonical = email.to_canonical_json();
+        let keys: Vec<&str> = canonical
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix('"')?.split('"').next())
+            .collect();
+        let context_keys = &keys[keys.iter().position(|&key| key == "context").unwrap()..];
+        assert_eq!(context_keys[..7], ["context", "a", "b", "a", "x", "y", "z"]);
+        assert!(canonical.contains("\"z\": 1.5\n"));
+    }
+
+    #[test]
+    fn test_compose_accumulated_batch() {
+        let entry = |id: &str, utc: &str, event: &str| {
+            let entry: Entry = serde_json::from_value(json!({
+                "id": id,
+                "utc": utc,
+                "notify_error": [],
+                "email": {
+                    "system": "sys",
+                    "subsystem": "sub",
+                    "from": "a@x.com",
+                    "to": ["b@x.com"],
+                    "cc": [],
+                    "bcc": [],
+                    "reply_to": [],
+                    "subject": "Events",
+                    "template": "ops_department",
+                    "alternative_content": "",
+                    "attachments": [],
+                    "unique_by": ""
+                },
+                "context": { "title": "Events", "+events": event }
+            }))
+            .unwrap();
+
+            Rc::new(ParsedEntry {
+                id: id.to_owned(),
+                path: None,
+                entry,
+            })
+        };
+
+        let entries_pool = vec![
+            entry("2", "2023-01-01T11:00:00+00:00", "second"),
+            entry("1", "2023-01-01T10:00:00+00:00", "first"),
+        ];
+        let emails_map = map_emails(&entries_pool);
+        let composed_emails = compose_emails(&emails_map).ok;
+
+        assert_eq!(composed_emails.len(), 1);
+        assert_eq!(
+            serde_json::to_value(&composed_emails[0].context).unwrap(),
+            json!({
+                "title": "Events",
+                "events": [
+                    {
+                        "order": 1,
+                        "checksum": string_crc32_iso_hdlc_checksum("\"first\""),
+                        "value": "first"
+                    },
+                    {
+                        "order": 2,
+                        "checksum": string_crc32_iso_hdlc_checksum("\"second\""),
+                        "value": "second"
+                    }
+                ]
+            })
+        );
+        assert_eq!(composed_emails[0].id, entries_pool[0].email_id());
+        assert_eq!(composed_emails[0].header.subject, "Events");
+    }
+
+    #[test]
+    fn test_entries_of_composed_emails() {
+        let entry = |id: &str, utc: &str, context: serde_json::Value| {
+            let entry: Entry = serde_json::from_value(json!({
+                "id": id,
+                "utc": utc,
+                "notify_error": [],
+                "email": {
+                    "system": "sys",
+                    "subsystem": "sub",
+                    "from": "a@x.com",
+                    "to": ["b@x.com"],
+                    "cc": [],
+                    "bcc": [],
+                    "reply_to": [],
+                    "subject": "Events",
+                    "template": "ops_department",
+                    "alternative_content": "",
+                    "attachments": [],
+                    "unique_by": ""
+                },
+                "context": context
+            }))
+            .unwrap();
+
+            Rc::new(ParsedEntry {
+                id: id.to_owned(),
+                path: None,
+                entry,
+            })
+        };
+        let entries_of = |email: &ComposedEmail, emails_map: &EmailEntries| {
+            entries_of(email, emails_map)
+                .map(|entry| entry.id.clone())
+                .collect::<Vec<_>>()
+        };
+
+        // Each E-mail composed in the single mode is of its own entry, though they share the E-mail ID
+        let entries_pool = vec![
+            entry(
+                "2",
+                "2023-01-01T11:00:00+00:00",
+                json!({ "title": "second" }),
+            ),
+            entry(
+                "1",
+                "2023-01-01T10:00:00+00:00",
+                json!({ "title": "first" }),
+            ),
+        ];
+        let emails_map = map_emails(&entries_pool);
+        let composed_emails = compose_emails(&emails_map).ok;
+
+        assert_eq!(composed_emails.len(), 2);
+        assert_eq!(composed_emails[0].id, composed_emails[1].id);
+        let mut single_entries: Vec<_> = composed_emails
+            .iter()
+            .map(|email| entries_of(email, &emails_map))
+            .collect();
+        single_entries.sort();
+        assert_eq!(single_entries, [["1"], ["2"]]);
+
+        // A batch is of every entry of its ID
+        let entries_pool = vec![
+            entry(
+                "2",
+                "2023-01-01T11:00:00+00:00",
+                json!({ "+events": "second" }),
+            ),
+            entry(
+                "1",
+                "2023-01-01T10:00:00+00:00",
+                json!({ "+events": "first" }),
+            ),
+        ];
+        let emails_map = map_emails(&entries_pool);
+        let composed_emails = compose_emails(&emails_map).ok;
+
+        assert_eq!(composed_emails.len(), 1);
+        assert_eq!(composed_emails[0].single_entry, None);
+        assert_eq!(entries_of(&composed_emails[0], &emails_map), ["1", "2"]);
+    }
+
+    #[test]
+    fn test_compose_duplicate_accumulation_key() {
+        let entry = |id: &str, context: serde_json::Value| {
+            let entry: Entry = serde_json::from_value(json!({
+                "id": id,
+                "utc": "2023-01-01T10:00:00+00:00",
+                "notify_error": [],
+                "email": {
+                    "system": "sys",
+                    "subsystem": "sub",
+                    "from": "a@x.com",
+                    "to": ["b@x.com"],
+                    "cc": [],
+                    "bcc": [],
+                    "reply_to": [],
+                    "subject": "Events",
+                    "template": "ops_department",
+                    "alternative_content": "",
+                    "attachments": [],
+                    "unique_by": id
+                },
+                "context": context
+            }))
+            .unwrap();
+
+            Rc::new(ParsedEntry {
+                id: id.to_owned(),
+                path: Some(PathBuf::from(format!("outbox/{id}.json"))),
+                entry,
+            })
+        };
+
+        let entries_pool = vec![
+            entry("top", json!({ "rows": [], "+rows": 1 })),
+            entry(
+                "nested",
+                json!({ "table": { "a/b": { "+rows": 1, "rows": 2 } } }),
+            ),
+            entry("fine", json!({ "table": { "+rows": 1 }, "rows": 2 })),
+        ];
+        let results = compose_emails(&map_emails(&entries_pool));
+
+        assert_eq!(results.ok.len(), 1);
+        assert_eq!(results.ok[0].header.unique_by, "fine");
+
+        let mut errors: Vec<String> = results.err.iter().map(|e| e.error.to_string()).collect();
+        errors.sort();
+        assert_eq!(
+            errors,
+            [
+                "The entry \"outbox/nested.json\" has both `/table/a~1b/rows` and its accumulated `+` key, the E-mail is not sent",
+                "The entry \"outbox/top.json\" has both `/rows` and its accumulated `+` key, the E-mail is not sent",
+            ]
+        );
+        assert!(results
+            .err
+            .iter()
+            .all(|failure| failure.entry_paths.len() == 1));
+    }
+
+    /// The accumulated form of the values, in order, as composed from `+key` entries.
+    fn accumulated(values: &[serde_json::Value]) -> serde_json::Value {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                json!({
+                    "order": i + 1,
+                    "checksum": string_crc32_iso_hdlc_checksum(&value.to_string()),
+                    "value": value
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_values_views_of_accumulated_arrays() {
+        let rows = [
+            json!({ "hostname": "db-2" }),
+            json!({ "hostname": "db-1" }),
+            json!({ "hostname": "web-1" }),
+        ];
+        let mut email = composed_email(json!({
+            "rows": accumulated(&rows),
+            "table": { "entries": accumulated(&[json!(3), json!(1)]) },
+            "plain": [1, 2],
+            "title": "Hosts"
+        }));
+        let report = add_values_views(&mut email.context);
+        assert!(report.warnings().is_empty());
+
+        // In the order of accumulation, next to the accumulated arrays which are kept for their metadata
+        assert_eq!(email.context["rows_values"], json!(rows));
+        assert_eq!(email.context["rows"], accumulated(&rows));
+        assert_eq!(email.context["table"]["entries_values"], json!([3, 1]));
+        assert!(email.context.get("plain_values").is_none());
+        assert!(email.context.get("title_values").is_none());
+
+        for (row, value) in email.context["rows"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .zip(email.context["rows_values"].as_array().unwrap())
+        {
+            assert_eq!(&row["value"], value);
+        }
+
+        let rendered = tera::Tera::one_off(
+            "{% for row in rows_values %}{{ row.hostname }} {% endfor %}",
+            &tera::Context::from_value(serde_json::Value::Object(email.context)).unwrap(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(rendered, "db-2 db-1 web-1 ");
+    }
+
+    #[test]
+    fn test_values_views_collision() {
+        let mut email = composed_email(json!({
+            "rows": accumulated(&[json!("a")]),
+            "rows_values": "producer",
+            "table": {
+                "entries": accumulated(&[json!("b")]),
+                "entries_values": []
+            }
+        }));
+
+        let report = add_values_views(&mut email.context);
+
+        assert_eq!(email.context["rows_values"], json!("producer"));
+        assert_eq!(email.context["table"]["entries_values"], json!([]));
+        assert!(report.is_empty());
+        assert_eq!(
+            report.to_string(),
+            "  - warning: The key `table.entries_values` already exists, the plain values of the accumulated `table.entries` are not added\n  \
+            - warning: The key `rows_values` already exists, the plain values of the accumulated `rows` are not added\n"
+        );
+    }
+
+    #[test]
+    fn test_batch_window() {
+        let t0: DateTime<Utc> = "2023-01-01T10:00:00Z".parse().unwrap();
+        let seconds = |s| t0 + Duration::seconds(s);
+
+        let entry = |id: &str, utc: DateTime<Utc>| {
+            let mut value = serde_json::to_value(Entry {
+                id: id.to_owned(),
+                utc: utc.into(),
+                notify_error: Vec::new(),
+                email: Email::default(),
+                context: JsonObject::new(),
+                signature: None,
+            })
+            .unwrap();
+            value["context"] = json!({ "+events": id });
+
+            Rc::new(ParsedEntry {
+                id: id.to_owned(),
+                path: None,
+                entry: serde_json::from_value(value).unwrap(),
+            })
+        };
+
+        let batch_window = BatchWindow {
+            window: Duration::seconds(10),
+            max_hold: Duration::seconds(60),
+        };
+
+        let mut entries_pool = vec![entry("1", seconds(0)), entry("2", seconds(5))];
+
+        // Still within the quiet period of the last entry
+        let mut emails_map = map_emails(&entries_pool);
+        assert_eq!(batch_window.hold_back(&mut emails_map, seconds(8)), 1);
+        assert!(emails_map.is_empty());
+
+        // A late entry joins the held batch
+        entries_pool.push(entry("3", seconds(12)));
+        let mut emails_map = map_emails(&entries_pool);
+        assert_eq!(batch_window.hold_back(&mut emails_map, seconds(20)), 1);
+
+        let mut emails_map = map_emails(&entries_pool);
+        assert_eq!(batch_window.hold_back(&mut emails_map, seconds(22)), 0);
+        let composed_emails = compose_emails(&emails_map).ok;
+        assert_eq!(composed_emails.len(), 1);
+        assert_eq!(
+            composed_emails[0].context["events"]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+
+        // After the flush, a new entry is a batch of its own
+        let later_pool = vec![entry("4", seconds(30))];
+        let mut emails_map = map_emails(&later_pool);
+        assert_eq!(batch_window.hold_back(&mut emails_map, seconds(35)), 1);
+        let mut emails_map = map_emails(&later_pool);
+        assert_eq!(batch_window.hold_back(&mut emails_map, seconds(40)), 0);
+        assert_eq!(
+            compose_emails(&emails_map).ok[0].context["events"][0]["value"],
+            "4"
+        );
+
+        // Entries that keep arriving are sent once held for `max_hold`
+        let busy_pool: Vec<_> = (0..=70)
+            .step_by(5)
+            .map(|s| entry(&s.to_string(), seconds(s)))
+            .collect();
+        let mut emails_map = map_emails(&busy_pool);
+        assert_eq!(batch_window.hold_back(&mut emails_map, seconds(72)), 0);
+    }
+
+    fn json_value() -> impl Strategy<Value = serde_json::Value> {
+        let leaf = prop_oneof![
+            Just(serde_json::Value::Null),
+            any::<bool>().prop_map(serde_json::Value::from),
+            any::<i64>().prop_map(serde_json::Value::from),
+            "\\PC{0,8}".prop_map(serde_json::Value::from),
+        ];
+
+        leaf.prop_recursive(3, 24, 4, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..4).prop_map(serde_json::Value::from),
+                prop::collection::vec(("[a-z]{1,6}", inner), 0..4)
+                    .prop_map(|pairs| serde_json::Value::Object(pairs.into_iter().collect())),
+            ]
+        })
+    }
+
+    /// A context without accumulation keys, as keys never start with `+`.
+    fn plain_context() -> impl Strategy<Value = JsonObject> {
+        prop::collection::vec(("[a-z]{1,6}", json_value()), 0..6)
+            .prop_map(|pairs| pairs.into_iter().collect())
+    }
+
+    proptest! {
+        #[test]
+        fn prop_accumulation_keeps_entries_order(values in prop::collection::vec(json_value(), 1..16)) {
+            let mut target = JsonObject::new();
+            let mut email_compose_method = EmailComposeMethod::Single;
+
+            for value in &values {
+                let source = json!({ "+events": value });
+                copy_and_accumulate(source.as_object().unwrap(), &mut target, &mut email_compose_method, "").unwrap();
+            }
+
+            prop_assert!(matches!(email_compose_method, EmailComposeMethod::Batch));
+
+            let events = target["events"].as_array().unwrap();
+            prop_assert_eq!(events.len(), values.len());
+
+            for (i, (event, value)) in events.iter().zip(&values).enumerate() {
+                prop_assert_eq!(&event["order"], &json!(i + 1));
+                prop_assert_eq!(&event["value"], value);
+            }
+        }
+
+        #[test]
+        fn prop_plain_merge_is_idempotent(context in plain_context()) {
+            let mut target = context.clone();
+            let mut email_compose_method = EmailComposeMethod::Single;
+
+            copy_and_accumulate(&context, &mut target, &mut email_compose_method, "").unwrap();
+            copy_and_accumulate(&context, &mut target, &mut email_compose_method, "").unwrap();
+
+            prop_assert_eq!(&target, &context);
+            prop_assert!(matches!(email_compose_method, EmailComposeMethod::Single));
+        }
+
+        // Checksums hash the value as written rather than its canonical form, so key reordering is not covered
+        #[test]
+        fn prop_checksum_is_deterministic(value in json_value()) {
+            let mut target = JsonObject::new();
+            let mut email_compose_method = EmailComposeMethod::Single;
+            let source = json!({ "+events": value });
+
+            copy_and_accumulate(source.as_object().unwrap(), &mut target, &mut email_compose_method, "").unwrap();
+            copy_and_accumulate(source.as_object().unwrap(), &mut target, &mut email_compose_method, "").unwrap();
+
+            let events = target["events"].as_array().unwrap();
+            let expected = json!(string_crc32_iso_hdlc_checksum(&value.to_string()));
+            prop_assert_eq!(&events[0]["checksum"], &expected);
+            prop_assert_eq!(&events[1]["checksum"], &expected);
+        }
+    }
+
+    fn composed_email(context: serde_json::Value) -> ComposedEmail {
+        ComposedEmail {
+            id: 1,
+            header: Email {
+                subject: "Events digest".to_owned(),
+                ..Default::default()
+            },
+            context: context.as_object().unwrap().clone(),
+            page: None,
+            single_entry: None,
+        }
+    }
+
+    #[test]
+    fn test_validate_addresses() {
+        let email = Email {
+            from: "OSA Mailer <osa@x.com>".to_owned(),
+            sender: Some("osa@@x.com".to_owned()),
+            to: vec![
+                "ops@x.com, dev.x.com".to_owned(),
+                "Ops <ops@x.com".to_owned(),
+            ],
+            cc: vec![
+                "{{ team }}@x.com".to_owned(),
+                "\"Doe, John\" <j@x.com>".to_owned(),
+            ],
+            bcc: vec!["qa@x.com".to_owned()],
+            reply_to: vec!["@".to_owned()],
+            ..Default::default()
+        };
+
+        let report = email.validate_addresses();
+
+        // Every invalid address is listed with its field
+        let invalid: Vec<String> = report
+            .to_string()
+            .lines()
+            .map(|line| line.split(':').next().unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            invalid,
+            [
+                "  - Invalid `sender` address `osa@@x.com`",
+                "  - Invalid `to` address `dev.x.com`",
+                "  - Invalid `to` address `Ops <ops@x.com`",
+                "  - Invalid `reply_to` address `@`",
+            ]
+        );
+
+        let valid = Email {
+            from: "a@x.com, b@x.com".to_owned(),
+            to: vec!["Ops <ops@x.com>".to_owned()],
+            ..Default::default()
+        };
+        assert!(valid.validate_addresses().is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_recipients_removed_and_reported() {
+        let mut email = Email {
+            to: vec!["Ops <ops@x.com>".to_owned(), "dev@x.com".to_owned()],
+            cc: vec!["OPS@x.com".to_owned(), "qa@x.com".to_owned()],
+            bcc: vec!["qa@x.com".to_owned()],
+            reply_to: vec!["ops@x.com".to_owned(), "ops@x.com".to_owned()],
+            ..Default::default()
+        };
+
+        let report = email.dedup_recipients();
+
+        assert_eq!(email.to, vec!["Ops <ops@x.com>", "dev@x.com"]);
+        assert_eq!(email.cc, vec!["qa@x.com"]);
+        assert!(email.bcc.is_empty());
+        assert_eq!(email.reply_to, vec!["ops@x.com"]);
+
+        // Non-fatal, every removal is listed with its field
+        assert!(report.is_empty());
+        assert_eq!(report.warnings().len(), 3);
+        assert_eq!(
+            report.to_string(),
+            "  - warning: The duplicate address `OPS@x.com` was removed from `cc`, already in `to`\n  \
+            - warning: The duplicate address `qa@x.com` was removed from `bcc`, already in `cc`\n  \
+            - warning: The duplicate address `ops@x.com` was removed from `reply_to`, already in `reply_to`\n"
+        );
+    }
+
+    #[test]
+    fn test_raw_html_entries() {
+        use crate::send::{FileTransport, MessageBuilder, SendOutcome, Transport};
+
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = dir.path().join("outbox");
+        let resources = dir.path().join(RESOURCES_DIR);
+        fs::create_dir_all(&outbox).unwrap();
+        fs::create_dir_all(resources.join("newsletter")).unwrap();
+        fs::write(resources.join("newsletter").join("logo.png"), b"png").unwrap();
+
+        let entry = |name: &str, email: serde_json::Value| {
+            let mut value = json!({
+                "id": name,
+                "utc": "2023-01-01T10:00:00+00:00",
+                "notify_error": [],
+                "email": {
+                    "system": "sys",
+                    "subsystem": "sub",
+                    "from": "a@x.com",
+                    "to": ["b@x.com"],
+                    "cc": [],
+                    "bcc": [],
+                    "reply_to": [],
+                    "subject": "Newsletter",
+                    "alternative_content": "",
+                    "attachments": [],
+                    "unique_by": ""
+                },
+                "context": {}
             });
+            value["email"]
+                .as_object_mut()
+                .unwrap()
+                .extend(email.as_object().unwrap().clone());
+            fs::write(outbox.join(format!("{name}.json")), value.to_string()).unwrap();
+        };
+
+        entry(
+            "raw",
+            json!({
+                "html_body": "<p>October</p><img src=\"logo.png\">",
+                "text_body": "October",
+                "resources_dir": "newsletter"
+            }),
+        );
+        entry("other", json!({ "html_body": "<p>November</p>" }));
+        entry(
+            "both",
+            json!({ "template": "ops_department", "html_body": "<p>Hi</p>" }),
+        );
+        entry("neither", json!({}));
+        entry(
+            "escaping",
+            json!({ "html_body": "<p>Hi</p>", "resources_dir": "../templates" }),
+        );
+
+        let results = load_entries(&outbox, &Verifier::default());
+        let mut rejected: Vec<&str> = results
+            .err
+            .iter()
+            .map(|e| e.entry_content.id.rsplit(['/', '\\']).next().unwrap())
+            .collect();
+        rejected.sort();
+        assert_eq!(rejected, vec!["both.json", "escaping.json", "neither.json"]);
+
+        // Distinct bodies are distinct E-mails
+        let mut composed_emails = compose_emails(&map_emails(&results.ok)).ok;
+        assert_eq!(composed_emails.len(), 2);
+        composed_emails.retain(|email| email.header.text_body.is_some());
+        let email = &composed_emails[0];
+        assert!(email.header.is_raw());
+
+        let images_root = email
+            .header
+            .resources_path(&dir.path().join("templates"), &resources);
+        assert_eq!(images_root, resources.join("newsletter"));
+
+        let message = MessageBuilder::new()
+            .from(&email.header.from)
+            .to_addresses(&email.header.to.join(", "))
+            .subject(&email.header.subject)
+            .alternative_content(email.header.text_body.as_deref().unwrap())
+            .content(
+                email.header.html_body.as_deref().unwrap(),
+                Some(&images_root),
+            )
+            .build()
+            .unwrap();
+
+        let mut transport = FileTransport::new(dir.path().join("sent"));
+        transport.establish(None).unwrap();
+        let SendOutcome::Written(path) = transport.send(message.try_into().unwrap()).unwrap()
+        else {
+            panic!("The file transport should write the E-mail");
+        };
+
+        let eml = fs::read_to_string(path).unwrap();
+        // `<p>October</p><img src="cid:image_0">`, with the image of the resources directory
+        assert!(
+            eml.contains("PHA+T2N0b2JlcjwvcD48aW1nIHNyYz0iY2lkOmltYWdlXzAiPg=="),
+            "{eml}"
+        );
+        assert!(eml.contains("Content-ID: <image_0>\r\n"), "{eml}");
+    }
+
+    #[test]
+    fn test_load_yaml_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = dir.path();
+
+        let entry = |id: &str, utc: &str, event: &str| {
+            json!({
+                "id": id,
+                "utc": utc,
+                "notify_error": [],
+                "email": {
+                    "system": "sys",
+                    "subsystem": "sub",
+                    "from": "a@x.com",
+                    "to": ["b@x.com"],
+                    "cc": [],
+                    "bcc": [],
+                    "reply_to": [],
+                    "subject": "Events",
+                    "template": "ops_department",
+                    "alternative_content": "",
+                    "attachments": [],
+                    "unique_by": ""
+                },
+                "context": { "title": "Events", "+events": { "name": event, "count": 2 } }
+            })
+        };
+
+        fs::write(
+            outbox.join("1.yaml"),
+            serde_yaml::to_string(&entry("1", "2023-01-01T10:00:00+00:00", "first")).unwrap(),
+        )
+        .unwrap();
+        fs::write(
+            outbox.join("2.json"),
+            entry("2", "2023-01-01T11:00:00+00:00", "second").to_string(),
+        )
+        .unwrap();
+        fs::write(
+            outbox.join("3.YML"),
+            serde_yaml::to_string(&entry("3", "2023-01-01T12:00:00+00:00", "third")).unwrap(),
+        )
+        .unwrap();
+        fs::write(outbox.join("broken.yml"), "id: [unclosed").unwrap();
+        fs::write(outbox.join("notes.txt"), "Not an entry").unwrap();
+
+        let results = load_entries(outbox, &Verifier::default());
+
+        assert_eq!(results.err.len(), 1);
+        assert!(results.err[0].entry_content.id.ends_with("broken.yml"));
+        assert!(matches!(results.err[0].error, EntryFormatError::Yaml(_)));
+
+        // Entries of either format batch into the same E-mail
+        let composed_emails = compose_emails(&map_emails(&results.ok)).ok;
+        assert_eq!(composed_emails.len(), 1);
+
+        let events: Vec<&str> = composed_emails[0].context["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event["value"]["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(events, vec!["first", "second", "third"]);
+    }
+
+    #[test]
+    fn test_archive_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = dir.path().join("outbox");
+        let archive = dir.path().join(SENT_DIR);
+        let now = DateTime::parse_from_rfc3339("2023-01-01T10:00:00.250+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let nested = outbox.join("backup").join("nightly");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(nested.join("report.json"), "nested").unwrap();
+        fs::write(outbox.join("report.json"), "first").unwrap();
+        fs::write(dead_letter::state_path(outbox.join("report.json")), "{}").unwrap();
+
+        let archived = archive_entry(&nested.join("report.json"), &archive, &outbox, now).unwrap();
+        assert_eq!(
+            archived,
+            archive
+                .join("backup")
+                .join("nightly")
+                .join("report.20230101T100000250Z.json")
+        );
+        assert_eq!(fs::read_to_string(&archived).unwrap(), "nested");
+        assert!(!nested.join("report.json").exists());
+
+        // A new entry under the same name, archived at the same time
+        let first = archive_entry(&outbox.join("report.json"), &archive, &outbox, now).unwrap();
+        assert!(!dead_letter::state_path(outbox.join("report.json")).exists());
+        fs::write(outbox.join("report.json"), "second").unwrap();
+        let second = archive_entry(&outbox.join("report.json"), &archive, &outbox, now).unwrap();
+
+        assert_eq!(first, archive.join("report.20230101T100000250Z.json"));
+        assert_eq!(second, archive.join("report.20230101T100000250Z-1.json"));
+        assert_eq!(fs::read_to_string(first).unwrap(), "first");
+        assert_eq!(fs::read_to_string(second).unwrap(), "second");
+
+        // Only entries of the outbox are archived
+        let stray = dir.path().join("stray.json");
+        fs::write(&stray, "stray").unwrap();
+        assert!(archive_entry(&stray, &archive, &outbox, now).is_err());
+        assert!(stray.exists());
+    }
+
+    #[test]
+    fn test_quarantine_unparsed() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = dir.path().join("outbox");
+        fs::create_dir_all(outbox.join("nested")).unwrap();
+        let now = DateTime::parse_from_rfc3339("2023-01-01T10:00:00+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let broken = outbox.join("nested").join("broken.json");
+        fs::write(&broken, r#"{ "id": "1", "utc": "#).unwrap();
+
+        let results = load_entries(&outbox, &Verifier::default());
+        assert_eq!(results.err.len(), 1);
+        let reason = results.err[0].error.to_string();
+
+        let quarantine = Quarantine::new(&outbox, None, None).unwrap();
+        let quarantined = quarantine_unparsed(&quarantine, &results.err[0], now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            quarantined,
+            outbox.join(FAILED_DIR).join("nested").join("broken.json")
+        );
+        assert!(!broken.exists());
+        assert!(quarantined.is_file());
+
+        let error = fs::read_to_string(parse_error_path(&quarantined)).unwrap();
+        assert_eq!(error, format!("2023-01-01T10:00:00+00:00\n{reason}\n"));
+        assert!(reason.contains("EOF while parsing"), "{reason}");
+
+        // Quarantined entries are not parsed again, nor are the error reports of the runs
+        fs::create_dir_all(outbox.join(ERRORS_DIR)).unwrap();
+        fs::write(
+            outbox.join(ERRORS_DIR).join("20230101T100000000Z.json"),
+            "[]",
+        )
+        .unwrap();
+        assert!(load_entries(&outbox, &Verifier::default()).err.is_empty());
+    }
+
+    #[test]
+    fn test_load_signed_entries() {
+        use crate::signing::{signed_content, UnsignedPolicy, SIGNATURE_FIELD};
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = dir.path();
+        let key = SigningKey::from_bytes(&[7; 32]);
+
+        let entry = |id: &str| {
+            json!({
+                "id": id,
+                "utc": "2023-01-01T10:00:00+00:00",
+                "notify_error": [],
+                "email": {
+                    "system": "backup", "subsystem": "scheduler", "from": "osa@example.com",
+                    "to": ["ops@example.com"], "cc": [], "bcc": [], "reply_to": [],
+                    "subject": "Daily report", "template": "ops_department",
+                    "alternative_content": "", "attachments": [], "unique_by": id
+                },
+                // A producer can't tell who it is
+                "context": { "_meta": { "producer": "billing" } }
+            })
+        };
+        let sign = |mut entry: serde_json::Value| {
+            let signature = key.sign(signed_content(&entry).as_bytes());
+            let value: String = signature
+                .to_bytes()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect();
+            entry[SIGNATURE_FIELD] = json!({ "key_id": "backup", "value": value });
+            entry
+        };
+
+        let mut tampered = sign(entry("tampered"));
+        tampered["email"]["to"] = json!(["attacker@example.com"]);
+
+        for (name, entry) in [
+            ("signed", sign(entry("signed"))),
+            ("unsigned", entry("unsigned")),
+            ("tampered", tampered),
+        ] {
+            fs::write(outbox.join(format!("{name}.json")), entry.to_string()).unwrap();
         }
+        // The signature of a YAML entry covers its JSON
+        fs::write(
+            outbox.join("signed.yaml"),
+            serde_yaml::to_string(&sign(entry("signed_yaml"))).unwrap(),
+        )
+        .unwrap();
+
+        let verifier = Verifier::new(
+            BTreeMap::from([("backup".to_owned(), key.verifying_key())]),
+            UnsignedPolicy::Accept,
+        );
+        let results = load_entries(outbox, &verifier);
+
+        assert_eq!(results.rejected.len(), 1);
+        assert!(results.rejected[0].id.ends_with("tampered.json"));
+        assert_eq!(
+            results.rejected[0].error,
+            SignatureError::Mismatch("backup".to_owned())
+        );
+
+        let producers: BTreeMap<&str, Option<&serde_json::Value>> = results
+            .ok
+            .iter()
+            .map(|parsed| {
+                let entry = &parsed.entry;
+                (entry.id.as_str(), entry.context["_meta"].get("producer"))
+            })
+            .collect();
+        assert_eq!(
+            producers,
+            BTreeMap::from([
+                ("signed", Some(&json!("backup"))),
+                ("signed_yaml", Some(&json!("backup"))),
+                ("unsigned", None)
+            ])
+        );
+    }
+
+    #[test]
+    fn test_paginate_partition_boundaries() {
+        let email = composed_email(json!({
+            "events": [1, 2, 3, 4, 5],
+            "title": "Disk usage"
+        }));
+
+        let page_sizes = HashMap::from([("events".to_owned(), 2)]);
+        let pages = paginate(email, &page_sizes);
+
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0].context["events"], json!([1, 2]));
+        assert_eq!(pages[1].context["events"], json!([3, 4]));
+        assert_eq!(pages[2].context["events"], json!([5]));
+    }
+
+    #[test]
+    fn test_paginate_meta_subject_and_shared_keys() {
+        let email = composed_email(json!({
+            "events": [1, 2, 3, 4],
+            "title": "Disk usage",
+            "_meta": { "source": "producer" }
+        }));
+
+        let page_sizes = HashMap::from([("events".to_owned(), 2)]);
+        let pages = paginate(email, &page_sizes);
+
+        for (i, page) in pages.iter().enumerate() {
+            assert_eq!(page.id, 1);
+            assert_eq!(
+                page.page,
+                Some(Page {
+                    number: i + 1,
+                    count: 2
+                })
+            );
+            assert_eq!(page.context["_meta"]["page"], json!(i + 1));
+            assert_eq!(page.context["_meta"]["page_count"], json!(2));
+            assert_eq!(page.context["_meta"]["source"], json!("producer"));
+            assert_eq!(page.context["title"], json!("Disk usage"));
+            assert_eq!(page.header.subject, format!("Events digest ({}/2)", i + 1));
+        }
+
+        assert!(pages[1].page.unwrap().is_last());
+    }
+
+    #[test]
+    fn test_paginate_nested_key() {
+        let email = composed_email(json!({
+            "table": { "type": 1, "entries": [1, 2, 3] }
+        }));
+
+        let page_sizes = HashMap::from([("table.entries".to_owned(), 2)]);
+        let pages = paginate(email, &page_sizes);
+
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].context["table"]["entries"], json!([1, 2]));
+        assert_eq!(pages[1].context["table"]["entries"], json!([3]));
+        assert_eq!(pages[1].context["table"]["type"], json!(1));
+    }
+
+    #[test]
+    fn test_paginate_within_page_size() {
+        let email = composed_email(json!({ "events": [1, 2] }));
+
+        let page_sizes = HashMap::from([("events".to_owned(), 2)]);
+        let pages = paginate(email, &page_sizes);
+
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].page, None);
+        assert_eq!(pages[0].header.subject, "Events digest");
+        assert!(pages[0].context.get("_meta").is_none());
     }
-    composed_emails
 }
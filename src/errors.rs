@@ -1,8 +1,21 @@
 #![allow(dead_code)]
 
 use std::error::Error;
+use std::path::PathBuf;
 
 use chrono::{DateTime, Utc};
+use serde::ser::SerializeStruct;
+use serde::Serialize;
+
+/// The failure to parse an entry file, by the format of the file.
+#[derive(thiserror::Error, Debug)]
+pub enum EntryFormatError {
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum EntryError {
@@ -22,13 +35,73 @@ pub enum EntryError {
     ParsingFailure {
         id: String,
         content: String,
-        error: serde_json::Error,
+        error: EntryFormatError,
+    },
+
+    #[error("Unable to read the entry \"{path}\": {error}")]
+    ReadFailure { path: String, error: std::io::Error },
+
+    #[error("Unable to write the entry \"{path}\": {error}")]
+    WriteFailure { path: String, error: std::io::Error },
+
+    #[error("The template `{0}` does not exist")]
+    MissingTemplate(String),
+
+    #[error("The entry doesn't match the entry schema:\n{}", .0.join("\n"))]
+    SchemaViolations(Vec<String>),
+
+    #[error("The `email` section requires exactly one of `template` or `html_body`")]
+    TemplateOrBody,
+
+    #[error("The resources directory `{0}` is not within the resources directory")]
+    InvalidResourcesDir(String),
+
+    #[error(
+        "The duplicate address `{address}` was removed from `{field}`, already in `{kept_in}`"
+    )]
+    DuplicateRecipient {
+        address: String,
+        field: &'static str,
+        kept_in: &'static str,
+    },
+
+    #[error("The key `{0}_values` already exists, the plain values of the accumulated `{0}` are not added")]
+    ValuesKeyCollision(String),
+
+    #[error("The entry \"{entry}\" has both `{pointer}` and its accumulated `+` key, the E-mail is not sent")]
+    DuplicateAccumulationKey { entry: String, pointer: String },
+
+    #[error("Invalid `{field}` address `{address}`: {reason}")]
+    InvalidAddress {
+        field: &'static str,
+        address: String,
+        reason: String,
     },
 }
 
 #[derive(Debug)]
 pub struct ErrorEvent(DateTime<Utc>, Box<dyn Error + Send + Sync + 'static>);
 
+impl ErrorEvent {
+    /// The causes of the error, from the closest.
+    pub fn causes(&self) -> impl Iterator<Item = &(dyn Error + 'static)> {
+        std::iter::successors(self.1.source(), |&cause| cause.source())
+    }
+}
+
+impl Serialize for ErrorEvent {
+    /// The time of the error, the error and its causes, as strings.
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let causes: Vec<String> = self.causes().map(|cause| cause.to_string()).collect();
+
+        let mut event = serializer.serialize_struct("ErrorEvent", 3)?;
+        event.serialize_field("timestamp", &self.0)?;
+        event.serialize_field("error", &self.1.to_string())?;
+        event.serialize_field("causes", &causes)?;
+        event.end()
+    }
+}
+
 impl<T: Error + Send + Sync + 'static> From<T> for ErrorEvent {
     fn from(error: T) -> Self {
         ErrorEvent(chrono::offset::Utc::now(), Box::new(error))
@@ -38,7 +111,7 @@ impl<T: Error + Send + Sync + 'static> From<T> for ErrorEvent {
 /// There could be a special case where an error type is not implementing the `std::error::Error` type.
 /// For these cases, you'll have to use this `ErrorWrapper<E>` and maybe implement your own `From<ErrorWrapper<E>>` for
 /// the `ErrorEvent` type. Currently this is used to wrap the `anyhow::Error` type.
-pub struct ErrorWrapper<E>(E);
+pub struct ErrorWrapper<E>(pub E);
 
 impl From<ErrorWrapper<anyhow::Error>> for ErrorEvent {
     fn from(error: ErrorWrapper<anyhow::Error>) -> Self {
@@ -46,13 +119,48 @@ impl From<ErrorWrapper<anyhow::Error>> for ErrorEvent {
     }
 }
 
-#[derive(Debug, Default)]
+/// Severity levels of reported events, from the most severe to the least severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+    Debug,
+}
+
+impl From<log::Level> for Severity {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => Severity::Error,
+            log::Level::Warn => Severity::Warning,
+            log::Level::Info => Severity::Info,
+            log::Level::Debug | log::Level::Trace => Severity::Debug,
+        }
+    }
+}
+
+/// An entry of the E-mail an error report is about.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReportedEntry {
+    /// The ID set by the producer, unknown for an entry that failed to parse
+    pub id: Option<String>,
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Serialize)]
 pub struct ErrorReport {
     /// Additional context for the errors, such as JSON file contents
     context: Option<String>,
 
+    /// The entries the errors are about
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    entries: Vec<ReportedEntry>,
+
     /// Errors regarding a specific context, such as multiple detected error in a JSON file.
     errors: Vec<ErrorEvent>,
+
+    /// Non-fatal issues, such as normalized fields, that the producer should still fix
+    warnings: Vec<ErrorEvent>,
 }
 
 impl ErrorReport {
@@ -67,12 +175,24 @@ impl ErrorReport {
         self
     }
 
+    #[inline]
+    pub fn add_warning<E: Into<ErrorEvent>>(mut self, warning: E) -> Self {
+        self.warnings.push(warning.into());
+        self
+    }
+
     #[inline]
     pub fn set_context(mut self, context: String) -> Self {
         self.context = Some(context);
         self
     }
 
+    #[inline]
+    pub fn add_entry(mut self, id: Option<String>, path: Option<PathBuf>) -> Self {
+        self.entries.push(ReportedEntry { id, path });
+        self
+    }
+
     #[inline]
     pub fn context(&self) -> Option<&str> {
         self.context.as_deref()
@@ -82,6 +202,48 @@ impl ErrorReport {
     pub fn errors(&self) -> &[ErrorEvent] {
         self.errors.as_slice()
     }
+
+    #[inline]
+    pub fn warnings(&self) -> &[ErrorEvent] {
+        self.warnings.as_slice()
+    }
+
+    #[inline]
+    pub fn entries(&self) -> &[ReportedEntry] {
+        self.entries.as_slice()
+    }
+
+    /// Whether there are no errors. Warnings don't count.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl std::fmt::Display for ErrorReport {
+    /// Lists the errors one per line, under the context when there's one.
+    /// The alternate format (`{:#}`) lists the causes of every error under it.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(context) = &self.context {
+            writeln!(f, "{context}:")?;
+        }
+
+        for event in &self.errors {
+            writeln!(f, "  - {}", event.1)?;
+
+            if f.alternate() {
+                for cause in event.causes() {
+                    writeln!(f, "    caused by: {cause}")?;
+                }
+            }
+        }
+
+        for ErrorEvent(_timestamp, warning) in &self.warnings {
+            writeln!(f, "  - warning: {warning}")?;
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -119,4 +281,69 @@ mod tests {
 
         assert_eq!(error_report.context(), Some("test_file.json"));
     }
+
+    #[test]
+    fn test_error_report_causes() {
+        let error = anyhow!("connection reset")
+            .context("Error reading image")
+            .context("Unable to build the E-mail");
+        let error_report = ErrorReport::new()
+            .set_context("E-mail `x`".to_string())
+            .add_error(ErrorWrapper(error));
+
+        assert_eq!(
+            error_report.to_string(),
+            "E-mail `x`:\n  - Unable to build the E-mail\n"
+        );
+        assert_eq!(
+            format!("{error_report:#}"),
+            "E-mail `x`:\n  - Unable to build the E-mail\n    caused by: Error reading image\n    \
+            caused by: connection reset\n"
+        );
+    }
+
+    #[test]
+    fn test_serialize_error_report() {
+        let error_report = ErrorReport::new()
+            .set_context("E-mail `0000abcd` failed to render".to_string())
+            .add_entry(Some("42".to_owned()), Some(PathBuf::from("outbox/a.json")))
+            .add_error(ErrorWrapper(
+                anyhow!("Variable `name` not found")
+                    .context("Failed to render `body.html`")
+                    .context("Failed to render"),
+            ));
+
+        let mut json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&error_report).unwrap()).unwrap();
+        let timestamp = json["errors"][0]["timestamp"].as_str().unwrap();
+        assert!(
+            DateTime::parse_from_rfc3339(timestamp).is_ok(),
+            "{timestamp}"
+        );
+        json["errors"][0]["timestamp"] = serde_json::Value::Null;
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "context": "E-mail `0000abcd` failed to render",
+                "entries": [{ "id": "42", "path": "outbox/a.json" }],
+                "errors": [{
+                    "timestamp": null,
+                    "error": "Failed to render",
+                    "causes": ["Failed to render `body.html`", "Variable `name` not found"]
+                }],
+                "warnings": []
+            })
+        );
+    }
+
+    #[test]
+    fn test_severity_from_log_level() {
+        assert_eq!(Severity::from(log::Level::Error), Severity::Error);
+        assert_eq!(Severity::from(log::Level::Warn), Severity::Warning);
+        assert_eq!(Severity::from(log::Level::Info), Severity::Info);
+        assert_eq!(Severity::from(log::Level::Debug), Severity::Debug);
+        assert_eq!(Severity::from(log::Level::Trace), Severity::Debug);
+        assert!(Severity::Error < Severity::Warning);
+    }
 }
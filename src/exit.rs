@@ -0,0 +1,124 @@
+use enum_iterator::Sequence;
+
+use crate::send::SendError;
+
+/// The exit codes of a run, as listed by `--help`. Keep in sync with [`ExitCode::code`].
+pub(crate) const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0    Success: the E-mails were sent, or the command succeeded
+  2    Some E-mails failed, or entries failed to parse or be removed, the others were sent
+  3    Every E-mail failed, or entries failed with nothing sent
+  4    Invalid configuration, arguments or input
+  5    Aborted: the mail relay is unreachable or refused the credentials, a state file of the run can't be
+       read, or the circuit breaker tripped
+  6    Nothing to send, with `--exit-idle` (0 otherwise)
+  7    Partial, resumable: `--max-run-time` was reached, the next run sends the remaining E-mails
+  130  Interrupted by Ctrl+C or SIGTERM";
+
+/// The context of an error with a state file of the run, e.g. the sent log, so it aborts the run rather than being
+/// taken for an invalid input.
+#[derive(thiserror::Error, Debug)]
+#[error("Unable to load the state of the run")]
+pub(crate) struct StateError;
+
+/// The outcome of a run, so schedulers and wrapper scripts can tell them apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Sequence)]
+pub(crate) enum ExitCode {
+    Success,
+    PartialFailure,
+    AllFailed,
+    InvalidConfig,
+    Aborted,
+    Idle,
+    Resumable,
+    Interrupted,
+}
+
+impl ExitCode {
+    pub(crate) const fn code(self) -> u8 {
+        match self {
+            ExitCode::Success => 0,
+            ExitCode::PartialFailure => 2,
+            ExitCode::AllFailed => 3,
+            ExitCode::InvalidConfig => 4,
+            ExitCode::Aborted => 5,
+            ExitCode::Idle => 6,
+            ExitCode::Resumable => 7,
+            ExitCode::Interrupted => 130,
+        }
+    }
+
+    /// The exit code of a run that stopped on an error: an unreachable mail relay or a [`StateError`] aborts the
+    /// run, every other error comes from the configuration, the arguments or the input of the run.
+    pub(crate) fn of_error(error: &anyhow::Error) -> Self {
+        let relay_unreachable = error.chain().any(|cause| {
+            matches!(
+                cause.downcast_ref::<SendError>(),
+                Some(SendError::Auth(_) | SendError::Connection(_))
+            )
+        });
+
+        if relay_unreachable || error.downcast_ref::<StateError>().is_some() {
+            ExitCode::Aborted
+        } else {
+            ExitCode::InvalidConfig
+        }
+    }
+}
+
+impl From<ExitCode> for std::process::ExitCode {
+    fn from(exit_code: ExitCode) -> Self {
+        std::process::ExitCode::from(exit_code.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_exit_codes_help() {
+        let codes: Vec<u8> = EXIT_CODES_HELP
+            .lines()
+            .filter_map(|line| line.split_whitespace().next()?.parse().ok())
+            .collect();
+
+        assert_eq!(
+            codes,
+            enum_iterator::all::<ExitCode>()
+                .map(ExitCode::code)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_exit_code_of_error() {
+        let unreachable = Err::<(), _>(SendError::Connection("timed out".to_owned()))
+            .context("Unable to send the E-mail")
+            .unwrap_err();
+        assert_eq!(ExitCode::of_error(&unreachable), ExitCode::Aborted);
+
+        let refused = anyhow::Error::from(SendError::Auth("535".to_owned()));
+        assert_eq!(ExitCode::of_error(&refused), ExitCode::Aborted);
+
+        let rejected = anyhow::Error::from(SendError::Permanent("550".to_owned()));
+        assert_eq!(ExitCode::of_error(&rejected), ExitCode::InvalidConfig);
+
+        // Rather than the invalid contents of a state file, e.g. a sent log that isn't UTF-8
+        let dir = tempfile::tempdir().unwrap();
+        let sent_log_path = dir.path().join(crate::sent_log::SENT_LOG_FILE);
+        std::fs::write(&sent_log_path, [0xff, 0xfe]).unwrap();
+        let unreadable = crate::sent_log::load_since::<crate::sent_log::SentRecord, _>(
+            &sent_log_path,
+            chrono::Utc::now(),
+        )
+        .context(StateError)
+        .context("Unable to send the outbox")
+        .unwrap_err();
+        assert_eq!(ExitCode::of_error(&unreadable), ExitCode::Aborted);
+
+        let invalid = anyhow::anyhow!("`--breaker-max-failure-percent` of 120 is not a percentage");
+        assert_eq!(ExitCode::of_error(&invalid), ExitCode::InvalidConfig);
+    }
+}
@@ -0,0 +1,221 @@
+use serde::Deserialize;
+
+use crate::entries::ComposedEmail;
+
+/// The prefix of the subject of a degraded E-mail.
+pub(crate) const DEGRADED_SUBJECT_PREFIX: &str = "[DEGRADED] ";
+
+/// The bytes of the pretty-printed context kept in a degraded E-mail.
+const CONTEXT_LIMIT: usize = 16 * 1024;
+
+/// The bytes of the render error kept in a degraded E-mail.
+const ERROR_LIMIT: usize = 2 * 1024;
+
+/// What happens to an E-mail whose template fails to render, set in `template.toml` as `on_render_failure`.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RenderFailurePolicy {
+    /// The E-mail fails, and its entries are kept for the next run
+    #[default]
+    Fail,
+
+    /// A plain-text E-mail of the context is sent instead, for critical alerts that must reach someone
+    FallbackText,
+}
+
+/// The text cut to `limit` bytes on a character boundary, noting how much was left out.
+fn truncate(text: &str, limit: usize) -> String {
+    if text.len() <= limit {
+        return text.to_owned();
+    }
+
+    let end = (0..=limit)
+        .rev()
+        .find(|i| text.is_char_boundary(*i))
+        .unwrap_or_default();
+
+    format!("{}\n... ({} more bytes)", &text[..end], text.len() - end)
+}
+
+/// The plain-text body of a degraded E-mail: its subject, the render error and the pretty-printed context.
+pub(crate) fn fallback_text(email: &ComposedEmail, error: &str) -> String {
+    let context =
+        serde_json::to_string_pretty(&email.context).expect("A context is always valid JSON");
+
+    format!(
+        "The template `{}` failed to render, the data of this E-mail is sent as plain text instead.\n\n\
+        Subject: {}\n\n\
+        Render error:\n{}\n\n\
+        Context:\n{}\n",
+        email.header.template,
+        email.header.subject,
+        truncate(error, ERROR_LIMIT),
+        truncate(&context, CONTEXT_LIMIT),
+    )
+}
+
+/// Degrades an E-mail whose template failed to render into a plain-text E-mail of its context, under the
+/// `fallback_text` policy: the subject is prefixed with `[DEGRADED]`, and the attached files are left out.
+/// Returns whether the E-mail was degraded, the E-mail is left untouched under the `fail` policy.
+pub(crate) fn degrade(
+    email: &mut ComposedEmail,
+    error: &anyhow::Error,
+    policy: RenderFailurePolicy,
+) -> bool {
+    if policy != RenderFailurePolicy::FallbackText {
+        return false;
+    }
+
+    let text = fallback_text(email, &format!("{error:#}"));
+
+    let header = &mut email.header;
+    header.subject = format!("{DEGRADED_SUBJECT_PREFIX}{}", header.subject);
+    header.alternative_content = text;
+    header.text_body = None;
+    header.attachments.clear();
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive;
+    use crate::entries::Email;
+    use crate::render::{self, ContextData, DetectionMethod, TemplateData, TemplateExtension};
+    use crate::send::{FileTransport, MessageBuilder, Transport};
+    use std::fs;
+    use std::rc::Rc;
+
+    const BROKEN_TEMPLATE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/breaker/broken_template.html"
+    );
+
+    fn failed_render(email: &ComposedEmail) -> anyhow::Error {
+        let template_data = TemplateData {
+            contents: Rc::new(fs::read_to_string(BROKEN_TEMPLATE).unwrap()),
+            file_path: None,
+            extensions: None,
+        };
+        let context_data = ContextData {
+            context: serde_json::Value::Object(email.context.clone()),
+            file_path: None,
+            trusted: false,
+            local_time: Default::default(),
+        };
+
+        let Err(e) = render::render(
+            &template_data,
+            &context_data,
+            DetectionMethod::Auto,
+            TemplateExtension::Auto,
+        ) else {
+            panic!("The broken template rendered");
+        };
+        e
+    }
+
+    fn email() -> ComposedEmail {
+        let mut email = ComposedEmail {
+            id: 1,
+            header: Email {
+                from: "osa@x.com".to_owned(),
+                to: vec!["oncall@x.com".to_owned()],
+                subject: "Backup failed".to_owned(),
+                template: "backup_alert".to_owned(),
+                alternative_content: "Backup failed".to_owned(),
+                attachments: vec!["backup.log".to_owned()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        email
+            .context
+            .insert("job".to_owned(), serde_json::json!("nightly"));
+        email
+            .context
+            .insert("status".to_owned(), serde_json::json!("failed"));
+        email
+    }
+
+    #[test]
+    fn test_degraded_message() {
+        let mut email = email();
+        let error = failed_render(&email);
+
+        assert!(degrade(
+            &mut email,
+            &error,
+            RenderFailurePolicy::FallbackText
+        ));
+        assert_eq!(email.header.subject, "[DEGRADED] Backup failed");
+        assert!(email.header.attachments.is_empty());
+
+        let text = &email.header.alternative_content;
+        assert!(text.contains("`backup_alert` failed to render"), "{text}");
+        assert!(text.contains("Subject: Backup failed\n"), "{text}");
+        assert!(text.contains(&format!("{error:#}")), "{text}");
+        assert!(text.contains("\"job\": \"nightly\""), "{text}");
+
+        // Built as main does for a degraded E-mail, without HTML content
+        let message = MessageBuilder::new()
+            .from(&email.header.from)
+            .to_addresses(&email.header.to.join(", "))
+            .subject(&email.header.subject)
+            .alternative_content(&email.header.alternative_content)
+            .attachments(&email.header.attachments.join(", "))
+            .build()
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut transport = FileTransport::new(dir.path().join("sent"));
+        transport.establish(None).unwrap();
+        transport.send(message.try_into().unwrap()).unwrap();
+
+        let sent: Vec<_> = fs::read_dir(dir.path().join("sent"))
+            .unwrap()
+            .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect();
+        assert_eq!(sent.len(), 1);
+        assert!(
+            sent[0].contains("Subject: [DEGRADED] Backup failed"),
+            "{}",
+            sent[0]
+        );
+        assert!(sent[0].contains("Content-Type: text/plain"), "{}", sent[0]);
+        assert!(!sent[0].contains("text/html"), "{}", sent[0]);
+
+        // Archived as any sent E-mail
+        archive::store(&dir.path().join("archive"), &email, "").unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("archive").join("00000001.txt")).unwrap(),
+            email.header.alternative_content
+        );
+    }
+
+    #[test]
+    fn test_fail_policy_is_unchanged() {
+        let mut email = email();
+        let error = failed_render(&email);
+
+        assert_eq!(RenderFailurePolicy::default(), RenderFailurePolicy::Fail);
+        assert!(!degrade(&mut email, &error, RenderFailurePolicy::Fail));
+        assert_eq!(email.header.subject, "Backup failed");
+        assert_eq!(email.header.alternative_content, "Backup failed");
+        assert_eq!(email.header.attachments, vec!["backup.log"]);
+    }
+
+    #[test]
+    fn test_context_is_capped() {
+        let mut email = email();
+        email.context.insert(
+            "log".to_owned(),
+            serde_json::json!("é".repeat(CONTEXT_LIMIT)),
+        );
+
+        let text = fallback_text(&email, "error");
+        assert!(text.len() < CONTEXT_LIMIT + 1024);
+        assert!(text.contains("more bytes)"), "{text}");
+    }
+}
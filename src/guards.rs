@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::entries::ComposedEmail;
+use crate::send;
+use crate::sent_log::SentRecord;
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum GuardError {
+    #[error("Unknown anomaly guard policy \"{0}\"")]
+    UnknownPolicy(String),
+}
+
+/// What happens to an E-mail that trips an anomaly guard.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum GuardPolicy {
+    /// The E-mail is not sent. Its entries are moved to the dead-letter directory when it can never pass the
+    /// guard, otherwise they are held in the outbox for a later run, without counting a send attempt
+    #[default]
+    Block,
+
+    /// The E-mail is sent, with a warning
+    Warn,
+}
+
+impl fmt::Display for GuardPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuardPolicy::Block => write!(f, "block"),
+            GuardPolicy::Warn => write!(f, "warn"),
+        }
+    }
+}
+
+impl FromStr for GuardPolicy {
+    type Err = GuardError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let res = match s.trim().to_lowercase().as_str() {
+            "block" => GuardPolicy::Block,
+            "warn" => GuardPolicy::Warn,
+            _ => return Err(GuardError::UnknownPolicy(s.to_string())),
+        };
+
+        Ok(res)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct GuardLimit {
+    pub(crate) max: usize,
+    pub(crate) policy: GuardPolicy,
+}
+
+/// The configured anomaly guards. A guard without a limit is disabled.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GuardLimits {
+    /// Maximum recipients (to, cc and bcc) of a single E-mail
+    pub(crate) max_recipients: Option<GuardLimit>,
+
+    /// Maximum E-mails sent per run for the same template
+    pub(crate) max_emails_per_template: Option<GuardLimit>,
+
+    /// Maximum sends of identical E-mail content within an hour
+    pub(crate) max_identical_per_hour: Option<GuardLimit>,
+}
+
+/// An anomaly detected in an E-mail about to be sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Anomaly {
+    TooManyRecipients { count: usize, max: usize },
+    TemplateRunLimit { template: String, max: usize },
+    RepeatedContent { content_hash: String, max: usize },
+}
+
+impl Anomaly {
+    /// Whether the E-mail can never pass this guard, as opposed to a limit that resets with time.
+    #[inline]
+    pub(crate) fn is_permanent(&self) -> bool {
+        matches!(self, Anomaly::TooManyRecipients { .. })
+    }
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anomaly::TooManyRecipients { count, max } => write!(
+                f,
+                "Anomaly `too-many-recipients`: {count} recipients exceed the maximum of {max}"
+            ),
+            Anomaly::TemplateRunLimit { template, max } => write!(
+                f,
+                "Anomaly `template-run-limit`: the template `{template}` already sent the maximum of {max} E-mails in this run"
+            ),
+            Anomaly::RepeatedContent { content_hash, max } => write!(
+                f,
+                "Anomaly `repeated-content`: identical content `{content_hash}` was already sent the maximum of {max} times within the last hour"
+            ),
+        }
+    }
+}
+
+/// An anomaly found by a guard, with the policy of that guard.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Triggered {
+    pub(crate) anomaly: Anomaly,
+    pub(crate) policy: GuardPolicy,
+}
+
+/// Evaluates the anomaly guards before each E-mail is sent, counting the sends of the current run.
+#[derive(Debug, Default)]
+pub(crate) struct AnomalyGuard {
+    limits: GuardLimits,
+    template_sends: HashMap<String, usize>,
+    content_sends: HashMap<String, usize>,
+}
+
+impl AnomalyGuard {
+    /// Creates the guard, counting the identical content sends of `recent` (the sent-log of the last hour).
+    pub(crate) fn new(limits: GuardLimits, recent: &[SentRecord]) -> Self {
+        let mut content_sends: HashMap<String, usize> = HashMap::new();
+
+        for record in recent {
+            *content_sends
+                .entry(record.content_hash.clone())
+                .or_default() += 1;
+        }
+
+        Self {
+            limits,
+            template_sends: HashMap::new(),
+            content_sends,
+        }
+    }
+
+    /// Returns the guards tripped by the given E-mail.
+    pub(crate) fn check(&self, email: &ComposedEmail) -> Vec<Triggered> {
+        let mut triggered = Vec::new();
+
+        if let Some(limit) = self.limits.max_recipients {
+            let header = &email.header;
+            let count = header
+                .to
+                .iter()
+                .chain(&header.cc)
+                .chain(&header.bcc)
+                .map(|addresses| send::split(addresses).count())
+                .sum();
+
+            if count > limit.max {
+                triggered.push(Triggered {
+                    anomaly: Anomaly::TooManyRecipients {
+                        count,
+                        max: limit.max,
+                    },
+                    policy: limit.policy,
+                });
+            }
+        }
+
+        if let Some(limit) = self.limits.max_emails_per_template {
+            let template = &email.header.template;
+
+            if self.template_sends.get(template).copied().unwrap_or(0) >= limit.max {
+                triggered.push(Triggered {
+                    anomaly: Anomaly::TemplateRunLimit {
+                        template: template.clone(),
+                        max: limit.max,
+                    },
+                    policy: limit.policy,
+                });
+            }
+        }
+
+        if let Some(limit) = self.limits.max_identical_per_hour {
+            let content_hash = email.content_hash();
+
+            if self.content_sends.get(&content_hash).copied().unwrap_or(0) >= limit.max {
+                triggered.push(Triggered {
+                    anomaly: Anomaly::RepeatedContent {
+                        content_hash,
+                        max: limit.max,
+                    },
+                    policy: limit.policy,
+                });
+            }
+        }
+
+        triggered
+    }
+
+    /// Counts an E-mail that was sent.
+    pub(crate) fn record_sent(&mut self, email: &ComposedEmail) {
+        *self
+            .template_sends
+            .entry(email.header.template.clone())
+            .or_default() += 1;
+        *self.content_sends.entry(email.content_hash()).or_default() += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entries::Email;
+    use chrono::Utc;
+
+    fn email(to: &[&str], template: &str) -> ComposedEmail {
+        ComposedEmail {
+            id: 1,
+            header: Email {
+                to: to.iter().map(|&a| a.to_owned()).collect(),
+                template: template.to_owned(),
+                ..Default::default()
+            },
+            context: Default::default(),
+            page: None,
+            single_entry: None,
+        }
+    }
+
+    fn limit(max: usize, policy: GuardPolicy) -> Option<GuardLimit> {
+        Some(GuardLimit { max, policy })
+    }
+
+    #[test]
+    fn test_max_recipients_guard() {
+        let guard = AnomalyGuard::new(
+            GuardLimits {
+                max_recipients: limit(2, GuardPolicy::Block),
+                ..Default::default()
+            },
+            &[],
+        );
+
+        assert!(guard.check(&email(&["a@x.com, b@x.com"], "t")).is_empty());
+
+        let triggered = guard.check(&email(&["a@x.com, b@x.com", "c@x.com"], "t"));
+        assert_eq!(
+            triggered,
+            vec![Triggered {
+                anomaly: Anomaly::TooManyRecipients { count: 3, max: 2 },
+                policy: GuardPolicy::Block,
+            }]
+        );
+        assert!(triggered[0].anomaly.is_permanent());
+    }
+
+    #[test]
+    fn test_max_emails_per_template_guard() {
+        let mut guard = AnomalyGuard::new(
+            GuardLimits {
+                max_emails_per_template: limit(2, GuardPolicy::Warn),
+                ..Default::default()
+            },
+            &[],
+        );
+
+        for to in ["a@x.com", "b@x.com"] {
+            let email = email(&[to], "alerts");
+            assert!(guard.check(&email).is_empty());
+            guard.record_sent(&email);
+        }
+
+        let triggered = guard.check(&email(&["c@x.com"], "alerts"));
+        assert_eq!(triggered.len(), 1);
+        assert_eq!(triggered[0].policy, GuardPolicy::Warn);
+        assert!(matches!(
+            triggered[0].anomaly,
+            Anomaly::TemplateRunLimit { max: 2, .. }
+        ));
+        assert!(!triggered[0].anomaly.is_permanent());
+
+        // Other templates have their own count
+        assert!(guard.check(&email(&["c@x.com"], "reports")).is_empty());
+    }
+
+    #[test]
+    fn test_max_identical_per_hour_guard() {
+        let repeated = email(&["a@x.com"], "alerts");
+        let recent = vec![SentRecord {
+            sent_at: Utc::now(),
+            email_id: repeated.id,
+            template: "alerts".to_owned(),
+            content_hash: repeated.content_hash(),
+            reply_token: None,
+        }];
+
+        let mut guard = AnomalyGuard::new(
+            GuardLimits {
+                max_identical_per_hour: limit(2, GuardPolicy::Block),
+                ..Default::default()
+            },
+            &recent,
+        );
+
+        // Sent once within the last hour already
+        assert!(guard.check(&repeated).is_empty());
+        guard.record_sent(&repeated);
+
+        let triggered = guard.check(&repeated);
+        assert_eq!(triggered.len(), 1);
+        assert_eq!(triggered[0].policy, GuardPolicy::Block);
+        assert!(matches!(
+            triggered[0].anomaly,
+            Anomaly::RepeatedContent { max: 2, .. }
+        ));
+
+        // Different content is not affected
+        assert!(guard.check(&email(&["b@x.com"], "alerts")).is_empty());
+    }
+
+    #[test]
+    fn test_guard_policy_from_str() {
+        assert_eq!("Warn".parse::<GuardPolicy>().unwrap(), GuardPolicy::Warn);
+        assert_eq!("block".parse::<GuardPolicy>().unwrap(), GuardPolicy::Block);
+        assert!("ignore".parse::<GuardPolicy>().is_err());
+    }
+}
@@ -0,0 +1,420 @@
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use crate::entries::{self, EmailEntries, Entry, ParsedEntry};
+
+/// The fields of an entry `email`, any of which can be part of the identity of its E-mail.
+pub(crate) const EMAIL_FIELDS: &[&str] = &[
+    "system",
+    "subsystem",
+    "from",
+    "sender",
+    "to",
+    "cc",
+    "bcc",
+    "reply_to",
+    "subject",
+    "template",
+    "alternative_content",
+    "attachments",
+    "bundle_attachments",
+    "unique_by",
+    "headers",
+    "charset",
+    "alternative",
+    "reply_token",
+    "display_timezone",
+    "html_body",
+    "text_body",
+    "resources_dir",
+];
+
+/// The field always part of the identity, so the entries of an E-mail share their template settings.
+const TEMPLATE_FIELD: &str = "template";
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub(crate) enum IdentityError {
+    #[error("Unknown identity field `{0}`, expected any of: {}", EMAIL_FIELDS.join(", "))]
+    UnknownField(String),
+
+    #[error("Unknown identity conflict policy \"{0}\"")]
+    UnknownConflictPolicy(String),
+}
+
+/// What happens to an E-mail whose entries differ by fields outside its identity, set as `identity_conflict`.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum IdentityConflict {
+    /// The fields of the first entry are sent, and the others reported as a warning
+    #[default]
+    FirstWins,
+
+    /// The E-mail fails, and its entries are moved to the dead-letter directory
+    Error,
+}
+
+impl fmt::Display for IdentityConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdentityConflict::FirstWins => write!(f, "first_wins"),
+            IdentityConflict::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl FromStr for IdentityConflict {
+    type Err = IdentityError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let res = match s.trim().to_lowercase().replace('-', "_").as_str() {
+            "first_wins" => IdentityConflict::FirstWins,
+            "error" => IdentityConflict::Error,
+            _ => return Err(IdentityError::UnknownConflictPolicy(s.to_string())),
+        };
+
+        Ok(res)
+    }
+}
+
+/// The fields of the entry `email` an E-mail ID is computed over, e.g. `["from", "to", "subject", "template"]`.
+/// `template` is always one of them.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "Vec<String>")]
+pub(crate) struct IdentityFields(BTreeSet<String>);
+
+impl IdentityFields {
+    #[inline]
+    pub(crate) fn contains(&self, field: &str) -> bool {
+        self.0.contains(field)
+    }
+}
+
+impl TryFrom<Vec<String>> for IdentityFields {
+    type Error = IdentityError;
+
+    fn try_from(fields: Vec<String>) -> Result<Self, Self::Error> {
+        let mut set = BTreeSet::from([TEMPLATE_FIELD.to_owned()]);
+
+        for field in fields {
+            if !EMAIL_FIELDS.contains(&field.as_str()) {
+                return Err(IdentityError::UnknownField(field));
+            }
+            set.insert(field);
+        }
+
+        Ok(Self(set))
+    }
+}
+
+impl FromStr for IdentityFields {
+    type Err = IdentityError;
+
+    /// Parses a list of fields separated by commas.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(str::to_owned)
+            .collect::<Vec<_>>()
+            .try_into()
+    }
+}
+
+impl fmt::Display for IdentityFields {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields: Vec<_> = self.0.iter().map(String::as_str).collect();
+        write!(f, "{}", fields.join(", "))
+    }
+}
+
+/// Which entries are sent as a single E-mail. Set in `template.toml` as `identity_fields` and `identity_conflict`,
+/// which take precedence over `--identity-fields` and `--identity-conflict`.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub(crate) struct IdentityConfig {
+    /// The fields the E-mail ID is computed over, the whole `email` when not set
+    #[serde(rename = "identity_fields")]
+    pub(crate) fields: Option<IdentityFields>,
+
+    /// What happens when the entries of an E-mail differ by other fields, `first_wins` when not set
+    #[serde(rename = "identity_conflict")]
+    pub(crate) conflict: Option<IdentityConflict>,
+}
+
+impl IdentityConfig {
+    /// Fills the settings missing here from `fallback`.
+    pub(crate) fn or(&self, fallback: &IdentityConfig) -> IdentityConfig {
+        IdentityConfig {
+            fields: self.fields.clone().or_else(|| fallback.fields.clone()),
+            conflict: self.conflict.or(fallback.conflict),
+        }
+    }
+
+    /// The ID of the E-mail the entry contributes to. Over the whole `email`, as [`Entry::email_id`], unless
+    /// `fields` are set, then over the canonical JSON of only these fields.
+    pub(crate) fn email_id(&self, entry: &Entry) -> u32 {
+        let Some(fields) = &self.fields else {
+            return entry.email_id();
+        };
+
+        let mut email = serde_json::to_value(entry.email())
+            .expect("Deserialized from JSON but cannot be converted to JSON?");
+        if let Some(email) = email.as_object_mut() {
+            email.retain(|field, _| fields.contains(field));
+        }
+
+        entries::crc32_iso_hdlc_checksum(entries::canonicalize(email).to_string().as_bytes())
+    }
+
+    /// The fields the entries of an E-mail differ by, in the order of the `email` fields. Outside `fields`, as
+    /// the entries share the others. None unless the entries are batched, each entry is otherwise an E-mail of its own.
+    pub(crate) fn conflicts(&self, entries: &[Rc<ParsedEntry>]) -> Vec<&'static str> {
+        if !entries.iter().any(|entry| entry.entry.accumulates()) {
+            return Vec::new();
+        }
+
+        let emails: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                serde_json::to_value(entry.entry.email())
+                    .expect("Deserialized from JSON but cannot be converted to JSON?")
+            })
+            .collect();
+        let Some((first, others)) = emails.split_first() else {
+            return Vec::new();
+        };
+
+        EMAIL_FIELDS
+            .iter()
+            .copied()
+            .filter(|field| {
+                others
+                    .iter()
+                    .any(|email| email.get(field) != first.get(field))
+            })
+            .collect()
+    }
+}
+
+/// The identity settings of a run: `--identity-fields` and `--identity-conflict`, overridden per template.
+#[derive(Debug, Default)]
+pub(crate) struct Identity {
+    default: IdentityConfig,
+    templates: HashMap<String, IdentityConfig>,
+}
+
+impl Identity {
+    pub(crate) fn new(default: IdentityConfig) -> Self {
+        Self {
+            default,
+            templates: HashMap::new(),
+        }
+    }
+
+    /// Overrides the settings of the E-mails of a template with its `template.toml` ones.
+    pub(crate) fn insert_template(&mut self, template: &str, config: &IdentityConfig) {
+        if *config != IdentityConfig::default() {
+            self.templates
+                .insert(template.to_owned(), config.or(&self.default));
+        }
+    }
+
+    /// The settings of the E-mails of a template.
+    pub(crate) fn config(&self, template: &str) -> &IdentityConfig {
+        self.templates.get(template).unwrap_or(&self.default)
+    }
+
+    #[inline]
+    pub(crate) fn email_id(&self, entry: &Entry) -> u32 {
+        self.config(entry.template()).email_id(entry)
+    }
+
+    /// The E-mails whose entries differ by fields outside their identity, ordered by E-mail ID, with these fields.
+    pub(crate) fn conflicts(&self, email_entries: &EmailEntries) -> Vec<(u32, Vec<&'static str>)> {
+        let mut conflicts: Vec<_> = email_entries
+            .iter()
+            .filter_map(|(&email_id, entries)| {
+                let template = entries.first()?.entry.template();
+                let fields = self.config(template).conflicts(entries);
+                (!fields.is_empty()).then_some((email_id, fields))
+            })
+            .collect();
+        conflicts.sort_by_key(|(email_id, _)| *email_id);
+        conflicts
+    }
+}
+
+impl fmt::Display for Identity {
+    /// The effective fields, e.g. `whole E-mail (first_wins), digest: from, template, to (error)`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let describe = |config: &IdentityConfig| {
+            let fields = match &config.fields {
+                Some(fields) => fields.to_string(),
+                None => "whole E-mail".to_owned(),
+            };
+            format!("{fields} ({})", config.conflict.unwrap_or_default())
+        };
+
+        let mut templates: Vec<_> = self.templates.iter().collect();
+        templates.sort_by_key(|(template, _)| template.as_str());
+
+        write!(f, "{}", describe(&self.default))?;
+        for (template, config) in templates {
+            write!(f, "; {template}: {}", describe(config))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entries::{map_emails_with, Email};
+
+    fn entry(id: &str, attachments: &[&str], subject: &str) -> Rc<ParsedEntry> {
+        let entry: Entry = serde_json::from_value(serde_json::json!({
+            "id": id,
+            "utc": format!("2024-01-01T00:00:0{id}Z"),
+            "notify_error": [],
+            "email": {
+                "system": "backup",
+                "subsystem": "scheduler",
+                "from": "osa@example.com",
+                "to": ["ops@example.com"],
+                "cc": [],
+                "bcc": [],
+                "reply_to": [],
+                "subject": subject,
+                "template": "digest",
+                "alternative_content": "",
+                "attachments": attachments,
+                "unique_by": ""
+            },
+            "context": { "+rows": id }
+        }))
+        .unwrap();
+
+        Rc::new(ParsedEntry {
+            id: id.to_owned(),
+            path: None,
+            entry,
+        })
+    }
+
+    fn reduced(conflict: IdentityConflict) -> IdentityConfig {
+        IdentityConfig {
+            fields: Some("from, to, cc, bcc, subject, unique_by".parse().unwrap()),
+            conflict: Some(conflict),
+        }
+    }
+
+    #[test]
+    fn test_email_fields() {
+        let email = serde_json::to_value(Email::default()).unwrap();
+
+        for field in email.as_object().unwrap().keys() {
+            assert!(EMAIL_FIELDS.contains(&field.as_str()), "{field}");
+        }
+    }
+
+    #[test]
+    fn test_identity_fields() {
+        let fields: IdentityFields = "subject, to".parse().unwrap();
+        assert_eq!(fields.to_string(), "subject, template, to");
+
+        assert_eq!(
+            "subject, custom_key".parse::<IdentityFields>(),
+            Err(IdentityError::UnknownField("custom_key".to_owned()))
+        );
+
+        let config: IdentityConfig =
+            toml::from_str("identity_fields = [\"to\"]\nidentity_conflict = \"error\"").unwrap();
+        assert_eq!(config.conflict, Some(IdentityConflict::Error));
+        assert!(toml::from_str::<IdentityConfig>("identity_fields = [\"nope\"]").is_err());
+    }
+
+    #[test]
+    fn test_batching_by_identity() {
+        let pool = vec![
+            entry("1", &["a.pdf"], "Digest"),
+            entry("2", &["b.pdf"], "Digest"),
+        ];
+
+        // The whole E-mail tells the entries apart by their attachments
+        let identity = Identity::default();
+        let emails_map = map_emails_with(&pool, |entry| identity.email_id(&entry.entry));
+        assert_eq!(emails_map.len(), 2);
+        assert!(identity.conflicts(&emails_map).is_empty());
+
+        let identity = Identity::new(reduced(IdentityConflict::FirstWins));
+        let emails_map = map_emails_with(&pool, |entry| identity.email_id(&entry.entry));
+        assert_eq!(emails_map.len(), 1);
+
+        let composed = entries::compose_emails(&emails_map).ok;
+        assert_eq!(composed.len(), 1);
+        assert_eq!(composed[0].header.attachments, ["a.pdf"]);
+        assert_eq!(composed[0].context["rows"].as_array().unwrap().len(), 2);
+
+        let email_id = *emails_map.keys().next().unwrap();
+        assert_eq!(
+            identity.conflicts(&emails_map),
+            [(email_id, vec!["attachments"])]
+        );
+
+        // The identity fields still tell the entries apart
+        let pool = vec![entry("1", &[], "Digest"), entry("2", &[], "Other")];
+        let emails_map = map_emails_with(&pool, |entry| identity.email_id(&entry.entry));
+        assert_eq!(emails_map.len(), 2);
+    }
+
+    #[test]
+    fn test_template_identity() {
+        let pool = vec![
+            entry("1", &["a.pdf"], "Digest"),
+            entry("2", &["b.pdf"], "Digest"),
+        ];
+
+        let mut identity = Identity::new(reduced(IdentityConflict::FirstWins));
+        identity.insert_template(
+            "digest",
+            &IdentityConfig {
+                fields: None,
+                conflict: Some(IdentityConflict::Error),
+            },
+        );
+        assert_eq!(
+            identity.config("digest").conflict,
+            Some(IdentityConflict::Error)
+        );
+        assert_eq!(
+            identity.config("other"),
+            &reduced(IdentityConflict::FirstWins)
+        );
+
+        let emails_map = map_emails_with(&pool, |entry| identity.email_id(&entry.entry));
+        assert_eq!(emails_map.len(), 1);
+        assert_eq!(identity.conflicts(&emails_map).len(), 1);
+        assert_eq!(
+            identity.to_string(),
+            "bcc, cc, from, subject, template, to, unique_by (first_wins); \
+            digest: bcc, cc, from, subject, template, to, unique_by (error)"
+        );
+    }
+
+    #[test]
+    fn test_conflict_policy() {
+        assert_eq!(
+            "first-wins".parse::<IdentityConflict>().unwrap(),
+            IdentityConflict::FirstWins
+        );
+        assert_eq!(
+            "error".parse::<IdentityConflict>().unwrap(),
+            IdentityConflict::Error
+        );
+        assert!("last_wins".parse::<IdentityConflict>().is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use anyhow::{Context, Result};
+use lol_html::html_content::{Comment, Element, TextChunk};
+use lol_html::{doc_comments, element, text, RewriteStrSettings};
+use std::cell::RefCell;
+use std::ops::Range;
+
+/// The attributes holding a single image reference, e.g. `<td background="bg.png">`.
+const REFERENCE_ATTRIBUTES: [&str; 2] = ["src", "background"];
+
+/// Finds the image references of the HTML contents with an HTML tokenizer, as byte ranges of the contents in
+/// the order of the document: the `src`, `background` and `srcset` attributes of any element, the CSS `url()`
+/// of `style` attributes and `<style>` blocks, and the same within conditional comments, e.g. `<!--[if mso]>`.
+/// Other comments are skipped. The references are unquoted and trimmed, and may still be URLs, e.g. `https://`.
+/// ## Error
+/// Fails if the contents can't be tokenized.
+pub(crate) fn reference_ranges(html_contents: &str) -> Result<Vec<Range<usize>>> {
+    let ranges = RefCell::new(Vec::new());
+    // The text of a `<style>` block may come in several chunks
+    let style_start = RefCell::new(None);
+
+    let on_element = |element: &mut Element| {
+        let mut ranges = ranges.borrow_mut();
+        for attribute in element.attributes() {
+            let Some(location) = attribute.value_source_location() else {
+                continue;
+            };
+            let range = location.bytes();
+            let value = &html_contents[range.clone()];
+
+            match attribute.name().as_str() {
+                name if REFERENCE_ATTRIBUTES.contains(&name) => {
+                    ranges.extend(trimmed(value, range.start));
+                }
+                "srcset" => ranges.extend(srcset_urls(value, range.start)),
+                "style" => ranges.extend(css_urls(value, range.start)),
+                _ => {}
+            }
+        }
+        Ok(())
+    };
+
+    let on_style = |chunk: &mut TextChunk| {
+        let range = chunk.source_location().bytes();
+        let start = *style_start.borrow_mut().get_or_insert(range.start);
+
+        if chunk.last_in_text_node() {
+            style_start.replace(None);
+            ranges
+                .borrow_mut()
+                .extend(css_urls(&html_contents[start..range.end], start));
+        }
+        Ok(())
+    };
+
+    let on_comment = |comment: &mut Comment| {
+        let text = comment.text();
+        if !text.trim_start().starts_with("[if") {
+            return Ok(());
+        }
+
+        let location = comment.source_location().bytes();
+        let Some(offset) = html_contents[location.clone()].find(&text) else {
+            return Ok(());
+        };
+        let start = location.start + offset;
+
+        let inner = reference_ranges(&html_contents[start..start + text.len()])?;
+        ranges.borrow_mut().extend(
+            inner
+                .into_iter()
+                .map(|range| range.start + start..range.end + start),
+        );
+        Ok(())
+    };
+
+    let settings = RewriteStrSettings::new()
+        .append_element_content_handler(element!("*", on_element))
+        .append_element_content_handler(text!("style", on_style))
+        .append_document_content_handler(doc_comments!(on_comment))
+        .with_strict(false);
+    lol_html::rewrite_str(html_contents, settings).context("Unable to parse the HTML contents")?;
+
+    let mut ranges = ranges.into_inner();
+    ranges.sort_by_key(|range| range.start);
+    Ok(ranges)
+}
+
+/// The quotes of a CSS string, as written in CSS or escaped within a `style` attribute.
+const CSS_QUOTES: [&str; 6] = ["\"", "'", "&quot;", "&#34;", "&#39;", "&apos;"];
+
+/// The range of a value without its surrounding whitespace, if any is left.
+fn trimmed(value: &str, offset: usize) -> Option<Range<usize>> {
+    let start = value.len() - value.trim_start().len();
+    let end = value.trim_end().len();
+
+    (start < end).then_some(offset + start..offset + end)
+}
+
+/// The URLs of a `srcset` attribute, e.g. `logo.png 1x, logo@2x.png 2x`, leaving out their descriptors.
+fn srcset_urls(value: &str, offset: usize) -> Vec<Range<usize>> {
+    let bytes = value.as_bytes();
+    let mut urls = Vec::new();
+    let mut position = 0;
+
+    while position < bytes.len() {
+        while position < bytes.len()
+            && (bytes[position].is_ascii_whitespace() || bytes[position] == b',')
+        {
+            position += 1;
+        }
+        let start = position;
+        while position < bytes.len() && !bytes[position].is_ascii_whitespace() {
+            position += 1;
+        }
+
+        // A URL ending with a comma has no descriptor, while a comma within it is part of it
+        let url = value[start..position].trim_end_matches(',');
+        if !url.is_empty() {
+            urls.push(offset + start..offset + start + url.len());
+        }
+        if url.len() == position - start {
+            while position < bytes.len() && bytes[position] != b',' {
+                position += 1;
+            }
+        }
+    }
+
+    urls
+}
+
+/// The `url()` references of CSS, quoted or not and with whitespace within the parentheses, skipping comments.
+fn css_urls(css: &str, offset: usize) -> Vec<Range<usize>> {
+    let bytes = css.as_bytes();
+    let mut urls = Vec::new();
+    let mut position = 0;
+
+    while position < bytes.len() {
+        if bytes[position..].starts_with(b"/*") {
+            position = css[position + 2..]
+                .find("*/")
+                .map_or(bytes.len(), |end| position + 2 + end + 2);
+            continue;
+        }
+
+        // `url(` as a whole function name, not the end of another, e.g. `myurl(`
+        let is_url = bytes.len() - position >= 4
+            && bytes[position..position + 4].eq_ignore_ascii_case(b"url(")
+            && (position == 0
+                || !(bytes[position - 1].is_ascii_alphanumeric()
+                    || matches!(bytes[position - 1], b'-' | b'_')));
+        if !is_url {
+            position += 1;
+            continue;
+        }
+
+        position += 4;
+        while position < bytes.len() && bytes[position].is_ascii_whitespace() {
+            position += 1;
+        }
+
+        let quote = CSS_QUOTES
+            .iter()
+            .find(|quote| bytes[position..].starts_with(quote.as_bytes()));
+        let (start, end) = match quote {
+            Some(quote) => {
+                let start = position + quote.len();
+                let end = css[start..]
+                    .find(quote)
+                    .map_or(bytes.len(), |end| start + end);
+                (start, end)
+            }
+            None => {
+                let start = position;
+                let end = css[start..]
+                    .find(|c: char| c == ')' || c.is_ascii_whitespace())
+                    .map_or(bytes.len(), |end| start + end);
+                (start, end)
+            }
+        };
+        if start < end {
+            urls.push(offset + start..offset + end);
+        }
+        position = end;
+    }
+
+    urls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn references(html: &str) -> Vec<&str> {
+        reference_ranges(html)
+            .unwrap()
+            .into_iter()
+            .map(|range| &html[range])
+            .collect()
+    }
+
+    #[test]
+    fn test_simple_references() {
+        let html =
+            r#"<p><img src="logo.png"></p><td background='bg.png' width=10><img src=footer.png>"#;
+        assert_eq!(references(html), ["logo.png", "bg.png", "footer.png"]);
+    }
+
+    #[test]
+    fn test_attributes_in_any_order() {
+        let html = r#"<img alt="a > b" width=10 src='logo.png' title="x src=no.png"><img
+            class="wide"
+            src="banner.png"/>"#;
+        assert_eq!(references(html), ["logo.png", "banner.png"]);
+    }
+
+    #[test]
+    fn test_srcset() {
+        let html = r#"<picture><source srcset="hero.webp 1x, hero@2x.webp 2x" type="image/webp">
+<img src="hero.png" srcset="hero.png, hero@2x.png 2x , hero@3x.png 3x,hero@4x.png 4x"></picture>"#;
+        assert_eq!(
+            references(html),
+            [
+                "hero.webp",
+                "hero@2x.webp",
+                "hero.png",
+                "hero.png",
+                "hero@2x.png",
+                "hero@3x.png",
+                "hero@4x.png"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_css_urls() {
+        let html = r#"<head><style>
+.a { background: url( 'top.png' ) no-repeat, url(bottom.png); }
+/* .old { background: url(old.png); } */
+.b { background-image: URL("my logo.png"); mask: myurl(no.png); }
+</style></head>
+<div style="background: url(&quot;x&quot;), url( 'bg.png' )">text url(no.png)</div>"#;
+        assert_eq!(
+            references(html),
+            ["top.png", "bottom.png", "my logo.png", "x", "bg.png"]
+        );
+    }
+
+    #[test]
+    fn test_conditional_comments() {
+        let html = r#"<!--[if mso]><v:rect><v:fill type="tile" src="bg.png" /></v:rect><![endif]-->
+<!-- <img src="commented.png"> -->
+<!--[if !mso]><!--><img src="logo.png"><!--<![endif]-->
+<div><!--[if gte mso 9]><img src='outlook.png'><![endif]--></div>"#;
+
+        let ranges = reference_ranges(html).unwrap();
+        let found: Vec<_> = ranges.iter().map(|range| &html[range.clone()]).collect();
+        assert_eq!(found, ["bg.png", "logo.png", "outlook.png"]);
+    }
+
+    #[test]
+    fn test_non_ascii() {
+        let html =
+            r#"<p style="font-family: 'Bücher'; background: url(bg.png)">é</p><img src="été.png">"#;
+        assert_eq!(references(html), ["bg.png", "été.png"]);
+    }
+
+    #[test]
+    fn test_malformed_html() {
+        assert_eq!(references(r#"<img src="logo.png"#), Vec::<&str>::new());
+        assert_eq!(
+            references(r#"<img src="">< img src="no.png">"#),
+            Vec::<&str>::new()
+        );
+        assert_eq!(
+            references("<select><style>a{}</style><img src=a.png>"),
+            ["a.png"]
+        );
+    }
+}
@@ -0,0 +1,527 @@
+use anyhow::{anyhow, Context, Result};
+use mailparse::{MailHeaderMap, ParsedMail};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::entries::{self, Entry, ENTRY_EXT};
+use crate::send::Authentication;
+use crate::tls;
+
+/// How long the mailbox may stay silent before the connection is considered dropped.
+const IMAP_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum ImapError {
+    #[error("Unable to communicate with the IMAP server: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("The IMAP server closed the connection")]
+    Closed,
+
+    #[error("The IMAP server refused `{command}`: {reply}")]
+    Refused { command: String, reply: String },
+
+    #[error("Unable to establish a TLS session with the IMAP server: {0}")]
+    Tls(String),
+}
+
+/// Where and how to poll the mailbox of ingested entries.
+#[derive(Debug)]
+pub(crate) struct ImapConfig {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) auth: Authentication,
+    pub(crate) username: String,
+    pub(crate) password: String,
+
+    /// The folder polled for new entries
+    pub(crate) folder: String,
+
+    /// Messages that were written to the outbox are moved here
+    pub(crate) processed_folder: String,
+
+    /// Messages that aren't valid entries are moved here
+    pub(crate) rejected_folder: String,
+}
+
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// The response to a single IMAP command.
+#[derive(Debug, Default)]
+struct Response {
+    /// The untagged lines, literals excluded
+    lines: Vec<String>,
+
+    /// The literals (`{size}` strings), in order
+    literals: Vec<Vec<u8>>,
+}
+
+/// A minimal IMAP4rev1 client, covering what is required to poll a single folder.
+pub(crate) struct ImapClient {
+    stream: BufReader<Box<dyn Stream>>,
+    tag: u32,
+}
+
+impl ImapClient {
+    /// Connects and logs in to the IMAP server, with the same `noauth`, `tls` and `starttls` methods as the mail relay.
+    pub(crate) fn connect(config: &ImapConfig) -> Result<Self, ImapError> {
+        let tcp = TcpStream::connect((config.host.as_str(), config.port))?;
+        tcp.set_read_timeout(Some(IMAP_TIMEOUT))?;
+        tcp.set_write_timeout(Some(IMAP_TIMEOUT))?;
+
+        let mut client = match config.auth {
+            Authentication::NoAuth => Self::new(Box::new(tcp))?,
+            Authentication::Tls => Self::new(Box::new(tls_stream(&config.host, tcp)?))?,
+            Authentication::Starttls => {
+                let mut plain = Self::new(Box::new(tcp.try_clone()?))?;
+                plain.command("STARTTLS")?;
+
+                // The server waits for the TLS handshake, so nothing is left in the plain buffer
+                Self {
+                    stream: BufReader::new(Box::new(tls_stream(&config.host, tcp)?)),
+                    tag: plain.tag,
+                }
+            }
+        };
+
+        client.login(&config.username, &config.password)?;
+
+        Ok(client)
+    }
+
+    /// Wraps an established stream, reading the server greeting.
+    fn new(stream: Box<dyn Stream>) -> Result<Self, ImapError> {
+        let mut client = Self {
+            stream: BufReader::new(stream),
+            tag: 0,
+        };
+
+        let greeting = client.read_line()?;
+        if !greeting.starts_with(b"* OK") && !greeting.starts_with(b"* PREAUTH") {
+            return Err(ImapError::Refused {
+                command: "connect".to_owned(),
+                reply: String::from_utf8_lossy(&greeting).trim_end().to_owned(),
+            });
+        }
+
+        Ok(client)
+    }
+
+    fn read_line(&mut self) -> Result<Vec<u8>, ImapError> {
+        let mut line = Vec::new();
+
+        if self.stream.read_until(b'\n', &mut line)? == 0 {
+            return Err(ImapError::Closed);
+        }
+
+        Ok(line)
+    }
+
+    /// Sends a command and reads its response, up to the tagged completion.
+    fn command(&mut self, command: &str) -> Result<Response, ImapError> {
+        self.tag += 1;
+        let tag = format!("A{:04}", self.tag);
+
+        let stream = self.stream.get_mut();
+        stream.write_all(format!("{tag} {command}\r\n").as_bytes())?;
+        stream.flush()?;
+
+        let mut response = Response::default();
+
+        loop {
+            let line = self.read_line()?;
+
+            if let Some(size) = literal_size(&line) {
+                let mut literal = vec![0; size];
+                self.stream.read_exact(&mut literal)?;
+                response.literals.push(literal);
+            }
+
+            let line = String::from_utf8_lossy(&line).trim_end().to_owned();
+
+            if let Some(status) = line.strip_prefix(&format!("{tag} ")) {
+                if status.starts_with("OK") {
+                    return Ok(response);
+                }
+
+                // Never echo the password of a refused login
+                let command = command.split(' ').next().unwrap_or(command).to_owned();
+                return Err(ImapError::Refused {
+                    command,
+                    reply: status.to_owned(),
+                });
+            }
+
+            response.lines.push(line);
+        }
+    }
+
+    fn login(&mut self, username: &str, password: &str) -> Result<(), ImapError> {
+        self.command(&format!("LOGIN {} {}", quote(username), quote(password)))?;
+        Ok(())
+    }
+
+    pub(crate) fn select(&mut self, folder: &str) -> Result<(), ImapError> {
+        self.command(&format!("SELECT {}", quote(folder)))?;
+        Ok(())
+    }
+
+    /// The UIDs of every message in the selected folder.
+    pub(crate) fn uid_search_all(&mut self) -> Result<Vec<u32>, ImapError> {
+        let response = self.command("UID SEARCH ALL")?;
+
+        Ok(response
+            .lines
+            .iter()
+            .filter_map(|line| line.strip_prefix("* SEARCH"))
+            .flat_map(|uids| uids.split_whitespace())
+            .filter_map(|uid| uid.parse().ok())
+            .collect())
+    }
+
+    /// The raw message of the given UID, without marking it as seen.
+    pub(crate) fn uid_fetch(&mut self, uid: u32) -> Result<Vec<u8>, ImapError> {
+        let command = format!("UID FETCH {uid} BODY.PEEK[]");
+        let response = self.command(&command)?;
+
+        response
+            .literals
+            .into_iter()
+            .next()
+            .ok_or_else(|| ImapError::Refused {
+                command,
+                reply: "No message body returned".to_owned(),
+            })
+    }
+
+    /// Copies a message into another folder and flags the original for deletion, see [`Self::expunge`].
+    pub(crate) fn uid_move(&mut self, uid: u32, folder: &str) -> Result<(), ImapError> {
+        self.command(&format!("UID COPY {uid} {}", quote(folder)))?;
+        self.command(&format!("UID STORE {uid} +FLAGS.SILENT (\\Deleted)"))?;
+        Ok(())
+    }
+
+    pub(crate) fn expunge(&mut self) -> Result<(), ImapError> {
+        self.command("EXPUNGE")?;
+        Ok(())
+    }
+
+    pub(crate) fn logout(mut self) -> Result<(), ImapError> {
+        self.command("LOGOUT")?;
+        Ok(())
+    }
+}
+
+/// Opens a TLS session over the given connection, see [`tls::client_stream`].
+fn tls_stream(
+    host: &str,
+    tcp: TcpStream,
+) -> Result<rustls::StreamOwned<rustls::ClientConnection, TcpStream>, ImapError> {
+    tls::client_stream(host, tcp).map_err(ImapError::Tls)
+}
+
+/// The size of the literal that follows the given response line, when it ends with `{size}`.
+fn literal_size(line: &[u8]) -> Option<usize> {
+    let line = std::str::from_utf8(line).ok()?.trim_end();
+    let size = line.strip_suffix('}')?;
+    let size = &size[size.rfind('{')? + 1..];
+
+    size.parse().ok()
+}
+
+/// Quotes an IMAP string argument.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// A fetched message that isn't a valid entry.
+#[derive(Debug)]
+pub(crate) struct Rejection {
+    pub(crate) uid: u32,
+
+    /// The address the message came from, if it has a readable one
+    pub(crate) sender: Option<String>,
+    pub(crate) subject: String,
+    pub(crate) message_id: Option<String>,
+    pub(crate) reason: String,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct PollSummary {
+    /// The outbox entry files written
+    pub(crate) accepted: Vec<PathBuf>,
+    pub(crate) rejected: Vec<Rejection>,
+}
+
+/// Extracts the entry carried by a message: its JSON attachment, or its plain text body.
+/// The entry is parsed and validated as any outbox entry, and returned as sent.
+pub(crate) fn entry_from_message(message: &ParsedMail, templates_path: &Path) -> Result<String> {
+    let parts = message.parts().collect::<Vec<_>>();
+
+    let is_json = |part: &ParsedMail| {
+        part.ctype.mimetype.eq_ignore_ascii_case("application/json")
+            || part
+                .get_content_disposition()
+                .params
+                .get("filename")
+                .is_some_and(|name| name.to_lowercase().ends_with(ENTRY_EXT))
+    };
+
+    let part = parts
+        .iter()
+        .find(|part| is_json(part))
+        .or_else(|| {
+            parts
+                .iter()
+                .find(|part| part.ctype.mimetype.eq_ignore_ascii_case("text/plain"))
+        })
+        .context("The message has neither a JSON attachment nor a plain text body")?;
+
+    let content = match part.get_body() {
+        Ok(body) => body,
+        Err(_) => {
+            String::from_utf8(part.get_body_raw()?).context("The entry is not valid UTF-8 text")?
+        }
+    };
+    let content = content.trim().to_owned();
+
+    let entry: Entry = serde_json::from_str(&content).context("The entry is not valid")?;
+    entries::validate_entry(&entry, templates_path)?;
+
+    Ok(content)
+}
+
+/// Writes an ingested entry into the outbox. The file appears at once, so a concurrent run never reads it partially.
+fn write_entry(outbox_path: &Path, uid: u32, content: &str) -> Result<PathBuf> {
+    fs::create_dir_all(outbox_path)
+        .with_context(|| format!("Unable to create outbox \"{}\"", outbox_path.display()))?;
+
+    // Named after the message and its content, so fetching the same message again overwrites it
+    let name = format!(
+        "imap-{uid}-{}{ENTRY_EXT}",
+        entries::string_crc32_iso_hdlc_checksum(content)
+    );
+    let path = outbox_path.join(name);
+    let partial_path = path.with_extension("part");
+
+    fs::write(&partial_path, content)
+        .with_context(|| format!("Unable to write entry \"{}\"", partial_path.display()))?;
+    fs::rename(&partial_path, &path)
+        .with_context(|| format!("Unable to write entry \"{}\"", path.display()))?;
+
+    Ok(path)
+}
+
+/// Fetches every message of the polled folder: valid entries are written into the outbox and their message
+/// moved to the processed folder, while the others are moved to the rejected folder.
+/// A message is only moved after its entry was written, so a failing poll never loses an entry.
+pub(crate) fn poll(
+    client: &mut ImapClient,
+    config: &ImapConfig,
+    outbox_path: &Path,
+    templates_path: &Path,
+) -> Result<PollSummary> {
+    client.select(&config.folder)?;
+
+    let mut summary = PollSummary::default();
+
+    for uid in client.uid_search_all()? {
+        let raw = client.uid_fetch(uid)?;
+
+        let parsed = mailparse::parse_mail(&raw);
+        let entry = match &parsed {
+            Ok(message) => entry_from_message(message, templates_path),
+            Err(e) => Err(anyhow!("Invalid message: {e}")),
+        };
+
+        match entry {
+            Ok(content) => {
+                summary
+                    .accepted
+                    .push(write_entry(outbox_path, uid, &content)?);
+                client.uid_move(uid, &config.processed_folder)?;
+            }
+            Err(e) => {
+                client.uid_move(uid, &config.rejected_folder)?;
+
+                let headers = parsed.as_ref().ok().map(|message| &message.headers);
+                let header = |name| headers.and_then(|headers| headers.get_first_value(name));
+
+                summary.rejected.push(Rejection {
+                    uid,
+                    sender: header("From")
+                        .and_then(|from| mailparse::addrparse(&from).ok())
+                        .and_then(|addresses| addresses.extract_single_info())
+                        .map(|address| address.addr),
+                    subject: header("Subject").unwrap_or_default(),
+                    message_id: header("Message-ID"),
+                    reason: format!("{e:#}"),
+                });
+            }
+        }
+    }
+
+    client.expunge()?;
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    const VALID_ENTRY: &str = r#"{
+        "id": "1",
+        "utc": "2023-01-01T00:00:00+00:00",
+        "notify_error": [],
+        "email": {
+            "system": "sys",
+            "subsystem": "sub",
+            "from": "a@x.com",
+            "to": ["b@x.com"],
+            "cc": [],
+            "bcc": [],
+            "reply_to": [],
+            "subject": "Hi",
+            "template": "ops_department",
+            "alternative_content": "",
+            "attachments": [],
+            "unique_by": ""
+        },
+        "context": {}
+    }"#;
+
+    /// A scripted IMAP server holding the given messages, by UID. Returns the commands it received.
+    fn imap_stub(messages: Vec<(u32, String)>) -> (u16, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut commands = Vec::new();
+
+            writer.write_all(b"* OK IMAP4rev1 stub ready\r\n").unwrap();
+
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+
+                let (tag, command) = line.trim_end().split_once(' ').unwrap();
+                let command = command.to_owned();
+
+                let mut reply = String::new();
+                if command == "UID SEARCH ALL" {
+                    let uids: Vec<String> =
+                        messages.iter().map(|(uid, _)| uid.to_string()).collect();
+                    reply = format!("* SEARCH {}\r\n", uids.join(" "));
+                } else if let Some(uid) = command.strip_prefix("UID FETCH ") {
+                    let uid: u32 = uid.split(' ').next().unwrap().parse().unwrap();
+                    let (_, message) = messages.iter().find(|(id, _)| *id == uid).unwrap();
+                    reply = format!(
+                        "* 1 FETCH (UID {uid} BODY[] {{{}}}\r\n{message})\r\n",
+                        message.len()
+                    );
+                } else if command == "LOGOUT" {
+                    reply = "* BYE\r\n".to_owned();
+                }
+
+                writer
+                    .write_all(format!("{reply}{tag} OK done\r\n").as_bytes())
+                    .unwrap();
+
+                commands.push(command);
+            }
+
+            commands
+        });
+
+        (port, handle)
+    }
+
+    fn config(port: u16) -> ImapConfig {
+        ImapConfig {
+            host: "127.0.0.1".to_owned(),
+            port,
+            auth: Authentication::NoAuth,
+            username: "mailer".to_owned(),
+            password: "secret".to_owned(),
+            folder: "INBOX".to_owned(),
+            processed_folder: "Processed".to_owned(),
+            rejected_folder: "Rejected".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_poll_ingests_valid_and_rejects_invalid_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = dir.path().join("outbox");
+        let templates = dir.path().join("templates");
+        fs::create_dir_all(templates.join("ops_department")).unwrap();
+        fs::write(templates.join("ops_department/template.html"), "").unwrap();
+
+        let attached = format!(
+            "From: Producer <producer@x.com>\r\nSubject: Entry\r\nMIME-Version: 1.0\r\n\
+             Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n\
+             --b\r\nContent-Type: text/plain\r\n\r\nSee attachment\r\n\
+             --b\r\nContent-Type: application/octet-stream\r\n\
+             Content-Disposition: attachment; filename=\"entry.json\"\r\n\r\n{VALID_ENTRY}\r\n--b--\r\n"
+        );
+        let invalid = "From: producer@x.com\r\nSubject: Broken\r\nMessage-ID: <1@x.com>\r\n\r\n{ \"id\": \r\n";
+
+        let (port, stub) = imap_stub(vec![(7, attached), (8, invalid.to_owned())]);
+
+        let config = config(port);
+        let mut client = ImapClient::connect(&config).unwrap();
+        let summary = poll(&mut client, &config, &outbox, &templates).unwrap();
+        client.logout().unwrap();
+
+        assert_eq!(summary.accepted.len(), 1);
+        let written = fs::read_to_string(&summary.accepted[0]).unwrap();
+        assert_eq!(written, VALID_ENTRY.trim());
+        assert!(entries::validate_entry_file(&summary.accepted[0], &templates).is_ok());
+
+        assert_eq!(summary.rejected.len(), 1);
+        let rejection = &summary.rejected[0];
+        assert_eq!(rejection.uid, 8);
+        assert_eq!(rejection.sender.as_deref(), Some("producer@x.com"));
+        assert_eq!(rejection.message_id.as_deref(), Some("<1@x.com>"));
+        assert!(
+            rejection.reason.contains("not valid"),
+            "{}",
+            rejection.reason
+        );
+
+        let commands = stub.join().unwrap();
+        assert_eq!(commands[0], "LOGIN \"mailer\" \"secret\"");
+        assert!(commands.contains(&"UID COPY 7 \"Processed\"".to_owned()));
+        assert!(commands.contains(&"UID COPY 8 \"Rejected\"".to_owned()));
+        assert!(commands.contains(&"EXPUNGE".to_owned()));
+    }
+
+    #[test]
+    fn test_entry_missing_template_is_rejected() {
+        let templates = tempfile::tempdir().unwrap();
+        let raw = format!("From: producer@x.com\r\nContent-Type: text/plain\r\n\r\n{VALID_ENTRY}");
+        let message = mailparse::parse_mail(raw.as_bytes()).unwrap();
+
+        let error = entry_from_message(&message, templates.path()).unwrap_err();
+        assert!(error.to_string().contains("ops_department"), "{error}");
+    }
+
+    #[test]
+    fn test_literal_size() {
+        assert_eq!(literal_size(b"* 1 FETCH (UID 7 BODY[] {42}\r\n"), Some(42));
+        assert_eq!(literal_size(b"* SEARCH 1 2\r\n"), None);
+    }
+}
@@ -0,0 +1,263 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::archive;
+use crate::entries::ComposedEmail;
+use crate::send::{self, AssetError};
+
+/// The attachment size over which the `link` policy applies, when `large_attachment_threshold` isn't set.
+pub(crate) const DEFAULT_THRESHOLD: u64 = 10 * 1024 * 1024;
+
+/// How long a download link is valid, when `large_attachment_expiry_hours` isn't set.
+/// S3-compatible stores don't sign links for longer.
+pub(crate) const DEFAULT_EXPIRY_HOURS: u64 = 7 * 24;
+
+/// What to do with attached files over the threshold, set in `template.toml` as `large_attachment_policy`.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LargeAttachmentPolicy {
+    /// The file is attached regardless of its size
+    #[default]
+    Attach,
+
+    /// The file is uploaded with an [`Uploader`] and replaced by an expiring download link,
+    /// rendered from the `_meta.large_files` context key
+    Link,
+}
+
+/// A file uploaded instead of being attached, as listed in `_meta.large_files`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LargeFile {
+    /// The file name, as it would have been attached
+    pub(crate) name: String,
+
+    /// The size in bytes
+    pub(crate) size: u64,
+    pub(crate) url: String,
+
+    /// When the link stops working
+    pub(crate) expiry: DateTime<Utc>,
+}
+
+/// An object store large attachments are uploaded to, e.g. an S3-compatible bucket.
+pub(crate) trait Uploader {
+    /// Uploads the file as `key`, returning a download link valid `expiry` long since `now`.
+    fn upload(
+        &self,
+        path: &Path,
+        key: &str,
+        now: DateTime<Utc>,
+        expiry: Duration,
+    ) -> Result<String>;
+}
+
+/// Uploads the attached files of the E-mail larger than `threshold` bytes, removing them from its
+/// attachments and listing their download links in `_meta.large_files`. Files that can't be read
+/// are left to be reported when attached.
+/// ## Error
+/// Fails on the first file that can't be uploaded, or when there's a file to upload without an uploader,
+/// leaving the E-mail unchanged.
+pub(crate) fn link_large_attachments(
+    email: &mut ComposedEmail,
+    threshold: u64,
+    expiry: Duration,
+    uploader: Option<&dyn Uploader>,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    let mut attachments = Vec::new();
+    let mut large_files = Vec::new();
+
+    for attachment in &email.header.attachments {
+        let path = Path::new(attachment);
+
+        let size = match fs::metadata(path) {
+            Ok(metadata) if metadata.len() > threshold => metadata.len(),
+            _ => {
+                attachments.push(attachment.clone());
+                continue;
+            }
+        };
+
+        let name = send::owned_filename_string(path)?;
+        // Unguessable, and unique to the E-mail so that every link can be revoked on its own
+        let key = format!(
+            "{}/{:032x}/{name}",
+            archive::archive_stem(email),
+            fastrand::u128(..)
+        );
+
+        let url = match uploader {
+            Some(uploader) => uploader.upload(path, &key, now, expiry),
+            None => Err(anyhow!("No object store is configured")),
+        }
+        .map_err(|e| AssetError::Upload {
+            path: attachment.clone(),
+            error: format!("{e:#}"),
+        })?;
+
+        log::info!("Linked attached file \"{attachment}\" ({size} bytes) as `{key}`");
+
+        large_files.push(LargeFile {
+            name,
+            size,
+            url,
+            expiry: now + expiry,
+        });
+    }
+
+    if large_files.is_empty() {
+        return Ok(());
+    }
+
+    email.header.attachments = attachments;
+
+    let meta = email
+        .context
+        .entry("_meta")
+        .or_insert_with(|| serde_json::Value::Object(Default::default()));
+
+    if let serde_json::Value::Object(meta) = meta {
+        meta.insert(
+            "large_files".to_owned(),
+            serde_json::to_value(large_files).expect("Large files are always valid JSON"),
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entries::Email;
+    use crate::send::MessageBuilder;
+    use std::cell::RefCell;
+
+    /// Records the uploaded files instead of sending them anywhere.
+    #[derive(Default)]
+    struct StubUploader {
+        uploaded: RefCell<Vec<(String, String)>>,
+        fail: bool,
+    }
+
+    impl Uploader for StubUploader {
+        fn upload(
+            &self,
+            path: &Path,
+            key: &str,
+            _now: DateTime<Utc>,
+            expiry: Duration,
+        ) -> Result<String> {
+            if self.fail {
+                return Err(anyhow!("403 Forbidden"));
+            }
+
+            self.uploaded
+                .borrow_mut()
+                .push((path.display().to_string(), key.to_owned()));
+
+            Ok(format!(
+                "https://files.x.com/{key}?expires={}",
+                expiry.num_seconds()
+            ))
+        }
+    }
+
+    #[test]
+    fn test_large_attachments_linked() {
+        let dir = tempfile::tempdir().unwrap();
+        let small = dir.path().join("summary.txt");
+        let large = dir.path().join("dump.bin");
+        fs::write(&small, "summary").unwrap();
+        fs::write(&large, vec![0x42; 2048]).unwrap();
+
+        let mut email = ComposedEmail {
+            id: 0xabcd,
+            header: Email {
+                attachments: vec![small.display().to_string(), large.display().to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let now: DateTime<Utc> = "2024-06-01T12:00:00Z".parse().unwrap();
+        let uploader = StubUploader::default();
+
+        link_large_attachments(&mut email, 1024, Duration::hours(24), Some(&uploader), now)
+            .unwrap();
+
+        let uploaded = uploader.uploaded.borrow();
+        assert_eq!(uploaded.len(), 1);
+        assert_eq!(uploaded[0].0, large.display().to_string());
+        assert!(uploaded[0].1.starts_with("0000abcd/"));
+        assert!(uploaded[0].1.ends_with("/dump.bin"));
+
+        assert_eq!(
+            email.context["_meta"]["large_files"],
+            serde_json::json!([{
+                "name": "dump.bin",
+                "size": 2048,
+                "url": format!("https://files.x.com/{}?expires=86400", uploaded[0].1),
+                "expiry": "2024-06-02T12:00:00Z",
+            }])
+        );
+        assert_eq!(email.header.attachments, vec![small.display().to_string()]);
+
+        let attachments = email.header.attachments.join(", ");
+        let message: lettre::Message = MessageBuilder::new()
+            .from("a@x.com")
+            .to_addresses("b@x.com")
+            .attachments(&attachments)
+            .build()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let formatted = String::from_utf8_lossy(&message.formatted()).into_owned();
+        assert!(formatted.contains("filename=\"summary.txt\""));
+        assert!(!formatted.contains("dump.bin"));
+    }
+
+    #[test]
+    fn test_failed_upload_fails_email() {
+        let dir = tempfile::tempdir().unwrap();
+        let large = dir.path().join("dump.bin");
+        fs::write(&large, vec![0x42; 2048]).unwrap();
+
+        let attachments = vec![large.display().to_string()];
+        let mut email = ComposedEmail {
+            header: Email {
+                attachments: attachments.clone(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let uploader = StubUploader {
+            fail: true,
+            ..Default::default()
+        };
+
+        let link = |email: &mut ComposedEmail, uploader: Option<&dyn Uploader>| {
+            link_large_attachments(email, 1024, Duration::hours(1), uploader, Utc::now())
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            link(&mut email, Some(&uploader)),
+            format!(
+                "Unable to upload attached file \"{}\": 403 Forbidden",
+                large.display()
+            )
+        );
+        assert_eq!(email.header.attachments, attachments);
+        assert!(email.context.get("_meta").is_none());
+
+        assert!(link(&mut email, None).ends_with(": No object store is configured"));
+
+        // Nothing to upload without a file over the threshold
+        let mut email = ComposedEmail::default();
+        link_large_attachments(&mut email, 1024, Duration::hours(1), None, Utc::now()).unwrap();
+    }
+}
@@ -1,7 +1,114 @@
+// The modules of the binary, of which the library only exposes the producer API below: their dead code is
+// linted with the binary
+#[allow(dead_code)]
+mod aliases;
+#[allow(dead_code)]
+mod alternative;
+#[allow(dead_code)]
 mod app;
+#[allow(dead_code)]
+mod approval;
+#[allow(dead_code)]
+mod archive;
+#[allow(dead_code)]
+mod audit;
+#[allow(dead_code)]
+mod bench;
+#[allow(dead_code)]
+mod bounce;
+#[allow(dead_code)]
+mod breaker;
+#[allow(dead_code)]
+mod bundle;
+#[allow(dead_code)]
+mod checkpoint;
+#[allow(dead_code)]
+mod cli;
+#[allow(dead_code)]
+mod config;
+#[allow(dead_code)]
+mod context_html;
+#[allow(dead_code)]
+mod dark_mode;
+#[allow(dead_code)]
+mod dead_letter;
+#[allow(dead_code)]
+mod dedup;
+#[allow(dead_code)]
 mod entries;
 mod errors;
+#[allow(dead_code)]
+mod exit;
+#[allow(dead_code)]
+mod fallback;
+#[allow(dead_code)]
+mod guards;
+#[allow(dead_code)]
+mod identity;
+#[allow(dead_code)]
+mod image_refs;
+#[cfg(feature = "ingest-imap")]
+#[allow(dead_code)]
+mod ingest;
+#[allow(dead_code)]
+mod large_files;
+#[allow(dead_code)]
+mod localtime;
+#[allow(dead_code)]
+mod logging;
+#[allow(dead_code)]
+mod mime_tree;
+#[allow(dead_code)]
+mod outbox;
+#[allow(dead_code)]
+mod paths;
+#[allow(dead_code)]
+mod policy;
+#[cfg(feature = "remote-images")]
+#[allow(dead_code)]
+mod remote_images;
+#[allow(dead_code)]
+mod removal_journal;
+#[allow(dead_code)]
 mod render;
+#[allow(dead_code)]
+mod reply_token;
+#[allow(dead_code)]
+mod retention;
+#[cfg(feature = "s3-links")]
+#[allow(dead_code)]
+mod s3;
+#[allow(dead_code)]
+mod schedule;
+#[allow(dead_code)]
+mod schema;
+#[allow(dead_code)]
 mod send;
+#[allow(dead_code)]
+mod sent_log;
+#[allow(dead_code)]
+mod signing;
+#[allow(dead_code)]
+mod stats;
+#[allow(dead_code)]
+mod telemetry;
+#[allow(dead_code)]
+mod templates;
+#[cfg(any(
+    feature = "ingest-imap",
+    feature = "s3-links",
+    feature = "remote-images"
+))]
+#[allow(dead_code)]
+mod tls;
+#[allow(dead_code)]
+mod tls_policy;
 
+pub use entries::Entry;
 pub use errors::EntryError;
+pub use outbox::{EntryBuilder, Outbox};
+pub use send::{
+    AttachmentEncoding, BodyCharset, CustomHeaders, FileTransport, FluentMessage, HeaderError,
+    LongHeaderPolicy, Message, MessageBuilder, NullTransport, SendOutcome, SendmailTransport,
+    SubjectTag, Transport,
+};
@@ -0,0 +1,443 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::DateTime;
+use chrono_tz::Tz;
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError,
+};
+use liquid_core::parser::{FilterArguments, ParameterReflection};
+use liquid_core::{Expression, Filter, FilterReflection, ParseFilter, Runtime, ValueView};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+
+/// The name of the filter, or helper with Handlebars, in every engine.
+pub(crate) const FILTER_NAME: &str = "localtime";
+
+/// The format of the displayed timestamps, e.g. `2024-03-10 21:30 EDT`, when neither the template nor the call sets one.
+pub(crate) const DEFAULT_FORMAT: &str = "%Y-%m-%d %H:%M %Z";
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum LocalTimeError {
+    #[error("Unknown display timezone \"{0}\", expected an IANA name such as `Europe/Berlin`")]
+    UnknownTimezone(String),
+
+    #[error("Invalid `localtime` format \"{0}\"")]
+    InvalidFormat(String),
+
+    #[error("`localtime` expects an RFC 3339 timestamp, got \"{0}\"")]
+    InvalidTimestamp(String),
+}
+
+/// How the `localtime` filter displays the RFC 3339 timestamps of the context, e.g. `display_timezone = "Asia/Tokyo"`.
+/// Set in `template.toml` under `[localtime]`. The `email.display_timezone` of an entry takes precedence.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub(crate) struct LocalTimeConfig {
+    /// The IANA timezone the timestamps are displayed in, UTC when not set
+    pub(crate) display_timezone: Option<String>,
+
+    /// The chrono format of the displayed timestamps, `%Y-%m-%d %H:%M %Z` when not set
+    pub(crate) format: Option<String>,
+
+    /// Fail the rendering on a value that isn't an RFC 3339 timestamp, instead of displaying it as is
+    pub(crate) strict: Option<bool>,
+}
+
+impl LocalTimeConfig {
+    /// Fills the settings missing here from `fallback`.
+    pub(crate) fn or(&self, fallback: &LocalTimeConfig) -> LocalTimeConfig {
+        LocalTimeConfig {
+            display_timezone: self
+                .display_timezone
+                .clone()
+                .or_else(|| fallback.display_timezone.clone()),
+            format: self.format.clone().or_else(|| fallback.format.clone()),
+            strict: self.strict.or(fallback.strict),
+        }
+    }
+
+    /// The `localtime` filter of the settings.
+    /// ## Error
+    /// Fails if the timezone isn't an IANA timezone name.
+    pub(crate) fn local_time(&self) -> Result<LocalTime, LocalTimeError> {
+        let timezone = match &self.display_timezone {
+            Some(name) => name
+                .parse()
+                .map_err(|_| LocalTimeError::UnknownTimezone(name.clone()))?,
+            None => Tz::UTC,
+        };
+
+        Ok(LocalTime {
+            timezone,
+            format: self
+                .format
+                .clone()
+                .unwrap_or_else(|| DEFAULT_FORMAT.to_owned()),
+            strict: self.strict.unwrap_or_default(),
+        })
+    }
+}
+
+/// The `localtime` filter, displaying an RFC 3339 timestamp in a timezone, the same way with every engine:
+/// `{{ at | localtime }}` and `{{ at | localtime(format="%H:%M") }}` with Tera,
+/// `{{localtime at}}` and `{{localtime at format="%H:%M"}}` with Handlebars,
+/// `{{ at | localtime }}` and `{{ at | localtime: "%H:%M" }}` with Liquid.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LocalTime {
+    timezone: Tz,
+    format: String,
+    strict: bool,
+}
+
+impl Default for LocalTime {
+    fn default() -> Self {
+        Self {
+            timezone: Tz::UTC,
+            format: DEFAULT_FORMAT.to_owned(),
+            strict: false,
+        }
+    }
+}
+
+impl LocalTime {
+    pub(crate) fn timezone(&self) -> Tz {
+        self.timezone
+    }
+
+    /// Displays the timestamp in the timezone, with `format` or else the configured one.
+    /// A value that isn't an RFC 3339 timestamp is displayed as is, unless strict.
+    /// ## Error
+    /// Fails if the format is invalid, or the value isn't a timestamp when strict.
+    pub(crate) fn display(
+        &self,
+        value: &str,
+        format: Option<&str>,
+    ) -> Result<String, LocalTimeError> {
+        let format = format.unwrap_or(&self.format);
+
+        // chrono panics on an invalid format only once it's displayed
+        let items: Vec<Item> = StrftimeItems::new(format).collect();
+        if items.iter().any(|item| matches!(item, Item::Error)) {
+            return Err(LocalTimeError::InvalidFormat(format.to_owned()));
+        }
+
+        match DateTime::parse_from_rfc3339(value.trim()) {
+            Ok(timestamp) => Ok(timestamp
+                .with_timezone(&self.timezone)
+                .format_with_items(items.iter())
+                .to_string()),
+            Err(_) if self.strict => Err(LocalTimeError::InvalidTimestamp(value.to_owned())),
+            Err(_) => Ok(value.to_owned()),
+        }
+    }
+}
+
+/// The text of a context value, without the quotes of a string. Nothing for a missing value.
+fn text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(text) => text.clone(),
+        serde_json::Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+impl tera::Filter for LocalTime {
+    fn filter(
+        &self,
+        value: &tera::Value,
+        args: &HashMap<String, tera::Value>,
+    ) -> tera::Result<tera::Value> {
+        let format = match args.get("format") {
+            Some(tera::Value::String(format)) => Some(format.as_str()),
+            Some(_) => return Err(tera::Error::msg("`localtime` expects a string `format`")),
+            None => None,
+        };
+
+        self.display(&text(value), format)
+            .map(tera::Value::String)
+            .map_err(|e| tera::Error::msg(e.to_string()))
+    }
+}
+
+impl HelperDef for LocalTime {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let value = h
+            .param(0)
+            .ok_or_else(|| RenderError::new("`localtime` expects a timestamp"))?;
+        let format = match h.hash_get("format").map(|format| format.value()) {
+            Some(serde_json::Value::String(format)) => Some(format.as_str()),
+            Some(_) => return Err(RenderError::new("`localtime` expects a string `format`")),
+            None => None,
+        };
+
+        let displayed = self
+            .display(&text(value.value()), format)
+            .map_err(|e| RenderError::new(e.to_string()))?;
+        out.write(&r.get_escape_fn()(&displayed))?;
+
+        Ok(())
+    }
+}
+
+const LIQUID_PARAMETERS: &[ParameterReflection] = &[ParameterReflection {
+    name: "format",
+    description: "The chrono format of the displayed timestamp",
+    is_optional: true,
+}];
+
+impl FilterReflection for LocalTime {
+    fn name(&self) -> &str {
+        FILTER_NAME
+    }
+
+    fn description(&self) -> &str {
+        "Displays an RFC 3339 timestamp in the display timezone"
+    }
+
+    fn positional_parameters(&self) -> &'static [ParameterReflection] {
+        LIQUID_PARAMETERS
+    }
+
+    fn keyword_parameters(&self) -> &'static [ParameterReflection] {
+        &[]
+    }
+}
+
+impl ParseFilter for LocalTime {
+    fn parse(&self, mut arguments: FilterArguments) -> liquid_core::Result<Box<dyn Filter>> {
+        let format = arguments.positional.next();
+        if arguments.positional.next().is_some() || arguments.keyword.next().is_some() {
+            return Err(liquid_core::Error::with_msg(
+                "`localtime` takes a single format argument",
+            ));
+        }
+
+        Ok(Box::new(LiquidLocalTime {
+            local_time: self.clone(),
+            format,
+        }))
+    }
+
+    fn reflection(&self) -> &dyn FilterReflection {
+        self
+    }
+}
+
+/// The `localtime` filter of a Liquid template, along with its format argument.
+#[derive(Debug)]
+struct LiquidLocalTime {
+    local_time: LocalTime,
+    format: Option<Expression>,
+}
+
+impl fmt::Display for LiquidLocalTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{FILTER_NAME}")
+    }
+}
+
+impl Filter for LiquidLocalTime {
+    fn evaluate(
+        &self,
+        input: &dyn ValueView,
+        runtime: &dyn Runtime,
+    ) -> liquid_core::Result<liquid_core::Value> {
+        let format = match &self.format {
+            Some(format) => Some(format.evaluate(runtime)?.to_kstr().to_string()),
+            None => None,
+        };
+        let value = if input.is_nil() {
+            String::new()
+        } else {
+            input.to_kstr().to_string()
+        };
+
+        self.local_time
+            .display(&value, format.as_deref())
+            .map(liquid_core::Value::scalar)
+            .map_err(|e| liquid_core::Error::with_msg(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{self, ContextData, DetectionMethod, TemplateData, TemplateExtension};
+    use serde_json::json;
+    use std::rc::Rc;
+
+    fn local_time(timezone: &str, strict: bool) -> LocalTime {
+        LocalTimeConfig {
+            display_timezone: Some(timezone.to_owned()),
+            format: None,
+            strict: Some(strict),
+        }
+        .local_time()
+        .unwrap()
+    }
+
+    fn render(
+        contents: &str,
+        context: serde_json::Value,
+        local_time: LocalTime,
+    ) -> anyhow::Result<String> {
+        let template_data = TemplateData {
+            contents: Rc::new(contents.to_owned()),
+            file_path: None,
+            extensions: None,
+        };
+        let context_data = ContextData {
+            context,
+            file_path: None,
+            trusted: false,
+            local_time,
+        };
+
+        let rendered = render::render(
+            &template_data,
+            &context_data,
+            DetectionMethod::Auto,
+            TemplateExtension::Auto,
+        )?;
+
+        Ok(rendered.0.to_string())
+    }
+
+    const TEMPLATES: [&str; 3] = [
+        "<!--TEMPLATE tera-->{{ at | localtime }}|{{ at | localtime(format=\"%H:%M\") }}",
+        "<!--TEMPLATE handlebars-->{{localtime at}}|{{localtime at format=\"%H:%M\"}}",
+        "<!--TEMPLATE liquid-->{{ at | localtime }}|{{ at | localtime: \"%H:%M\" }}",
+    ];
+
+    #[test]
+    fn test_same_timestamp_in_two_timezones() {
+        let context = json!({ "at": "2024-06-01T12:00:00Z" });
+
+        for template in TEMPLATES {
+            assert_eq!(
+                render(template, context.clone(), local_time("Europe/Berlin", true)).unwrap(),
+                "2024-06-01 14:00 CEST|14:00",
+                "{template}"
+            );
+            assert_eq!(
+                render(template, context.clone(), local_time("Asia/Tokyo", true)).unwrap(),
+                "2024-06-01 21:00 JST|21:00",
+                "{template}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_timestamp() {
+        let context = json!({ "at": "yesterday <noon>" });
+
+        for template in TEMPLATES {
+            let error =
+                render(template, context.clone(), local_time("Asia/Tokyo", true)).unwrap_err();
+            assert!(
+                format!("{error:?}").contains("RFC 3339 timestamp, got \"yesterday <noon>\""),
+                "{template}: {error:?}"
+            );
+        }
+
+        // Displayed as is, escaped by the engines that escape
+        let lenient = local_time("Asia/Tokyo", false);
+        assert_eq!(
+            render(TEMPLATES[0], context.clone(), lenient.clone()).unwrap(),
+            "yesterday &lt;noon&gt;|yesterday &lt;noon&gt;"
+        );
+        assert_eq!(
+            render(TEMPLATES[1], context.clone(), lenient.clone()).unwrap(),
+            "yesterday &lt;noon&gt;|yesterday &lt;noon&gt;"
+        );
+        assert_eq!(
+            render(TEMPLATES[2], context, lenient).unwrap(),
+            "yesterday <noon>|yesterday <noon>"
+        );
+    }
+
+    #[test]
+    fn test_dst_boundaries() {
+        let new_york = local_time("America/New_York", true);
+
+        // Spring forward: 01:59:59 EST is followed by 03:00:00 EDT
+        assert_eq!(
+            new_york
+                .display("2024-03-10T06:59:59Z", Some("%H:%M:%S %Z"))
+                .unwrap(),
+            "01:59:59 EST"
+        );
+        assert_eq!(
+            new_york
+                .display("2024-03-10T07:00:00Z", Some("%H:%M:%S %Z"))
+                .unwrap(),
+            "03:00:00 EDT"
+        );
+
+        // Fall back: 01:30 happens twice, first in EDT then in EST
+        assert_eq!(
+            new_york
+                .display("2024-11-03T05:30:00Z", Some("%H:%M %Z"))
+                .unwrap(),
+            "01:30 EDT"
+        );
+        assert_eq!(
+            new_york
+                .display("2024-11-03T06:30:00Z", Some("%H:%M %Z"))
+                .unwrap(),
+            "01:30 EST"
+        );
+
+        // A timestamp with an offset designates the same instant
+        assert_eq!(
+            new_york
+                .display("2024-03-10T07:59:00+01:00", Some("%H:%M %Z"))
+                .unwrap(),
+            "01:59 EST"
+        );
+    }
+
+    #[test]
+    fn test_config() {
+        let template = LocalTimeConfig {
+            display_timezone: Some("Europe/Berlin".to_owned()),
+            format: Some("%d.%m.%Y".to_owned()),
+            strict: None,
+        };
+        let entry = LocalTimeConfig {
+            display_timezone: Some("Asia/Tokyo".to_owned()),
+            ..Default::default()
+        };
+
+        let local_time = entry.or(&template).local_time().unwrap();
+        assert_eq!(local_time.timezone(), Tz::Asia__Tokyo);
+        assert_eq!(
+            local_time.display("2024-06-01T20:00:00Z", None).unwrap(),
+            "02.06.2024"
+        );
+        assert_eq!(
+            LocalTimeConfig::default().local_time().unwrap(),
+            LocalTime::default()
+        );
+
+        assert!(matches!(
+            LocalTimeConfig {
+                display_timezone: Some("Mars/Olympus".to_owned()),
+                ..Default::default()
+            }
+            .local_time(),
+            Err(LocalTimeError::UnknownTimezone(_))
+        ));
+        assert!(matches!(
+            LocalTime::default().display("2024-06-01T20:00:00Z", Some("%Q")),
+            Err(LocalTimeError::InvalidFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,287 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fmt;
+use std::str::FromStr;
+
+#[cfg(feature = "syslog")]
+use chrono::{DateTime, SecondsFormat, Utc};
+#[cfg(feature = "syslog")]
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+#[cfg(any(feature = "syslog", all(windows, feature = "eventlog")))]
+use crate::errors::Severity;
+
+/// The name the mailer reports under to the system logs.
+#[cfg(any(feature = "syslog", all(windows, feature = "eventlog")))]
+const APP_NAME: &str = "osa_mailer";
+
+/// The `user-level messages` syslog facility.
+#[cfg(feature = "syslog")]
+const SYSLOG_FACILITY_USER: u8 = 1;
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum LoggingError {
+    #[error("Unknown log target \"{0}\"")]
+    UnknownLogTarget(String),
+}
+
+/// Where log records are written to, in addition to the console.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum LogTarget {
+    #[default]
+    Console,
+    EventLog,
+    Syslog,
+}
+
+impl fmt::Display for LogTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogTarget::Console => write!(f, "console"),
+            LogTarget::EventLog => write!(f, "eventlog"),
+            LogTarget::Syslog => write!(f, "syslog"),
+        }
+    }
+}
+
+impl FromStr for LogTarget {
+    type Err = LoggingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let res = match s.trim().to_lowercase().as_str() {
+            "console" => LogTarget::Console,
+            "eventlog" => LogTarget::EventLog,
+            "syslog" => LogTarget::Syslog,
+            _ => return Err(LoggingError::UnknownLogTarget(s.to_string())),
+        };
+
+        Ok(res)
+    }
+}
+
+/// Returns the RFC 5424 severity code of the given severity.
+#[cfg(feature = "syslog")]
+fn syslog_severity(severity: Severity) -> u8 {
+    match severity {
+        Severity::Error => 3,
+        Severity::Warning => 4,
+        Severity::Info => 6,
+        Severity::Debug => 7,
+    }
+}
+
+/// Formats a single RFC 5424 syslog message, without message ID and structured data.
+#[cfg(feature = "syslog")]
+fn format_syslog_message(
+    severity: Severity,
+    timestamp: DateTime<Utc>,
+    hostname: &str,
+    process_id: u32,
+    message: &str,
+) -> String {
+    format!(
+        "<{pri}>1 {timestamp} {hostname} {APP_NAME} {process_id} - - {message}",
+        pri = SYSLOG_FACILITY_USER * 8 + syslog_severity(severity),
+        timestamp = timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+    )
+}
+
+/// Sends RFC 5424 messages over UDP to a local or remote syslog daemon.
+#[cfg(feature = "syslog")]
+struct SyslogSink {
+    socket: UdpSocket,
+    hostname: String,
+}
+
+#[cfg(feature = "syslog")]
+impl SyslogSink {
+    fn connect(address: &str) -> std::io::Result<Self> {
+        let address = address.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Unable to resolve syslog address \"{address}\""),
+            )
+        })?;
+
+        let local_address: SocketAddr = if address.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+
+        let socket = UdpSocket::bind(local_address)?;
+        socket.connect(address)?;
+
+        // The hostname is informational only, the NILVALUE is used when it's unknown
+        let hostname = crate::schedule::hostname()
+            .filter(|name| !name.contains(char::is_whitespace))
+            .unwrap_or_else(|| "-".to_owned());
+
+        Ok(Self { socket, hostname })
+    }
+
+    fn send(&self, severity: Severity, message: &str) {
+        let message = format_syslog_message(
+            severity,
+            Utc::now(),
+            &self.hostname,
+            std::process::id(),
+            message,
+        );
+
+        // There's nowhere left to report a failing log sink
+        let _ = self.socket.send(message.as_bytes());
+    }
+}
+
+/// Writes every record to the console, and forwards them to the selected system log.
+struct Logger {
+    level: LevelFilter,
+
+    #[cfg(feature = "syslog")]
+    syslog: Option<SyslogSink>,
+
+    #[cfg(all(windows, feature = "eventlog"))]
+    eventlog: Option<eventlog::EventLog>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        eprintln!("[{}] {}", record.level(), record.args());
+
+        #[cfg(feature = "syslog")]
+        if let Some(syslog) = &self.syslog {
+            syslog.send(record.level().into(), &record.args().to_string());
+        }
+
+        // Only errors and warnings are worth an entry in the Windows Event Log
+        #[cfg(all(windows, feature = "eventlog"))]
+        if let Some(eventlog) = &self.eventlog {
+            if Severity::from(record.level()) <= Severity::Warning {
+                eventlog.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the global logger, writing to the console and the given target.
+///
+/// A target that can't be initialized, or that this build doesn't include, falls back to the console alone
+/// with a warning, so an unattended run still goes on.
+#[cfg_attr(
+    not(any(feature = "syslog", all(windows, feature = "eventlog"))),
+    allow(unused_mut, unused_variables)
+)]
+pub(crate) fn init(target: LogTarget, syslog_address: &str) {
+    let mut logger = Logger {
+        level: LevelFilter::Info,
+
+        #[cfg(feature = "syslog")]
+        syslog: None,
+
+        #[cfg(all(windows, feature = "eventlog"))]
+        eventlog: None,
+    };
+
+    let mut warning = None;
+
+    match target {
+        LogTarget::Console => {}
+
+        #[cfg(feature = "syslog")]
+        LogTarget::Syslog => match SyslogSink::connect(syslog_address) {
+            Ok(sink) => logger.syslog = Some(sink),
+            Err(e) => warning = Some(format!("Unable to initialize the syslog sink: {e}")),
+        },
+
+        #[cfg(all(windows, feature = "eventlog"))]
+        LogTarget::EventLog => {
+            // Registering the event source requires elevated rights, but it may already be registered
+            if let Err(e) = eventlog::register(APP_NAME) {
+                eprintln!("[WARN] Unable to register the `{APP_NAME}` event source: {e}");
+            }
+
+            match eventlog::EventLog::new(APP_NAME, log::Level::Warn) {
+                Ok(sink) => logger.eventlog = Some(sink),
+                Err(e) => warning = Some(format!("Unable to initialize the Event Log sink: {e}")),
+            }
+        }
+
+        #[allow(unreachable_patterns)]
+        _ => {
+            warning = Some(format!(
+                "The `{target}` log target is not included in this build"
+            ))
+        }
+    }
+
+    let level = logger.level;
+
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(level);
+    }
+
+    if let Some(warning) = warning {
+        log::warn!("{warning}. Logging to the console only");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_target_from_str() {
+        assert_eq!("Syslog".parse::<LogTarget>().unwrap(), LogTarget::Syslog);
+        assert_eq!(
+            "eventlog".parse::<LogTarget>().unwrap(),
+            LogTarget::EventLog
+        );
+        assert!("journald".parse::<LogTarget>().is_err());
+    }
+
+    #[cfg(feature = "syslog")]
+    #[test]
+    fn test_syslog_severity_mapping() {
+        assert_eq!(syslog_severity(log::Level::Error.into()), 3);
+        assert_eq!(syslog_severity(log::Level::Warn.into()), 4);
+        assert_eq!(syslog_severity(log::Level::Info.into()), 6);
+        assert_eq!(syslog_severity(log::Level::Trace.into()), 7);
+    }
+
+    #[cfg(feature = "syslog")]
+    #[test]
+    fn test_syslog_message_sent() {
+        let daemon = UdpSocket::bind("127.0.0.1:0").unwrap();
+        daemon
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+
+        let sink = SyslogSink::connect(&daemon.local_addr().unwrap().to_string()).unwrap();
+        sink.send(Severity::Warning, "Unable to load template file");
+
+        let mut buffer = [0; 1024];
+        let size = daemon.recv(&mut buffer).unwrap();
+        let message = std::str::from_utf8(&buffer[..size]).unwrap();
+
+        // user facility (1) * 8 + warning (4)
+        assert!(message.starts_with("<12>1 "), "{message}");
+        assert!(message.contains(&format!(" {APP_NAME} {} - - ", std::process::id())));
+        assert!(message.ends_with(" Unable to load template file"));
+
+        let timestamp = "2023-01-01T10:20:30.456Z".parse().unwrap();
+        assert_eq!(
+            format_syslog_message(Severity::Error, timestamp, "host", 42, "failed"),
+            "<11>1 2023-01-01T10:20:30.456Z host osa_mailer 42 - - failed"
+        );
+    }
+}
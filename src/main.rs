@@ -1,184 +1,3411 @@
-#[macro_use]
-extern crate lazy_static;
-
 use anyhow::Context;
-use entries::Entry;
+use clap::{CommandFactory, FromArgMatches};
 use lettre::transport::smtp::authentication::Credentials;
-use std::{env, fs, rc::Rc};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    env, fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+};
 
+use crate::context_html::ContextHtmlPolicy;
+use crate::dark_mode::DarkMode;
 use crate::render::{ContextData, TemplateData};
 
 // https://stackoverflow.com/questions/65356683/how-to-mutate-serde-json-value-by-adding-additional-fields
 
+mod aliases;
+mod alternative;
+mod app;
+mod approval;
+mod archive;
+mod audit;
+mod bench;
+mod bounce;
+mod breaker;
+mod bundle;
+mod checkpoint;
+mod cli;
+mod config;
+mod context_html;
+mod dark_mode;
+mod dead_letter;
+mod dedup;
 mod entries;
 mod errors;
+mod exit;
+mod fallback;
+mod guards;
+mod identity;
+mod image_refs;
+#[cfg(feature = "ingest-imap")]
+mod ingest;
+mod large_files;
+mod localtime;
+mod logging;
+mod mime_tree;
+// The producer API of the library, unused by the binary
+#[allow(dead_code)]
+mod outbox;
+mod paths;
+mod policy;
+#[cfg(feature = "remote-images")]
+mod remote_images;
+mod removal_journal;
 mod render;
+mod reply_token;
+mod retention;
+#[cfg(feature = "s3-links")]
+mod s3;
+mod schedule;
+mod schema;
 mod send;
+mod sent_log;
+mod signing;
+mod stats;
+mod telemetry;
+mod templates;
+#[cfg(any(
+    feature = "ingest-imap",
+    feature = "s3-links",
+    feature = "remote-images"
+))]
+mod tls;
+mod tls_policy;
 
 const ENTRY_DIR: &str = "outbox";
-const ENTRY_EXT: &str = ".json";
 const TEMPLATE_DIR: &str = "templates";
 
-fn main() -> anyhow::Result<()> {
-    let current_exe =
-        env::current_exe().context("Unable to get the current binary file from the OS.")?;
+fn main() -> std::process::ExitCode {
+    match run() {
+        Ok(exit_code) => exit_code.into(),
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            exit::ExitCode::of_error(&e).into()
+        }
+    }
+}
+
+/// Runs the command of the arguments, returning the exit code of the contract in [`exit::EXIT_CODES_HELP`].
+fn run() -> anyhow::Result<exit::ExitCode> {
+    // Without a verbatim `\\?\` prefix, e.g. when started from a UNC share
+    let current_exe = paths::simplified(
+        env::current_exe().context("Unable to get the current binary file from the OS.")?,
+    );
     let current_exe_dir = current_exe
         .parent()
         .context("Unable to get current binary file directory")?;
 
-    let entries_path = current_exe_dir.join(ENTRY_DIR);
+    run_from(env::args_os(), current_exe_dir, None)
+}
 
-    let entry_parse_results = entries::load_entries(entries_path, ENTRY_EXT);
+/// Runs the command of `args` with the files of `current_exe_dir`, see [`run`]. The E-mails are sent over
+/// `transport` when given, rather than the transport of the arguments.
+fn run_from<I, T>(
+    args: I,
+    current_exe_dir: &Path,
+    transport: Option<Box<dyn send::Transport>>,
+) -> anyhow::Result<exit::ExitCode>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let matches = match cli::Cli::command().try_get_matches_from(args) {
+        Ok(matches) => matches,
+        Err(e) => return Ok(cli_exit(e)),
+    };
+    let mut cli = match cli::Cli::from_arg_matches(&matches) {
+        Ok(cli) => cli,
+        Err(e) => return Ok(cli_exit(e)),
+    };
+    let (file_config, config_problem) = load_checked_config(&cli)?;
+    file_config.apply(&mut cli, &matches);
+    let effective_config = || {
+        config::EffectiveConfig::new(&cli::Cli::command(), &matches, &file_config, |name| {
+            env::var(name).ok()
+        })
+    };
+    let run_started_at = chrono::Utc::now();
+    let deadline = schedule::Deadline::new(std::time::Instant::now(), cli.max_run_time);
 
-    eprintln!("Entry parsing errors: {:?}", entry_parse_results.err);
+    logging::init(cli.log_target, &cli.syslog_address);
 
-    let entries_pool = entry_parse_results.ok;
+    #[cfg(feature = "otel")]
+    let telemetry = telemetry::Telemetry::init(cli.otlp_endpoint.as_deref());
+    #[cfg(not(feature = "otel"))]
+    let telemetry = telemetry::Telemetry::default();
+    let run_span = telemetry.span("run");
 
-    let emails_map = entries::map_emails(&entries_pool); // Each E-Mail ID with its E-mail contents, in order
+    let entries_path = match &cli.outbox {
+        Some(outbox) => outbox.clone(),
+        None => current_exe_dir.join(ENTRY_DIR),
+    };
+    let templates_path = match &cli.templates {
+        Some(templates) => templates.clone(),
+        None => current_exe_dir.join(TEMPLATE_DIR),
+    };
+    let resources_path = current_exe_dir.join(entries::RESOURCES_DIR);
 
-    let composed_emails = entries::compose_emails(&emails_map);
+    if let Some(cli::Command::Config(command)) = &cli.command {
+        match command {
+            cli::ConfigCommand::Show {
+                format,
+                redact,
+                unsafe_show_secrets,
+            } => {
+                let mut config = effective_config();
+                config.redact(match (redact, unsafe_show_secrets) {
+                    (true, _) => config::Redaction::Identifying,
+                    (_, true) => config::Redaction::Nothing,
+                    _ => config::Redaction::Secrets,
+                });
+                print!("{}", config.render(*format));
+            }
+            cli::ConfigCommand::Check => {
+                let problems: Vec<_> = config_problem
+                    .into_iter()
+                    .chain(check_config(
+                        &cli,
+                        current_exe_dir,
+                        &entries_path,
+                        &templates_path,
+                    ))
+                    .collect();
 
-    println!(
-        "composed_emails = {}",
-        serde_json::to_string_pretty(&composed_emails).unwrap() // TODO: Replace with ErrorReport
-    );
+                for problem in &problems {
+                    log::error!("{:?}", problem);
+                }
 
-    let templates_path = current_exe_dir.join(TEMPLATE_DIR);
+                if !problems.is_empty() {
+                    anyhow::bail!("{} configuration error(s)", problems.len());
+                }
 
-    // TODO: Make static and use CLI ARGUMENTS instead
-    let server = env::var("SERVER").unwrap_or_else(|_| "localhost".to_string());
-    let port: u16 = env::var("PORT")
-        .unwrap_or_else(|_| "25".to_string())
-        .parse()?;
+                println!("The configuration is valid");
+            }
+        }
 
-    let auth: send::Authentication = env::var("AUTH")
-        .unwrap_or_else(|_| "noauth".to_string())
-        .parse()?;
+        return Ok(exit::ExitCode::Success);
+    }
 
-    // Establish one connection to send all E-mails
-    println!("Mail-Relay: \"{server}:{port}\" [{auth}]");
-    let mut connection = send::Connection::new(&server, port, auth);
+    if let Some(cli::Command::NewTemplate { name }) = &cli.command {
+        for path in templates::scaffold(&templates_path, name)? {
+            println!("Created \"{}\"", path.display());
+        }
 
-    let credentials: Option<Credentials> = match (env::var("USERNAME"), env::var("PASSWORD")) {
-        (Ok(username), Ok(password)) => Some(Credentials::new(username, password)),
-        _ => None,
-    };
+        return Ok(exit::ExitCode::Success);
+    }
 
-    connection.establish(credentials);
+    let quarantine = dead_letter::Quarantine::new(
+        &entries_path,
+        cli.failed_dir.as_deref(),
+        cli.max_send_attempts,
+    )?;
+    let error_report_dir = app::error_report_dir(
+        &entries_path,
+        current_exe_dir,
+        cli.error_report_dir.as_deref(),
+    )?;
 
-    for email in composed_emails {
-        let email_template_images_root = templates_path.join(&email.header.template);
+    if let Some(cli::Command::RequeueFailed {
+        filter_template,
+        dry_run,
+    }) = cli.command
+    {
+        let decisions = dead_letter::requeue_failed(
+            &quarantine,
+            &templates_path,
+            filter_template.as_deref(),
+            dry_run,
+        )?;
 
-        let email_template_path: render::AbsolutePath =
-            email_template_images_root.join("template.html").into();
+        for decision in &decisions {
+            println!("{decision}");
+        }
 
-        let template_data = TemplateData {
-            contents: {
-                let contents = fs::read_to_string(&email_template_path).with_context(|| {
-                    format!(
-                        "Unable to load template file \"{}\"",
-                        email_template_path.display()
-                    )
-                })?;
-                Rc::new(contents)
-            },
-            file_path: { Some(&email_template_path) },
-        };
+        let conflicts = decisions
+            .iter()
+            .filter(|decision| matches!(decision.outcome, dead_letter::RequeueOutcome::Conflict(_)))
+            .count();
+        if conflicts > 0 {
+            log::error!("{conflicts} entries not requeued, as the outbox already holds an entry of the same path");
+            return Ok(exit::ExitCode::PartialFailure);
+        }
+
+        return Ok(exit::ExitCode::Success);
+    }
+
+    if let Some(cli::Command::ResolveBounce { token }) = &cli.command {
+        let bounce_log_path = current_exe_dir.join(bounce::BOUNCE_LOG_FILE);
 
-        let context_data = ContextData {
-            context: serde_json::Value::Object(email.context.clone()),
-            file_path: None,
+        let Some(record) = bounce::resolve(&bounce_log_path, token, chrono::Utc::now())? else {
+            anyhow::bail!("Unknown or expired bounce token `{token}`");
         };
 
-        let rendered_template_result = render::render(
-            &template_data,
-            &context_data,
-            render::DetectionMethod::Auto,
-            render::TemplateExtension::Auto,
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&record)
+                .context("Unable to serialize the bounce record")?
         );
+        return Ok(exit::ExitCode::Success);
+    }
 
-        match rendered_template_result {
-            Ok(rendered_template) => {
-                let html_payload = rendered_template.0;
+    if let Some(cli::Command::VerifyArchive { dir }) = &cli.command {
+        let Some(archive_dir) = dir.as_ref().or(cli.archive_cas.as_ref()) else {
+            anyhow::bail!("No audit archive to verify, see `--archive-cas`");
+        };
 
-                let to = email.header.to.join(", ");
-                let cc = email.header.cc.join(", ");
-                let bcc = email.header.bcc.join(", ");
-                let reply_to = email.header.reply_to.join(", ");
-                let attachments = email.header.attachments.join(", ");
+        let findings = audit::verify(archive_dir)?;
+        for finding in &findings {
+            println!("{finding}");
+        }
 
-                // Build E-mail
-                // let message = send::Message::new()
-                //     .from(&email.header.from)
-                //     .to_addresses(&to)
-                //     .cc_addresses(&cc)
-                //     .bcc_addresses(&bcc)
-                //     .reply_to_addresses(&reply_to)
-                //     .subject(&email.header.subject)
-                //     .alternative_content(&email.header.alternative_content)
-                //     .content(&html_payload, Some(&email_template_images_root))
-                //     .attachments(&attachments);
+        if !findings.is_empty() {
+            anyhow::bail!(
+                "{} problem(s) found in the audit archive \"{}\"",
+                findings.len(),
+                archive_dir.display()
+            );
+        }
 
-                let message = match send::MessageBuilder::new()
-                    .from(&email.header.from)
-                    .to_addresses(&to)
-                    .cc_addresses(&cc)
-                    .bcc_addresses(&bcc)
-                    .reply_to_addresses(&reply_to)
-                    .subject(&email.header.subject)
-                    .alternative_content(&email.header.alternative_content)
-                    .content(&html_payload, Some(&email_template_images_root))
-                    .attachments(&attachments)
-                    .build()
-                {
-                    Ok(v) => v,
-                    Err(e) => {
-                        eprintln!("{:?}", e);
-                        continue;
-                    }
-                };
+        println!("The audit archive is intact");
+        return Ok(exit::ExitCode::Success);
+    }
 
-                // Lower privilege.
-                // let connection = connection;
+    if let Some(cli::Command::Schema { format }) = &cli.command {
+        let schema = schema::entry_schema();
 
-                // Convert to Lettre Message & Send E-mail
-                let message = match message.try_into() {
-                    Ok(v) => v,
-                    Err(e) => {
-                        eprintln!("{:?}", e);
-                        continue;
+        match format {
+            schema::SchemaFormat::JsonSchema => print!("{}", schema::to_json(&schema)),
+            schema::SchemaFormat::Markdown => print!("{}", schema::to_markdown(&schema)),
+        }
+        return Ok(exit::ExitCode::Success);
+    }
+
+    let stats_path = current_exe_dir.join(stats::STATS_FILE);
+
+    if let Some(cli::Command::Stats {
+        by_template,
+        month,
+        format,
+    }) = &cli.command
+    {
+        let month = match month {
+            Some(month) => month.parse()?,
+            None => stats::Month::of(chrono::Utc::now()),
+        };
+
+        let summary = stats::monthly(&stats_path, month, *by_template)?;
+        print!("{}", stats::render(&summary, *format));
+        return Ok(exit::ExitCode::Success);
+    }
+
+    if let Some(cli::Command::Bench {
+        entries,
+        template,
+        batch_ratio,
+        context,
+        outbox,
+        format,
+    }) = &cli.command
+    {
+        // Synthesized into a temporary outbox, removed along with its entries
+        let temp_outbox;
+        let outbox_path = match (outbox, template) {
+            (Some(outbox), _) => outbox.clone(),
+            (None, Some(template)) => {
+                let context = match context {
+                    Some(path) => {
+                        serde_json::from_str(&fs::read_to_string(path).with_context(|| {
+                            format!("Unable to read bench context \"{}\"", path.display())
+                        })?)
+                        .with_context(|| {
+                            format!("Unable to parse bench context \"{}\"", path.display())
+                        })?
                     }
+                    None => Default::default(),
                 };
 
-                match connection.send(message) {
-                    Ok(_) => {
-                        println!("Email sent successfully!");
-
-                        // Get E-mail ID, retrieve its Entries and remove them
-                        if let Some(email_entries) = emails_map.get(&email.id) {
-                            for entry in email_entries {
-                                if let Some(ref entry_path) = entry.path {
-                                    // FIXME: Handle case for removal failure (maybe use in-memory blacklist that both ignores the entry and tries to remove it)
-                                    let _ = fs::remove_file(entry_path);
-                                }
-                            }
-                        }
-                    }
-                    // Sending failure
-                    Err(e) => {
-                        eprintln!("{e}");
-                        continue;
+                temp_outbox = tempfile::tempdir().context("Unable to create the bench outbox")?;
+                let synthesis = bench::Synthesis {
+                    entries: *entries,
+                    template: template.clone(),
+                    batch_ratio: *batch_ratio,
+                    context,
+                };
+                let emails = bench::synthesize(temp_outbox.path(), &templates_path, &synthesis)?;
+                println!("Synthesized {entries} entries of {emails} E-mail(s)");
+
+                temp_outbox.path().to_owned()
+            }
+            (None, None) => unreachable!("A template is required without an outbox"),
+        };
+
+        let verifier = load_verifier(&cli)?;
+        let report = bench::run(
+            &outbox_path,
+            &templates_path,
+            &resources_path,
+            &verifier,
+            |email, template_configs| {
+                render_attachments(email, &templates_path, template_configs)?;
+                Ok(render_email(email, &templates_path, template_configs)?.0)
+            },
+        )?;
+
+        print!("{}", bench::render(&report, *format));
+        return Ok(exit::ExitCode::Success);
+    }
+
+    let approval_path = current_exe_dir.join(approval::APPROVAL_DIR);
+
+    if let Some(cli::Command::Approve {
+        email_id,
+        all: _,
+        list,
+    }) = &cli.command
+    {
+        if *list {
+            for (_, manifest) in approval::parked(&approval_path)? {
+                println!("{manifest}");
+            }
+            return Ok(exit::ExitCode::Success);
+        }
+
+        let approved = approval::approve(&approval_path, email_id.as_deref(), chrono::Utc::now())?;
+
+        if approved.is_empty() {
+            anyhow::bail!("No parked E-mail to approve");
+        }
+
+        for manifest in &approved {
+            println!("Approved {manifest}");
+        }
+    }
+
+    if let Some(cli::Command::Deny { email_id, reason }) = &cli.command {
+        let denied = approval::deny(&approval_path, email_id)?;
+
+        if denied.is_empty() {
+            anyhow::bail!("No parked E-mail `{email_id}`");
+        }
+
+        for manifest in &denied {
+            fail_entries(manifest.entries.iter(), &quarantine, reason, true);
+            println!("Denied {manifest}");
+        }
+
+        return Ok(exit::ExitCode::Success);
+    }
+
+    #[cfg(feature = "ingest-imap")]
+    if let Some(cli::Command::IngestImap(args)) = &cli.command {
+        let retain = || {
+            if let Err(e) = apply_retention(
+                &cli,
+                current_exe_dir,
+                &entries_path,
+                &templates_path,
+                chrono::Utc::now(),
+            ) {
+                log::error!("{:?}", e);
+            }
+        };
+        ingest_imap(
+            args,
+            &entries_path,
+            &templates_path,
+            &cli.relay(),
+            cli.relay_credentials(),
+            &cli.subject_tag(),
+            &retain,
+        )?;
+        return Ok(exit::ExitCode::Success);
+    }
+
+    if let Some(path) = &cli.config_snapshot {
+        let mut config = effective_config();
+        config.redact(config::Redaction::Secrets);
+        config.write_snapshot(path)?;
+    }
+
+    let subject_tag = cli.subject_tag();
+
+    // Organization wide headers, appended to every E-mail
+    let global_headers = match &cli.include_headers_file {
+        Some(path) => send::load_headers_file(path)?,
+        None => send::CustomHeaders::new(),
+    };
+
+    // Reported only, the E-mails of a template with a missing image fail on their own
+    if cli.check_template_assets {
+        for problem in template_asset_problems(&templates_path) {
+            log::error!("{:?}", problem);
+        }
+    }
+
+    // Read on every run, so membership changes apply without touching the producers
+    let alias_book = load_aliases(&cli)?;
+
+    let tls_policies = load_tls_policies(&cli)?;
+
+    let mut app_state = app::AppState::default();
+
+    // Before anything is written, so nothing of this run is pruned
+    for pruned in apply_retention(
+        &cli,
+        current_exe_dir,
+        &entries_path,
+        &templates_path,
+        run_started_at,
+    )? {
+        app_state.record_pruned(pruned);
+    }
+
+    let verifier = load_verifier(&cli)?;
+
+    let mut load_span = run_span.child("load_entries");
+    let entry_parse_results = entries::load_entries(&entries_path, &verifier);
+    load_span.set_attribute("entries", entry_parse_results.ok.len());
+    load_span.set_attribute("errors", entry_parse_results.err.len());
+    load_span.set_attribute("rejected", entry_parse_results.rejected.len());
+
+    // Neither composing nor a dry run changes the outbox
+    let keep_outbox = cli.compose_only || cli.dry_run;
+
+    if !entry_parse_results.err.is_empty() {
+        log::error!("Entry parsing errors: {:?}", entry_parse_results.err);
+        load_span.record_error(&format!("{:?}", entry_parse_results.err));
+
+        // An entry that fails to parse fails again on every run, until it's fixed and requeued
+        if !(keep_outbox || cli.keep_unparsable) {
+            for parse_error in &entry_parse_results.err {
+                match entries::quarantine_unparsed(&quarantine, parse_error, chrono::Utc::now()) {
+                    Ok(Some(path)) => {
+                        log::warn!("Moved the unparsable entry to \"{}\"", path.display())
                     }
+                    Ok(None) => {}
+                    Err(e) => log::error!("{:?}", e),
                 }
             }
+        }
+    }
 
-            // Rendering failure
+    for parse_error in entry_parse_results.err {
+        let report = errors::ErrorReport::new()
+            .set_context(parse_error.entry())
+            .add_entry(None, parse_error.path().map(Path::to_path_buf))
+            .add_error(parse_error.error);
+        app_state.add_error_report(app::FailureCategory::Entry, report);
+    }
+
+    // Anyone able to write into the outbox could send as a producer, so a bad signature is never retried
+    for rejected in entry_parse_results.rejected {
+        let reason = format!("SECURITY: {}", rejected.error);
+        log::error!("The entry \"{}\" was rejected. {reason}", rejected.id);
+
+        if !keep_outbox {
+            fail_entries(rejected.path.iter(), &quarantine, &reason, true);
+        }
+
+        let report = errors::ErrorReport::new()
+            .set_context(format!("The entry \"{}\" was rejected", rejected.id))
+            .add_entry(None, rejected.path)
+            .add_error(rejected.error);
+        app_state.add_error_report(app::FailureCategory::Entry, report);
+    }
+    load_span.end();
+
+    let sent_entries = if cli.archive_sent {
+        let archive_root = entries_path
+            .parent()
+            .unwrap_or(&entries_path)
+            .join(entries::SENT_DIR);
+        removal_journal::SentEntries::Archive {
+            outbox_path: entries_path.clone(),
+            archive_root,
+        }
+    } else {
+        removal_journal::SentEntries::Remove
+    };
+
+    // Leftovers of E-mails sent by a previous run, which couldn't be removed, are never composed again
+    let removal_journal_path = current_exe_dir.join(removal_journal::REMOVAL_JOURNAL_FILE);
+    let mut removal_journal =
+        removal_journal::RemovalJournal::load(&removal_journal_path).context(exit::StateError)?;
+    let leftovers = removal_journal.sort_out(entry_parse_results.ok);
+
+    for path in &leftovers.unconfirmed {
+        log::warn!(
+            "The entry \"{}\" may have been sent by an interrupted run, sending it again",
+            path.display()
+        );
+    }
+
+    if !leftovers.sent.is_empty() {
+        println!(
+            "Skipped {} entry file(s) of sent E-mails",
+            leftovers.sent.len()
+        );
+
+        if !keep_outbox {
+            removal_journal::remove_leftovers(&leftovers.sent, &sent_entries);
+        }
+    }
+
+    let entries_pool = leftovers.pending;
+
+    // Changing the identity fields changes which entries are batched together, and the E-mail IDs
+    let identity = load_identity(&cli, &templates_path, &entries_pool);
+    println!("Identity fields: {identity}");
+
+    // Each E-Mail ID with its E-mail contents, in order
+    let mut emails_map =
+        entries::map_emails_with(&entries_pool, |entry| identity.email_id(&entry.entry));
+
+    if let Some(window) = cli.batch_window {
+        let batch_window = entries::BatchWindow {
+            window: chrono::Duration::seconds(window as i64),
+            max_hold: chrono::Duration::seconds(cli.batch_max_hold as i64),
+        };
+
+        let held = batch_window.hold_back(&mut emails_map, chrono::Utc::now());
+        if held > 0 {
+            println!("Held back {held} E-mail(s) within the batch window");
+        }
+    }
+
+    for (email_id, fields) in identity.conflicts(&emails_map) {
+        let template = emails_map[&email_id][0].entry.template().to_owned();
+        let reason = format!(
+            "The entries of the E-mail differ by `{}`, outside its identity fields",
+            fields.join("`, `")
+        );
+
+        match identity.config(&template).conflict.unwrap_or_default() {
+            identity::IdentityConflict::FirstWins => {
+                log::warn!("E-mail `{email_id:08x}`: {reason}, those of its first entry are sent")
+            }
+            identity::IdentityConflict::Error => {
+                log::error!("E-mail `{email_id:08x}`: {reason}");
+                let entries = emails_map.remove(&email_id).unwrap_or_default();
+                if !keep_outbox {
+                    let entry_paths = entries.iter().filter_map(|entry| entry.path.as_ref());
+                    fail_entries(entry_paths, &quarantine, &reason, true);
+                }
+                app_state.record_failed();
+            }
+        }
+    }
+
+    let mut compose_span = run_span.child("compose");
+    let compose_results = entries::compose_emails(&emails_map);
+    compose_span.set_attribute("emails", compose_results.ok.len());
+    compose_span.set_attribute("errors", compose_results.err.len());
+
+    // An ambiguous context is the same on every run, so its entries are not kept for the next one
+    for failure in &compose_results.err {
+        let reason = failure.error.to_string();
+        log::error!("E-mail `{:08x}`: {reason}", failure.email_id);
+        compose_span.record_error(&reason);
+
+        if !keep_outbox {
+            fail_entries(failure.entry_paths.iter(), &quarantine, &reason, true);
+        }
+        app_state.record_failed();
+    }
+    compose_span.end();
+
+    let composed_emails = compose_results.ok;
+
+    if cli.compose_only {
+        let output = entries::canonical_json(&composed_emails);
+
+        match &cli.compose_output {
+            Some(path) => fs::write(path, output).with_context(|| {
+                format!("Unable to write composed E-mails to \"{}\"", path.display())
+            })?,
+            None => print!("{output}"),
+        }
+
+        return Ok(exit::ExitCode::Success);
+    }
+
+    // Raw E-mails are sent without a templates directory, the others are kept in the outbox until it exists
+    let templates_missing = match templates::require_root(&templates_path, &composed_emails) {
+        Ok(()) => false,
+        Err(e) => {
+            log::error!("{:?}", e);
+            true
+        }
+    };
+
+    // Split oversized accumulated arrays into numbered follow-up E-mails, as configured per template
+    let mut paged_emails = Vec::new();
+    let mut template_configs: HashMap<String, templates::TemplateConfig> = HashMap::new();
+
+    for mut email in composed_emails {
+        match alias_book.expand(&mut email.header) {
+            Ok(expansions) => {
+                for expansion in expansions {
+                    println!("E-mail `{:08x}`: {expansion}", email.id);
+                }
+            }
             Err(e) => {
-                eprintln!("{:?}", e);
+                log::error!("E-mail `{:08x}`: {e}", email.id);
+                let entry_paths = entries::entries_of(&email, &emails_map)
+                    .filter_map(|entry| entry.path.as_ref());
+                if !keep_outbox {
+                    fail_entries(entry_paths, &quarantine, &e.to_string(), true);
+                }
+                app_state.record_failed();
                 continue;
             }
         }
-    } // Each E-mail
 
-    Ok(())
+        let duplicates = email.header.dedup_recipients();
+        if !duplicates.warnings().is_empty() {
+            log::warn!(
+                "{}",
+                duplicates.set_context(format!("E-mail `{:08x}`", email.id))
+            );
+        }
+
+        // Every malformed address is reported at once, before any connection, rather than one failed send at a time
+        let invalid_addresses = email.header.validate_addresses();
+        if !invalid_addresses.is_empty() {
+            let entries = entries::entries_of(&email, &emails_map);
+            let report = entries
+                .clone()
+                .fold(invalid_addresses, |report, entry| {
+                    report.add_entry(Some(entry.entry.id().to_owned()), entry.path.clone())
+                })
+                .set_context(format!("E-mail `{:08x}` has invalid addresses", email.id));
+            let reason = report.to_string();
+            log::error!("{reason}");
+
+            if !keep_outbox {
+                let entry_paths = entries.filter_map(|entry| entry.path.as_ref());
+                fail_entries(entry_paths, &quarantine, &reason, true);
+            }
+            app_state.record_failed();
+            app_state.add_error_report(app::FailureCategory::Entry, report);
+            continue;
+        }
+
+        // Rendered by their producer, without a template to configure the pages
+        if email.header.is_raw() {
+            paged_emails.push(email);
+            continue;
+        }
+
+        if templates_missing {
+            app_state.record_failed();
+            continue;
+        }
+
+        let template = &email.header.template;
+
+        if !template_configs.contains_key(template) {
+            match templates::TemplateConfig::load(templates_path.join(template)) {
+                Ok(v) => {
+                    if v.context_html_policy == ContextHtmlPolicy::Trust {
+                        log::warn!(
+                            "Template `{template}` renders the context of its entries unescaped, with `context_html_policy = \"trust\"`"
+                        );
+                    }
+                    template_configs.insert(template.clone(), v)
+                }
+                Err(e) => {
+                    log::error!("{:?}", e);
+                    app_state.record_failed();
+                    continue;
+                }
+            };
+        }
+
+        let page_sizes = &template_configs[template].page_size;
+
+        // Pages sent by a previous run, which stopped before the last one, aren't sent again
+        let entry_paths =
+            entries::entries_of(&email, &emails_map).filter_map(|entry| entry.path.as_deref());
+        let delivered_pages = dead_letter::delivered_pages(entry_paths).unwrap_or_else(|e| {
+            log::warn!("{:?}", e);
+            Default::default()
+        });
+
+        for mut page in entries::paginate(email, page_sizes) {
+            if let Some(number) = page
+                .page
+                .map(|page| page.number)
+                .filter(|number| delivered_pages.contains(number))
+            {
+                println!(
+                    "E-mail `{}`: page {number} was already sent",
+                    archive::archive_stem(&page)
+                );
+                continue;
+            }
+
+            let collisions = entries::add_values_views(&mut page.context);
+            if !collisions.warnings().is_empty() {
+                log::warn!(
+                    "{}",
+                    collisions.set_context(format!("E-mail `{}`", archive::archive_stem(&page)))
+                );
+            }
+
+            if let Err(e) = render_attachments(&mut page, &templates_path, &template_configs) {
+                log::error!("E-mail `{}`: {:?}", archive::archive_stem(&page), e);
+                app_state.record_failed();
+                continue;
+            }
+
+            paged_emails.push(page);
+        }
+    }
+
+    let composed_emails = paged_emails;
+
+    if let Some(cli::Command::Diff {
+        against,
+        output,
+        ignore_whitespace,
+    }) = &cli.command
+    {
+        fs::create_dir_all(output)
+            .with_context(|| format!("Unable to create diff directory \"{}\"", output.display()))?;
+
+        let mut summary = archive::DiffSummary::default();
+
+        for mut email in composed_emails {
+            let html = match render_email(&mut email, &templates_path, &template_configs) {
+                Ok((html, _)) => html,
+                Err(e) => {
+                    log::error!("{:?}", e);
+                    continue;
+                }
+            };
+
+            let email_diff = archive::diff_email(against, &email, &html, *ignore_whitespace)?;
+            summary.add(email_diff.outcome);
+
+            if let Some(diff) = email_diff.diff {
+                let diff_path = output
+                    .join(&email_diff.stem)
+                    .with_extension(archive::DIFF_EXT);
+                fs::write(&diff_path, diff)
+                    .with_context(|| format!("Unable to write diff \"{}\"", diff_path.display()))?;
+            }
+
+            println!("{:?}: {}", email_diff.outcome, email_diff.stem);
+        }
+
+        println!("{summary}");
+        return Ok(exit::ExitCode::Success);
+    }
+
+    if let Some(cli::Command::Lint { schema }) = &cli.command {
+        let mut failed_emails = 0;
+
+        let invalid_entries = if *schema {
+            lint_entry_schema(&entries_path)
+        } else {
+            0
+        };
+
+        for mut email in composed_emails.iter().cloned() {
+            let passed = match render_email(&mut email, &templates_path, &template_configs) {
+                Ok((html, _)) => check_policy(&email, &html, &template_configs),
+                Err(e) => {
+                    log::error!("{:?}", e);
+                    false
+                }
+            };
+
+            if !passed {
+                failed_emails += 1;
+            }
+        }
+
+        println!(
+            "{} E-mail(s) checked, {failed_emails} failed",
+            composed_emails.len()
+        );
+
+        let missing_images = template_asset_problems(&templates_path);
+        for problem in &missing_images {
+            log::error!("{:?}", problem);
+        }
+
+        // Normalized when loaded, so only reported for the authors to fix the source
+        if templates_path.is_dir() {
+            match templates::check_all_sources(&templates_path) {
+                Ok(all_findings) => {
+                    for (file, findings) in all_findings {
+                        for finding in findings {
+                            log::warn!("Template file `{file}`: {finding}");
+                        }
+                    }
+                }
+                Err(e) => log::error!("{:?}", e),
+            }
+        }
+
+        if invalid_entries > 0 {
+            anyhow::bail!("{invalid_entries} entry file(s) don't match the entry schema");
+        }
+
+        if failed_emails > 0 {
+            anyhow::bail!("{failed_emails} E-mail(s) failed the content policy");
+        }
+
+        if !missing_images.is_empty() {
+            anyhow::bail!(
+                "{} image(s) referenced by the templates are missing",
+                missing_images.len()
+            );
+        }
+
+        return Ok(exit::ExitCode::Success);
+    }
+
+    println!(
+        "composed_emails = {}",
+        entries::canonical_json(&composed_emails) // TODO: Replace with ErrorReport
+    );
+
+    if cli.verify_assets {
+        let mut failed_emails = 0;
+
+        for mut email in composed_emails.iter().cloned() {
+            let context = format!("E-mail `{}`", archive::archive_stem(&email));
+
+            let report = match render_email(&mut email, &templates_path, &template_configs) {
+                Ok((html, _)) => send::verify_assets(
+                    &html,
+                    Some(
+                        &email
+                            .header
+                            .resources_path(&templates_path, &resources_path),
+                    ),
+                    &email.header.attachments.join(", "),
+                ),
+                Err(e) => errors::ErrorReport::new().add_error(errors::ErrorWrapper(e)),
+            };
+
+            if !report.is_empty() {
+                failed_emails += 1;
+                log::error!("{}", report.set_context(context));
+            }
+        }
+
+        if failed_emails > 0 {
+            anyhow::bail!(
+                "{failed_emails} E-mail(s) failed the asset verification, nothing was sent"
+            );
+        }
+    }
+
+    // Sleeps are cut short on Ctrl+C or SIGTERM, and the remaining E-mails are kept for the next run
+    let shutdown = Arc::new(schedule::Shutdown::default());
+
+    if cli.splay.is_some() || cli.send_delay > 0 || cli.send_retries > 0 {
+        let handler_shutdown = shutdown.clone();
+
+        if let Err(e) = ctrlc::set_handler(move || handler_shutdown.request()) {
+            log::warn!("Unable to install the shutdown signal handler: {e}");
+        }
+    }
+
+    // Spread the start of cron-driven runs across a fleet. There's nothing to wait for without E-mails
+    if let Some(max) = cli
+        .splay
+        .filter(|_| !cli.dry_run && !composed_emails.is_empty())
+    {
+        let splay = schedule::splay_duration(
+            std::time::Duration::from_secs(max),
+            cli.splay_mode,
+            schedule::hostname().as_deref(),
+        );
+
+        log::info!(
+            "Splay ({}): waiting {:.1} seconds before sending",
+            cli.splay_mode,
+            splay.as_secs_f64()
+        );
+
+        if !shutdown.sleep(splay) {
+            log::warn!("Shutdown requested during the splay, nothing was sent");
+            return Ok(exit::ExitCode::Interrupted);
+        }
+    }
+
+    let relay = cli.relay();
+
+    // Only a mail relay connection can be held to the TLS policies of the recipient domains
+    let relay_encrypted =
+        (cli.transport == send::TransportKind::Smtp).then(|| relay.auth().is_encrypted());
+
+    let transport: Box<dyn send::Transport + '_> = match (transport, cli.transport) {
+        (Some(transport), _) => transport,
+        (None, send::TransportKind::Smtp) => {
+            // Establish one connection to send all E-mails
+            println!(
+                "Mail-Relay: \"{}:{}\" [{}], timeout {}s {}",
+                relay.relay(),
+                relay.port(),
+                relay.auth(),
+                relay.timeout().as_secs(),
+                effective_config().sources(&["server", "port", "auth", "timeout"])
+            );
+            let mode = if cli.dry_run {
+                send::ConnectionMode::DryRun
+            } else {
+                send::ConnectionMode::Live
+            };
+            let connection = send::Connection::new(relay).mode(mode);
+            match load_dkim(&cli)? {
+                Some(dkim) => {
+                    println!(
+                        "DKIM: selector `{}` of `{}`",
+                        cli.dkim_selector.as_deref().unwrap_or_default(),
+                        cli.dkim_domain.as_deref().unwrap_or_default()
+                    );
+                    Box::new(connection.dkim(dkim))
+                }
+                None => Box::new(connection),
+            }
+        }
+        (None, send::TransportKind::File) => {
+            println!("Transport: file \"{}\"", cli.transport_dir.display());
+            Box::new(send::FileTransport::new(&cli.transport_dir))
+        }
+        (None, send::TransportKind::Sendmail) => {
+            println!("Transport: sendmail `{}`", cli.sendmail_command.display());
+            Box::new(send::SendmailTransport::new(&cli.sendmail_command))
+        }
+        (None, send::TransportKind::Null) => {
+            println!("Transport: null, E-mails are discarded");
+            Box::new(send::NullTransport)
+        }
+    };
+
+    // Deferred E-mails and dropped connections are retried before the E-mail fails
+    let mut transport: Box<dyn send::Transport + '_> = Box::new(
+        send::RetryingTransport::new(transport)
+            .max_retries(cli.send_retries)
+            .base_delay(std::time::Duration::from_millis(cli.retry_delay))
+            .multiplier(cli.retry_multiplier)
+            .shutdown(shutdown.clone()),
+    );
+
+    transport.establish(
+        cli.relay_credentials()
+            .map(|(username, password)| Credentials::new(username, password)),
+    )?;
+
+    // Rather than render the whole outbox only to fail on the first E-mail. An idle run has nothing to send.
+    if !cli.skip_connection_test && !composed_emails.is_empty() {
+        transport.test_connection().context(
+            "The connection test failed, nothing was sent (see `--skip-connection-test`)",
+        )?;
+    }
+
+    let attachment_encoding = if cli.attachment_encoding.requires_relay_support() {
+        let capabilities = transport.relay_capabilities().unwrap_or_else(|e| {
+            log::warn!("Unable to query the mail relay capabilities, assuming none: {e:?}");
+            send::RelayCapabilities::default()
+        });
+        cli.attachment_encoding.negotiate(&capabilities)
+    } else {
+        cli.attachment_encoding
+    };
+    println!("Attachment encoding: {attachment_encoding}");
+
+    let image_fetcher = remote_image_fetcher(&cli)?;
+    let message_settings = MessageSettings {
+        subject_tag: &subject_tag,
+        image_fetcher: image_fetcher.as_deref(),
+        attachment_encoding,
+        global_headers: &global_headers,
+        long_header_policy: cli.long_header_policy,
+        message_id_domain: cli.message_id_domain.as_deref(),
+    };
+
+    if cli.dry_run {
+        if let Some(dir) = &cli.dry_run_dir {
+            fs::create_dir_all(dir).with_context(|| {
+                format!(
+                    "Unable to create the dry run directory \"{}\"",
+                    dir.display()
+                )
+            })?;
+        }
+
+        let bundle_dir = tempfile::tempdir()
+            .context("Unable to create the directory of the attachment bundles")?;
+
+        for mut email in composed_emails {
+            let email_stem = archive::archive_stem(&email);
+
+            let formatted = match dry_run_message(
+                &mut email,
+                bundle_dir.path(),
+                &templates_path,
+                &resources_path,
+                &template_configs,
+                &message_settings,
+            ) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::error!("E-mail `{email_stem}`: {e:?}");
+                    app_state.record_failed();
+
+                    let report = entries::entries_of(&email, &emails_map)
+                        .fold(errors::ErrorReport::new(), |report, entry| {
+                            report.add_entry(Some(entry.entry.id().to_owned()), entry.path.clone())
+                        })
+                        .set_context(format!("E-mail `{email_stem}` failed to build"))
+                        .add_error(errors::ErrorWrapper(e));
+                    app_state.add_error_report(app::FailureCategory::Build, report);
+                    continue;
+                }
+            };
+
+            match &cli.dry_run_dir {
+                Some(dir) => {
+                    let path = dir.join(&email_stem).with_extension("eml");
+                    fs::write(&path, &formatted)
+                        .with_context(|| format!("Unable to write \"{}\"", path.display()))?;
+                    println!("E-mail `{email_stem}` written to \"{}\"", path.display());
+                }
+                None => {
+                    println!("E-mail `{email_stem}`:");
+                    println!("{}", String::from_utf8_lossy(&formatted));
+                }
+            }
+            app_state.record_sent();
+        }
+
+        println!(
+            "Dry run: {} message(s) built, {} failed, nothing was sent",
+            app_state.sent_emails(),
+            app_state.failed_emails()
+        );
+        if let Some(summary) = app_state.failure_summary() {
+            print!("{summary}");
+        }
+        write_error_output(&cli, &app_state);
+        return Ok(app_state.exit_code(false));
+    }
+
+    // Keep a day of sent E-mails, the anomaly guards count the last hour of it
+    let sent_log_path = current_exe_dir.join(sent_log::SENT_LOG_FILE);
+    let now = chrono::Utc::now();
+
+    if let Err(e) = sent_log::compact::<sent_log::SentRecord, _>(
+        &sent_log_path,
+        now - sent_log::SENT_LOG_RETENTION,
+    ) {
+        log::warn!("{:?}", e);
+    }
+
+    let recent_sends = sent_log::load_since(&sent_log_path, now - chrono::Duration::hours(1))
+        .context(exit::StateError)?;
+    let mut anomaly_guard = guards::AnomalyGuard::new(cli.guard_limits(), &recent_sends);
+
+    // Resume a backlog stopped by `--max-run-time`, without rendering the E-mails its runs already sent
+    let checkpoint_path = current_exe_dir.join(checkpoint::CHECKPOINT_FILE);
+    let previous_checkpoint = checkpoint::Checkpoint::load(&checkpoint_path).unwrap_or_else(|e| {
+        log::warn!("{:?}", e);
+        None
+    });
+
+    let composed_emails = match &previous_checkpoint {
+        Some(checkpoint) => {
+            let sent_records = sent_log::load_since(&sent_log_path, checkpoint.backlog_started_at)
+                .context(exit::StateError)?;
+            let (pending, skipped) = checkpoint::skip_completed(composed_emails, &sent_records);
+
+            println!(
+                "Resuming the backlog stopped at {}: {skipped} E-mail(s) already sent are skipped",
+                checkpoint.stopped_at.to_rfc3339()
+            );
+            pending
+        }
+        None => composed_emails,
+    };
+
+    // Tokens of the VERP envelope senders, so a bounce can be traced back to its E-mail
+    let bounce_log_path = current_exe_dir.join(bounce::BOUNCE_LOG_FILE);
+    let mut bounce_tokens: HashSet<String> = HashSet::new();
+
+    if let Some(bounce_address) = &cli.bounce_address {
+        bounce::verp_address(bounce_address, "token")?;
+
+        let since = now - bounce::BOUNCE_TOKEN_RETENTION;
+        if let Err(e) = sent_log::compact::<bounce::BounceRecord, _>(&bounce_log_path, since) {
+            log::warn!("{:?}", e);
+        }
+
+        bounce_tokens = sent_log::load_since::<bounce::BounceRecord, _>(&bounce_log_path, since)
+            .context(exit::StateError)?
+            .into_iter()
+            .map(|record| record.token)
+            .collect();
+    }
+
+    let approval_expiry = chrono::Duration::seconds(cli.approval_expiry as i64);
+
+    // Fingerprints of the templates with a dedup window, so alert storms are sent once
+    let mut dedup_log = dedup::DedupLog::load(current_exe_dir.join(dedup::DEDUP_LOG_FILE), now)
+        .context(exit::StateError)?;
+
+    // E-mail IDs with a page that wasn't sent. Their entries stay in the outbox, so the remaining pages are skipped.
+    let mut failed_pages: HashSet<u32> = HashSet::new();
+    let mut held_notifications = Vec::new();
+
+    let mut attempted_any = false;
+    let mut relay_lost = false;
+    let total_emails = composed_emails.len();
+    let mut deadline_exceeded = None;
+    let mut sent_stems = Vec::new();
+    let mut breaker = breaker::CircuitBreaker::new(cli.breaker_limits(), total_emails);
+    let uploader = large_file_uploader(&cli)?;
+    let bundle_dir =
+        tempfile::tempdir().context("Unable to create the directory of the attachment bundles")?;
+    let mut run_stats = stats::RunStats::default();
+
+    for (index, mut email) in composed_emails.into_iter().enumerate() {
+        if failed_pages.contains(&email.id) {
+            continue;
+        }
+
+        if attempted_any && cli.send_delay > 0 {
+            let delay = schedule::jittered(
+                std::time::Duration::from_millis(cli.send_delay),
+                cli.jitter,
+                fastrand::f64(),
+            );
+            let remaining = deadline.remaining(std::time::Instant::now());
+            shutdown.sleep(remaining.map_or(delay, |remaining| delay.min(remaining)));
+        }
+
+        if shutdown.is_requested() {
+            log::warn!("Shutdown requested, the remaining E-mails are kept for the next run");
+            app_state.stop(app::Stop::Interrupted);
+            break;
+        }
+
+        // The remaining pages of an E-mail are sent past the deadline, so the run never stops mid-batch
+        if deadline.stops_before(std::time::Instant::now(), email.page) {
+            deadline_exceeded = Some(total_emails - index);
+            break;
+        }
+
+        attempted_any = true;
+        breaker.record_attempt();
+
+        let mut email_span = run_span.child("email");
+        email_span.set_attribute("email.id", archive::archive_stem(&email));
+        email_span.set_attribute("email.template", email.header.template.as_str());
+        email_span.set_attribute(
+            "email.recipients",
+            email.header.to.len() + email.header.cc.len() + email.header.bcc.len(),
+        );
+
+        // Cleared again once the page is sent successfully
+        if email.page.is_some() {
+            failed_pages.insert(email.id);
+        }
+
+        // Before rendering, so the template can link the files from `_meta.large_files`, and the size limit
+        // applies to a bundle rather than to its files
+        let linked = bundle_attachments(&mut email, bundle_dir.path()).and_then(|()| {
+            link_large_attachments(&mut email, &template_configs, uploader.as_deref())
+        });
+
+        // Taken apart from the E-mail, which is updated while rendering
+        let email_stem = archive::archive_stem(&email);
+        let email_from = email.header.from.clone();
+        let email_entries: Vec<_> = entries::entries_of(&email, &emails_map).cloned().collect();
+
+        let entry_paths = || email_entries.iter().filter_map(|entry| entry.path.as_ref());
+
+        // The content failures the circuit breaker counts are reported by its alert if it trips
+        let held_by_breaker = breaker.is_counting();
+
+        // Counts a render or build failure, the entries are kept for the next run
+        let template = email.header.template.clone();
+        let mut trip_breaker = |stage, reason: String| {
+            let ownership = template_configs
+                .get(&template)
+                .map(|config| config.ownership.clone())
+                .unwrap_or_default();
+
+            // On-call learns who to turn to from the failure report
+            if !ownership.is_empty() {
+                log::error!(
+                    "E-mail `{email_stem}` of template `{template}` failed to {stage}, {ownership}"
+                );
+            }
+
+            let failure = breaker::ContentFailure {
+                email: email_stem.clone(),
+                stage,
+                reason,
+                ownership,
+            };
+            let notify_error = email_entries
+                .iter()
+                .flat_map(|entry| entry.entry.notify_error());
+
+            breaker.record_failure(failure, &email_from, notify_error)
+        };
+
+        // The report of a failure of the E-mail, for the summary of the run
+        let failure_report = |category: app::FailureCategory, error: errors::ErrorEvent| {
+            email_entries
+                .iter()
+                .fold(errors::ErrorReport::new(), |report, entry| {
+                    report.add_entry(Some(entry.entry.id().to_owned()), entry.path.clone())
+                })
+                .set_context(format!("E-mail `{email_stem}` failed to {category}"))
+                .add_error(error)
+        };
+
+        // Reports a failure to the `notify_error` addresses of the entries, over the transport of the run,
+        // returning the report for the summary of the run. An entry is notified once, not by every run it fails
+        let email_subject = email.header.subject.clone();
+        let mut notify_failure = |category: app::FailureCategory, error: errors::ErrorEvent| {
+            let report = failure_report(category, error);
+
+            let unnotified: Vec<_> = email_entries
+                .iter()
+                .filter(|entry| {
+                    entry
+                        .path
+                        .as_deref()
+                        .is_none_or(|path| !dead_letter::is_notified(path))
+                })
+                .collect();
+            let to: BTreeSet<&str> = unnotified
+                .iter()
+                .flat_map(|entry| entry.entry.notify_error())
+                .map(String::as_str)
+                .collect();
+            let entry_paths: Vec<&Path> = unnotified
+                .iter()
+                .filter_map(|entry| entry.path.as_deref())
+                .collect();
+
+            let notification = send::ErrorNotification {
+                from: &email_from,
+                to: to.into_iter().collect(),
+                subject: &email_subject,
+                report: &report,
+                entry_paths: entry_paths.clone(),
+            };
+
+            if held_by_breaker && category != app::FailureCategory::Send {
+                if !notification.to.is_empty() {
+                    match notification.message(&subject_tag) {
+                        Ok(message) => held_notifications.push(HeldNotification {
+                            message,
+                            to: notification.to.join(", "),
+                            entry_paths: entry_paths.into_iter().map(Path::to_owned).collect(),
+                        }),
+                        Err(e) => log::error!("Unable to notify of the failed E-mail: {e:#}"),
+                    }
+                }
+            } else if send::send_error_notification(transport.as_ref(), &notification, &subject_tag)
+            {
+                record_notified(entry_paths);
+            }
+            report
+        };
+
+        if let Err(e) = linked {
+            log::error!("{:?}", e);
+            email_span.set_attribute("email.outcome", "build_failed");
+            email_span.record_error(&e);
+
+            app_state.record_failed();
+            let reason = format!("{e:#}");
+            let report =
+                notify_failure(app::FailureCategory::Build, errors::ErrorWrapper(e).into());
+            app_state.add_error_report(app::FailureCategory::Build, report);
+            if trip_breaker(breaker::Stage::Build, reason) {
+                break;
+            }
+            continue;
+        }
+
+        let mut blocking_anomaly = None;
+
+        for triggered in anomaly_guard.check(&email) {
+            match triggered.policy {
+                guards::GuardPolicy::Warn => log::warn!("{}", triggered.anomaly),
+                guards::GuardPolicy::Block => {
+                    log::error!("{}", triggered.anomaly);
+                    blocking_anomaly.get_or_insert(triggered.anomaly);
+                }
+            }
+        }
+
+        if let Some(anomaly) = blocking_anomaly {
+            // A limit that resets with time holds the entries for a later run, which isn't an attempt to send them
+            if anomaly.is_permanent() {
+                fail_entries(entry_paths(), &quarantine, &anomaly.to_string(), true);
+            } else {
+                println!("E-mail `{email_stem}` held for a later run");
+            }
+            email_span.set_attribute("email.outcome", "blocked");
+            email_span.record_error(&anomaly);
+            app_state.record_failed();
+            continue;
+        }
+
+        // Paged E-mails are sent whole, and raw ones have no template to configure it
+        let dedup_config = template_configs
+            .get(&email.header.template)
+            .map(|config| &config.dedup)
+            .filter(|_| email.page.is_none() && !email.header.is_raw());
+
+        let mut dedup_fingerprint = None;
+
+        if let Some((config, window)) =
+            dedup_config.and_then(|config| config.window().map(|window| (config, window)))
+        {
+            let mut masked = email.clone();
+            masked.context = dedup::mask(&email.context, &config.ignore);
+
+            // A failure is reported by the actual rendering
+            if let Ok((html, _)) = render_html(&masked, &templates_path, &template_configs) {
+                let fingerprint = dedup::fingerprint(&html);
+                let now = chrono::Utc::now();
+
+                if dedup_log.is_duplicate(&email.header.template, &fingerprint, now - window) {
+                    println!("E-mail `{email_stem}` suppressed as a duplicate of a recent one");
+                    email_span.set_attribute("email.outcome", "suppressed");
+
+                    let record = dedup::DedupRecord {
+                        recorded_at: now,
+                        template: email.header.template.clone(),
+                        fingerprint,
+                        suppressed: true,
+                    };
+                    if let Err(e) = dedup_log.record(record) {
+                        log::warn!("{:?}", e);
+                    }
+
+                    // Done with as if sent, the next E-mail of the template counts it
+                    let journaled_entries: Vec<removal_journal::JournaledEntry> = email_entries
+                        .iter()
+                        .filter_map(|entry| removal_journal::JournaledEntry::of(entry))
+                        .collect();
+                    if let Err(e) = removal_journal.record(removal_journal::RemovalRecord {
+                        recorded_at: now,
+                        email_id: email.id,
+                        content_hash: email.content_hash(),
+                        status: removal_journal::RemovalStatus::Sent,
+                        entries: journaled_entries.clone(),
+                    }) {
+                        log::warn!("{:?}", e);
+                    }
+                    for entry in &journaled_entries {
+                        if let Err(e) = sent_entries.retire(&entry.path) {
+                            log::warn!(
+                                "Unable to remove the entry \"{}\" of a suppressed E-mail: {e}",
+                                entry.path.display()
+                            );
+
+                            let report = errors::ErrorReport::new()
+                                .set_context(format!(
+                                    "Unable to remove the entry \"{}\" of a suppressed E-mail",
+                                    entry.path.display()
+                                ))
+                                .add_entry(None, Some(entry.path.clone()))
+                                .add_error(e);
+                            app_state.add_error_report(app::FailureCategory::Removal, report);
+                        }
+                    }
+                    continue;
+                }
+
+                let suppressed = dedup_log.pending_suppressions(&email.header.template);
+                if suppressed > 0 {
+                    entries::meta_object(&mut email.context).insert(
+                        dedup::SUPPRESSED_DUPLICATES_KEY.to_owned(),
+                        serde_json::json!(suppressed),
+                    );
+                }
+                dedup_fingerprint = Some(fingerprint);
+            }
+        }
+
+        let email_template_images_root = email
+            .header
+            .resources_path(&templates_path, &resources_path);
+
+        let mut render_span = email_span.child("render");
+        let rendered_template_result = render_email(&mut email, &templates_path, &template_configs);
+        if let Err(e) = &rendered_template_result {
+            render_span.record_error(e);
+        }
+        render_span.end();
+
+        // Critical templates still reach their recipients, as a plain-text E-mail of the context
+        let on_render_failure = template_configs
+            .get(&email.header.template)
+            .map(|config| config.on_render_failure)
+            .unwrap_or_default();
+        let mut degraded = false;
+
+        let rendered_template_result = match rendered_template_result {
+            Err(e) if fallback::degrade(&mut email, &e, on_render_failure) => {
+                log::error!("{:?}", e);
+                println!("E-mail `{email_stem}` degraded to plain text, as its template failed to render");
+                degraded = true;
+                Ok((Rc::new(String::new()), None))
+            }
+            result => result,
+        };
+
+        match rendered_template_result {
+            Ok((html_payload, engine)) => {
+                if !degraded && !check_policy(&email, &html_payload, &template_configs) {
+                    email_span.set_attribute("email.outcome", "policy_failed");
+                    app_state.record_failed();
+                    continue;
+                }
+
+                if template_configs
+                    .get(&email.header.template)
+                    .is_some_and(|config| config.requires_approval)
+                {
+                    let gate = approval::gate(
+                        &approval_path,
+                        &email,
+                        &html_payload,
+                        entry_paths().cloned().collect(),
+                        chrono::Utc::now(),
+                        approval_expiry,
+                    );
+
+                    match gate {
+                        Ok(approval::Gate::Approved) => {}
+                        Ok(approval::Gate::Parked) => {
+                            email_span.set_attribute("email.outcome", "parked");
+                            println!(
+                                "E-mail `{}` parked for approval",
+                                archive::archive_stem(&email)
+                            );
+                            continue;
+                        }
+                        Err(e) => {
+                            log::error!("{:?}", e);
+                            app_state.record_failed();
+                            continue;
+                        }
+                    }
+                }
+
+                // Set only by a verified signature
+                let producer = email
+                    .context
+                    .get("_meta")
+                    .and_then(|meta| meta.get("producer"))
+                    .and_then(|producer| producer.as_str());
+                if let Some(producer) = producer {
+                    println!("Producer: `{producer}`");
+                    email_span.set_attribute("email.producer", producer);
+                }
+
+                // The entry settings take precedence over the template ones
+                let template_reply_token = template_configs
+                    .get(&email.header.template)
+                    .map(|config| config.reply_token.clone())
+                    .unwrap_or_default();
+
+                // The token of a degraded E-mail can't be rendered either
+                let reply_token_config = if degraded {
+                    reply_token::ReplyTokenConfig::default()
+                } else {
+                    email
+                        .header
+                        .reply_token
+                        .clone()
+                        .unwrap_or_default()
+                        .or(&template_reply_token)
+                };
+
+                let reply_token = match reply_token::apply(&mut email, engine, &reply_token_config)
+                {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::error!("{:?}", e);
+                        email_span.set_attribute("email.outcome", "build_failed");
+                        email_span.record_error(&e);
+
+                        app_state.record_failed();
+                        let reason = format!("{e:#}");
+                        let report = notify_failure(
+                            app::FailureCategory::Build,
+                            errors::ErrorWrapper(e).into(),
+                        );
+                        app_state.add_error_report(app::FailureCategory::Build, report);
+                        if trip_breaker(breaker::Stage::Build, reason) {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                if let Some(reply_token) = &reply_token {
+                    println!("Reply token: `{reply_token}`");
+                    email_span.set_attribute("email.reply_token", reply_token.as_str());
+                }
+
+                // Build E-mail
+                // let message = send::Message::new()
+                //     .from(&email.header.from)
+                //     .to_addresses(&to)
+                //     .cc_addresses(&cc)
+                //     .bcc_addresses(&bcc)
+                //     .reply_to_addresses(&reply_to)
+                //     .subject(&email.header.subject)
+                //     .alternative_content(&email.header.alternative_content)
+                //     .content(&html_payload, Some(&email_template_images_root))
+                //     .attachments(&attachments);
+
+                // The entry charsets take precedence over the template ones
+                let template_charset = template_configs
+                    .get(&email.header.template)
+                    .map(|config| config.charset.clone())
+                    .unwrap_or_default();
+
+                let (text_charset, html_charset) = match email
+                    .header
+                    .charset
+                    .clone()
+                    .unwrap_or_default()
+                    .or(&template_charset)
+                    .resolve()
+                {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::error!("{e}");
+                        app_state.record_failed();
+                        continue;
+                    }
+                };
+
+                let bounce_token = cli
+                    .bounce_address
+                    .as_ref()
+                    .map(|_| bounce::generate_token(&bounce_tokens));
+                let envelope_from = match (&cli.bounce_address, &bounce_token) {
+                    (Some(bounce_address), Some(token)) => {
+                        Some(bounce::verp_address(bounce_address, token)?)
+                    }
+                    _ => None,
+                };
+
+                let content = (!degraded)
+                    .then_some((html_payload.as_str(), email_template_images_root.as_path()));
+
+                let message = match build_message(
+                    &email,
+                    content,
+                    engine,
+                    (text_charset, html_charset),
+                    envelope_from.as_deref(),
+                    &template_configs,
+                    &message_settings,
+                ) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::error!("{:?}", e);
+                        email_span.set_attribute("email.outcome", "build_failed");
+                        email_span.record_error(&e);
+
+                        app_state.record_failed();
+                        let reason = format!("{e:#}");
+                        let report = notify_failure(
+                            app::FailureCategory::Build,
+                            errors::ErrorWrapper(e).into(),
+                        );
+                        app_state.add_error_report(app::FailureCategory::Build, report);
+                        if trip_breaker(breaker::Stage::Build, reason) {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                // A missing image doesn't hold the E-mail back, its reference is left as it is
+                if let Some(report) = message.skipped_images_report() {
+                    let report = report.set_context(format!(
+                        "E-mail `{email_stem}` of the template `{}` has images that were not embedded",
+                        email.header.template
+                    ));
+                    log::warn!("{report}");
+                    app_state.add_error_report(app::FailureCategory::Image, report);
+                }
+
+                let attachments_size = message.attachments_size();
+                println!("Attachments size: {attachments_size} bytes");
+                if let Some(message_id) = message.message_id() {
+                    log::info!("E-mail `{email_stem}` has the Message-ID {message_id}");
+                }
+
+                // Lower privilege.
+                // let connection = connection;
+
+                // Convert to Lettre Message & Send E-mail
+                let message: lettre::Message = match message.try_into() {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::error!("{:?}", e);
+                        email_span.set_attribute("email.outcome", "build_failed");
+                        email_span.record_error(&e);
+
+                        app_state.record_failed();
+                        let reason = format!("{e:#}");
+                        let report = notify_failure(
+                            app::FailureCategory::Build,
+                            errors::ErrorWrapper(e).into(),
+                        );
+                        app_state.add_error_report(app::FailureCategory::Build, report);
+                        if trip_breaker(breaker::Stage::Build, reason) {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                // Measured as sent, for the volume stats
+                let formatted = message.formatted();
+                let encoded_size = formatted.len();
+
+                if cli.dump_mime_structure {
+                    print!("MIME structure:\n{}", mime_tree::structure(&formatted));
+                }
+
+                let recipients: Vec<String> = message
+                    .envelope()
+                    .to()
+                    .iter()
+                    .map(|address| address.to_string())
+                    .collect();
+
+                if let Some(encrypted) = relay_encrypted.filter(|_| !tls_policies.is_empty()) {
+                    let domains = message
+                        .envelope()
+                        .to()
+                        .iter()
+                        .map(|address| address.domain());
+
+                    match tls_policy::check(&tls_policies, domains, encrypted) {
+                        Ok(plaintext_domains) => {
+                            for domain in plaintext_domains {
+                                log::warn!(
+                                    "E-mail `{email_stem}` is sent to `{domain}` over a plaintext connection, \
+                                    against its `testing` TLS policy"
+                                );
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("E-mail `{email_stem}`: {e}");
+                            email_span.set_attribute("email.outcome", "tls_policy_failed");
+                            email_span.record_error(&e);
+                            app_state.record_failed();
+                            continue;
+                        }
+                    }
+                }
+
+                let mut send_span = email_span.child("send");
+                if let Some(message_id) = message.headers().get_raw("Message-ID") {
+                    send_span.set_attribute("message_id", message_id);
+                }
+
+                let send_result = send_email(
+                    transport.as_ref(),
+                    message,
+                    &email,
+                    &email_entries,
+                    &mut removal_journal,
+                    &quarantine,
+                );
+                if let Err(e) = &send_result {
+                    send_span.record_error(e);
+                }
+                send_span.end();
+
+                match send_result {
+                    Ok(sent) => {
+                        // Discarded E-mails are not part of the mail volume
+                        let is_delivered = !matches!(sent.outcome, send::SendOutcome::Discarded);
+
+                        match &sent.outcome {
+                            send::SendOutcome::Sent => println!("Email sent successfully!"),
+                            send::SendOutcome::Written(path) => {
+                                println!("Email written to \"{}\"", path.display())
+                            }
+                            send::SendOutcome::Discarded => println!("Email discarded"),
+                        }
+                        app_state.record_sent();
+                        if degraded {
+                            app_state.record_degraded();
+                            email_span.set_attribute("email.outcome", "degraded");
+                        } else {
+                            email_span.set_attribute("email.outcome", "sent");
+                        }
+
+                        if is_delivered {
+                            let template = if email.header.is_raw() {
+                                stats::RAW_TEMPLATE
+                            } else {
+                                &email.header.template
+                            };
+                            run_stats.record(template, encoded_size, attachments_size, &recipients);
+                        }
+
+                        anomaly_guard.record_sent(&email);
+
+                        // Audited before the entries are removed
+                        if let Some(archive_cas) = &cli.archive_cas {
+                            if let Err(e) =
+                                audit::store(archive_cas, email.id, &formatted, &recipients)
+                            {
+                                log::error!("{:?}", e);
+                            }
+                        }
+
+                        if let Some(token) = bounce_token {
+                            let bounce_record = bounce::BounceRecord {
+                                token: token.clone(),
+                                email_id: email.id,
+                                recipients,
+                                sent_at: chrono::Utc::now(),
+                            };
+
+                            if let Err(e) = sent_log::append(&bounce_log_path, &bounce_record) {
+                                log::warn!("{:?}", e);
+                            }
+
+                            bounce_tokens.insert(token);
+                        }
+
+                        if let Err(e) = approval::release(&approval_path, &email) {
+                            log::warn!("{:?}", e);
+                        }
+
+                        if let Some(archive_dir) = &cli.archive_dir {
+                            if let Err(e) = archive::store(archive_dir, &email, &html_payload) {
+                                log::warn!("{:?}", e);
+                            }
+                        }
+
+                        let sent_record = sent_log::SentRecord {
+                            sent_at: chrono::Utc::now(),
+                            email_id: email.id,
+                            template: email.header.template.clone(),
+                            content_hash: email.content_hash(),
+                            reply_token,
+                        };
+
+                        if let Err(e) = sent_log::append(&sent_log_path, &sent_record) {
+                            log::warn!("{:?}", e);
+                        }
+                        sent_stems.push(email_stem.clone());
+
+                        if let Some(fingerprint) = dedup_fingerprint.take() {
+                            let record = dedup::DedupRecord {
+                                recorded_at: chrono::Utc::now(),
+                                template: email.header.template.clone(),
+                                fingerprint,
+                                suppressed: false,
+                            };
+                            if let Err(e) = dedup_log.record(record) {
+                                log::warn!("{:?}", e);
+                            }
+                        }
+
+                        failed_pages.remove(&email.id);
+
+                        for report in sent.retire_entries(&mut removal_journal, &sent_entries) {
+                            app_state.add_error_report(app::FailureCategory::Removal, report);
+                        }
+                    }
+                    // Sending failure
+                    Err(e) => {
+                        log::error!("{e}");
+                        email_span.set_attribute("email.outcome", "send_failed");
+
+                        // Every other E-mail would fail the same way, nor could the failure be notified. The
+                        // failures of the run are still reported
+                        if let send::SendError::Auth(_) | send::SendError::Connection(_) = e {
+                            log::error!(
+                                "The mail relay is unreachable or refused the credentials, the run is aborted"
+                            );
+                            relay_lost = true;
+                            let report = failure_report(app::FailureCategory::Send, e.into());
+                            app_state.add_error_report(app::FailureCategory::Send, report);
+                            app_state.record_failed();
+                            app_state.stop(app::Stop::Aborted);
+                            break;
+                        }
+
+                        let report = notify_failure(app::FailureCategory::Send, e.into());
+                        app_state.add_error_report(app::FailureCategory::Send, report);
+                        app_state.record_failed();
+                        continue;
+                    }
+                }
+            }
+
+            // Rendering failure
+            Err(e) => {
+                log::error!("{:?}", e);
+                email_span.set_attribute("email.outcome", "render_failed");
+
+                app_state.record_failed();
+                let reason = format!("{e:#}");
+                let report =
+                    notify_failure(app::FailureCategory::Render, errors::ErrorWrapper(e).into());
+                app_state.add_error_report(app::FailureCategory::Render, report);
+                if trip_breaker(breaker::Stage::Render, reason) {
+                    break;
+                }
+                continue;
+            }
+        }
+    } // Each E-mail
+
+    if let Err(e) = removal_journal.compact() {
+        log::warn!("{:?}", e);
+    }
+
+    if let Err(e) = run_stats.save(&stats_path, chrono::Utc::now()) {
+        log::warn!("{:?}", e);
+    }
+
+    let mut alerted = false;
+    if breaker.is_tripped() {
+        log::error!("Circuit breaker tripped, the run is aborted: {breaker}");
+
+        match breaker.alert().map(|alert| alert.message(&subject_tag)) {
+            Some(Ok(message)) => match transport.send(message) {
+                Ok(_) => alerted = true,
+                Err(e) => log::error!("Unable to send the circuit breaker alert: {e}"),
+            },
+            Some(Err(e)) => log::error!("Unable to build the circuit breaker alert: {e:?}"),
+            None => log::warn!("No `notify_error` address to alert of the aborted run"),
+        }
+
+        app_state.stop(app::Stop::Aborted);
+    }
+
+    // Sent on their own when the alert of the breaker didn't report them, kept for a later run without a relay
+    for held in held_notifications.into_iter().filter(|_| !relay_lost) {
+        if alerted || send::send_notification(transport.as_ref(), held.message, &held.to) {
+            record_notified(held.entry_paths.iter().map(PathBuf::as_path));
+        }
+    }
+
+    transport.close();
+
+    if app_state.degraded_emails() > 0 {
+        log::warn!(
+            "{} E-mail(s) sent degraded to plain text, as their template failed to render",
+            app_state.degraded_emails()
+        );
+    }
+
+    if let Some(remaining) = deadline_exceeded {
+        log::warn!(
+            "Maximum run time of {} seconds exceeded: {} E-mail(s) sent, \
+            {remaining} kept in the outbox for the next run",
+            cli.max_run_time.unwrap_or_default().as_secs(),
+            app_state.sent_emails()
+        );
+
+        let checkpoint = checkpoint::Checkpoint::resume(
+            previous_checkpoint,
+            run_started_at,
+            chrono::Utc::now(),
+            sent_stems,
+            remaining,
+        );
+        if let Err(e) = checkpoint.save(&checkpoint_path) {
+            log::warn!("{:?}", e);
+        }
+
+        app_state.stop(app::Stop::Resumable);
+    } else if previous_checkpoint.is_some() && !app_state.is_stopped() {
+        // Every E-mail of the backlog was attempted
+        if let Err(e) = checkpoint::Checkpoint::clear(&checkpoint_path) {
+            log::warn!("{:?}", e);
+        }
+    }
+
+    if let Some(summary) = app_state.failure_summary() {
+        print!("{summary}");
+    }
+
+    match app_state.write_error_reports(&error_report_dir, chrono::Utc::now()) {
+        Ok(Some(path)) => println!("Error report written to \"{}\"", path.display()),
+        Ok(None) => {}
+        Err(e) => log::error!("{:?}", e),
+    }
+    write_error_output(&cli, &app_state);
+
+    Ok(app_state.exit_code(cli.exit_idle))
+}
+
+/// Prints the help, the version or the argument error of clap, mapping the argument errors onto the exit code contract
+/// instead of the exit code 2 of clap.
+fn cli_exit(error: clap::Error) -> exit::ExitCode {
+    let _ = error.print();
+
+    if error.use_stderr() {
+        exit::ExitCode::InvalidConfig
+    } else {
+        exit::ExitCode::Success
+    }
+}
+
+/// Validates every entry file of the outbox against the entry schema, logging the violations.
+/// Returns the number of invalid entry files.
+fn lint_entry_schema(entries_path: &Path) -> usize {
+    let schema = schema::entry_schema();
+    let mut invalid_entries = 0;
+
+    for entry in entries::entry_files(entries_path) {
+        let violations = match schema::validate_file(&schema, entry.path()) {
+            Ok(violations) => violations,
+            Err(e) => {
+                log::error!("{:?}", e);
+                invalid_entries += 1;
+                continue;
+            }
+        };
+
+        if !violations.is_empty() {
+            log::error!(
+                "The entry file \"{}\" doesn't match the entry schema:\n{}",
+                entry.path().display(),
+                violations.join("\n")
+            );
+            invalid_entries += 1;
+        }
+    }
+
+    invalid_entries
+}
+
+/// Validates the effective configuration as a run would read it, without connecting or sending.
+/// Returns every problem found, including a missing templates directory required by the E-mails of the outbox.
+fn check_config(
+    cli: &cli::Cli,
+    current_exe_dir: &Path,
+    entries_path: &Path,
+    templates_path: &Path,
+) -> Vec<anyhow::Error> {
+    let mut problems = Vec::new();
+
+    if let Some(bounce_address) = &cli.bounce_address {
+        if let Err(e) = bounce::verp_address(bounce_address, "token") {
+            problems.push(e);
+        }
+    }
+
+    if let Some(path) = &cli.include_headers_file {
+        if let Err(e) = send::load_headers_file(path) {
+            problems.push(e);
+        }
+    }
+
+    if let Err(e) = load_tls_policies(cli) {
+        problems.push(e);
+    }
+
+    if let Err(e) = app::error_report_dir(
+        entries_path,
+        current_exe_dir,
+        cli.error_report_dir.as_deref(),
+    ) {
+        problems.push(e);
+    }
+
+    if let Err(e) = load_retention(cli, current_exe_dir, entries_path, templates_path) {
+        problems.push(e);
+    }
+
+    if let Some(percent) = cli.breaker_max_failure_percent {
+        if !(0.0..=100.0).contains(&percent) {
+            problems.push(anyhow::anyhow!(
+                "`--breaker-max-failure-percent` of {percent} is not a percentage"
+            ));
+        }
+    }
+
+    if let Err(e) = large_file_uploader(cli) {
+        problems.push(e);
+    }
+
+    if let Err(e) = remote_image_fetcher(cli) {
+        problems.push(e);
+    }
+
+    if let Err(e) = load_aliases(cli) {
+        problems.push(e);
+    }
+
+    if let Err(e) = load_dkim(cli) {
+        problems.push(e);
+    }
+
+    let verifier = match load_verifier(cli) {
+        Ok(verifier) => verifier,
+        Err(e) => {
+            problems.push(e);
+            signing::Verifier::default()
+        }
+    };
+
+    let entries_pool = entries::load_entries(entries_path, &verifier).ok;
+    let compose_results = entries::compose_emails(&entries::map_emails(&entries_pool));
+    problems.extend(
+        compose_results
+            .err
+            .into_iter()
+            .map(|failure| anyhow::Error::new(failure.error)),
+    );
+
+    let composed_emails = compose_results.ok;
+    if let Err(e) = templates::require_root(templates_path, &composed_emails) {
+        problems.push(e);
+    }
+
+    problems.extend(template_asset_problems(templates_path));
+
+    if templates_path.is_dir() {
+        match templates::unowned_templates(templates_path) {
+            Ok(unowned) => {
+                for template in unowned {
+                    log::warn!(
+                        "Template `{template}` declares no `owner` or `escalation_email` in `{}`",
+                        templates::TEMPLATE_CONFIG_FILE
+                    );
+                }
+            }
+            Err(e) => problems.push(e),
+        }
+    }
+
+    problems
+}
+
+/// Checks the image references of every template, logging the unused images and the skipped dynamic references.
+/// Returns the missing images, naming their template, or the template that couldn't be checked.
+fn template_asset_problems(templates_path: &Path) -> Vec<anyhow::Error> {
+    if !templates_path.is_dir() {
+        return Vec::new();
+    }
+
+    let all_findings = match templates::check_all_assets(templates_path) {
+        Ok(v) => v,
+        Err(e) => return vec![e],
+    };
+
+    let mut problems = Vec::new();
+
+    for (template, findings) in all_findings {
+        for finding in findings {
+            match finding {
+                templates::AssetFinding::Missing(_) => {
+                    problems.push(anyhow::anyhow!("Template `{template}`: {finding}"))
+                }
+                templates::AssetFinding::Unused(_) => {
+                    log::warn!("Template `{template}`: {finding}")
+                }
+                templates::AssetFinding::Dynamic(_) => {
+                    log::info!("Template `{template}`: {finding}")
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+/// A username and password from the `{prefix}USERNAME` and `{prefix}PASSWORD` environment variables, when both are set.
+#[cfg(feature = "ingest-imap")]
+fn env_credentials(prefix: &str) -> Option<(String, String)> {
+    match (
+        env::var(format!("{prefix}USERNAME")),
+        env::var(format!("{prefix}PASSWORD")),
+    ) {
+        (Ok(username), Ok(password)) => Some((username, password)),
+        _ => None,
+    }
+}
+
+/// Polls the IMAP mailbox for entries until stopped, or once with `--once`.
+/// Failing polls are retried on the next interval, except with `--once`. `retain` prunes the directories of
+/// `--retention-file` before every poll.
+#[cfg(feature = "ingest-imap")]
+fn ingest_imap(
+    args: &cli::IngestImapArgs,
+    entries_path: &Path,
+    templates_path: &Path,
+    relay: &send::SmtpConnectionInfo,
+    relay_credentials: Option<(String, String)>,
+    subject_tag: &send::SubjectTag,
+    retain: &dyn Fn(),
+) -> anyhow::Result<()> {
+    let auth: send::Authentication = args.auth.parse()?;
+    let (username, password) = env_credentials("IMAP_")
+        .or_else(|| relay_credentials.clone())
+        .context("No IMAP credentials, set `IMAP_USERNAME` and `IMAP_PASSWORD`")?;
+
+    let config = ingest::ImapConfig {
+        host: args.host.clone(),
+        port: args.port.unwrap_or(match auth {
+            send::Authentication::Tls => 993,
+            _ => 143,
+        }),
+        auth,
+        username,
+        password,
+        folder: args.folder.clone(),
+        processed_folder: args.processed_folder.clone(),
+        rejected_folder: args.rejected_folder.clone(),
+    };
+
+    println!(
+        "IMAP mailbox: \"{}:{}\" [{}] {}",
+        config.host, config.port, config.auth, config.folder
+    );
+
+    loop {
+        retain();
+
+        let result = ingest::ImapClient::connect(&config)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut client| {
+                let summary = ingest::poll(&mut client, &config, entries_path, templates_path)?;
+                client.logout()?;
+                Ok(summary)
+            });
+
+        match result {
+            Ok(summary) => {
+                for path in &summary.accepted {
+                    println!("Ingested entry \"{}\"", path.display());
+                }
+
+                for rejection in &summary.rejected {
+                    log::warn!(
+                        "Rejected message {} from {}: {}",
+                        rejection.uid,
+                        rejection.sender.as_deref().unwrap_or("an unknown sender"),
+                        rejection.reason
+                    );
+                }
+
+                if let Some(reply_from) = &args.reply_from {
+                    if let Err(e) = reply_rejections(
+                        reply_from,
+                        &summary.rejected,
+                        relay,
+                        relay_credentials.clone(),
+                        subject_tag,
+                    ) {
+                        log::error!("{:?}", e);
+                    }
+                }
+            }
+            Err(e) if args.once => return Err(e),
+            Err(e) => log::error!("{:?}", e),
+        }
+
+        if args.once {
+            return Ok(());
+        }
+
+        std::thread::sleep(std::time::Duration::from_secs(args.poll_interval));
+    }
+}
+
+/// Replies to the sender of every rejected message with the reason, through the mail relay.
+#[cfg(feature = "ingest-imap")]
+fn reply_rejections(
+    reply_from: &str,
+    rejections: &[ingest::Rejection],
+    relay: &send::SmtpConnectionInfo,
+    relay_credentials: Option<(String, String)>,
+    subject_tag: &send::SubjectTag,
+) -> anyhow::Result<()> {
+    if rejections
+        .iter()
+        .all(|rejection| rejection.sender.is_none())
+    {
+        return Ok(());
+    }
+
+    use send::Transport;
+
+    let mut connection = send::Connection::new(relay.clone());
+    connection.establish(
+        relay_credentials.map(|(username, password)| Credentials::new(username, password)),
+    )?;
+
+    for rejection in rejections {
+        let Some(sender) = &rejection.sender else {
+            continue;
+        };
+
+        let subject = format!("Rejected: {}", rejection.subject);
+        let body = format!(
+            "Your message could not be accepted as an OSA Mailer entry.\n\n{}\n",
+            rejection.reason
+        );
+
+        let mut builder = send::MessageBuilder::new();
+        builder
+            .from(reply_from)
+            .to_addresses(sender)
+            .subject(&subject)
+            .subject_tag(subject_tag)
+            .alternative_content(&body);
+
+        let references = rejection.message_id.iter().cloned().collect::<Vec<_>>();
+        if let Some(message_id) = &rejection.message_id {
+            builder
+                .in_reply_to(message_id.clone())
+                .references(&references);
+        }
+
+        let message = builder.build()?.try_into()?;
+
+        if let Err(e) = connection.send(message) {
+            log::error!("Unable to reply to {sender}: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// The identity of the E-mails of `--identity-fields` and `--identity-conflict`, overridden by the `template.toml`
+/// of the templates of the entries. A configuration failing to load is reported once its E-mails are rendered.
+fn load_identity(
+    cli: &cli::Cli,
+    templates_path: &Path,
+    entries_pool: &[Rc<entries::ParsedEntry>],
+) -> identity::Identity {
+    let mut identity = identity::Identity::new(cli.identity());
+
+    let templates: HashSet<&str> = entries_pool
+        .iter()
+        .map(|entry| entry.entry.template())
+        .filter(|template| !template.is_empty())
+        .collect();
+
+    for template in templates {
+        if let Ok(config) = templates::TemplateConfig::load(templates_path.join(template)) {
+            identity.insert_template(template, &config.identity);
+        }
+    }
+
+    identity
+}
+
+/// Fails the entries of an E-mail that wasn't sent. Entries of a `permanent` failure are moved to the
+/// dead-letter directory, otherwise they stay in the outbox for the next run, until `--max-send-attempts`.
+fn fail_entries<'a>(
+    entry_paths: impl Iterator<Item = &'a PathBuf>,
+    quarantine: &dead_letter::Quarantine,
+    reason: &str,
+    permanent: bool,
+) {
+    for entry_path in entry_paths {
+        let result = if permanent {
+            quarantine.quarantine(entry_path, reason).map(Some)
+        } else {
+            quarantine.record_attempt(entry_path, reason)
+        };
+
+        match result {
+            Ok(Some(path)) if !permanent => log::warn!(
+                "Moved the entry to \"{}\" after its last failed attempt",
+                path.display()
+            ),
+            Ok(_) => {}
+            Err(e) => log::error!("{:?}", e),
+        }
+    }
+}
+
+/// An E-mail accepted by the transport, see [`send_email`].
+struct SentEmail<'a> {
+    outcome: send::SendOutcome,
+    page: Option<entries::Page>,
+    entries: &'a [Rc<entries::ParsedEntry>],
+
+    /// The journaled entries of the E-mail, none for a page before the last
+    removal_record: removal_journal::RemovalRecord,
+}
+
+impl SentEmail<'_> {
+    /// Removes or archives the entries of the sent E-mail, journaled as sent first, returning the reports of the
+    /// entries that couldn't be removed, which are removed by the next runs. The entries of a page before the last
+    /// are kept until the last page is sent, noting the page sent in their sidecars.
+    fn retire_entries(
+        mut self,
+        removal_journal: &mut removal_journal::RemovalJournal,
+        sent_entries: &removal_journal::SentEntries,
+    ) -> Vec<errors::ErrorReport> {
+        if let Some(page) = self.page.filter(|page| !page.is_last()) {
+            for path in self.entries.iter().filter_map(|entry| entry.path.as_ref()) {
+                if let Err(e) = dead_letter::record_delivered_page(path, page.number) {
+                    log::warn!("{:?}", e);
+                }
+            }
+            return Vec::new();
+        }
+
+        self.removal_record.recorded_at = chrono::Utc::now();
+        self.removal_record.status = removal_journal::RemovalStatus::Sent;
+        if let Err(e) = removal_journal.record(self.removal_record.clone()) {
+            log::warn!("{:?}", e);
+        }
+
+        let mut reports = Vec::new();
+        for entry in &self.removal_record.entries {
+            if let Err(e) = sent_entries.retire(&entry.path) {
+                log::warn!(
+                    "Unable to remove the entry \"{}\" of a sent E-mail: {e}",
+                    entry.path.display()
+                );
+
+                let report = errors::ErrorReport::new()
+                    .set_context(format!(
+                        "Unable to remove the entry \"{}\" of a sent E-mail",
+                        entry.path.display()
+                    ))
+                    .add_entry(None, Some(entry.path.clone()))
+                    .add_error(e);
+                reports.push(report);
+            }
+        }
+
+        reports
+    }
+}
+
+/// Sends the message of an E-mail, journaling its entries beforehand, so entries left behind by a partial removal
+/// are recognized as sent. The entries are retired with [`SentEmail::retire_entries`] once the run is done with it.
+/// ## Error
+/// Fails with the send error. The entries of a transient failure are kept in the outbox for the next run, those of
+/// a permanent one are moved to the dead-letter directory, and the others are left as they are.
+fn send_email<'a>(
+    transport: &dyn send::Transport,
+    message: lettre::Message,
+    email: &entries::ComposedEmail,
+    email_entries: &'a [Rc<entries::ParsedEntry>],
+    removal_journal: &mut removal_journal::RemovalJournal,
+    quarantine: &dead_letter::Quarantine,
+) -> Result<SentEmail<'a>, send::SendError> {
+    let journaled_entries = if email.page.is_some_and(|page| !page.is_last()) {
+        Vec::new()
+    } else {
+        email_entries
+            .iter()
+            .filter_map(|entry| removal_journal::JournaledEntry::of(entry))
+            .collect()
+    };
+    let removal_record = removal_journal::RemovalRecord {
+        recorded_at: chrono::Utc::now(),
+        email_id: email.id,
+        content_hash: email.content_hash(),
+        status: removal_journal::RemovalStatus::Pending,
+        entries: journaled_entries,
+    };
+
+    if !removal_record.entries.is_empty() {
+        if let Err(e) = removal_journal.record(removal_record.clone()) {
+            log::warn!("{:?}", e);
+        }
+    }
+
+    match transport.send(message) {
+        Ok(outcome) => Ok(SentEmail {
+            outcome,
+            page: email.page,
+            entries: email_entries,
+            removal_record,
+        }),
+        Err(e) => {
+            let entry_paths = email_entries.iter().filter_map(|entry| entry.path.as_ref());
+
+            match &e {
+                send::SendError::Transient(reason) => {
+                    fail_entries(entry_paths, quarantine, reason, false)
+                }
+                send::SendError::Permanent(reason) => {
+                    fail_entries(entry_paths, quarantine, reason, true)
+                }
+                send::SendError::Auth(_) | send::SendError::Connection(_) => {}
+            }
+
+            Err(e)
+        }
+    }
+}
+
+/// The notification of a failed E-mail held back while the circuit breaker counts the failures, see
+/// [`breaker::CircuitBreaker::is_counting`]. Sent at the end of the run, unless the alert of the breaker reports it.
+struct HeldNotification {
+    message: lettre::Message,
+    to: String,
+    entry_paths: Vec<PathBuf>,
+}
+
+/// Notes in the sidecars of the entries that their failure was notified.
+fn record_notified<'a>(entry_paths: impl IntoIterator<Item = &'a Path>) {
+    for entry_path in entry_paths {
+        if let Err(e) = dead_letter::record_notified(entry_path) {
+            log::warn!("{:?}", e);
+        }
+    }
+}
+
+/// Writes the failures of the run into `--error-output`, if set.
+fn write_error_output(cli: &cli::Cli, app_state: &app::AppState) {
+    if let Some(path) = &cli.error_output {
+        if let Err(e) = app_state.write_error_output(path) {
+            log::error!("{:?}", e);
+        }
+    }
+}
+
+/// The configuration file of `--config`, resolved relative to the binary directory, or `config.toml` next to the
+/// binary. Empty when there is no `config.toml`.
+fn load_config(cli: &cli::Cli) -> anyhow::Result<config::Config> {
+    let path = relative_path::RelativePath::new(
+        cli.config_file
+            .as_deref()
+            .unwrap_or(Path::new(config::CONFIG_FILE)),
+    )?;
+
+    match config::Config::load(path.as_ref())? {
+        Some(config) => Ok(config),
+        None if cli.config_file.is_some() => anyhow::bail!("No configuration file \"{path}\""),
+        None => Ok(config::Config::default()),
+    }
+}
+
+/// The configuration file, see [`load_config`]. With `config check`, a configuration file that can't be loaded is
+/// returned along with the default configuration, for the check to report it with the other problems.
+/// ## Error
+/// Fails if the configuration file can't be loaded, other than with `config check`.
+fn load_checked_config(cli: &cli::Cli) -> anyhow::Result<(config::Config, Option<anyhow::Error>)> {
+    match load_config(cli) {
+        Ok(config) => Ok((config, None)),
+        Err(e)
+            if matches!(
+                cli.command,
+                Some(cli::Command::Config(cli::ConfigCommand::Check))
+            ) =>
+        {
+            Ok((config::Config::default(), Some(e)))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The group aliases of `--aliases-file`, resolved relative to the binary directory. None without the argument.
+fn load_aliases(cli: &cli::Cli) -> anyhow::Result<aliases::AliasBook> {
+    match &cli.aliases_file {
+        Some(path) => aliases::load(relative_path::RelativePath::new(path)?),
+        None => Ok(aliases::AliasBook::default()),
+    }
+}
+
+/// The DKIM signing settings of `--dkim-key`, resolved relative to the binary directory. None without the argument.
+fn load_dkim(cli: &cli::Cli) -> anyhow::Result<Option<lettre::message::dkim::DkimConfig>> {
+    match (&cli.dkim_key, &cli.dkim_selector, &cli.dkim_domain) {
+        (Some(path), Some(selector), Some(domain)) => Ok(Some(send::load_dkim_config(
+            relative_path::RelativePath::new(path)?,
+            selector,
+            domain,
+        )?)),
+        _ => Ok(None),
+    }
+}
+
+/// The TLS policies of the recipient domains of `--tls-policy-file`, resolved relative to the binary directory.
+/// Empty without the argument.
+fn load_tls_policies(cli: &cli::Cli) -> anyhow::Result<tls_policy::PolicyTable> {
+    match &cli.tls_policy_file {
+        Some(path) => tls_policy::load(relative_path::RelativePath::new(path)?),
+        None => Ok(tls_policy::PolicyTable::default()),
+    }
+}
+
+/// The retention rules of `--retention-file`, by directory relative to the binary directory. The directories of
+/// the outbox, templates, resources and approvals are never pruned, nor is the binary directory with the state
+/// files, e.g. the sent log.
+fn load_retention(
+    cli: &cli::Cli,
+    current_exe_dir: &Path,
+    entries_path: &Path,
+    templates_path: &Path,
+) -> anyhow::Result<std::collections::BTreeMap<PathBuf, retention::RetentionRule>> {
+    let resources_path = current_exe_dir.join(entries::RESOURCES_DIR);
+    let approval_path = current_exe_dir.join(approval::APPROVAL_DIR);
+
+    match &cli.retention_file {
+        Some(path) => retention::load(
+            relative_path::RelativePath::new(path)?,
+            current_exe_dir,
+            &[
+                current_exe_dir,
+                entries_path,
+                templates_path,
+                &resources_path,
+                &approval_path,
+            ],
+        ),
+        None => Ok(Default::default()),
+    }
+}
+
+/// Prunes the directories of `--retention-file`, resolved relative to the binary directory, prints what was
+/// removed and returns it, by directory. The entries of the removal journal are never removed.
+/// ## Error
+/// Fails if the retention file or the removal journal can't be loaded. A directory that can't be pruned is
+/// reported only.
+fn apply_retention(
+    cli: &cli::Cli,
+    current_exe_dir: &Path,
+    entries_path: &Path,
+    templates_path: &Path,
+    run_started_at: chrono::DateTime<chrono::Utc>,
+) -> anyhow::Result<Vec<retention::Pruned>> {
+    let rules = load_retention(cli, current_exe_dir, entries_path, templates_path)?;
+    if rules.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let removal_journal = removal_journal::RemovalJournal::load(
+        current_exe_dir.join(removal_journal::REMOVAL_JOURNAL_FILE),
+    )?;
+    let protected = removal_journal.referenced();
+    let mut pruned_dirs = Vec::new();
+
+    for (dir, rule) in &rules {
+        let dir = current_exe_dir.join(dir);
+
+        match retention::prune(
+            &dir,
+            rule,
+            run_started_at,
+            &protected,
+            cli.retention_dry_run,
+        ) {
+            Ok(pruned) => {
+                for path in &pruned.removed {
+                    log::debug!("Retention: \"{}\"", path.display());
+                }
+                if !pruned.removed.is_empty() || pruned.dry_run {
+                    println!("Retention {pruned}");
+                }
+                pruned_dirs.push(pruned);
+            }
+            Err(e) => log::error!("{:?}", e),
+        }
+    }
+
+    Ok(pruned_dirs)
+}
+
+/// The verifier of the entry signatures, with the producer keys of `--producer-keys` resolved relative to the
+/// binary directory.
+fn load_verifier(cli: &cli::Cli) -> anyhow::Result<signing::Verifier> {
+    let keys = match &cli.producer_keys {
+        Some(path) => signing::load_keys(relative_path::RelativePath::new(path)?)?,
+        None => Default::default(),
+    };
+
+    Ok(signing::Verifier::new(keys, cli.unsigned_entries))
+}
+
+/// The object store of `large_attachment_policy = "link"`, when one is configured.
+fn large_file_uploader(cli: &cli::Cli) -> anyhow::Result<Option<Box<dyn large_files::Uploader>>> {
+    #[cfg(feature = "s3-links")]
+    if let (Some(endpoint), Some(bucket)) = (&cli.s3_endpoint, &cli.s3_bucket) {
+        let uploader = s3::S3Uploader::new(s3::S3Config {
+            endpoint: endpoint.clone(),
+            bucket: bucket.clone(),
+            region: cli.s3_region.clone(),
+            access_key_id: cli.s3_access_key_id.clone().unwrap_or_default(),
+            secret_access_key: cli.s3_secret_access_key.clone().unwrap_or_default(),
+        })?;
+
+        return Ok(Some(Box::new(uploader)));
+    }
+
+    #[cfg(not(feature = "s3-links"))]
+    let _ = cli;
+
+    Ok(None)
+}
+
+/// The fetcher of the remote images of the templates, with `--remote-images`.
+/// ## Error
+/// Fails if the HTTP client can't be set up.
+fn remote_image_fetcher(cli: &cli::Cli) -> anyhow::Result<Option<Box<dyn send::ImageFetcher>>> {
+    #[cfg(feature = "remote-images")]
+    if cli.remote_images {
+        let fetcher = remote_images::HttpImageFetcher::new(
+            std::time::Duration::from_secs(cli.remote_image_timeout),
+            cli.remote_image_max_size,
+        )?;
+
+        return Ok(Some(Box::new(fetcher)));
+    }
+
+    #[cfg(not(feature = "remote-images"))]
+    let _ = cli;
+
+    Ok(None)
+}
+
+/// Replaces the large attached files of an E-mail by download links, when its template has
+/// `large_attachment_policy = "link"`.
+fn link_large_attachments(
+    email: &mut entries::ComposedEmail,
+    template_configs: &HashMap<String, templates::TemplateConfig>,
+    uploader: Option<&dyn large_files::Uploader>,
+) -> anyhow::Result<()> {
+    let Some(config) = template_configs
+        .get(&email.header.template)
+        .filter(|config| {
+            config.large_attachment_policy == large_files::LargeAttachmentPolicy::Link
+        })
+    else {
+        return Ok(());
+    };
+
+    let threshold = config
+        .large_attachment_threshold
+        .unwrap_or(large_files::DEFAULT_THRESHOLD);
+    let expiry_hours = config
+        .large_attachment_expiry_hours
+        .unwrap_or(large_files::DEFAULT_EXPIRY_HOURS);
+
+    large_files::link_large_attachments(
+        email,
+        threshold,
+        chrono::Duration::hours(expiry_hours as i64),
+        uploader,
+        chrono::Utc::now(),
+    )
+}
+
+/// Replaces the attached files of an E-mail with the zip file of its `bundle_attachments`, written within `dir`,
+/// when there are more than the threshold. The files that can't be read stay attached on their own.
+/// ## Error
+/// Fails if the bundle can't be built or written.
+fn bundle_attachments(email: &mut entries::ComposedEmail, dir: &Path) -> anyhow::Result<()> {
+    let Some(config) = &email.header.bundle_attachments else {
+        return Ok(());
+    };
+    let Some(bundle) = bundle::bundle(&email.header.attachments, config)? else {
+        return Ok(());
+    };
+
+    // Only the file name, a producer can't write elsewhere
+    let name = Path::new(&config.name)
+        .file_name()
+        .with_context(|| format!("The bundle name `{}` is not a file name", config.name))?;
+    let bundle_dir = dir.join(archive::archive_stem(email));
+    let bundle_path = bundle_dir.join(name);
+
+    fs::create_dir_all(&bundle_dir)
+        .and_then(|()| fs::write(&bundle_path, &bundle.data))
+        .with_context(|| format!("Unable to write the bundle \"{}\"", bundle_path.display()))?;
+
+    println!(
+        "Bundled {} attached file(s) into `{}` ({} bytes): {}",
+        bundle.members.len(),
+        config.name,
+        bundle.data.len(),
+        bundle.members.join(", ")
+    );
+
+    let mut attachments = bundle.unreadable;
+    attachments.push(bundle_path.display().to_string());
+    email.header.attachments = attachments;
+
+    Ok(())
+}
+
+/// Checks the rendered HTML of an E-mail against the content policy of its template, logging every violation.
+/// Returns `false` when a violation of `error` severity fails the E-mail.
+fn check_policy(
+    email: &entries::ComposedEmail,
+    html: &str,
+    template_configs: &HashMap<String, templates::TemplateConfig>,
+) -> bool {
+    let Some(config) = template_configs.get(&email.header.template) else {
+        return true;
+    };
+
+    let stem = archive::archive_stem(email);
+    let violations = config.policy.check(html);
+
+    for violation in &violations {
+        match violation.severity {
+            policy::RuleSeverity::Error => log::error!("E-mail `{stem}`: {violation}"),
+            _ => log::warn!("E-mail `{stem}`: {violation}"),
+        }
+    }
+
+    !policy::has_errors(&violations)
+}
+
+/// Renders the templated attached file paths of an E-mail with the engine of its template, against the
+/// context of the E-mail, e.g. `reports/{{ report_date }}/summary.pdf`. Raw E-mails have no engine to render them.
+fn render_attachments(
+    email: &mut entries::ComposedEmail,
+    templates_path: &Path,
+    template_configs: &HashMap<String, templates::TemplateConfig>,
+) -> anyhow::Result<()> {
+    if email.header.is_raw()
+        || !email
+            .header
+            .attachments
+            .iter()
+            .any(|attachment| send::is_templated(attachment))
+    {
+        return Ok(());
+    }
+
+    let (email_template_path, engine_extensions) =
+        email_template(email, templates_path, template_configs)?;
+
+    let contents = render::read_template(&email_template_path)?;
+
+    let engine = render::detect_engine(&TemplateData {
+        contents: Rc::new(contents),
+        file_path: Some(&email_template_path),
+        extensions: Some(&engine_extensions),
+    });
+
+    email.header.attachments = send::render_attachments(
+        &email.header.attachments,
+        &serde_json::Value::Object(email.context.clone()),
+        engine,
+    )?;
+
+    Ok(())
+}
+
+/// The settings of a run applying to the message of every E-mail.
+struct MessageSettings<'a> {
+    subject_tag: &'a send::SubjectTag,
+    image_fetcher: Option<&'a dyn send::ImageFetcher>,
+    attachment_encoding: send::AttachmentEncoding,
+    global_headers: &'a send::CustomHeaders,
+    long_header_policy: send::LongHeaderPolicy,
+    message_id_domain: Option<&'a str>,
+}
+
+/// Builds the message of a rendered E-mail. The HTML `content` is embedding the images of its resources directory,
+/// without it only the plain-text alternative is sent, as for a degraded E-mail.
+fn build_message(
+    email: &entries::ComposedEmail,
+    content: Option<(&str, &Path)>,
+    engine: Option<render::TemplateEngine>,
+    (text_charset, html_charset): (Option<send::BodyCharset>, Option<send::BodyCharset>),
+    envelope_from: Option<&str>,
+    template_configs: &HashMap<String, templates::TemplateConfig>,
+    settings: &MessageSettings,
+) -> anyhow::Result<send::Message> {
+    let to = email.header.to.join(", ");
+    let cc = email.header.cc.join(", ");
+    let bcc = email.header.bcc.join(", ");
+    let reply_to = email.header.reply_to.join(", ");
+    let attachments = email.header.attachments.join(", ");
+    let address_context = serde_json::Value::Object(email.context.clone());
+
+    // The entry settings take precedence over the template ones
+    let generate_text = email
+        .header
+        .alternative
+        .as_ref()
+        .and_then(|alternative| alternative.generate_text)
+        .or_else(|| {
+            template_configs
+                .get(&email.header.template)
+                .and_then(|config| config.alternative.generate_text)
+        })
+        .unwrap_or(true);
+
+    let mut message_builder = send::MessageBuilder::new();
+    message_builder
+        .from(&email.header.from)
+        .to_addresses(&to)
+        .cc_addresses(&cc)
+        .bcc_addresses(&bcc)
+        .reply_to_addresses(&reply_to)
+        .subject(&email.header.subject)
+        .subject_tag(settings.subject_tag)
+        .alternative_content(
+            email
+                .header
+                .text_body
+                .as_deref()
+                .unwrap_or(&email.header.alternative_content),
+        )
+        .generate_text(generate_text)
+        .attachments(&attachments)
+        .attachment_encoding(settings.attachment_encoding)
+        .global_headers(settings.global_headers)
+        .headers(&email.header.headers)
+        .charsets(text_charset, html_charset)
+        .long_header_policy(settings.long_header_policy);
+
+    if let Some((html, images_root)) = content {
+        message_builder.content(html, Some(images_root));
+    }
+
+    if let Some(fetcher) = settings.image_fetcher {
+        message_builder.image_fetcher(fetcher);
+    }
+
+    if let Some(domain) = settings.message_id_domain {
+        message_builder.message_id_domain(domain);
+    }
+
+    if let Some(sender) = &email.header.sender {
+        message_builder.sender(sender);
+    }
+
+    if let Some(unsubscribe) = &email.header.unsubscribe {
+        message_builder.list_unsubscribe(
+            unsubscribe.url.as_deref(),
+            unsubscribe.mailto.as_deref(),
+            unsubscribe.one_click,
+        );
+    }
+
+    if let Some(envelope_from) = envelope_from {
+        message_builder.envelope_from(envelope_from);
+    }
+
+    if let Some(engine) = engine.filter(|_| {
+        template_configs
+            .get(&email.header.template)
+            .is_some_and(|config| config.render_addresses)
+    }) {
+        message_builder.address_context(&address_context, engine);
+    }
+
+    message_builder.build()
+}
+
+/// Renders and builds the message of an E-mail for `--dry-run`, formatted as it would be sent.
+/// Its attached files are bundled within `bundle_dir`, but large ones are never uploaded.
+/// ## Error
+/// Fails if the E-mail fails to render, the content policy, or to build, without degrading to plain text.
+fn dry_run_message(
+    email: &mut entries::ComposedEmail,
+    bundle_dir: &Path,
+    templates_path: &Path,
+    resources_path: &Path,
+    template_configs: &HashMap<String, templates::TemplateConfig>,
+    settings: &MessageSettings,
+) -> anyhow::Result<Vec<u8>> {
+    bundle_attachments(email, bundle_dir)?;
+
+    let images_root = email.header.resources_path(templates_path, resources_path);
+    let (html, engine) = render_email(email, templates_path, template_configs)?;
+
+    if !check_policy(email, &html, template_configs) {
+        anyhow::bail!("The E-mail failed the content policy");
+    }
+
+    let template_config = template_configs
+        .get(&email.header.template)
+        .cloned()
+        .unwrap_or_default();
+
+    let reply_token_config = email
+        .header
+        .reply_token
+        .clone()
+        .unwrap_or_default()
+        .or(&template_config.reply_token);
+    if let Some(reply_token) = reply_token::apply(email, engine, &reply_token_config)? {
+        println!("Reply token: `{reply_token}`");
+    }
+
+    let charsets = email
+        .header
+        .charset
+        .clone()
+        .unwrap_or_default()
+        .or(&template_config.charset)
+        .resolve()?;
+
+    let message: lettre::Message = build_message(
+        email,
+        Some((&html, &images_root)),
+        engine,
+        charsets,
+        None,
+        template_configs,
+        settings,
+    )?
+    .try_into()?;
+
+    Ok(message.formatted())
+}
+
+/// Renders the HTML of a composed E-mail with its template, along with the engine the template uses,
+/// then applies its `[alternative]` settings: the preview text and the generated plain-text alternative.
+/// The `html_body` of a raw E-mail is used as is, without an engine.
+fn render_email(
+    email: &mut entries::ComposedEmail,
+    templates_path: &Path,
+    template_configs: &HashMap<String, templates::TemplateConfig>,
+) -> anyhow::Result<(Rc<String>, Option<render::TemplateEngine>)> {
+    let (mut html, engine) = render_html(email, templates_path, template_configs)?;
+
+    let template_config = template_configs.get(&email.header.template);
+
+    // Before the preheader, which is injected into the body
+    if let Some(config) = template_config.filter(|config| config.dark_mode != DarkMode::Off) {
+        html = Rc::new(dark_mode::apply(
+            &html,
+            config.dark_mode,
+            &config.dark_colors,
+        ));
+    }
+
+    // The entry settings take precedence over the template ones
+    let template_alternative = template_config
+        .map(|config| config.alternative.clone())
+        .unwrap_or_default();
+    let alternative = email
+        .header
+        .alternative
+        .clone()
+        .unwrap_or_default()
+        .or(&template_alternative);
+
+    let html = alternative::apply(email, html, engine, &alternative)?;
+
+    Ok((html, engine))
+}
+
+/// The main template file of the E-mail, found with the engine extensions of its template configuration.
+/// ## Error
+/// Fails if the configuration maps an extension to an unknown engine.
+fn email_template(
+    email: &entries::ComposedEmail,
+    templates_path: &Path,
+    template_configs: &HashMap<String, templates::TemplateConfig>,
+) -> anyhow::Result<(render::AbsolutePath, render::EngineExtensions)> {
+    let engine_extensions = template_configs
+        .get(&email.header.template)
+        .map(|config| config.engine_extensions())
+        .transpose()?
+        .unwrap_or_default();
+
+    let template_dir = templates_path.join(&email.header.template);
+    let email_template_path = templates::template_file(&template_dir, &engine_extensions).into();
+
+    Ok((email_template_path, engine_extensions))
+}
+
+fn render_html(
+    email: &entries::ComposedEmail,
+    templates_path: &Path,
+    template_configs: &HashMap<String, templates::TemplateConfig>,
+) -> anyhow::Result<(Rc<String>, Option<render::TemplateEngine>)> {
+    if let Some(html_body) = &email.header.html_body {
+        return Ok((Rc::new(html_body.clone()), None));
+    }
+
+    let (email_template_path, engine_extensions) =
+        email_template(email, templates_path, template_configs)?;
+
+    let template_data = TemplateData {
+        contents: Rc::new(render::read_template(&email_template_path)?),
+        file_path: { Some(&email_template_path) },
+        extensions: Some(&engine_extensions),
+    };
+
+    // A dedicated step, before the engine escapes the values
+    let context_html_policy = template_configs
+        .get(&email.header.template)
+        .map(|config| config.context_html_policy)
+        .unwrap_or_default();
+
+    // The entry timezone takes precedence over the template one
+    let template_localtime = template_configs
+        .get(&email.header.template)
+        .map(|config| config.localtime.clone())
+        .unwrap_or_default();
+    let local_time = localtime::LocalTimeConfig {
+        display_timezone: email.header.display_timezone.clone(),
+        ..Default::default()
+    }
+    .or(&template_localtime)
+    .local_time()?;
+
+    let mut context = context_html::prepare(&email.context, context_html_policy);
+    if let Some(context) = context.as_object_mut() {
+        entries::meta_object(context).insert(
+            "timezone".to_owned(),
+            serde_json::Value::String(local_time.timezone().name().to_owned()),
+        );
+    }
+
+    let context_data = ContextData {
+        context,
+        file_path: None,
+        trusted: context_html_policy == ContextHtmlPolicy::Trust,
+        local_time,
+    };
+
+    let rendered_template = render::render(
+        &template_data,
+        &context_data,
+        render::DetectionMethod::Auto,
+        render::TemplateExtension::Auto,
+    )?;
+
+    Ok((
+        rendered_template.0,
+        Some(render::detect_engine(&template_data)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every E-mail with the same result.
+    struct ResultTransport(fn() -> Result<send::SendOutcome, send::SendError>);
+
+    impl send::Transport for ResultTransport {
+        fn establish(&mut self, _credentials: Option<Credentials>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn send(&self, _msg: lettre::Message) -> Result<send::SendOutcome, send::SendError> {
+            self.0()
+        }
+    }
+
+    /// Sends the first E-mails, then loses the mail relay.
+    struct LostRelayTransport {
+        sent: std::cell::Cell<usize>,
+        reachable: usize,
+    }
+
+    impl send::Transport for LostRelayTransport {
+        fn establish(&mut self, _credentials: Option<Credentials>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn send(&self, _msg: lettre::Message) -> Result<send::SendOutcome, send::SendError> {
+            if self.sent.get() == self.reachable {
+                return Err(send::SendError::Connection("refused".to_owned()));
+            }
+
+            self.sent.set(self.sent.get() + 1);
+            Ok(send::SendOutcome::Discarded)
+        }
+    }
+
+    /// An outbox of two entries, batched into one E-mail.
+    struct Outbox {
+        dir: tempfile::TempDir,
+        entries: Vec<Rc<entries::ParsedEntry>>,
+        quarantine: dead_letter::Quarantine,
+    }
+
+    impl Outbox {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let outbox_path = dir.path().join("outbox");
+            fs::create_dir_all(&outbox_path).unwrap();
+
+            let entries = ["1", "2"]
+                .into_iter()
+                .map(|id| {
+                    let path = outbox_path.join(format!("{id}.json"));
+                    fs::write(&path, "{}").unwrap();
+
+                    Rc::new(entries::ParsedEntry {
+                        id: id.to_owned(),
+                        path: Some(path),
+                        entry: entries::Entry::new(
+                            id.to_owned(),
+                            chrono::Utc::now().fixed_offset(),
+                            Vec::new(),
+                            Default::default(),
+                            Default::default(),
+                        ),
+                    })
+                })
+                .collect();
+            let quarantine = dead_letter::Quarantine::new(&outbox_path, None, None).unwrap();
+
+            Self {
+                dir,
+                entries,
+                quarantine,
+            }
+        }
+
+        fn removal_journal(&self) -> removal_journal::RemovalJournal {
+            removal_journal::RemovalJournal::load(self.journal_path()).unwrap()
+        }
+
+        fn journal_path(&self) -> PathBuf {
+            self.dir.path().join(removal_journal::REMOVAL_JOURNAL_FILE)
+        }
+
+        /// The statuses recorded in the removal journal, in order.
+        fn journaled(&self) -> Vec<removal_journal::RemovalStatus> {
+            sent_log::load_since::<removal_journal::RemovalRecord, _>(
+                self.journal_path(),
+                chrono::DateTime::<chrono::Utc>::MIN_UTC,
+            )
+            .unwrap()
+            .into_iter()
+            .map(|record| record.status)
+            .collect()
+        }
+
+        fn entry_paths(&self) -> Vec<PathBuf> {
+            self.entries
+                .iter()
+                .filter_map(|entry| entry.path.clone())
+                .collect()
+        }
+
+        fn send(
+            &self,
+            email: &entries::ComposedEmail,
+            removal_journal: &mut removal_journal::RemovalJournal,
+            result: fn() -> Result<send::SendOutcome, send::SendError>,
+        ) -> Result<SentEmail<'_>, send::SendError> {
+            let message = lettre::Message::builder()
+                .from("a@x.com".parse().unwrap())
+                .to("b@x.com".parse().unwrap())
+                .subject("Events")
+                .body(String::from("Events"))
+                .unwrap();
+
+            send_email(
+                &ResultTransport(result),
+                message,
+                email,
+                &self.entries,
+                removal_journal,
+                &self.quarantine,
+            )
+        }
+    }
+
+    #[test]
+    fn test_config_check_reports_malformed_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join(config::CONFIG_FILE);
+        fs::write(&config_path, "server = ").unwrap();
+        let config_arg = config_path.to_str().unwrap();
+
+        let cli = |args: &[&str]| {
+            let matches = cli::Cli::command()
+                .try_get_matches_from(["osa_mailer", "--config", config_arg].iter().chain(args))
+                .unwrap();
+            cli::Cli::from_arg_matches(&matches).unwrap()
+        };
+
+        let (_, problem) = load_checked_config(&cli(&["config", "check"])).unwrap();
+        assert!(format!("{:?}", problem.unwrap()).contains(config_arg));
+
+        // Any other command stops on it
+        assert!(load_checked_config(&cli(&[])).is_err());
+
+        fs::write(&config_path, "server = \"smtp.x.com\"").unwrap();
+        let (_, problem) = load_checked_config(&cli(&["config", "check"])).unwrap();
+        assert!(problem.is_none());
+    }
+
+    #[test]
+    fn test_lost_relay_reports_the_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox_path = dir.path().join("outbox");
+        let error_output = dir.path().join("errors.json");
+
+        for subject in ["Backup failed", "Disk full", "Certificate expired"] {
+            let entry = outbox::EntryBuilder::new()
+                .from("osa@example.com")
+                .to(["ops@example.com"])
+                .subject(subject)
+                .html_body("<p>Check the server</p>")
+                .build()
+                .unwrap();
+            outbox::Outbox::new(&outbox_path).enqueue(&entry).unwrap();
+        }
+
+        let transport = LostRelayTransport {
+            sent: Default::default(),
+            reachable: 1,
+        };
+        let exit_code = run_from(
+            [
+                "osa_mailer",
+                "--outbox",
+                outbox_path.to_str().unwrap(),
+                "--templates",
+                dir.path().join("templates").to_str().unwrap(),
+                "--error-output",
+                error_output.to_str().unwrap(),
+                "--send-retries",
+                "0",
+            ],
+            dir.path(),
+            Some(Box::new(transport)),
+        )
+        .unwrap();
+        assert_eq!(exit_code, exit::ExitCode::Aborted);
+
+        // The failure is reported, and the E-mails after it are kept for the next run
+        let reports: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&error_output).unwrap()).unwrap();
+        let reports = reports.as_array().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0]["category"], "send");
+
+        let error_reports: Vec<_> = fs::read_dir(outbox_path.join(app::ERRORS_DIR))
+            .unwrap()
+            .collect();
+        assert_eq!(error_reports.len(), 1);
+        assert_eq!(entries::entry_files(&outbox_path).count(), 2);
+    }
+
+    #[test]
+    fn test_send_email_retires_entries() {
+        let outbox = Outbox::new();
+        let mut removal_journal = outbox.removal_journal();
+
+        let sent = outbox
+            .send(&Default::default(), &mut removal_journal, || {
+                Ok(send::SendOutcome::Sent)
+            })
+            .unwrap();
+        assert!(matches!(sent.outcome, send::SendOutcome::Sent));
+
+        // Journaled before sending, retired once done with
+        assert_eq!(
+            outbox.journaled(),
+            [removal_journal::RemovalStatus::Pending]
+        );
+        assert!(outbox.entry_paths().iter().all(|path| path.is_file()));
+
+        let reports = sent.retire_entries(&mut removal_journal, &Default::default());
+        assert!(reports.is_empty());
+        assert_eq!(
+            outbox.journaled(),
+            [
+                removal_journal::RemovalStatus::Pending,
+                removal_journal::RemovalStatus::Sent
+            ]
+        );
+        assert!(outbox.entry_paths().iter().all(|path| !path.exists()));
+    }
+
+    #[test]
+    fn test_send_email_keeps_entries_of_early_pages() {
+        let outbox = Outbox::new();
+        let mut removal_journal = outbox.removal_journal();
+        let email = entries::ComposedEmail {
+            page: Some(entries::Page {
+                number: 1,
+                count: 2,
+            }),
+            ..Default::default()
+        };
+
+        let sent = outbox
+            .send(&email, &mut removal_journal, || Ok(send::SendOutcome::Sent))
+            .unwrap();
+        let reports = sent.retire_entries(&mut removal_journal, &Default::default());
+        assert!(reports.is_empty());
+
+        let entry_paths = outbox.entry_paths();
+        assert!(entry_paths.iter().all(|path| path.is_file()));
+        assert_eq!(
+            dead_letter::delivered_pages(entry_paths.iter().map(PathBuf::as_path)).unwrap(),
+            [1].into()
+        );
+        assert!(outbox.journaled().is_empty());
+    }
+
+    #[test]
+    fn test_send_email_fails_entries() {
+        let outbox = Outbox::new();
+        let mut removal_journal = outbox.removal_journal();
+        let email = entries::ComposedEmail::default();
+
+        // Kept for the next run
+        let sent = outbox.send(&email, &mut removal_journal, || {
+            Err(send::SendError::Transient("busy".to_owned()))
+        });
+        assert!(matches!(sent, Err(send::SendError::Transient(_))));
+        for path in outbox.entry_paths() {
+            let state = dead_letter::EntryState::load(&path).unwrap();
+            assert_eq!(state.attempts, 1);
+            assert_eq!(state.last_error.as_deref(), Some("busy"));
+        }
+
+        // Every other E-mail fails the same way, it's not held against these entries
+        let sent = outbox.send(&email, &mut removal_journal, || {
+            Err(send::SendError::Connection("refused".to_owned()))
+        });
+        assert!(matches!(sent, Err(send::SendError::Connection(_))));
+        for path in outbox.entry_paths() {
+            assert_eq!(dead_letter::EntryState::load(&path).unwrap().attempts, 1);
+        }
+
+        // No later run can deliver them
+        let sent = outbox.send(&email, &mut removal_journal, || {
+            Err(send::SendError::Permanent("no such user".to_owned()))
+        });
+        assert!(matches!(sent, Err(send::SendError::Permanent(_))));
+        let failed_dir = outbox
+            .dir
+            .path()
+            .join("outbox")
+            .join(dead_letter::FAILED_DIR);
+        for path in outbox.entry_paths() {
+            assert!(!path.exists());
+            assert!(failed_dir.join(path.file_name().unwrap()).is_file());
+        }
+    }
 }
@@ -0,0 +1,332 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A part of a formatted message, without its body: the message itself, a multipart or a single part.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MimeNode {
+    /// The media type, lowercase and without parameters, `text/plain` if not given
+    pub(crate) content_type: String,
+    pub(crate) charset: Option<String>,
+    pub(crate) transfer_encoding: Option<String>,
+    pub(crate) disposition: Option<String>,
+    pub(crate) filename: Option<String>,
+    pub(crate) content_id: Option<String>,
+
+    /// The size of the encoded body of a single part, in bytes as formatted
+    pub(crate) size: usize,
+    pub(crate) parts: Vec<MimeNode>,
+}
+
+impl MimeNode {
+    fn is_multipart(&self) -> bool {
+        self.content_type.starts_with("multipart/")
+    }
+
+    fn write_tree(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(f, "{}{}", "  ".repeat(depth), self.content_type)?;
+
+        let attributes = [
+            ("charset", &self.charset),
+            ("encoding", &self.transfer_encoding),
+            ("disposition", &self.disposition),
+            ("filename", &self.filename),
+            ("cid", &self.content_id),
+        ];
+        for (name, value) in attributes {
+            if let Some(value) = value {
+                write!(f, " {name}={value}")?;
+            }
+        }
+
+        if !self.is_multipart() {
+            write!(f, " size={}", self.size)?;
+        }
+        writeln!(f)?;
+
+        self.parts
+            .iter()
+            .try_for_each(|part| part.write_tree(f, depth + 1))
+    }
+}
+
+/// The indented tree of the parts, a line per part.
+impl fmt::Display for MimeNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_tree(f, 0)
+    }
+}
+
+/// The lowercase header names of the part, with their unfolded values. A later header replaces an earlier one.
+fn headers<'a>(lines: impl Iterator<Item = &'a str>) -> BTreeMap<String, String> {
+    let mut headers: BTreeMap<String, String> = BTreeMap::new();
+    let mut last: Option<String> = None;
+
+    for line in lines {
+        if line.starts_with([' ', '\t']) {
+            if let Some(value) = last.as_ref().and_then(|name| headers.get_mut(name)) {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            let name = name.trim().to_ascii_lowercase();
+            headers.insert(name.clone(), value.trim().to_owned());
+            last = Some(name);
+        }
+    }
+
+    headers
+}
+
+/// Splits a header value into its lowercase main value and its parameters, e.g. `text/html; charset=utf-8`.
+/// The parameter names are lowercase, their values unquoted.
+fn parameters(value: &str) -> (String, BTreeMap<String, String>) {
+    let mut fields = value.split(';');
+    let main = fields
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    let parameters = fields
+        .filter_map(|field| field.split_once('='))
+        .map(|(name, value)| {
+            (
+                name.trim().to_ascii_lowercase(),
+                value.trim().trim_matches('"').to_owned(),
+            )
+        })
+        .collect();
+
+    (main, parameters)
+}
+
+/// The lines of the body between each `--boundary` delimiter line, up to the closing `--boundary--`.
+fn split_parts<'a>(body: &[&'a str], boundary: &str) -> Vec<Vec<&'a str>> {
+    let delimiter = format!("--{boundary}");
+    let closing = format!("--{boundary}--");
+
+    let mut parts = Vec::new();
+    let mut current: Option<Vec<&str>> = None;
+
+    for &line in body {
+        if line == closing {
+            break;
+        }
+        if line == delimiter {
+            parts.extend(current.replace(Vec::new()));
+        } else if let Some(part) = current.as_mut() {
+            part.push(line);
+        }
+    }
+    parts.extend(current);
+
+    parts
+}
+
+fn parse_part(lines: &[&str]) -> MimeNode {
+    let blank = lines
+        .iter()
+        .position(|line| line.is_empty())
+        .unwrap_or(lines.len());
+    let (header_lines, body) = (&lines[..blank], lines.get(blank + 1..).unwrap_or_default());
+
+    let headers = headers(header_lines.iter().copied());
+
+    let (content_type, type_parameters) = headers
+        .get("content-type")
+        .map(|value| parameters(value))
+        .unwrap_or_else(|| ("text/plain".to_owned(), BTreeMap::new()));
+    let (disposition, mut disposition_parameters) = headers
+        .get("content-disposition")
+        .map(|value| parameters(value))
+        .map_or((None, BTreeMap::new()), |(disposition, parameters)| {
+            (Some(disposition), parameters)
+        });
+
+    let parts = match type_parameters.get("boundary") {
+        Some(boundary) if content_type.starts_with("multipart/") => split_parts(body, boundary)
+            .iter()
+            .map(|part| parse_part(part))
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let size = if parts.is_empty() {
+        // The line breaks of the body count as formatted, the one before the next delimiter belongs to it
+        body.iter().map(|line| line.len()).sum::<usize>() + 2 * body.len().saturating_sub(1)
+    } else {
+        0
+    };
+
+    let filename = disposition_parameters
+        .remove("filename")
+        .or_else(|| disposition_parameters.remove("filename*"))
+        .or_else(|| type_parameters.get("name").cloned());
+
+    MimeNode {
+        content_type,
+        charset: type_parameters
+            .get("charset")
+            .map(|c| c.to_ascii_lowercase()),
+        transfer_encoding: headers
+            .get("content-transfer-encoding")
+            .map(|encoding| encoding.to_ascii_lowercase()),
+        disposition,
+        filename,
+        content_id: headers.get("content-id").cloned(),
+        size,
+        parts,
+    }
+}
+
+/// The MIME structure of a formatted message: its content types, dispositions, file names, content IDs and body
+/// sizes, for `--dump-mime-structure` and to pin the multipart nesting clients depend on.
+pub(crate) fn structure(formatted: &[u8]) -> MimeNode {
+    let formatted = String::from_utf8_lossy(formatted);
+    let lines: Vec<&str> = formatted.split("\r\n").collect();
+
+    // The last line break ends the message
+    let lines = match lines.split_last() {
+        Some((&"", lines)) => lines,
+        _ => &lines,
+    };
+
+    parse_part(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::send::{Message, MessageBuilder};
+    use lettre::Message as LettreMessage;
+    use std::fs;
+
+    fn tree(message: Message) -> String {
+        let message: LettreMessage = message.try_into().unwrap();
+        structure(&message.formatted()).to_string()
+    }
+
+    fn builder<'a>() -> MessageBuilder<'a> {
+        let mut builder = MessageBuilder::new();
+        builder
+            .from("sender@x.com")
+            .to_addresses("a@x.com")
+            .subject("Daily report");
+        builder
+    }
+
+    const HTML: &str = "<p>3 jobs failed</p>";
+    const TEXT: &str = "3 jobs failed";
+
+    #[test]
+    fn test_structure_html_only() {
+        assert_eq!(
+            tree(builder().content(HTML, None).build().unwrap()),
+            "\
+multipart/related
+  text/html charset=utf-8 encoding=base64 size=28
+"
+        );
+    }
+
+    #[test]
+    fn test_structure_html_and_text() {
+        assert_eq!(
+            tree(
+                builder()
+                    .content(HTML, None)
+                    .build()
+                    .and_then(|message| message.alternative_content(TEXT, None))
+                    .unwrap()
+            ),
+            "\
+multipart/alternative
+  text/plain charset=utf-8 encoding=base64 size=20
+  multipart/related
+    text/html charset=utf-8 encoding=base64 size=28
+"
+        );
+    }
+
+    #[test]
+    fn test_structure_html_with_images() {
+        let resources = tempfile::tempdir().unwrap();
+        fs::write(resources.path().join("logo.png"), b"\x89PNG\r\n\x1a\n").unwrap();
+        fs::write(resources.path().join("banner.jpg"), b"\xff\xd8\xff\xe0").unwrap();
+
+        let html = r#"<img src="logo.png"><div style="background: url('banner.jpg')"></div>"#;
+
+        assert_eq!(
+            tree(
+                builder()
+                    .content(html, Some(resources.path()))
+                    .build()
+                    .unwrap()
+            ),
+            "\
+multipart/related
+  text/html charset=utf-8 encoding=base64 size=102
+  image/png encoding=base64 disposition=inline cid=<image_0> size=12
+  image/jpeg encoding=base64 disposition=inline cid=<image_1> size=8
+"
+        );
+    }
+
+    #[test]
+    fn test_structure_html_text_and_attachments() {
+        let resources = tempfile::tempdir().unwrap();
+        let report = resources.path().join("report.pdf");
+        fs::write(&report, b"%PDF-1.7").unwrap();
+        let attachments = report.display().to_string();
+
+        assert_eq!(
+            tree(
+                builder()
+                    .content(HTML, None)
+                    .attachments(&attachments)
+                    .build()
+                    .and_then(|message| message.alternative_content(TEXT, None))
+                    .unwrap()
+            ),
+            "\
+multipart/mixed
+  multipart/alternative
+    text/plain charset=utf-8 encoding=base64 size=20
+    multipart/related
+      text/html charset=utf-8 encoding=base64 size=28
+  multipart/mixed
+    application/pdf encoding=7bit disposition=attachment filename=report.pdf size=8
+"
+        );
+    }
+
+    #[test]
+    fn test_structure_text_only() {
+        assert_eq!(
+            tree(
+                builder()
+                    .build()
+                    .and_then(|message| message.alternative_content(TEXT, None))
+                    .unwrap()
+            ),
+            "\
+multipart/alternative
+  text/plain charset=utf-8 encoding=base64 size=20
+"
+        );
+    }
+
+    #[test]
+    fn test_structure_empty() {
+        assert_eq!(
+            tree(builder().build().unwrap()),
+            "\
+multipart/mixed
+  text/plain charset=utf-8 encoding=7bit size=0
+"
+        );
+    }
+}
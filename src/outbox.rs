@@ -0,0 +1,399 @@
+use chrono::Utc;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::entries::{self, Email, Entry, ENTRY_EXT};
+use crate::errors::EntryError;
+
+/// Builds an outbox entry, checked by [`EntryBuilder::build`] as `lint` checks the entry files.
+///
+/// ```
+/// use osa_mailer::EntryBuilder;
+/// use serde_json::json;
+///
+/// let entry = EntryBuilder::new()
+///     .from("osa@example.com")
+///     .to(["ops@example.com"])
+///     .subject("Disk usage")
+///     .template("welcome")
+///     .context(json!({ "title": "Disk usage" }))
+///     .accumulate("rows", json!({ "host": "db-1", "usage": 93 }))
+///     .build()?;
+///
+/// // Entries of the same E-mail share its ID, and are sent as a single E-mail
+/// let other = EntryBuilder::new()
+///     .from("osa@example.com")
+///     .to(["ops@example.com"])
+///     .subject("Disk usage")
+///     .template("welcome")
+///     .accumulate("rows", json!({ "host": "db-2", "usage": 97 }))
+///     .build()?;
+/// assert_eq!(entry.email_id(), other.email_id());
+/// # Ok::<(), osa_mailer::EntryError>(())
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct EntryBuilder {
+    id: Option<String>,
+    notify_error: Vec<String>,
+    email: Email,
+    context: Option<serde_json::Value>,
+    accumulated: Vec<(String, serde_json::Value)>,
+}
+
+impl EntryBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The ID of the entry, generated when not set.
+    pub fn id(&mut self, id: &str) -> &mut Self {
+        self.id = Some(id.to_owned());
+        self
+    }
+
+    /// The producing system, e.g. `backup`.
+    pub fn system(&mut self, system: &str) -> &mut Self {
+        self.email.system = system.to_owned();
+        self
+    }
+
+    /// The part of the producing system, e.g. `scheduler`.
+    pub fn subsystem(&mut self, subsystem: &str) -> &mut Self {
+        self.email.subsystem = subsystem.to_owned();
+        self
+    }
+
+    pub fn from(&mut self, from: &str) -> &mut Self {
+        self.email.from = from.to_owned();
+        self
+    }
+
+    pub fn sender(&mut self, sender: &str) -> &mut Self {
+        self.email.sender = Some(sender.to_owned());
+        self
+    }
+
+    pub fn to<I: IntoIterator<Item = S>, S: Into<String>>(&mut self, to: I) -> &mut Self {
+        self.email.to = to.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn cc<I: IntoIterator<Item = S>, S: Into<String>>(&mut self, cc: I) -> &mut Self {
+        self.email.cc = cc.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn bcc<I: IntoIterator<Item = S>, S: Into<String>>(&mut self, bcc: I) -> &mut Self {
+        self.email.bcc = bcc.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn reply_to<I: IntoIterator<Item = S>, S: Into<String>>(
+        &mut self,
+        reply_to: I,
+    ) -> &mut Self {
+        self.email.reply_to = reply_to.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// The addresses notified when the E-mail of the entry fails.
+    pub fn notify_error<I: IntoIterator<Item = S>, S: Into<String>>(
+        &mut self,
+        notify_error: I,
+    ) -> &mut Self {
+        self.notify_error = notify_error.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn subject(&mut self, subject: &str) -> &mut Self {
+        self.email.subject = subject.to_owned();
+        self
+    }
+
+    /// The template rendering the E-mail, instead of an [`EntryBuilder::html_body`].
+    pub fn template(&mut self, template: &str) -> &mut Self {
+        self.email.template = template.to_owned();
+        self
+    }
+
+    /// HTML rendered by the producer, sent as is instead of rendering a [`EntryBuilder::template`].
+    pub fn html_body(&mut self, html_body: &str) -> &mut Self {
+        self.email.html_body = Some(html_body.to_owned());
+        self
+    }
+
+    /// The plain-text alternative of an [`EntryBuilder::html_body`].
+    pub fn text_body(&mut self, text_body: &str) -> &mut Self {
+        self.email.text_body = Some(text_body.to_owned());
+        self
+    }
+
+    pub fn alternative_content(&mut self, alternative_content: &str) -> &mut Self {
+        self.email.alternative_content = alternative_content.to_owned();
+        self
+    }
+
+    pub fn attachments<I: IntoIterator<Item = S>, S: Into<String>>(
+        &mut self,
+        attachments: I,
+    ) -> &mut Self {
+        self.email.attachments = attachments.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Any text telling apart E-mails that are otherwise identical, so their entries are not batched together.
+    pub fn unique_by(&mut self, unique_by: &str) -> &mut Self {
+        self.email.unique_by = unique_by.to_owned();
+        self
+    }
+
+    /// The IANA timezone the `localtime` filter displays the timestamps in, e.g. `America/New_York`.
+    pub fn display_timezone(&mut self, display_timezone: &str) -> &mut Self {
+        self.email.display_timezone = Some(display_timezone.to_owned());
+        self
+    }
+
+    /// A custom header of the E-mail, e.g. `X-Campaign-Id`.
+    pub fn header(&mut self, name: &str, value: &str) -> &mut Self {
+        self.email.headers.insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    /// The values the template renders, a JSON object.
+    pub fn context(&mut self, context: serde_json::Value) -> &mut Self {
+        self.context = Some(context);
+        self
+    }
+
+    /// A value accumulated across the entries of the E-mail, as the `+key` context key, replacing an earlier value
+    /// of the same key. The template renders them as `key_values`.
+    pub fn accumulate(&mut self, key: &str, value: serde_json::Value) -> &mut Self {
+        self.accumulated.retain(|(existing, _)| existing != key);
+        self.accumulated.push((key.to_owned(), value));
+        self
+    }
+
+    /// Builds the entry.
+    /// ## Error
+    /// Fails if the context is not a JSON object, or the entry doesn't pass the entry schema or the rules of its
+    /// `email` section, e.g. without a template or an HTML body.
+    pub fn build(&self) -> Result<Entry, EntryError> {
+        let mut context = match &self.context {
+            Some(serde_json::Value::Object(context)) => context.clone(),
+            Some(_) => return Err(EntryError::WrongFieldType("context")),
+            None => serde_json::Map::new(),
+        };
+
+        for (key, value) in &self.accumulated {
+            context.insert(format!("+{key}"), value.clone());
+        }
+
+        let utc = Utc::now();
+        let id = self
+            .id
+            .clone()
+            .unwrap_or_else(|| format!("{}-{:016x}", utc.timestamp_micros(), fastrand::u64(..)));
+
+        let entry = Entry::new(
+            id,
+            utc.fixed_offset(),
+            self.notify_error.clone(),
+            self.email.clone(),
+            context,
+        );
+        entries::check_entry(&entry)?;
+
+        Ok(entry)
+    }
+}
+
+/// The outbox directory entries are enqueued into, read by the next run.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    path: PathBuf,
+    templates_path: Option<PathBuf>,
+}
+
+impl Outbox {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            templates_path: None,
+        }
+    }
+
+    /// Checks the template of every enqueued entry exists in this templates directory, as a run does.
+    pub fn templates<P: AsRef<Path>>(&mut self, templates_path: P) -> &mut Self {
+        self.templates_path = Some(templates_path.as_ref().to_owned());
+        self
+    }
+
+    /// Writes the entry into the outbox, named after its E-mail and its contents. The file appears at once, so a
+    /// concurrent run never reads it partially. Returns the ID of the E-mail of the entry.
+    ///
+    /// ```
+    /// use osa_mailer::{EntryBuilder, Outbox};
+    ///
+    /// let dir = tempfile::tempdir()?;
+    /// let entry = EntryBuilder::new()
+    ///     .from("osa@example.com")
+    ///     .to(["ops@example.com"])
+    ///     .subject("Backup failed")
+    ///     .html_body("<p>The nightly backup failed</p>")
+    ///     .build()?;
+    ///
+    /// let email_id = Outbox::new(dir.path()).enqueue(&entry)?;
+    /// assert_eq!(email_id, entry.email_id());
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    /// ## Error
+    /// Fails if the entry doesn't pass the checks of [`EntryBuilder::build`], its template doesn't exist, or it
+    /// can't be written.
+    pub fn enqueue(&self, entry: &Entry) -> Result<u32, EntryError> {
+        entries::check_entry(entry)?;
+        if let Some(templates_path) = &self.templates_path {
+            entries::validate_entry(entry, templates_path)?;
+        }
+
+        let write_failure = |path: &Path, error| EntryError::WriteFailure {
+            path: path.display().to_string(),
+            error,
+        };
+
+        fs::create_dir_all(&self.path).map_err(|error| write_failure(&self.path, error))?;
+
+        let content = serde_json::to_string(entry).expect("An entry is always valid JSON");
+        let email_id = entry.email_id();
+
+        let path = self.path.join(format!(
+            "{email_id:08x}-{}{ENTRY_EXT}",
+            entries::string_crc32_iso_hdlc_checksum(&content)
+        ));
+        let partial_path = path.with_extension("part");
+
+        fs::write(&partial_path, &content).map_err(|error| write_failure(&partial_path, error))?;
+        fs::rename(&partial_path, &path).map_err(|error| write_failure(&path, error))?;
+
+        Ok(email_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signing::Verifier;
+    use serde_json::json;
+
+    fn builder() -> EntryBuilder {
+        let mut builder = EntryBuilder::new();
+        builder
+            .system("backup")
+            .from("osa@example.com")
+            .to(["ops@example.com"])
+            .subject("Disk usage")
+            .template("ops_department")
+            .context(json!({ "title": "Disk usage" }));
+        builder
+    }
+
+    #[test]
+    fn test_round_trip_through_load_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let templates_path = dir.path().join("templates");
+        fs::create_dir_all(templates_path.join("ops_department")).unwrap();
+        fs::write(
+            templates_path
+                .join("ops_department")
+                .join(crate::templates::TEMPLATE_FILE),
+            "",
+        )
+        .unwrap();
+
+        let mut outbox = Outbox::new(dir.path().join("outbox"));
+        outbox.templates(&templates_path);
+
+        let mut email_ids = Vec::new();
+        for (host, usage) in [("db-1", 93), ("db-2", 97)] {
+            let entry = builder()
+                .accumulate("rows", json!({ "host": host, "usage": usage }))
+                .build()
+                .unwrap();
+            email_ids.push(outbox.enqueue(&entry).unwrap());
+        }
+        assert_eq!(email_ids[0], email_ids[1]);
+
+        // Read back as a run reads the outbox, without partial files
+        let loaded = entries::load_entries(dir.path().join("outbox"), &Verifier::default());
+        assert!(loaded.err.is_empty());
+        assert_eq!(loaded.ok.len(), 2);
+        assert!(loaded
+            .ok
+            .iter()
+            .all(|entry| entry.email_id() == email_ids[0]));
+        assert_eq!(fs::read_dir(dir.path().join("outbox")).unwrap().count(), 2);
+
+        let composed = entries::compose_emails(&entries::map_emails(&loaded.ok)).ok;
+        assert_eq!(composed.len(), 1);
+        assert_eq!(composed[0].id, email_ids[0]);
+        assert_eq!(composed[0].context["title"], "Disk usage");
+        let rows: Vec<_> = composed[0].context["rows"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row["value"]["host"].clone())
+            .collect();
+        assert_eq!(rows, vec![json!("db-1"), json!("db-2")]);
+
+        // An unknown template is refused, as a run refuses it
+        let entry = builder().template("missing").build().unwrap();
+        assert!(matches!(
+            outbox.enqueue(&entry),
+            Err(EntryError::MissingTemplate(template)) if template == "missing"
+        ));
+    }
+
+    #[test]
+    fn test_accumulate_key_form() {
+        let entry = builder()
+            .accumulate("rows", json!([1, 2]))
+            .accumulate("rows", json!([3]))
+            .build()
+            .unwrap();
+
+        let value = serde_json::to_value(&entry).unwrap();
+        assert_eq!(value["context"]["+rows"], json!([3]));
+        assert!(value["context"].get("rows").is_none());
+        assert!(!value["id"].as_str().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_build_matches_lint_rules() {
+        // Neither a template nor an HTML body
+        assert!(matches!(
+            builder().template("").build(),
+            Err(EntryError::TemplateOrBody)
+        ));
+
+        // Both
+        assert!(matches!(
+            builder().html_body("<p>Hi</p>").build(),
+            Err(EntryError::TemplateOrBody)
+        ));
+
+        assert!(matches!(
+            builder().context(json!([1])).build(),
+            Err(EntryError::WrongFieldType("context"))
+        ));
+
+        // The built entries pass the schema `lint --schema` checks the entry files against
+        let entry = builder().build().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        Outbox::new(dir.path()).enqueue(&entry).unwrap();
+        let file = fs::read_dir(dir.path()).unwrap().next().unwrap().unwrap();
+        assert!(
+            crate::schema::validate_file(&crate::schema::entry_schema(), &file.path())
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,261 @@
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// The prefix of the verbatim paths returned by Windows, e.g. by `canonicalize`: `\\?\C:\mailer`.
+const VERBATIM_PREFIX: &str = r"\\?\";
+
+/// The verbatim prefix of a UNC path: `\\?\UNC\fileserver\mailer` is `\\fileserver\mailer`.
+const VERBATIM_UNC_PREFIX: &str = r"\\?\UNC\";
+
+/// The longest path the Windows file APIs accept without the verbatim prefix, including its terminating null.
+const MAX_PATH: usize = 260;
+
+/// Removes the verbatim prefix of a disk or UNC path, e.g. `\\?\UNC\fileserver\mailer` becomes
+/// `\\fileserver\mailer`. Other paths, such as `\\?\Volume{..}\`, are kept as they are.
+pub(crate) fn strip_verbatim(path: &str) -> Cow<'_, str> {
+    if let Some(unc) = path.strip_prefix(VERBATIM_UNC_PREFIX) {
+        return Cow::Owned(format!(r"\\{unc}"));
+    }
+
+    match path.strip_prefix(VERBATIM_PREFIX) {
+        Some(disk) if is_disk_path(disk) => Cow::Borrowed(disk),
+        _ => Cow::Borrowed(path),
+    }
+}
+
+/// The verbatim form of an absolute disk or UNC path, which Windows accepts beyond `MAX_PATH`.
+/// As verbatim paths are not normalized by Windows, `/` becomes `\` and `.` components are removed.
+/// Relative and already verbatim paths are kept as they are.
+pub(crate) fn to_verbatim(path: &str) -> Cow<'_, str> {
+    if path.starts_with(VERBATIM_PREFIX) {
+        return Cow::Borrowed(path);
+    }
+
+    let path = path.replace('/', "\\");
+
+    let verbatim = if let Some(unc) = path.strip_prefix(r"\\") {
+        format!("{VERBATIM_UNC_PREFIX}{unc}")
+    } else if is_disk_path(&path) {
+        format!("{VERBATIM_PREFIX}{path}")
+    } else {
+        return Cow::Owned(path);
+    };
+
+    Cow::Owned(
+        verbatim
+            .split('\\')
+            .filter(|component| *component != ".")
+            .collect::<Vec<_>>()
+            .join("\\"),
+    )
+}
+
+/// Whether the path starts with a drive and its root, e.g. `C:\`.
+fn is_disk_path(path: &str) -> bool {
+    let bytes = path.as_bytes();
+
+    bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && bytes[2] == b'\\'
+}
+
+/// The path as shown to users and in error messages, without a verbatim prefix.
+pub(crate) fn display(path: &Path) -> String {
+    strip_verbatim(&path.to_string_lossy()).into_owned()
+}
+
+/// The path without its verbatim prefix, as kept by [`crate::render::AbsolutePath`] and used to
+/// build template names. Only Windows paths have one.
+pub(crate) fn simplified(path: PathBuf) -> PathBuf {
+    let stripped = match path.to_str() {
+        Some(string) if cfg!(windows) && string.starts_with(VERBATIM_PREFIX) => {
+            strip_verbatim(string).into_owned()
+        }
+        _ => return path,
+    };
+
+    PathBuf::from(stripped)
+}
+
+/// The path to give to the file system calls: on Windows, the verbatim form of an absolute path
+/// too long for `MAX_PATH`. Only use it at the call, never to display or to join further.
+pub(crate) fn long_path(path: &Path) -> Cow<'_, Path> {
+    if cfg!(windows) && path.as_os_str().len() >= MAX_PATH {
+        if let Some(string) = path.to_str() {
+            let verbatim = to_verbatim(string);
+
+            if verbatim.starts_with(VERBATIM_PREFIX) && verbatim != string {
+                return Cow::Owned(PathBuf::from(verbatim.into_owned()));
+            }
+        }
+    }
+
+    Cow::Borrowed(path)
+}
+
+/// The absolute path with its symbolic links, `.` and `..` resolved, so paths to the same file compare equal.
+/// The components past the part of the path that exists are resolved lexically.
+pub(crate) fn resolved(path: &Path) -> PathBuf {
+    let mut existing = path;
+    let mut missing = Vec::new();
+
+    let mut resolved = loop {
+        let current = if existing.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            existing
+        };
+        if let Ok(canonical) = fs::canonicalize(long_path(current)) {
+            break simplified(canonical);
+        }
+
+        match (existing.parent(), existing.components().next_back()) {
+            (Some(parent), Some(component)) => {
+                missing.push(component);
+                existing = parent;
+            }
+            _ => return path.to_owned(),
+        }
+    };
+
+    for component in missing.into_iter().rev() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            component => resolved.push(component),
+        }
+    }
+
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_verbatim() {
+        assert_eq!(strip_verbatim(r"\\?\C:\mailer\outbox"), r"C:\mailer\outbox");
+        assert_eq!(
+            strip_verbatim(r"\\?\UNC\fileserver\mailer\templates"),
+            r"\\fileserver\mailer\templates"
+        );
+
+        // Without a verbatim prefix, or without an equivalent non-verbatim form
+        for path in [
+            r"\\fileserver\mailer",
+            r"C:\mailer",
+            "templates/daily",
+            "/opt/mailer",
+            r"\\?\Volume{b75e2c83-0000-0000-0000-602f00000000}\mailer",
+            r"\\?\pipe\mailer",
+        ] {
+            assert_eq!(strip_verbatim(path), path);
+        }
+    }
+
+    #[test]
+    fn test_to_verbatim() {
+        assert_eq!(
+            to_verbatim(r"C:\mailer\.\templates/daily/template.html"),
+            r"\\?\C:\mailer\templates\daily\template.html"
+        );
+        assert_eq!(
+            to_verbatim(r"\\fileserver\mailer\outbox"),
+            r"\\?\UNC\fileserver\mailer\outbox"
+        );
+        assert_eq!(to_verbatim(r"\\?\C:\mailer"), r"\\?\C:\mailer");
+        assert_eq!(to_verbatim("templates/daily"), r"templates\daily");
+
+        // Round trips through the display form
+        for path in [r"C:\mailer\outbox", r"\\fileserver\mailer\outbox"] {
+            assert_eq!(strip_verbatim(&to_verbatim(path)), path);
+        }
+    }
+
+    #[test]
+    fn test_display_and_long_path() {
+        assert_eq!(
+            display(Path::new("/opt/mailer/outbox")),
+            "/opt/mailer/outbox"
+        );
+        assert_eq!(
+            display(Path::new(r"\\?\UNC\fileserver\mailer")),
+            r"\\fileserver\mailer"
+        );
+
+        let short = Path::new("/opt/mailer/templates/daily/template.html");
+        assert_eq!(long_path(short), short);
+    }
+
+    #[test]
+    fn test_resolved() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = simplified(fs::canonicalize(dir.path()).unwrap());
+        fs::create_dir_all(root.join("outbox")).unwrap();
+
+        assert_eq!(resolved(&dir.path().join("outbox")), root.join("outbox"));
+        assert_eq!(
+            resolved(&dir.path().join("other/../outbox/./failed")),
+            root.join("outbox").join("failed")
+        );
+        assert_eq!(
+            resolved(&dir.path().join("outbox/missing/../failed")),
+            root.join("outbox").join("failed")
+        );
+        assert_eq!(
+            resolved(Path::new("outbox")),
+            simplified(std::env::current_dir().unwrap()).join("outbox")
+        );
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("outbox"), root.join("link")).unwrap();
+            assert_eq!(
+                resolved(&dir.path().join("link/failed")),
+                root.join("outbox").join("failed")
+            );
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_paths() {
+        assert_eq!(
+            simplified(PathBuf::from(r"\\?\UNC\fileserver\mailer\templates")),
+            Path::new(r"\\fileserver\mailer\templates")
+        );
+        assert_eq!(
+            simplified(PathBuf::from(r"\\?\C:\mailer")),
+            Path::new(r"C:\mailer")
+        );
+
+        let long = format!(
+            r"\\fileserver\mailer\{}\template.html",
+            "d".repeat(MAX_PATH)
+        );
+        assert_eq!(
+            long_path(Path::new(&long)),
+            Path::new(&format!(
+                r"\\?\UNC\fileserver\mailer\{}\template.html",
+                "d".repeat(MAX_PATH)
+            ))
+        );
+        assert_eq!(
+            long_path(Path::new(r"\\fileserver\mailer\template.html")),
+            Path::new(r"\\fileserver\mailer\template.html")
+        );
+
+        // A long path is read through its verbatim form
+        let dir = tempfile::tempdir().unwrap();
+        let mut deep = simplified(std::fs::canonicalize(dir.path()).unwrap());
+        while deep.as_os_str().len() < MAX_PATH {
+            deep.push("a".repeat(40));
+        }
+        std::fs::create_dir_all(long_path(&deep)).unwrap();
+        let file = deep.join("template.html");
+        std::fs::write(long_path(&file), "long").unwrap();
+        assert_eq!(std::fs::read_to_string(long_path(&file)).unwrap(), "long");
+    }
+}
@@ -0,0 +1,378 @@
+use lazy_static::lazy_static;
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use std::fmt;
+
+lazy_static! {
+    static ref ANCHOR_TAG_PATTERN: Regex = RegexBuilder::new(r"<a\b[^>]*>")
+        .case_insensitive(true)
+        .build()
+        .unwrap();
+    static ref IMAGE_TAG_PATTERN: Regex = RegexBuilder::new(r"<img\b[^>]*>")
+        .case_insensitive(true)
+        .build()
+        .unwrap();
+    static ref URL_ATTRIBUTE_PATTERN: Regex =
+        RegexBuilder::new(r#"\b(?:href|src|action)\s*=\s*["']?([^"'\s>]+)"#)
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+    static ref IP_URL_PATTERN: Regex = RegexBuilder::new(
+        r"^[a-z][a-z0-9+.-]*://(?:[^@/]*@)?(?:\d{1,3}(?:\.\d{1,3}){3}|\[[0-9a-f:.]+\])(?::\d+)?(?:[/?#]|$)"
+    )
+    .case_insensitive(true)
+    .build()
+    .unwrap();
+    static ref BLANK_TARGET_PATTERN: Regex =
+        RegexBuilder::new(r#"\btarget\s*=\s*["']?_blank\b"#)
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+    static ref NOOPENER_PATTERN: Regex =
+        RegexBuilder::new(r#"\brel\s*=\s*(?:"[^"]*\bnoopener\b[^"]*"|'[^']*\bnoopener\b[^']*'|noopener\b)"#)
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+    static ref ALT_ATTRIBUTE_PATTERN: Regex = RegexBuilder::new(r"\balt\s*=")
+        .case_insensitive(true)
+        .build()
+        .unwrap();
+}
+
+/// The longest snippet of offending HTML quoted in a violation.
+const MAX_SNIPPET_CHARS: usize = 80;
+
+/// What a rule violation does to the E-mail.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RuleSeverity {
+    /// The rule is not checked
+    Off,
+
+    /// The E-mail is sent, with a warning
+    Warn,
+
+    /// The E-mail is not sent
+    Error,
+}
+
+impl fmt::Display for RuleSeverity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleSeverity::Off => write!(f, "off"),
+            RuleSeverity::Warn => write!(f, "warn"),
+            RuleSeverity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// The built-in content policy rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rule {
+    /// A `http://` link or resource
+    InsecureLink,
+
+    /// A link or resource addressed by a raw IP address instead of a host name
+    IpUrl,
+
+    /// A `target="_blank"` link without `rel="noopener"`
+    BlankTarget,
+
+    /// An image without alternative text
+    MissingAlt,
+
+    /// A word of the `forbidden_words` list
+    ForbiddenWord,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::InsecureLink => write!(f, "insecure-link"),
+            Rule::IpUrl => write!(f, "ip-url"),
+            Rule::BlankTarget => write!(f, "blank-target"),
+            Rule::MissingAlt => write!(f, "missing-alt"),
+            Rule::ForbiddenWord => write!(f, "forbidden-word"),
+        }
+    }
+}
+
+/// The content policy of the rendered HTML, set in `template.toml` under `[policy]`.
+/// Every built-in rule warns by default, and no word is forbidden.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub(crate) struct PolicyConfig {
+    pub(crate) insecure_link: RuleSeverity,
+    pub(crate) ip_url: RuleSeverity,
+    pub(crate) blank_target: RuleSeverity,
+    pub(crate) missing_alt: RuleSeverity,
+    pub(crate) forbidden_word: RuleSeverity,
+
+    /// Words that must not appear in the text of the E-mail, matched as whole words ignoring case
+    pub(crate) forbidden_words: Vec<String>,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            insecure_link: RuleSeverity::Warn,
+            ip_url: RuleSeverity::Warn,
+            blank_target: RuleSeverity::Warn,
+            missing_alt: RuleSeverity::Warn,
+            forbidden_word: RuleSeverity::Warn,
+            forbidden_words: Vec::new(),
+        }
+    }
+}
+
+/// A policy rule broken by the rendered HTML.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Violation {
+    pub(crate) rule: Rule,
+    pub(crate) severity: RuleSeverity,
+
+    /// 1-based line and column (in characters) of the offending HTML
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+    pub(crate) snippet: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Policy `{}` ({}) at {}:{}: `{}`",
+            self.rule, self.severity, self.line, self.column, self.snippet
+        )
+    }
+}
+
+impl PolicyConfig {
+    fn severity(&self, rule: Rule) -> RuleSeverity {
+        match rule {
+            Rule::InsecureLink => self.insecure_link,
+            Rule::IpUrl => self.ip_url,
+            Rule::BlankTarget => self.blank_target,
+            Rule::MissingAlt => self.missing_alt,
+            Rule::ForbiddenWord => self.forbidden_word,
+        }
+    }
+
+    /// Scans the rendered HTML for violations of the enabled rules, in order of appearance.
+    pub(crate) fn check(&self, html: &str) -> Vec<Violation> {
+        let mut found: Vec<(Rule, usize, &str)> = Vec::new();
+
+        for url in URL_ATTRIBUTE_PATTERN
+            .captures_iter(html)
+            .filter_map(|cap| cap.get(1))
+        {
+            if url.as_str().to_lowercase().starts_with("http://") {
+                found.push((Rule::InsecureLink, url.start(), url.as_str()));
+            }
+
+            if IP_URL_PATTERN.is_match(url.as_str()) {
+                found.push((Rule::IpUrl, url.start(), url.as_str()));
+            }
+        }
+
+        for tag in ANCHOR_TAG_PATTERN.find_iter(html) {
+            if BLANK_TARGET_PATTERN.is_match(tag.as_str())
+                && !NOOPENER_PATTERN.is_match(tag.as_str())
+            {
+                found.push((Rule::BlankTarget, tag.start(), tag.as_str()));
+            }
+        }
+
+        for tag in IMAGE_TAG_PATTERN.find_iter(html) {
+            if !ALT_ATTRIBUTE_PATTERN.is_match(tag.as_str()) {
+                found.push((Rule::MissingAlt, tag.start(), tag.as_str()));
+            }
+        }
+
+        for word in &self.forbidden_words {
+            let Ok(pattern) = RegexBuilder::new(&format!(r"\b{}\b", regex::escape(word)))
+                .case_insensitive(true)
+                .build()
+            else {
+                continue;
+            };
+
+            // Only the text counts, not the tags and their attributes
+            for word in pattern
+                .find_iter(html)
+                .filter(|word| !is_within_tag(html, word.start()))
+            {
+                found.push((Rule::ForbiddenWord, word.start(), word.as_str()));
+            }
+        }
+
+        let mut violations: Vec<Violation> = found
+            .into_iter()
+            .filter_map(|(rule, offset, snippet)| {
+                let severity = self.severity(rule);
+                if severity == RuleSeverity::Off {
+                    return None;
+                }
+
+                let (line, column) = position(html, offset);
+
+                Some(Violation {
+                    rule,
+                    severity,
+                    line,
+                    column,
+                    snippet: truncate(snippet),
+                })
+            })
+            .collect();
+
+        violations.sort_by_key(|violation| (violation.line, violation.column));
+        violations
+    }
+}
+
+/// Whether the byte offset is inside an HTML tag.
+fn is_within_tag(html: &str, offset: usize) -> bool {
+    let before = &html[..offset];
+    before.rfind('<') > before.rfind('>')
+}
+
+/// The 1-based line and column of a byte offset.
+fn position(html: &str, offset: usize) -> (usize, usize) {
+    let before = &html[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+fn truncate(snippet: &str) -> String {
+    let snippet = snippet.replace(['\r', '\n'], " ");
+
+    if snippet.chars().count() <= MAX_SNIPPET_CHARS {
+        return snippet;
+    }
+
+    snippet.chars().take(MAX_SNIPPET_CHARS).collect::<String>() + "…"
+}
+
+/// Whether any of the violations fails the E-mail.
+#[inline]
+pub(crate) fn has_errors(violations: &[Violation]) -> bool {
+    violations
+        .iter()
+        .any(|violation| violation.severity == RuleSeverity::Error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(violations: &[Violation]) -> Vec<Rule> {
+        violations.iter().map(|violation| violation.rule).collect()
+    }
+
+    #[test]
+    fn test_insecure_link_rule() {
+        let policy = PolicyConfig::default();
+        let html = "<p>\n  <a href=\"http://x.com/report\">Report</a> <a href=\"https://x.com\">Ok</a>\n</p>";
+
+        let violations = policy.check(html);
+        assert_eq!(rules(&violations), vec![Rule::InsecureLink]);
+        assert_eq!(violations[0].snippet, "http://x.com/report");
+        assert_eq!((violations[0].line, violations[0].column), (2, 12));
+        assert_eq!(violations[0].severity, RuleSeverity::Warn);
+    }
+
+    #[test]
+    fn test_ip_url_rule() {
+        let policy = PolicyConfig::default();
+
+        let violations = policy.check(r#"<img alt="" src="https://10.0.0.12:8443/logo.png">"#);
+        assert_eq!(rules(&violations), vec![Rule::IpUrl]);
+
+        let violations = policy.check(r#"<a href="https://[::1]/x">x</a>"#);
+        assert_eq!(rules(&violations), vec![Rule::IpUrl]);
+
+        // Both insecure and addressed by IP
+        let violations = policy.check(r#"<a href="http://192.168.1.1">x</a>"#);
+        assert_eq!(rules(&violations), vec![Rule::InsecureLink, Rule::IpUrl]);
+
+        assert!(policy
+            .check(r#"<a href="https://v1.2.3.4.x.com/">x</a>"#)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_blank_target_rule() {
+        let policy = PolicyConfig::default();
+
+        let violations = policy.check(r#"<a href="https://x.com" target="_blank">x</a>"#);
+        assert_eq!(rules(&violations), vec![Rule::BlankTarget]);
+        assert!(violations[0].snippet.starts_with("<a href="));
+
+        assert!(policy
+            .check(r#"<a href="https://x.com" target="_blank" rel="noopener noreferrer">x</a>"#)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_missing_alt_rule() {
+        let policy = PolicyConfig::default();
+
+        let violations = policy.check(r#"<img src="cid:logo"><img src="cid:chart" alt="Chart">"#);
+        assert_eq!(rules(&violations), vec![Rule::MissingAlt]);
+        assert_eq!(violations[0].column, 1);
+    }
+
+    #[test]
+    fn test_forbidden_word_rule() {
+        let policy = PolicyConfig {
+            forbidden_words: vec!["guaranteed".to_owned()],
+            ..Default::default()
+        };
+
+        let html = r#"<p class="guaranteed">Results GUARANTEED, not guaranteedly</p>"#;
+        let violations = policy.check(html);
+        assert_eq!(rules(&violations), vec![Rule::ForbiddenWord]);
+        assert_eq!(violations[0].snippet, "GUARANTEED");
+    }
+
+    #[test]
+    fn test_rule_severity_config() {
+        let config: crate::templates::TemplateConfig = toml::from_str(
+            r#"
+            [policy]
+            insecure_link = "error"
+            missing_alt = "off"
+            forbidden_words = ["free"]
+            "#,
+        )
+        .unwrap();
+
+        let policy = config.policy;
+        assert_eq!(policy.ip_url, RuleSeverity::Warn);
+
+        let violations = policy.check(r#"<img src="http://x.com/a.png"> Free"#);
+        assert_eq!(
+            violations
+                .iter()
+                .map(|violation| (violation.rule, violation.severity))
+                .collect::<Vec<_>>(),
+            vec![
+                (Rule::InsecureLink, RuleSeverity::Error),
+                (Rule::ForbiddenWord, RuleSeverity::Warn)
+            ]
+        );
+        assert!(has_errors(&violations));
+        assert!(violations[0]
+            .to_string()
+            .starts_with("Policy `insecure-link` (error) at 1:11:"));
+
+        let config: Result<crate::templates::TemplateConfig, _> =
+            toml::from_str("[policy]\nip_url = \"fatal\"");
+        assert!(config.is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use anyhow::{anyhow, Context, Result};
+use std::io::Read;
+use std::time::Duration;
+
+use crate::send::ImageFetcher;
+use crate::tls;
+
+/// Fetches the remote images of the templates over HTTP(S), verifying servers as with SMTP.
+#[derive(Debug)]
+pub(crate) struct HttpImageFetcher {
+    client: reqwest::blocking::Client,
+
+    /// The largest image fetched, in bytes
+    max_size: u64,
+}
+
+impl HttpImageFetcher {
+    /// ## Error
+    /// Fails if the HTTP client can't be set up.
+    pub(crate) fn new(timeout: Duration, max_size: u64) -> Result<Self> {
+        let tls_config = tls::client_config().map_err(|e| anyhow!(e))?;
+
+        let client = reqwest::blocking::Client::builder()
+            .timeout(timeout)
+            .tls_backend_preconfigured(tls_config)
+            .build()
+            .context("Unable to set up the HTTP client of remote images")?;
+
+        Ok(Self { client, max_size })
+    }
+}
+
+impl ImageFetcher for HttpImageFetcher {
+    fn fetch(&self, url: &str) -> Result<Vec<u8>> {
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .and_then(|response| response.error_for_status())?;
+
+        let too_large = || anyhow!("The image is larger than {} bytes", self.max_size);
+        if response
+            .content_length()
+            .is_some_and(|length| length > self.max_size)
+        {
+            return Err(too_large());
+        }
+
+        // The announced length may be missing or wrong, so the body is read one byte past the limit
+        let mut data = Vec::new();
+        response.take(self.max_size + 1).read_to_end(&mut data)?;
+        if data.len() as u64 > self.max_size {
+            return Err(too_large());
+        }
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    /// Serves a single request with the given status line and body, returning the request line.
+    fn serve_once(
+        status: &'static str,
+        body: &'static [u8],
+    ) -> (u16, std::thread::JoinHandle<String>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+            }
+
+            let headers = format!(
+                "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            (&stream).write_all(headers.as_bytes()).unwrap();
+            (&stream).write_all(body).unwrap();
+            request_line
+        });
+
+        (port, server)
+    }
+
+    #[test]
+    fn test_fetch() {
+        let fetcher = HttpImageFetcher::new(Duration::from_secs(10), 1024).unwrap();
+
+        let (port, server) = serve_once("200 OK", PNG);
+        let data = fetcher
+            .fetch(&format!("http://127.0.0.1:{port}/logo.png"))
+            .unwrap();
+        assert_eq!(data, PNG);
+        assert!(server.join().unwrap().starts_with("GET /logo.png "));
+
+        let (port, server) = serve_once("404 Not Found", b"");
+        assert!(fetcher
+            .fetch(&format!("http://127.0.0.1:{port}/missing.png"))
+            .is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_fetch_too_large() {
+        let fetcher = HttpImageFetcher::new(Duration::from_secs(10), 4).unwrap();
+
+        let (port, server) = serve_once("200 OK", PNG);
+        let error = fetcher
+            .fetch(&format!("http://127.0.0.1:{port}/logo.png"))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "The image is larger than 4 bytes");
+        server.join().unwrap();
+    }
+}
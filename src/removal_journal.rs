@@ -0,0 +1,443 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::dead_letter;
+use crate::entries::{self, ParsedEntry};
+use crate::sent_log::{self, LogRecord};
+
+/// The entries of the E-mails being sent, one JSON record per line, kept next to the sent-log.
+/// Entries of a sent E-mail that couldn't be removed are recognized by it, so they are never sent again.
+pub(crate) const REMOVAL_JOURNAL_FILE: &str = "removal_journal.jsonl";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RemovalStatus {
+    /// Recorded before sending. Without a later `sent` record, the run stopped before the E-mail was accepted
+    Pending,
+
+    /// The E-mail was accepted, its entries are due for removal
+    Sent,
+}
+
+/// An entry contributing to an E-mail, identified by its file and its contents, so a new entry written
+/// under the same file name is not mistaken for it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct JournaledEntry {
+    pub(crate) path: PathBuf,
+    pub(crate) checksum: String,
+}
+
+impl JournaledEntry {
+    /// The journaled form of an entry read from a file. Entries without a file are never journaled.
+    pub(crate) fn of(entry: &ParsedEntry) -> Option<Self> {
+        Some(Self {
+            path: entry.path.clone()?,
+            checksum: entry.checksum(),
+        })
+    }
+}
+
+/// The entries of an E-mail, before or after it was sent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct RemovalRecord {
+    pub(crate) recorded_at: DateTime<Utc>,
+    pub(crate) email_id: u32,
+    pub(crate) content_hash: String,
+    pub(crate) status: RemovalStatus,
+    pub(crate) entries: Vec<JournaledEntry>,
+}
+
+impl LogRecord for RemovalRecord {
+    #[inline]
+    fn sent_at(&self) -> DateTime<Utc> {
+        self.recorded_at
+    }
+}
+
+/// The journaled entries of the previous runs.
+#[derive(Debug, Default)]
+pub(crate) struct RemovalJournal {
+    path: PathBuf,
+    records: Vec<RemovalRecord>,
+    sent: HashSet<JournaledEntry>,
+}
+
+/// The outbox entries, sorted out by [`RemovalJournal::sort_out`].
+#[derive(Default)]
+pub(crate) struct Leftovers {
+    /// The entries to compose
+    pub(crate) pending: Vec<Rc<ParsedEntry>>,
+
+    /// Entries of sent E-mails that couldn't be removed by a previous run
+    pub(crate) sent: Vec<PathBuf>,
+
+    /// Entries of an E-mail a previous run may have sent without recording it, composed again
+    pub(crate) unconfirmed: Vec<PathBuf>,
+}
+
+/// Removes an entry file along with its delivery state, if any.
+pub(crate) fn remove_entry(entry_path: &Path) -> std::io::Result<()> {
+    fs::remove_file(entry_path)?;
+    let _ = fs::remove_file(dead_letter::state_path(entry_path));
+    Ok(())
+}
+
+/// What becomes of the entries of sent E-mails.
+#[derive(Debug, Clone, Default)]
+pub(crate) enum SentEntries {
+    /// Removed from the outbox
+    #[default]
+    Remove,
+
+    /// Moved into `archive_root`, see [`entries::archive_entry`]
+    Archive {
+        outbox_path: PathBuf,
+        archive_root: PathBuf,
+    },
+}
+
+impl SentEntries {
+    /// Removes or archives the entry of a sent E-mail. An entry that can't be archived is removed.
+    pub(crate) fn retire(&self, entry_path: &Path) -> std::io::Result<()> {
+        if let Self::Archive {
+            outbox_path,
+            archive_root,
+        } = self
+        {
+            match entries::archive_entry(entry_path, archive_root, outbox_path, Utc::now()) {
+                Ok(_) => return Ok(()),
+                Err(e) => log::warn!("{e:#}, removing the entry instead"),
+            }
+        }
+
+        remove_entry(entry_path)
+    }
+}
+
+/// Retries the removal, or archiving, of the leftovers of sent E-mails, returning the ones that still can't be removed.
+pub(crate) fn remove_leftovers(leftovers: &[PathBuf], sent_entries: &SentEntries) -> Vec<PathBuf> {
+    leftovers
+        .iter()
+        .filter(|path| match sent_entries.retire(path) {
+            Ok(()) => false,
+            Err(e) => {
+                log::warn!(
+                    "Unable to remove the entry \"{}\" of a sent E-mail: {e}",
+                    path.display()
+                );
+                true
+            }
+        })
+        .cloned()
+        .collect()
+}
+
+impl RemovalJournal {
+    /// Loads the journal. A missing journal has no records.
+    pub(crate) fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let records: Vec<RemovalRecord> = sent_log::load_since(path, DateTime::<Utc>::MIN_UTC)?;
+
+        let sent = records
+            .iter()
+            .filter(|record| record.status == RemovalStatus::Sent)
+            .flat_map(|record| record.entries.iter().cloned())
+            .collect();
+
+        Ok(Self {
+            path: path.to_owned(),
+            records,
+            sent,
+        })
+    }
+
+    /// Appends a record to the journal.
+    pub(crate) fn record(&mut self, record: RemovalRecord) -> Result<()> {
+        sent_log::append(&self.path, &record)?;
+
+        if record.status == RemovalStatus::Sent {
+            self.sent.extend(record.entries.iter().cloned());
+        }
+        self.records.push(record);
+
+        Ok(())
+    }
+
+    /// Separates the leftovers of sent E-mails from the outbox entries, so only the entries that weren't sent
+    /// are composed. A batch is never sent again, not even partially.
+    pub(crate) fn sort_out(&self, entries: Vec<Rc<ParsedEntry>>) -> Leftovers {
+        let unconfirmed: HashSet<&JournaledEntry> = self
+            .records
+            .iter()
+            .filter(|record| record.status == RemovalStatus::Pending)
+            .flat_map(|record| record.entries.iter())
+            .filter(|entry| !self.sent.contains(*entry))
+            .collect();
+
+        let mut leftovers = Leftovers::default();
+
+        for entry in entries {
+            match JournaledEntry::of(&entry) {
+                Some(journaled) if self.sent.contains(&journaled) => {
+                    leftovers.sent.push(journaled.path)
+                }
+                Some(journaled) => {
+                    if unconfirmed.contains(&journaled) {
+                        leftovers.unconfirmed.push(journaled.path);
+                    }
+                    leftovers.pending.push(entry);
+                }
+                None => leftovers.pending.push(entry),
+            }
+        }
+
+        leftovers
+    }
+
+    /// The entry files of every journaled E-mail, pending or sent, which retention never removes.
+    pub(crate) fn referenced(&self) -> HashSet<PathBuf> {
+        self.records
+            .iter()
+            .flat_map(|record| record.entries.iter())
+            .map(|entry| entry.path.clone())
+            .collect()
+    }
+
+    /// Drops the records whose entries are all gone, keeping the sent E-mails that still have leftovers.
+    /// Pending records were sorted out by [`RemovalJournal::sort_out`] and are dropped as well.
+    pub(crate) fn compact(&mut self) -> Result<()> {
+        self.records.retain(|record| {
+            record.status == RemovalStatus::Sent
+                && record.entries.iter().any(|entry| entry.path.is_file())
+        });
+
+        let mut contents = String::new();
+        for record in &self.records {
+            contents
+                .push_str(&serde_json::to_string(record).expect("Log record is always valid JSON"));
+            contents.push('\n');
+        }
+
+        if contents.is_empty() && !self.path.is_file() {
+            return Ok(());
+        }
+
+        sent_log::replace(&self.path, &contents).with_context(|| {
+            format!(
+                "Unable to write removal journal \"{}\"",
+                self.path.display()
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entries::{self, ComposedEmail, Entry, ENTRY_EXT};
+    use crate::signing::Verifier;
+    use serde_json::json;
+
+    fn write_entry(outbox: &Path, id: &str, utc: &str, event: &str) {
+        let entry = json!({
+            "id": id,
+            "utc": utc,
+            "notify_error": [],
+            "email": {
+                "system": "sys",
+                "subsystem": "sub",
+                "from": "a@x.com",
+                "to": ["b@x.com"],
+                "cc": [],
+                "bcc": [],
+                "reply_to": [],
+                "subject": "Digest",
+                "template": "ops_department",
+                "alternative_content": "",
+                "attachments": [],
+                "unique_by": ""
+            },
+            "context": { "+events": event }
+        });
+        fs::write(outbox.join(format!("{id}{ENTRY_EXT}")), entry.to_string()).unwrap();
+    }
+
+    fn compose(entries: &[Rc<ParsedEntry>]) -> Vec<ComposedEmail> {
+        entries::compose_emails(&entries::map_emails(&entries.to_vec())).ok
+    }
+
+    fn events(email: &ComposedEmail) -> Vec<String> {
+        email.context["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event["value"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    /// Journals the E-mail as pending, then as sent, the way a run does around sending it.
+    fn send(journal: &mut RemovalJournal, email: &ComposedEmail, entries: &[Rc<ParsedEntry>]) {
+        let record = |status| RemovalRecord {
+            recorded_at: Utc::now(),
+            email_id: email.id,
+            content_hash: email.content_hash(),
+            status,
+            entries: entries
+                .iter()
+                .filter_map(|e| JournaledEntry::of(e))
+                .collect(),
+        };
+
+        journal.record(record(RemovalStatus::Pending)).unwrap();
+        journal.record(record(RemovalStatus::Sent)).unwrap();
+    }
+
+    #[test]
+    fn test_partially_removed_batch_is_not_sent_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = dir.path().join("outbox");
+        fs::create_dir_all(&outbox).unwrap();
+        let journal_path = dir.path().join(REMOVAL_JOURNAL_FILE);
+
+        for (i, event) in ["a", "b", "c", "d"].iter().enumerate() {
+            write_entry(
+                &outbox,
+                &format!("{i}"),
+                &format!("2023-01-01T10:0{i}:00+00:00"),
+                event,
+            );
+        }
+
+        // The first run sends the batch, but only removes two of its four entries
+        let mut journal = RemovalJournal::load(&journal_path).unwrap();
+        let entries = entries::load_entries(&outbox, &Verifier::default()).ok;
+        let emails = compose(&entries);
+        assert_eq!(emails.len(), 1);
+        assert_eq!(events(&emails[0]), vec!["a", "b", "c", "d"]);
+
+        send(&mut journal, &emails[0], &entries);
+        for id in ["0", "2"] {
+            remove_entry(&outbox.join(format!("{id}{ENTRY_EXT}"))).unwrap();
+        }
+        journal.compact().unwrap();
+
+        // Only the new entry of the next run is sent, the leftovers are archived
+        write_entry(&outbox, "4", "2023-01-01T11:00:00+00:00", "e");
+
+        let mut journal = RemovalJournal::load(&journal_path).unwrap();
+        let leftovers = journal.sort_out(entries::load_entries(&outbox, &Verifier::default()).ok);
+
+        let mut sent = leftovers.sent.clone();
+        sent.sort();
+        assert_eq!(
+            sent,
+            vec![
+                outbox.join(format!("1{ENTRY_EXT}")),
+                outbox.join(format!("3{ENTRY_EXT}"))
+            ]
+        );
+        let archive_root = dir.path().join(entries::SENT_DIR);
+        let sent_entries = SentEntries::Archive {
+            outbox_path: outbox.clone(),
+            archive_root: archive_root.clone(),
+        };
+        assert!(remove_leftovers(&leftovers.sent, &sent_entries).is_empty());
+        assert!(!sent[0].exists());
+        assert_eq!(fs::read_dir(&archive_root).unwrap().count(), 2);
+        assert!(leftovers.unconfirmed.is_empty());
+
+        let emails = compose(&leftovers.pending);
+        assert_eq!(emails.len(), 1);
+        assert_eq!(events(&emails[0]), vec!["e"]);
+
+        // Without leftovers, the sent E-mail is no longer journaled
+        journal.compact().unwrap();
+        assert!(RemovalJournal::load(&journal_path)
+            .unwrap()
+            .records
+            .is_empty());
+    }
+
+    #[test]
+    fn test_leftovers_that_cannot_be_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = dir.path().join("outbox");
+        fs::create_dir_all(&outbox).unwrap();
+        let journal_path = dir.path().join(REMOVAL_JOURNAL_FILE);
+
+        write_entry(&outbox, "1", "2023-01-01T10:00:00+00:00", "a");
+        let entries = entries::load_entries(&outbox, &Verifier::default()).ok;
+
+        let mut journal = RemovalJournal::load(&journal_path).unwrap();
+        send(&mut journal, &compose(&entries)[0], &entries);
+
+        // A directory in place of the entry file can't be removed as a file
+        let entry_path = outbox.join(format!("1{ENTRY_EXT}"));
+        let parsed = Rc::new(ParsedEntry {
+            id: "1".to_owned(),
+            path: Some(outbox.join("locked")),
+            entry: serde_json::from_str::<Entry>(&fs::read_to_string(&entry_path).unwrap())
+                .unwrap(),
+        });
+        fs::create_dir_all(outbox.join("locked")).unwrap();
+        journal
+            .record(RemovalRecord {
+                recorded_at: Utc::now(),
+                email_id: 1,
+                content_hash: String::new(),
+                status: RemovalStatus::Sent,
+                entries: vec![JournaledEntry::of(&parsed).unwrap()],
+            })
+            .unwrap();
+
+        let leftovers = journal.sort_out(vec![parsed]);
+        assert!(leftovers.pending.is_empty());
+        assert_eq!(
+            remove_leftovers(&leftovers.sent, &SentEntries::Remove),
+            vec![outbox.join("locked")]
+        );
+
+        // An entry rewritten under the same name is new content
+        write_entry(&outbox, "1", "2023-01-02T10:00:00+00:00", "a");
+        let leftovers = journal.sort_out(entries::load_entries(&outbox, &Verifier::default()).ok);
+        assert_eq!(leftovers.pending.len(), 1);
+        assert!(entry_path.is_file());
+    }
+
+    #[test]
+    fn test_unconfirmed_send_is_composed_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = dir.path().join("outbox");
+        fs::create_dir_all(&outbox).unwrap();
+
+        write_entry(&outbox, "1", "2023-01-01T10:00:00+00:00", "a");
+        let entries = entries::load_entries(&outbox, &Verifier::default()).ok;
+        let email = &compose(&entries)[0];
+
+        let mut journal = RemovalJournal::load(dir.path().join(REMOVAL_JOURNAL_FILE)).unwrap();
+        journal
+            .record(RemovalRecord {
+                recorded_at: Utc::now(),
+                email_id: email.id,
+                content_hash: email.content_hash(),
+                status: RemovalStatus::Pending,
+                entries: entries
+                    .iter()
+                    .filter_map(|e| JournaledEntry::of(e))
+                    .collect(),
+            })
+            .unwrap();
+
+        let leftovers = journal.sort_out(entries);
+        assert_eq!(leftovers.pending.len(), 1);
+        assert_eq!(
+            leftovers.unconfirmed,
+            vec![outbox.join(format!("1{ENTRY_EXT}"))]
+        );
+    }
+}
@@ -14,6 +14,10 @@ use std::{
 };
 use tera::Tera;
 
+use crate::localtime::{self, LocalTime};
+use crate::paths;
+use crate::templates::SHARED_TEMPLATE_DIR;
+
 // TODO: Add feature: (function) Dynamic QRCode
 // TODO: Add feature: (function) Defang Values
 // TODO: Template configurations (default + selected) + support for zipped templates (Which could include license and other metadata)
@@ -21,27 +25,37 @@ use tera::Tera;
 // A simple implementation of `% touch path` (ignores existing files)
 // Inspired by: https://doc.rust-lang.org/rust-by-example/std_misc/fs.html
 fn touch<P: AsRef<Path>>(path: P) -> Result<()> {
-    OpenOptions::new().create(true).write(true).open(path)?;
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(paths::long_path(path.as_ref()))?;
     Ok(())
 }
 
+// Canonicalize seem to be having trouble on Windows with relative paths that include a backslash.
+// This work around is meant to make sure that before Canonicalize encounters the given path,
+// its backslashes will be replaced with regular ones so `canonicalize` will be able to handle it.
+#[inline]
+fn slash_relative_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    if path.as_ref().has_root() {
+        path.as_ref().into()
+    } else {
+        (&*path.as_ref().to_slash_lossy()).into()
+    }
+}
+
 // This function attempts to be ignorant about any problems.
 // It just tries to figure out if a given file path location.
 // If the path doesn't exists, it assumes someone else will scream about it.
 // On failure, it just returns the original Path.
 #[inline]
 fn new_canonicalize_path_buf<P: AsRef<Path>>(path: P) -> PathBuf {
-    // Canonicalize seem to be having trouble on Windows with relative paths that include a backslash.
-    // This work around is meant to make sure that before Canonicalize encounters the given path,
-    // its backslashes will be replaced with regular ones so `canonicalize` will be able to handle it.
-    let path: PathBuf = if path.as_ref().has_root() {
-        path.as_ref().into()
-    } else {
-        (&*path.as_ref().to_slash_lossy()).into()
-    };
+    let path = slash_relative_path(path);
 
-    match fs::canonicalize(&path) {
-        Ok(abs_path) => abs_path,
+    // Windows returns a verbatim `\\?\` path, which is not shown to users nor used to name templates
+    match fs::canonicalize(paths::long_path(&path)) {
+        Ok(abs_path) => paths::simplified(abs_path),
         // On failure of getting the full path, keep the relative path.
         //
         // Possible failures of `fs::canonicalize`:
@@ -82,6 +96,25 @@ pub(crate) struct AbsolutePath {
     path: PathBuf,
 }
 
+impl AbsolutePath {
+    /// Resolves the given path to an absolute one, unlike the `From` conversions which keep
+    /// an unresolvable path as given.
+    /// ## Error
+    /// Fails if the path doesn't exist, or if one of its non-final components is not a directory.
+    /// Nothing is created on the file system.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn try_new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = slash_relative_path(path);
+
+        let abs_path = fs::canonicalize(paths::long_path(&path))
+            .with_context(|| format!("Unable to resolve path \"{}\"", paths::display(&path)))?;
+
+        Ok(AbsolutePath {
+            path: paths::simplified(abs_path),
+        })
+    }
+}
+
 impl AsRef<Path> for AbsolutePath {
     #[inline]
     fn as_ref(&self) -> &Path {
@@ -137,7 +170,7 @@ impl FromStr for AbsolutePath {
 
 impl std::fmt::Display for AbsolutePath {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.path.to_string_lossy())
+        write!(f, "{}", paths::display(&self.path))
     }
 }
 
@@ -181,6 +214,7 @@ impl Deref for AbsolutePath {
 /// Scan the template for reference to other templates, such as:
 /// `{% include %}`, `{% extend %}` or `{% import %}` calls
 #[inline]
+#[allow(dead_code)]
 fn find_template_references<P: AsRef<Path>>(content: &str, cwd: Option<P>) -> Vec<AbsolutePath> {
     let re = Regex::new(
         r#"\{%\s+?(?:import|include|extend)\s+?"(?P<template>[a-zA-Z0-9.\-/\\_]+?)"\s.*?%\}"#,
@@ -205,6 +239,99 @@ fn find_template_references<P: AsRef<Path>>(content: &str, cwd: Option<P>) -> Ve
     buf
 }
 
+/// The byte order mark some editors save UTF-8 files with.
+pub(crate) const BOM: char = '\u{feff}';
+
+/// The source of a template as the engines get it: without a leading byte order mark, and with LF line endings.
+/// A template saved with a BOM or CRLF line endings renders, and hashes, the same as its LF twin on every run.
+pub(crate) fn normalize_source(contents: &str) -> String {
+    contents
+        .strip_prefix(BOM)
+        .unwrap_or(contents)
+        .replace("\r\n", "\n")
+}
+
+/// Reads a template file, normalized by [`normalize_source`].
+/// ## Error
+/// Fails if the file can't be read.
+pub(crate) fn read_template(path: &Path) -> Result<String> {
+    let contents = fs::read_to_string(paths::long_path(path))
+        .with_context(|| format!("Unable to load template file \"{}\"", paths::display(path)))?;
+
+    Ok(normalize_source(&contents))
+}
+
+/// Loads the templates a Tera template can extend, include or import: every `.html` file of its
+/// directory, then of the shared directory, named by their path relative to it. A template of the
+/// directory takes precedence over a shared one of the same name.
+fn tera_with_layouts(home_dir: &Path, shared_dir: Option<&Path>) -> Result<Tera> {
+    let mut templates: Vec<(String, String)> = Vec::new();
+    let mut names = std::collections::HashSet::new();
+
+    for dir in std::iter::once(home_dir).chain(shared_dir) {
+        let dir = paths::long_path(dir);
+
+        for entry in walkdir::WalkDir::new(&dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .filter(|e| e.path().extension() == Some(OsStr::new("html")))
+        {
+            let Ok(relative_path) = entry.path().strip_prefix(&dir) else {
+                continue;
+            };
+            let name = relative_path.to_slash_lossy().into_owned();
+
+            if !names.insert(name.clone()) {
+                continue;
+            }
+
+            let contents = read_template(entry.path())?;
+
+            // Without its magic comment, as `{% extends %}` must be the first tag
+            let contents = match Template::from(contents.as_str()) {
+                Template::Tera(contents) => contents.to_string(),
+                _ => contents,
+            };
+
+            templates.push((name, contents));
+        }
+    }
+
+    log::debug!("Tera templates: {names:?}");
+
+    let mut tera = Tera::default();
+    tera.add_raw_templates(templates)
+        .context("Unable to create Tera instance")?;
+
+    Ok(tera)
+}
+
+/// Checks that every block a template overrides is defined by one of its bases, as Tera silently
+/// drops the others.
+fn check_overridden_blocks(tera: &Tera, template_name: &str) -> Result<()> {
+    let template = tera.get_template(template_name)?;
+
+    let Some(parent) = &template.parent else {
+        return Ok(());
+    };
+
+    for block in template.blocks.keys() {
+        let is_defined = template.parents.iter().any(|base| {
+            tera.get_template(base)
+                .is_ok_and(|base| base.blocks.contains_key(block))
+        });
+
+        if !is_defined {
+            return Err(anyhow!(
+                "The template `{template_name}` overrides the block `{block}`, which its base `{parent}` doesn't define"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
 /// Supported template engines
 #[non_exhaustive]
 #[derive(Clone, Copy, Debug, PartialEq, Sequence, strum_macros::Display)]
@@ -215,22 +342,27 @@ pub(crate) enum TemplateEngine {
     None,
 }
 
+impl TemplateEngine {
+    /// The names of the engine, accepted by `--engine`, as template file extensions and in the magic comment.
+    pub(crate) fn names(&self) -> &'static [&'static str] {
+        match self {
+            TemplateEngine::Tera => &["tera"],
+            TemplateEngine::Liquid => &["liq", "liquid"],
+            TemplateEngine::Handlebars => &["hbs", "handlebars"],
+            TemplateEngine::None => &["none"],
+        }
+    }
+}
+
 impl FromStr for TemplateEngine {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let res = match s.to_lowercase().as_str() {
-            "tera" => TemplateEngine::Tera,
-            "liquid" | "liq" => TemplateEngine::Liquid,
-            "handlebars" | "hbs" => TemplateEngine::Handlebars,
-            "none" => TemplateEngine::None,
-            _ => {
-                return Err(anyhow!(
-                    "Please try one of the supported engines in `--engine-list`"
-                ))
-            }
-        };
-        Ok(res)
+        let name = s.to_lowercase();
+
+        enum_iterator::all::<TemplateEngine>()
+            .find(|engine| engine.names().contains(&name.as_str()))
+            .ok_or_else(|| anyhow!("Please try one of the supported engines in `--engine-list`"))
     }
 }
 
@@ -249,6 +381,64 @@ impl FromStr for TemplateEngine {
 //     }
 // }
 
+/// Maps template file name suffixes (e.g. `liquid` or `tera.html`) to the engine rendering them.
+/// When several suffixes match a file name, the longest one wins.
+#[derive(Debug, Clone)]
+pub(crate) struct EngineExtensions(Vec<(String, TemplateEngine)>);
+
+impl Default for EngineExtensions {
+    /// The short and long engine names, also with a composite `.html` extension (`.tera.html` or `.html.tera`)
+    fn default() -> Self {
+        let mut extensions = Self(Vec::new());
+
+        for engine in enum_iterator::all::<TemplateEngine>().filter(|e| *e != TemplateEngine::None)
+        {
+            for name in engine.names() {
+                extensions.insert(name, engine);
+                extensions.insert(&format!("{name}.html"), engine);
+                extensions.insert(&format!("html.{name}"), engine);
+            }
+        }
+
+        extensions
+    }
+}
+
+impl EngineExtensions {
+    /// Maps the file name suffix to the engine, replacing a previous mapping of the same suffix.
+    pub(crate) fn insert(&mut self, suffix: &str, engine: TemplateEngine) {
+        let suffix = suffix.trim_start_matches('.').to_lowercase();
+
+        match self.0.iter_mut().find(|(s, _)| *s == suffix) {
+            Some(mapping) => mapping.1 = engine,
+            None => self.0.push((suffix, engine)),
+        }
+    }
+
+    /// Whether the suffix is mapped to an engine, e.g. `tera.html`.
+    pub(crate) fn contains(&self, suffix: &str) -> bool {
+        let suffix = suffix.trim_start_matches('.').to_lowercase();
+
+        self.0.iter().any(|(s, _)| *s == suffix)
+    }
+
+    /// Returns the engine of the longest suffix matching the file name.
+    pub(crate) fn engine_for(&self, file_name: &str) -> Option<TemplateEngine> {
+        let file_name = file_name.to_lowercase();
+
+        self.0
+            .iter()
+            .filter(|(suffix, _)| {
+                file_name
+                    .strip_suffix(suffix.as_str())
+                    .is_some_and(|stem| stem.ends_with('.'))
+            })
+            .max_by_key(|(suffix, _)| suffix.len())
+            .map(|(_, engine)| *engine)
+    }
+}
+
+#[allow(dead_code)]
 pub fn rendered_path<P: AsRef<Path>>(input_path: P) -> PathBuf {
     let file_extension = input_path.as_ref().extension();
 
@@ -270,7 +460,10 @@ pub fn rendered_path<P: AsRef<Path>>(input_path: P) -> PathBuf {
 impl From<&str> for Template {
     /// Inspect the String contents for a magic comment `<!--template engine_name-->`, and return the appropriate `Template` enum variation for rendering.
     fn from(contents: &str) -> Self {
-        let re = RegexBuilder::new(r#"<!--template\s+(?P<engine>\w+)\s?-->"#)
+        let contents = contents.strip_prefix(BOM).unwrap_or(contents);
+
+        // Whitespace and line breaks are allowed around the engine name
+        let re = RegexBuilder::new(r#"<!--\s*template\s+(?P<engine>\w+)\s*-->"#)
             .case_insensitive(true)
             .build()
             .expect("Bad regex pattern.");
@@ -293,11 +486,11 @@ impl From<&str> for Template {
 
             log::debug!("Detected magic comment: `{engine}`");
 
-            match engine.as_str() {
-                "tera" => Template::Tera(contents),
-                "hbs" | "handlebars" => Template::Handlebars(contents),
-                "liq" | "liquid" => Template::Liquid(contents),
-                unknown_engine => Template::Unknown(unknown_engine.to_owned(), contents),
+            match engine.parse() {
+                Ok(TemplateEngine::Tera) => Template::Tera(contents),
+                Ok(TemplateEngine::Handlebars) => Template::Handlebars(contents),
+                Ok(TemplateEngine::Liquid) => Template::Liquid(contents),
+                Ok(TemplateEngine::None) | Err(_) => Template::Unknown(engine, contents),
             }
         } else {
             Template::NoEngine(Rc::new(contents.to_owned()))
@@ -307,7 +500,7 @@ impl From<&str> for Template {
 
 impl<'arg> From<&TemplateData<'arg>> for Template {
     /// Loads a template file into a Template enum type.
-    /// Decides on the engine type by first inspecting the file extension (`.tera`, `.hbs` or `.liq`).
+    /// Decides on the engine type by first inspecting the file extension (see [`EngineExtensions`], e.g. `.tera`, `.hbs` or `.liquid`).
     /// If no special extension is provided then the contents of the template are inspected for the magic comment `<!--TEMPLATE engine_name-->`.
     ///
     /// Engine Names: `tera`, `handlebars` or `hbs`, `liquid` or `liq`
@@ -315,17 +508,23 @@ impl<'arg> From<&TemplateData<'arg>> for Template {
         // Checking for template file extension to determine the template engine.
         // Notice the early returns.
         if let Some(template_file) = td.file_path {
-            if let Some(extension) = template_file.extension() {
-                // if let Some(ref extension) = template_file.parts.extension {
-                let file_extension = &*extension.to_string_lossy();
-                // let file_extension = extension.as_str();
+            if let Some(file_name) = template_file.file_name() {
+                let default_extensions;
+                let extensions = match td.extensions {
+                    Some(extensions) => extensions,
+                    None => {
+                        default_extensions = EngineExtensions::default();
+                        &default_extensions
+                    }
+                };
 
                 let contents = td.contents.clone();
-                match file_extension {
-                    "tera" => return Template::Tera(contents),
-                    "hbs" => return Template::Handlebars(contents),
-                    "liq" => return Template::Liquid(contents),
-                    _ => {} // ignore unknown extensions
+                match extensions.engine_for(&file_name.to_string_lossy()) {
+                    Some(TemplateEngine::Tera) => return Template::Tera(contents),
+                    Some(TemplateEngine::Handlebars) => return Template::Handlebars(contents),
+                    Some(TemplateEngine::Liquid) => return Template::Liquid(contents),
+                    Some(TemplateEngine::None) => return Template::NoEngine(contents),
+                    None => {} // ignore unknown extensions
                 };
             }
         }
@@ -337,12 +536,21 @@ impl<'arg> From<&TemplateData<'arg>> for Template {
 pub(crate) struct TemplateData<'a> {
     pub(crate) contents: Rc<String>,
     pub(crate) file_path: Option<&'a AbsolutePath>,
+
+    /// The file extensions mapping to engines, the defaults when not set
+    pub(crate) extensions: Option<&'a EngineExtensions>,
 }
 
-// #[allow(unused)]
 pub(crate) struct ContextData {
     pub(crate) context: serde_json::Value,
+    #[allow(dead_code)]
     pub(crate) file_path: Option<AbsolutePath>,
+
+    /// Render the values without escaping, as trusted HTML
+    pub(crate) trusted: bool,
+
+    /// The `localtime` filter of the template, displaying the timestamps in the display timezone
+    pub(crate) local_time: LocalTime,
 }
 
 pub(crate) struct RenderedTemplate(pub(crate) Rc<String>);
@@ -392,11 +600,20 @@ enum Template {
     Tera(Contents),
     Handlebars(Contents),
     Liquid(Contents),
-    Unknown(EngineName, Contents),
+    Unknown(EngineName, #[allow(dead_code)] Contents),
     NoEngine(Contents),
 }
 
 impl Template {
+    fn engine(&self) -> TemplateEngine {
+        match self {
+            Template::Tera(_) => TemplateEngine::Tera,
+            Template::Handlebars(_) => TemplateEngine::Handlebars,
+            Template::Liquid(_) => TemplateEngine::Liquid,
+            Template::Unknown(_, _) | Template::NoEngine(_) => TemplateEngine::None,
+        }
+    }
+
     fn get_engine(&self) -> &'static str {
         match self {
             Template::Tera(_) => "tera",
@@ -408,6 +625,50 @@ impl Template {
     }
 }
 
+/// The engine a template is rendered with, as detected by `DetectionMethod::Auto`.
+pub(crate) fn detect_engine(template_data: &TemplateData) -> TemplateEngine {
+    Template::from(template_data).engine()
+}
+
+/// Renders a short inline template, such as an address field, with the given engine.
+/// Nothing is escaped, and no other template can be referenced.
+/// With `strict`, an undefined variable fails with every engine, as it already does with Tera and Liquid.
+pub(crate) fn render_inline(
+    contents: &str,
+    context: &serde_json::Value,
+    engine: TemplateEngine,
+    strict: bool,
+) -> Result<String> {
+    let rendered = match engine {
+        TemplateEngine::Tera => {
+            let context = tera::Context::from_value(context.clone())
+                .context("Tera rejected Context object.")?;
+
+            Tera::one_off(contents, &context, false)
+                .context("Tera is unable to render the template.")?
+        }
+        TemplateEngine::Handlebars => {
+            let mut handlebars = Handlebars::new();
+            handlebars.register_escape_fn(handlebars::no_escape);
+            handlebars.set_strict_mode(strict);
+
+            handlebars
+                .render_template(contents, context)
+                .context("Handlebars is unable to render the template.")?
+        }
+        TemplateEngine::Liquid => liquid::ParserBuilder::with_stdlib()
+            .build()
+            .context("Liquid is unable to build the parser.")?
+            .parse(contents)
+            .context("Liquid is unable to parse the template.")?
+            .render(&liquid::to_object(context).context("Liquid rejected Context object.")?)
+            .context("Liquid is unable to render the template.")?,
+        TemplateEngine::None => contents.to_owned(),
+    };
+
+    Ok(rendered)
+}
+
 pub(crate) fn render<'a>(
     template_data: &'a TemplateData,
     context_data: &'a ContextData,
@@ -475,31 +736,17 @@ pub(crate) fn render<'a>(
                 .parent()
                 .context("Failed to get home directory")?;
 
-            let templates_home_dir_glob = templates_home_dir.join("**");
-
-            let templates_home_dir_glob = templates_home_dir_glob.join("*.html");
-
-            let templates_home_dir_glob = templates_home_dir_glob.to_string_lossy();
-
-            log::debug!("Tera templates path: {templates_home_dir_glob}");
-
-            // TODO: Better to create an instance of `Tera::default()` and have a deep scan for the templates to add only the references ones into a HashSet, than to add every file in the template's directory.
-            // let mut tera = Tera::default();
-
-            // let template_references: Vec<(AbsolutePath, Option<String>)> =
-            //     find_template_references(&contents, template_path)
-            //         .into_iter()
-            //         .map(|p| {
-            //             let file_name = p.file_name().map(|fp| fp.to_string_lossy().to_string());
-            //             (p, file_name)
-            //         })
-            //         .collect();
-
-            // tera.add_template_files(template_references)
-            //     .context("Tera failed loading partial template files")?;
+            // Layouts shared by every template, when the main template is in a template directory
+            let shared_dir = template_data
+                .file_path
+                .and_then(|_| templates_home_dir.parent())
+                .map(|templates_dir| templates_dir.join(SHARED_TEMPLATE_DIR));
 
-            let mut tera =
-                Tera::new(&templates_home_dir_glob).context("Unable to create Tera instance")?;
+            let mut tera = tera_with_layouts(templates_home_dir, shared_dir.as_deref())?;
+            tera.register_filter(localtime::FILTER_NAME, context_data.local_time.clone());
+            if context_data.trusted {
+                tera.autoescape_on(Vec::new());
+            }
 
             // Force extension or auto detect (default `.html`)
             let template_type = if let TemplateExtension::Force(ext) = template_extension {
@@ -510,29 +757,56 @@ pub(crate) fn render<'a>(
                     Some(ext) => ext.to_string_lossy(),
                     None => Cow::Borrowed("html"),
                 }
-                // match path.parts.extension {
-                //     Some(ref ext) => Cow::Borrowed(ext.as_str()),
-                //     None => Cow::Borrowed("html"),
-                // }
             } else {
                 Cow::Borrowed("html")
             };
 
             log::debug!("Tera: Using extension \"{template_type}\"");
-            let in_memory_template = format!("__in_memory__.{}", template_type);
 
-            // Adds a virtual in-memory file for the main template. We need the `.html` extension to enforce HTML escaping.
-            tera.add_raw_template(&in_memory_template, &contents)
-                .context("Tera is unable to add the main template as raw template.")?;
+            // The main template is registered under its file name, so other templates can extend it.
+            // The extension enforces HTML escaping.
+            let main_template = match template_data.file_path.and_then(|path| path.file_name()) {
+                Some(file_name)
+                    if Path::new(file_name).extension() == Some(OsStr::new(&*template_type)) =>
+                {
+                    file_name.to_string_lossy().into_owned()
+                }
+                Some(file_name) => format!("{}.{template_type}", file_name.to_string_lossy()),
+                None => format!("__in_memory__.{template_type}"),
+            };
 
-            let rendered = tera
-                .render(&in_memory_template, &context)
-                .context("Tera is unable to render the template.")?;
+            // Overrides the file loaded from the template directory, with the same contents
+            tera.add_raw_template(&main_template, &contents)
+                .with_context(|| {
+                    format!("Tera is unable to add the main template `{main_template}`.")
+                })?;
+
+            check_overridden_blocks(&tera, &main_template)?;
+
+            let rendered = tera.render(&main_template, &context).with_context(|| {
+                match tera
+                    .get_template(&main_template)
+                    .ok()
+                    .and_then(|template| template.parent.as_ref())
+                {
+                    Some(parent) => format!(
+                        "Tera is unable to render the template `{main_template}`, extending `{parent}`."
+                    ),
+                    None => format!("Tera is unable to render the template `{main_template}`."),
+                }
+            })?;
 
             Rc::new(rendered)
         }
         Template::Handlebars(contents) => {
-            let handlebars = Handlebars::new();
+            let mut handlebars = Handlebars::new();
+            if context_data.trusted {
+                handlebars.register_escape_fn(handlebars::no_escape);
+            }
+            handlebars.register_helper(
+                localtime::FILTER_NAME,
+                Box::new(context_data.local_time.clone()),
+            );
             let render = handlebars.render_template(&contents, &context_data.context);
             // match render {
             //     Ok(contents) => contents,
@@ -553,6 +827,7 @@ pub(crate) fn render<'a>(
         Template::Liquid(contents) => {
             // TODO: Enable partials using `find_template_references()`
             let template = liquid::ParserBuilder::with_stdlib()
+                .filter(context_data.local_time.clone())
                 .build()
                 .context("Liquid is unable to build the parser.")?
                 .parse(&contents);
@@ -581,3 +856,239 @@ pub(crate) fn render<'a>(
     };
     Ok(RenderedTemplate(result))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_absolute_path_try_new() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("template.html");
+        fs::write(&file, "").unwrap();
+
+        let resolved = AbsolutePath::try_new(&file).unwrap();
+        assert!(resolved.is_absolute());
+        assert_eq!(resolved.path, fs::canonicalize(&file).unwrap());
+
+        // A file is not a directory, and nothing is touched under it
+        let nested = file.join("nested.html");
+        assert!(AbsolutePath::try_new(&nested).is_err());
+
+        let missing = dir.path().join("missing.html");
+        assert!(AbsolutePath::try_new(&missing).is_err());
+        assert!(!missing.exists());
+    }
+
+    fn detected_engine(file_name: &str, extensions: Option<&EngineExtensions>) -> &'static str {
+        let file_path: AbsolutePath = std::env::temp_dir().join(file_name).into();
+        let template_data = TemplateData {
+            contents: Rc::new(String::new()),
+            file_path: Some(&file_path),
+            extensions,
+        };
+
+        Template::from(&template_data).get_engine()
+    }
+
+    #[test]
+    fn test_long_form_extensions() {
+        assert_eq!(detected_engine("foo.handlebars", None), "handlebars");
+        assert_eq!(detected_engine("foo.liquid", None), "liquid");
+        assert_eq!(detected_engine("foo.hbs", None), "handlebars");
+    }
+
+    #[test]
+    fn test_engine_names_as_extensions() {
+        let detected = |file_name: String| {
+            let file_path: AbsolutePath = std::env::temp_dir().join(file_name).into();
+            detect_engine(&TemplateData {
+                contents: Rc::new(String::new()),
+                file_path: Some(&file_path),
+                extensions: None,
+            })
+        };
+
+        // Every name accepted by `--engine` is an extension too, alone or along `.html`
+        for engine in enum_iterator::all::<TemplateEngine>().filter(|e| *e != TemplateEngine::None)
+        {
+            for name in engine.names() {
+                assert_eq!(name.parse::<TemplateEngine>().unwrap(), engine);
+                assert_eq!(detected(format!("foo.{name}")), engine);
+                assert_eq!(detected(format!("foo.html.{name}")), engine);
+                assert_eq!(detected(format!("foo.{name}.html")), engine);
+            }
+        }
+    }
+
+    #[test]
+    fn test_composite_extensions() {
+        assert_eq!(detected_engine("foo.tera.html", None), "tera");
+        assert_eq!(detected_engine("foo.html.tera", None), "tera");
+        assert_eq!(detected_engine("foo.LIQUID.HTML", None), "liquid");
+        assert_eq!(detected_engine("foo.html", None), "no_engine");
+        // Only whole extensions match
+        assert_eq!(detected_engine("photera", None), "no_engine");
+    }
+
+    #[test]
+    fn test_configured_extensions() {
+        let mut extensions = EngineExtensions::default();
+        extensions.insert(".html", TemplateEngine::Tera);
+        extensions.insert("mustache", TemplateEngine::Handlebars);
+
+        assert_eq!(detected_engine("template.html", Some(&extensions)), "tera");
+        assert_eq!(
+            detected_engine("template.mustache", Some(&extensions)),
+            "handlebars"
+        );
+        // The longest suffix wins
+        assert_eq!(
+            detected_engine("template.liquid.html", Some(&extensions)),
+            "liquid"
+        );
+    }
+
+    fn render_file(path: &Path, context: serde_json::Value) -> Result<String> {
+        let file_path = AbsolutePath::try_new(path).unwrap();
+        let template_data = TemplateData {
+            contents: Rc::new(read_template(path).unwrap()),
+            file_path: Some(&file_path),
+            extensions: None,
+        };
+        let context_data = ContextData {
+            context,
+            file_path: None,
+            trusted: false,
+            local_time: Default::default(),
+        };
+
+        render(
+            &template_data,
+            &context_data,
+            DetectionMethod::Auto,
+            TemplateExtension::Auto,
+        )
+        .map(|rendered| rendered.0.to_string())
+    }
+
+    #[test]
+    fn test_tera_template_inheritance() {
+        use crate::templates::{scaffold, LAYOUT_FILE, SHARED_TEMPLATE_DIR, TEMPLATE_FILE};
+
+        let dir = tempfile::tempdir().unwrap();
+        let created = scaffold(dir.path(), "report").unwrap();
+        let layout = dir.path().join(SHARED_TEMPLATE_DIR).join(LAYOUT_FILE);
+        let template = dir.path().join("report").join(TEMPLATE_FILE);
+        assert_eq!(created, vec![layout.clone(), template.clone()]);
+        assert!(scaffold(dir.path(), "report").is_err());
+        assert!(scaffold(dir.path(), "../report").is_err());
+
+        // The scaffolded template renders with the starter layout
+        let html = render_file(&template, serde_json::json!({ "message": "<b>Hi</b>" })).unwrap();
+        assert!(html.contains("<p>&lt;b&gt;Hi&lt;&#x2F;b&gt;</p>"), "{html}");
+        assert!(html.contains("<h1>Notification</h1>"));
+
+        fs::write(
+            &template,
+            r#"<!--TEMPLATE tera-->
+{% extends "base.html" %}
+{% block header %}{{ super() }}<h2>{{ subtitle }}</h2>{% endblock header %}
+{% block content %}<p>Disk usage {{ usage }}%</p>{% endblock content %}"#,
+        )
+        .unwrap();
+
+        let context = serde_json::json!({ "title": "Ops", "subtitle": "Daily", "usage": 93 });
+        let html = render_file(&template, context.clone()).unwrap();
+        assert!(html.contains("<p>Disk usage 93%</p>"), "{html}");
+        assert!(
+            html.contains("<h1>Ops</h1>\n        <h2>Daily</h2>"),
+            "{html}"
+        );
+        // Inherited as is
+        assert!(html.contains("Sent by OSA Mailer"));
+
+        // A layout of the template directory takes precedence over the shared one
+        fs::write(
+            dir.path().join("report").join(LAYOUT_FILE),
+            "[{% block header %}{% endblock header %}|{% block content %}{% endblock content %}]",
+        )
+        .unwrap();
+        let html = render_file(&template, context.clone()).unwrap();
+        assert_eq!(html, "[<h2>Daily</h2>|<p>Disk usage 93%</p>]");
+        fs::remove_file(dir.path().join("report").join(LAYOUT_FILE)).unwrap();
+
+        // Both templates are named by the errors
+        fs::write(
+            &template,
+            "<!--TEMPLATE tera-->\n{% extends \"base.html\" %}{% block sidebar %}{% endblock sidebar %}",
+        )
+        .unwrap();
+        let error = format!("{:#}", render_file(&template, context.clone()).unwrap_err());
+        assert!(
+            error.contains(
+                "`template.html` overrides the block `sidebar`, which its base `base.html`"
+            ),
+            "{error}"
+        );
+
+        fs::write(
+            &template,
+            "<!--TEMPLATE tera-->\n{% extends \"missing.html\" %}",
+        )
+        .unwrap();
+        let error = format!("{:#}", render_file(&template, context).unwrap_err());
+        assert!(
+            error.contains("'template.html' is inheriting from 'missing.html'"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_bom_template_detects_its_engine() {
+        for contents in [
+            "\u{feff}<!--TEMPLATE tera-->\r\n<p>{{ name }}</p>",
+            "\u{feff}  <!-- template\r\n  handlebars \r\n-->\r\n<p>{{ name }}</p>",
+        ] {
+            let engine = Template::from(contents).engine();
+            assert_ne!(engine, TemplateEngine::None, "{contents:?}");
+
+            let (Template::Tera(source) | Template::Handlebars(source)) = Template::from(contents)
+            else {
+                unreachable!()
+            };
+            assert_eq!(source.as_str(), "<p>{{ name }}</p>");
+        }
+
+        assert_eq!(
+            Template::from("\u{feff}<!--TEMPLATE liquid-->").engine(),
+            TemplateEngine::Liquid
+        );
+        assert_eq!(normalize_source("\u{feff}<p>\r\n</p>"), "<p>\n</p>");
+    }
+
+    #[test]
+    fn test_crlf_template_renders_as_lf_twin() {
+        let dir = tempfile::tempdir().unwrap();
+        let lf = "<!--TEMPLATE tera-->\n<h1>{{ title }}</h1>\n<ul>\n{% for item in items %}  <li>{{ item }}</li>\n{% endfor %}</ul>\n";
+        let crlf = format!("{BOM}{}", lf.replace('\n', "\r\n"));
+
+        let lf_path = dir.path().join("lf.html");
+        let crlf_path = dir.path().join("crlf.html");
+        fs::write(&lf_path, lf).unwrap();
+        fs::write(&crlf_path, &crlf).unwrap();
+
+        let context = serde_json::json!({ "title": "Disks", "items": ["sda", "sdb"] });
+        let lf_html = render_file(&lf_path, context.clone()).unwrap();
+        let crlf_html = render_file(&crlf_path, context).unwrap();
+
+        assert_eq!(crlf_html, lf_html);
+        assert!(!crlf_html.contains(['\r', BOM]), "{crlf_html:?}");
+
+        // The same dedup fingerprint, run after run
+        assert_eq!(
+            crate::dedup::fingerprint(&crlf_html),
+            crate::dedup::fingerprint(&lf_html)
+        );
+    }
+}
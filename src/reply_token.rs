@@ -0,0 +1,359 @@
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::entries::{self, ComposedEmail};
+use crate::render::{self, TemplateEngine};
+
+/// The separator between the local part of the reply address and the token, when not set.
+pub(crate) const DEFAULT_SEPARATOR: &str = "+";
+
+/// The header repeating the token, when not set.
+pub(crate) const DEFAULT_HEADER: &str = "X-Ticket-Token";
+
+/// The context key of the E-mail ID the token can be rendered from, within `_meta`.
+pub(crate) const EMAIL_ID_KEY: &str = "email_id";
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub(crate) enum ReplyTokenError {
+    #[error("A reply token requires a `reply_to` address")]
+    NoReplyAddress,
+
+    #[error("The reply token `{0}` has characters other than letters, digits, `-`, `_` and `.`")]
+    InvalidToken(String),
+
+    #[error("The reply token separator `{0}` is not one of `+`, `-`, `_`, `.` and `=`")]
+    InvalidSeparator(String),
+
+    #[error("The reply address `{address}` doesn't support plus-addressing with `{separator}`")]
+    UnsupportedAddress { address: String, separator: String },
+}
+
+/// The token threading the replies of an E-mail into a ticketing system, added to the local part of its reply
+/// addresses, e.g. `support+ticket-ab12cd@example.com`, and repeated in a header.
+/// Set in `template.toml` under `[reply_token]`, or per entry as `email.reply_token`, which takes precedence.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub(crate) struct ReplyTokenConfig {
+    /// The token, rendered with the template engine against the context and `_meta.email_id`,
+    /// e.g. `ticket-{{ _meta.email_id }}`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) token: Option<String>,
+
+    /// The separator between the local part and the token, `+` when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) separator: Option<String>,
+
+    /// The header repeating the token, `X-Ticket-Token` when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) header: Option<String>,
+}
+
+impl ReplyTokenConfig {
+    /// Fills the settings missing here from `fallback`.
+    pub(crate) fn or(&self, fallback: &ReplyTokenConfig) -> ReplyTokenConfig {
+        ReplyTokenConfig {
+            token: self.token.clone().or_else(|| fallback.token.clone()),
+            separator: self
+                .separator
+                .clone()
+                .or_else(|| fallback.separator.clone()),
+            header: self.header.clone().or_else(|| fallback.header.clone()),
+        }
+    }
+}
+
+/// Whether the token is safe in an unquoted local part.
+fn is_token(text: &str) -> bool {
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// The reply address with the token added to its local part, e.g. `Support <support+ticket-ab12cd@example.com>`.
+/// ## Error
+/// Fails if the local part is quoted, empty or already has the separator, as the token couldn't be told apart.
+pub(crate) fn plus_address(
+    address: &str,
+    token: &str,
+    separator: &str,
+) -> Result<String, ReplyTokenError> {
+    let unsupported = || ReplyTokenError::UnsupportedAddress {
+        address: address.to_owned(),
+        separator: separator.to_owned(),
+    };
+
+    // The address of `Name <address>`
+    let (prefix, mailbox, suffix) = match (address.rfind('<'), address.rfind('>')) {
+        (Some(open), Some(close)) if open < close => (
+            &address[..=open],
+            &address[open + 1..close],
+            &address[close..],
+        ),
+        _ => ("", address.trim(), ""),
+    };
+
+    let (local_part, domain) = mailbox.rsplit_once('@').ok_or_else(unsupported)?;
+
+    if local_part.is_empty()
+        || domain.is_empty()
+        || local_part.starts_with('"')
+        || local_part.contains(separator)
+    {
+        return Err(unsupported());
+    }
+
+    Ok(format!(
+        "{prefix}{local_part}{separator}{token}@{domain}{suffix}"
+    ))
+}
+
+/// Applies the reply token of an E-mail: renders it with the engine of the template against the context, adds it
+/// to every `reply_to` address and sets its header, overriding a global header of the same name.
+/// The token of a raw E-mail is used as is. Returns the token, or None when not set or rendered empty.
+/// ## Error
+/// Fails if the token can't be rendered, or the token, the separator or a reply address is invalid.
+pub(crate) fn apply(
+    email: &mut ComposedEmail,
+    engine: Option<TemplateEngine>,
+    config: &ReplyTokenConfig,
+) -> Result<Option<String>> {
+    let Some(token) = &config.token else {
+        return Ok(None);
+    };
+
+    let token = match engine {
+        Some(engine) => {
+            let mut context = email.context.clone();
+            entries::meta_object(&mut context).insert(
+                EMAIL_ID_KEY.to_owned(),
+                serde_json::json!(format!("{:08x}", email.id)),
+            );
+
+            render::render_inline(token, &serde_json::Value::Object(context), engine, true)
+                .with_context(|| format!("Unable to render the reply token \"{token}\""))?
+        }
+        None => token.clone(),
+    };
+    let token = token.trim();
+
+    if token.is_empty() {
+        return Ok(None);
+    }
+    if !is_token(token) {
+        return Err(ReplyTokenError::InvalidToken(token.to_owned()).into());
+    }
+
+    let separator = config.separator.as_deref().unwrap_or(DEFAULT_SEPARATOR);
+    if !matches!(separator, "+" | "-" | "_" | "." | "=") {
+        return Err(ReplyTokenError::InvalidSeparator(separator.to_owned()).into());
+    }
+
+    let header = &mut email.header;
+    if header.reply_to.is_empty() {
+        return Err(ReplyTokenError::NoReplyAddress.into());
+    }
+
+    header.reply_to = header
+        .reply_to
+        .iter()
+        .map(|address| plus_address(address, token, separator))
+        .collect::<Result<_, _>>()?;
+
+    header.headers.insert(
+        config
+            .header
+            .as_deref()
+            .unwrap_or(DEFAULT_HEADER)
+            .to_owned(),
+        token.to_owned(),
+    );
+
+    Ok(Some(token.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entries::Email;
+    use crate::send::MessageBuilder;
+    use crate::sent_log::{self, SentRecord};
+    use chrono::Utc;
+
+    fn email(reply_to: &[&str]) -> ComposedEmail {
+        let mut email = ComposedEmail {
+            id: 0xab12cd,
+            header: Email {
+                from: "osa@example.com".to_owned(),
+                to: vec!["ops@example.com".to_owned()],
+                reply_to: reply_to.iter().map(|address| address.to_string()).collect(),
+                subject: "Disk full".to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        email
+            .context
+            .insert("queue".to_owned(), serde_json::json!("db"));
+        email
+    }
+
+    fn config(token: &str, separator: Option<&str>) -> ReplyTokenConfig {
+        ReplyTokenConfig {
+            token: Some(token.to_owned()),
+            separator: separator.map(str::to_owned),
+            header: None,
+        }
+    }
+
+    #[test]
+    fn test_token_substitution() {
+        let mut email = email(&["Support <support@example.com>", "helpdesk@example.com"]);
+
+        let token = apply(
+            &mut email,
+            Some(TemplateEngine::Tera),
+            &config("{{ queue }}-ticket-{{ _meta.email_id }}", None),
+        )
+        .unwrap();
+
+        assert_eq!(token.as_deref(), Some("db-ticket-00ab12cd"));
+        assert_eq!(
+            email.header.reply_to,
+            vec![
+                "Support <support+db-ticket-00ab12cd@example.com>",
+                "helpdesk+db-ticket-00ab12cd@example.com"
+            ]
+        );
+        assert_eq!(email.header.headers[DEFAULT_HEADER], "db-ticket-00ab12cd");
+
+        // The E-mail ID is not added to the context of the template
+        assert!(!email.context.contains_key("_meta"));
+
+        // A raw E-mail has no engine, and an empty token is no token
+        let mut raw = self::email(&["support@example.com"]);
+        assert_eq!(
+            apply(&mut raw, None, &config("ticket-1", None)).unwrap(),
+            Some("ticket-1".to_owned())
+        );
+        let mut untouched = self::email(&["support@example.com"]);
+        assert_eq!(
+            apply(
+                &mut untouched,
+                Some(TemplateEngine::Tera),
+                &config("{{ '' }}", None)
+            )
+            .unwrap(),
+            None
+        );
+        assert_eq!(untouched.header.reply_to, vec!["support@example.com"]);
+
+        let mut invalid = self::email(&["support@example.com"]);
+        let error = apply(&mut invalid, None, &config("ticket 1", None)).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&ReplyTokenError::InvalidToken("ticket 1".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_separator() {
+        assert_eq!(
+            plus_address("support@example.com", "t1", "-"),
+            Ok("support-t1@example.com".to_owned())
+        );
+
+        // The separator already in the local part would make the token ambiguous
+        assert_eq!(
+            plus_address("support+ops@example.com", "t1", "+"),
+            Err(ReplyTokenError::UnsupportedAddress {
+                address: "support+ops@example.com".to_owned(),
+                separator: "+".to_owned()
+            })
+        );
+        assert_eq!(
+            plus_address("support+ops@example.com", "t1", "="),
+            Ok("support+ops=t1@example.com".to_owned())
+        );
+        assert!(plus_address("\"sup port\"@example.com", "t1", "+").is_err());
+        assert!(plus_address("support", "t1", "+").is_err());
+
+        let mut email = email(&["support@example.com"]);
+        let error = apply(&mut email, None, &config("t1", Some("#"))).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&ReplyTokenError::InvalidSeparator("#".to_owned()))
+        );
+
+        let mut email = self::email(&[]);
+        let error = apply(&mut email, None, &config("t1", None)).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&ReplyTokenError::NoReplyAddress));
+
+        // The entry settings take precedence over the template ones
+        let entry = ReplyTokenConfig {
+            separator: Some("-".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            entry.or(&config("t1", Some("="))),
+            ReplyTokenConfig {
+                token: Some("t1".to_owned()),
+                separator: Some("-".to_owned()),
+                header: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_header_and_sent_log() {
+        let mut email = email(&["support@example.com"]);
+        let config = ReplyTokenConfig {
+            header: Some("X-Helpdesk-Token".to_owned()),
+            ..config("ticket-{{ _meta.email_id }}", None)
+        };
+
+        let token = apply(&mut email, Some(TemplateEngine::Tera), &config)
+            .unwrap()
+            .unwrap();
+
+        let message: lettre::Message = MessageBuilder::new()
+            .from(&email.header.from)
+            .to_addresses(&email.header.to.join(", "))
+            .reply_to_addresses(&email.header.reply_to.join(", "))
+            .subject(&email.header.subject)
+            .headers(&email.header.headers)
+            .build()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+        assert!(
+            formatted.contains("Reply-To: support+ticket-00ab12cd@example.com\r\n"),
+            "{formatted}"
+        );
+        assert!(
+            formatted.contains("X-Helpdesk-Token: ticket-00ab12cd\r\n"),
+            "{formatted}"
+        );
+
+        // Recorded in the sent-log for correlation
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(sent_log::SENT_LOG_FILE);
+        let now = Utc::now();
+        sent_log::append(
+            &path,
+            &SentRecord {
+                sent_at: now,
+                email_id: email.id,
+                template: email.header.template.clone(),
+                content_hash: email.content_hash(),
+                reply_token: Some(token),
+            },
+        )
+        .unwrap();
+
+        let records: Vec<SentRecord> = sent_log::load_since(&path, now).unwrap();
+        assert_eq!(records[0].reply_token.as_deref(), Some("ticket-00ab12cd"));
+    }
+}
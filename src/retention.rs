@@ -0,0 +1,462 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+/// The limits of a directory, any of them may be left unset, e.g. `max_age_days = 30`.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RetentionRule {
+    /// Files last modified longer ago are removed
+    pub(crate) max_age_days: Option<u64>,
+
+    /// The oldest files are removed while the directory holds more bytes
+    pub(crate) max_total_bytes: Option<u64>,
+
+    /// The oldest files are removed while the directory holds more files
+    pub(crate) max_files: Option<usize>,
+}
+
+/// The files removed from a directory, or that would be removed on a dry run.
+#[derive(Debug, Default)]
+pub(crate) struct Pruned {
+    pub(crate) dir: PathBuf,
+    pub(crate) removed: Vec<PathBuf>,
+    pub(crate) freed_bytes: u64,
+    pub(crate) dry_run: bool,
+}
+
+impl fmt::Display for Pruned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "\"{}\": {} {} file(s), {} bytes",
+            self.dir.display(),
+            if self.dry_run {
+                "would remove"
+            } else {
+                "removed"
+            },
+            self.removed.len(),
+            self.freed_bytes
+        )
+    }
+}
+
+/// A file of a pruned directory.
+struct Candidate {
+    path: PathBuf,
+    modified: DateTime<Utc>,
+    len: u64,
+}
+
+/// Loads the retention rules of a TOML file, or a JSON file for a `.json` extension, mapping each directory to its
+/// rule, e.g. `[sent_mail]` followed by `max_files = 1000`. The directories are relative to `base_dir`, and must
+/// neither be nor hold any of the `reserved` ones, e.g. the outbox.
+/// ## Error
+/// Fails if the file can't be read or parsed, if a rule has an unknown limit, or if a directory is absolute, goes
+/// up with `..` or holds a reserved directory.
+pub(crate) fn load<P: AsRef<Path>>(
+    path: P,
+    base_dir: &Path,
+    reserved: &[&Path],
+) -> Result<BTreeMap<PathBuf, RetentionRule>> {
+    let path = path.as_ref();
+
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read retention file \"{}\"", path.display()))?;
+
+    let rules: BTreeMap<PathBuf, RetentionRule> = match path
+        .extension()
+        .and_then(|ext| ext.to_str())
+    {
+        Some(ext) if ext.eq_ignore_ascii_case("json") => serde_json::from_str(&contents)
+            .with_context(|| format!("Unable to parse retention file \"{}\"", path.display()))?,
+        _ => toml::from_str(&contents)
+            .with_context(|| format!("Unable to parse retention file \"{}\"", path.display()))?,
+    };
+
+    for dir in rules.keys() {
+        check_dir(dir, base_dir, reserved)
+            .with_context(|| format!("Invalid retention file \"{}\"", path.display()))?;
+    }
+
+    Ok(rules)
+}
+
+/// Checks that a directory of the rules is within `base_dir` and holds none of the `reserved` directories.
+fn check_dir(dir: &Path, base_dir: &Path, reserved: &[&Path]) -> Result<()> {
+    if dir
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        bail!(
+            "The directory \"{}\" is not relative to the binary directory, or goes up with `..`",
+            dir.display()
+        );
+    }
+
+    let pruned = comparable(&base_dir.join(dir));
+    if let Some(reserved) = reserved
+        .iter()
+        .find(|reserved| comparable(reserved).starts_with(&pruned))
+    {
+        bail!(
+            "The directory \"{}\" can't be pruned, as it holds \"{}\"",
+            dir.display(),
+            reserved.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// The path of a file as compared against the protected ones.
+fn comparable(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+/// Prunes the files of a directory and its subdirectories down to the rule, oldest-first: first the files over
+/// the age limit, then the oldest ones while the directory is over its size or file count limit.
+/// Files modified since `run_started_at` and the `protected` files are never removed, but count towards the limits.
+/// A file that can't be removed is warned about and skipped. A missing directory has nothing to prune.
+/// ## Error
+/// Fails if the directory can't be listed.
+pub(crate) fn prune(
+    dir: &Path,
+    rule: &RetentionRule,
+    run_started_at: DateTime<Utc>,
+    protected: &HashSet<PathBuf>,
+    dry_run: bool,
+) -> Result<Pruned> {
+    let mut pruned = Pruned {
+        dir: dir.to_owned(),
+        dry_run,
+        ..Default::default()
+    };
+
+    if !dir.exists() {
+        return Ok(pruned);
+    }
+
+    let protected: HashSet<PathBuf> = protected.iter().map(|path| comparable(path)).collect();
+
+    let mut files = Vec::new();
+    for entry in WalkDir::new(dir) {
+        let entry =
+            entry.with_context(|| format!("Unable to list directory \"{}\"", dir.display()))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let metadata = entry
+            .metadata()
+            .with_context(|| format!("Unable to read \"{}\"", entry.path().display()))?;
+        let modified = metadata
+            .modified()
+            .map(DateTime::<Utc>::from)
+            .unwrap_or(run_started_at);
+
+        files.push(Candidate {
+            path: entry.into_path(),
+            modified,
+            len: metadata.len(),
+        });
+    }
+    files.sort_by(|a, b| a.modified.cmp(&b.modified).then(a.path.cmp(&b.path)));
+
+    let mut total_bytes: u64 = files.iter().map(|file| file.len).sum();
+    let mut total_files = files.len();
+    // An age limit beyond the representable dates never expires a file
+    let expired_before = rule.max_age_days.and_then(|days| {
+        i64::try_from(days)
+            .ok()
+            .and_then(TimeDelta::try_days)
+            .and_then(|age| run_started_at.checked_sub_signed(age))
+    });
+
+    for file in files {
+        let expired = expired_before.is_some_and(|before| file.modified < before);
+        let oversized = rule.max_total_bytes.is_some_and(|max| total_bytes > max);
+        let overcrowded = rule.max_files.is_some_and(|max| total_files > max);
+
+        if !(expired || oversized || overcrowded) {
+            continue;
+        }
+        if file.modified >= run_started_at || protected.contains(&comparable(&file.path)) {
+            continue;
+        }
+
+        if !dry_run {
+            if let Err(e) = fs::remove_file(&file.path) {
+                log::warn!("Unable to remove \"{}\": {e}", file.path.display());
+                continue;
+            }
+        }
+
+        total_bytes -= file.len;
+        total_files -= 1;
+        pruned.freed_bytes += file.len;
+        pruned.removed.push(file.path);
+    }
+
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::removal_journal::{
+        JournaledEntry, RemovalJournal, RemovalRecord, RemovalStatus, REMOVAL_JOURNAL_FILE,
+    };
+    use std::fs::File;
+    use std::time::SystemTime;
+
+    /// Writes a file of `len` bytes, last modified `age_days` before `now`.
+    fn fixture(dir: &Path, name: &str, len: usize, age_days: i64, now: DateTime<Utc>) -> PathBuf {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, vec![b'x'; len]).unwrap();
+
+        let modified: SystemTime = (now - TimeDelta::days(age_days)).into();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        path
+    }
+
+    /// A tree of six files of 100 bytes, aged 1 to 6 days, with the oldest ones nested.
+    fn tree(dir: &Path, now: DateTime<Utc>) -> Vec<PathBuf> {
+        (1..=6)
+            .rev()
+            .map(|age| {
+                let name = if age > 3 {
+                    format!("2024/old-{age}.eml")
+                } else {
+                    format!("new-{age}.eml")
+                };
+                fixture(dir, &name, 100, age, now)
+            })
+            .collect()
+    }
+
+    fn remaining(dir: &Path) -> usize {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().file_type().is_file())
+            .count()
+    }
+
+    #[test]
+    fn test_age_based_pruning() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        let files = tree(dir.path(), now);
+
+        let rule = RetentionRule {
+            max_age_days: Some(3),
+            ..Default::default()
+        };
+
+        // A dry run removes nothing
+        let pruned = prune(dir.path(), &rule, now, &HashSet::new(), true).unwrap();
+        assert_eq!(pruned.removed, files[..3]);
+        assert_eq!(remaining(dir.path()), 6);
+        assert!(pruned
+            .to_string()
+            .ends_with("would remove 3 file(s), 300 bytes"));
+
+        let pruned = prune(dir.path(), &rule, now, &HashSet::new(), false).unwrap();
+        assert_eq!(pruned.removed, files[..3]);
+        assert_eq!(pruned.freed_bytes, 300);
+        assert!(pruned.to_string().ends_with("removed 3 file(s), 300 bytes"));
+        assert!(files[3..].iter().all(|file| file.exists()));
+
+        // Nothing left to prune, nor a directory to prune
+        let pruned = prune(dir.path(), &rule, now, &HashSet::new(), false).unwrap();
+        assert!(pruned.removed.is_empty());
+        let missing = dir.path().join("missing");
+        assert!(prune(&missing, &rule, now, &HashSet::new(), false)
+            .unwrap()
+            .removed
+            .is_empty());
+    }
+
+    #[test]
+    fn test_huge_age_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        tree(dir.path(), now);
+
+        for days in [u64::MAX, i64::MAX as u64, 1 << 40, 1 << 35] {
+            let rule = RetentionRule {
+                max_age_days: Some(days),
+                ..Default::default()
+            };
+            let pruned = prune(dir.path(), &rule, now, &HashSet::new(), false).unwrap();
+            assert!(pruned.removed.is_empty());
+        }
+        assert_eq!(remaining(dir.path()), 6);
+    }
+
+    #[test]
+    fn test_size_based_pruning() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        let files = tree(dir.path(), now);
+        fixture(dir.path(), "large.eml", 250, 0, now - TimeDelta::hours(1));
+
+        // 850 bytes down to 500, oldest-first
+        let rule = RetentionRule {
+            max_total_bytes: Some(500),
+            ..Default::default()
+        };
+        let pruned = prune(dir.path(), &rule, now, &HashSet::new(), false).unwrap();
+
+        assert_eq!(pruned.removed, files[..4]);
+        assert_eq!(pruned.freed_bytes, 400);
+        assert_eq!(remaining(dir.path()), 3);
+    }
+
+    #[test]
+    fn test_count_based_pruning() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        let files = tree(dir.path(), now);
+
+        // Files of the current run are kept, even over the limit
+        let current = fixture(
+            dir.path(),
+            "current.eml",
+            100,
+            0,
+            now + TimeDelta::seconds(1),
+        );
+
+        let rule = RetentionRule {
+            max_files: Some(2),
+            ..Default::default()
+        };
+        let pruned = prune(dir.path(), &rule, now, &HashSet::new(), false).unwrap();
+
+        assert_eq!(pruned.removed, files[..5]);
+        assert!(files[5].exists());
+        assert!(current.exists());
+
+        // The limits combine, each removing the oldest files it requires
+        let dir = tempfile::tempdir().unwrap();
+        let files = tree(dir.path(), now);
+        let rule = RetentionRule {
+            max_age_days: Some(5),
+            max_total_bytes: Some(450),
+            max_files: Some(5),
+        };
+        let pruned = prune(dir.path(), &rule, now, &HashSet::new(), false).unwrap();
+        assert_eq!(pruned.removed, files[..2]);
+    }
+
+    #[test]
+    fn test_journal_protection() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        let failed = dir.path().join("failed");
+        let files = tree(&failed, now);
+
+        // The oldest file is an entry of an E-mail pending removal
+        let mut journal = RemovalJournal::load(dir.path().join(REMOVAL_JOURNAL_FILE)).unwrap();
+        journal
+            .record(RemovalRecord {
+                recorded_at: now,
+                email_id: 1,
+                content_hash: String::new(),
+                status: RemovalStatus::Pending,
+                entries: vec![JournaledEntry {
+                    path: files[0].clone(),
+                    checksum: String::new(),
+                }],
+            })
+            .unwrap();
+
+        let journal = RemovalJournal::load(dir.path().join(REMOVAL_JOURNAL_FILE)).unwrap();
+        let protected = journal.referenced();
+
+        let rule = RetentionRule {
+            max_age_days: Some(1),
+            ..Default::default()
+        };
+        let pruned = prune(&failed, &rule, now, &protected, false).unwrap();
+
+        assert!(files[0].exists());
+        assert_eq!(pruned.removed, files[1..5]);
+    }
+
+    #[test]
+    fn test_load_retention_file() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let path = dir.path().join("retention.toml");
+        fs::write(
+            &path,
+            "[sent_mail]\nmax_age_days = 30\nmax_files = 1000\n\n[\"outbox/failed\"]\nmax_total_bytes = 1048576\n",
+        )
+        .unwrap();
+        let rules = load(&path, dir.path(), &[]).unwrap();
+        assert_eq!(
+            rules[Path::new("sent_mail")],
+            RetentionRule {
+                max_age_days: Some(30),
+                max_total_bytes: None,
+                max_files: Some(1000),
+            }
+        );
+        assert_eq!(
+            rules[Path::new("outbox/failed")].max_total_bytes,
+            Some(1048576)
+        );
+
+        let path = dir.path().join("retention.json");
+        fs::write(&path, r#"{ "sent_mail": { "max_age": 30 } }"#).unwrap();
+        assert!(load(&path, dir.path(), &[]).is_err());
+    }
+
+    #[test]
+    fn test_reserved_directories_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = dir.path().join("outbox");
+        let templates = dir.path().join("templates");
+        fs::create_dir_all(&outbox).unwrap();
+        let reserved = [dir.path(), outbox.as_path(), templates.as_path()];
+
+        let path = dir.path().join("retention.json");
+        let load_dir = |name: &str| {
+            fs::write(&path, format!(r#"{{ {name:?}: {{ "max_files": 1 }} }}"#)).unwrap();
+            load(&path, dir.path(), &reserved)
+        };
+
+        assert!(load_dir("sent_mail").is_ok());
+        assert!(load_dir("outbox/failed").is_ok());
+        for name in [
+            "",
+            ".",
+            "outbox",
+            "./outbox/",
+            "templates",
+            "sent_mail/../outbox",
+            "../elsewhere",
+            "/var/mail",
+        ] {
+            let error = load_dir(name).unwrap_err();
+            assert!(
+                format!("{error:#}").contains("The directory"),
+                "{name}: {error:#}"
+            );
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::entries::{crc32_iso_hdlc_checksum, Page};
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum ScheduleError {
+    #[error("Unknown splay mode \"{0}\"")]
+    UnknownSplayMode(String),
+
+    #[error("Invalid duration \"{0}\", expected seconds or units such as `90s`, `10m` or `1h30m`")]
+    InvalidDuration(String),
+}
+
+/// How the start delay of a run is chosen within `--splay`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum SplayMode {
+    /// Derived from the hostname, so every host keeps its own slot across runs
+    #[default]
+    Host,
+
+    /// Drawn again on every run
+    Random,
+}
+
+impl fmt::Display for SplayMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplayMode::Host => write!(f, "host"),
+            SplayMode::Random => write!(f, "random"),
+        }
+    }
+}
+
+impl FromStr for SplayMode {
+    type Err = ScheduleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let res = match s.trim().to_lowercase().as_str() {
+            "host" => SplayMode::Host,
+            "random" => SplayMode::Random,
+            _ => return Err(ScheduleError::UnknownSplayMode(s.to_string())),
+        };
+
+        Ok(res)
+    }
+}
+
+/// Parses a duration of whole seconds, e.g. `600`, or of `h`, `m` and `s` units, e.g. `10m` or `1h30m`.
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, ScheduleError> {
+    let invalid = || ScheduleError::InvalidDuration(s.to_string());
+    let trimmed = s.trim();
+
+    if let Ok(secs) = trimmed.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut secs = 0u64;
+    let mut digits = String::new();
+
+    for c in trimmed.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let value: u64 = digits.parse().map_err(|_| invalid())?;
+        secs += value * unit;
+        digits.clear();
+    }
+
+    if !digits.is_empty() || trimmed.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// The name of this host, from the environment or `/etc/hostname`.
+pub(crate) fn hostname() -> Option<String> {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+}
+
+/// The delay before a run starts, up to `max`.
+/// The `Host` mode hashes the hostname, and falls back to a random delay when it's unknown.
+pub(crate) fn splay_duration(max: Duration, mode: SplayMode, hostname: Option<&str>) -> Duration {
+    let range = max.as_millis() as u64 + 1;
+
+    let millis = match (mode, hostname) {
+        (SplayMode::Host, Some(hostname)) => {
+            u64::from(crc32_iso_hdlc_checksum(hostname.as_bytes())) % range
+        }
+        _ => fastrand::u64(..range),
+    };
+
+    Duration::from_millis(millis)
+}
+
+/// Spreads a delay by up to `factor` of itself in both directions, with `random` in `[0, 1)`.
+/// A factor of `0.2` turns a 10 seconds delay into anything between 8 and 12 seconds.
+pub(crate) fn jittered(delay: Duration, factor: f64, random: f64) -> Duration {
+    let factor = factor.clamp(0.0, 1.0);
+    let scale = 1.0 + factor * (random * 2.0 - 1.0);
+
+    delay.mul_f64(scale.max(0.0))
+}
+
+/// The wall-clock limit of a run. No new E-mail is started once it's exceeded.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Deadline {
+    at: Option<Instant>,
+}
+
+impl Deadline {
+    /// A deadline `max_run_time` after `started`, or none.
+    pub(crate) fn new(started: Instant, max_run_time: Option<Duration>) -> Self {
+        Self {
+            at: max_run_time.map(|max_run_time| started + max_run_time),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_exceeded(&self, now: Instant) -> bool {
+        self.at.is_some_and(|at| now >= at)
+    }
+
+    /// Whether the run stops before an E-mail at `now`: once the deadline is exceeded, unless the E-mail is a
+    /// follow-up page. The pages of an E-mail are a batch, sent whole so no run stops in the middle of one.
+    pub(crate) fn stops_before(&self, now: Instant, page: Option<Page>) -> bool {
+        self.is_exceeded(now) && page.is_none_or(|page| page.number <= 1)
+    }
+
+    /// The time left at `now`, when there's a deadline.
+    pub(crate) fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.at.map(|at| at.saturating_duration_since(now))
+    }
+}
+
+/// A shutdown request, set by the signal handler and awaited by the sleeps of a run.
+#[derive(Debug, Default)]
+pub(crate) struct Shutdown {
+    requested: Mutex<bool>,
+    signal: Condvar,
+}
+
+impl Shutdown {
+    pub(crate) fn request(&self) {
+        *self.requested.lock().unwrap() = true;
+        self.signal.notify_all();
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        *self.requested.lock().unwrap()
+    }
+
+    /// Sleeps for the given duration, unless a shutdown is requested meanwhile.
+    /// Returns `false` when the sleep was interrupted.
+    pub(crate) fn sleep(&self, duration: Duration) -> bool {
+        let requested = self.requested.lock().unwrap();
+
+        let (requested, _) = self
+            .signal
+            .wait_timeout_while(requested, duration, |requested| !*requested)
+            .unwrap();
+
+        !*requested
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    #[test]
+    fn test_host_splay_is_deterministic() {
+        let max = Duration::from_secs(300);
+
+        let first = splay_duration(max, SplayMode::Host, Some("mailer-01"));
+        assert_eq!(
+            first,
+            splay_duration(max, SplayMode::Host, Some("mailer-01"))
+        );
+        assert!(first <= max);
+
+        // crc32("mailer-01") % 300001
+        let expected = u64::from(crc32_iso_hdlc_checksum(b"mailer-01")) % 300_001;
+        assert_eq!(first, Duration::from_millis(expected));
+
+        let hosts: std::collections::HashSet<Duration> = (1..=40)
+            .map(|i| splay_duration(max, SplayMode::Host, Some(&format!("mailer-{i:02}"))))
+            .collect();
+        assert!(hosts.len() > 30, "The fleet should spread out");
+
+        for _ in 0..100 {
+            assert!(splay_duration(max, SplayMode::Random, None) <= max);
+        }
+    }
+
+    #[test]
+    fn test_jittered_delay() {
+        let delay = Duration::from_secs(10);
+
+        assert_eq!(jittered(delay, 0.2, 0.0), Duration::from_secs(8));
+        assert_eq!(jittered(delay, 0.2, 0.5), delay);
+        assert_eq!(jittered(delay, 0.0, 0.9), delay);
+        assert!(jittered(delay, 0.2, 0.999) < Duration::from_secs(12));
+    }
+
+    #[test]
+    fn test_deadline_stops_remaining_sends() {
+        let started = Instant::now();
+        let deadline = Deadline::new(started, Some(Duration::from_secs(10)));
+
+        // Every send takes 4 seconds of a simulated clock
+        let mut now = started;
+        let mut sent = Vec::new();
+        for email in 0..5 {
+            if deadline.is_exceeded(now) {
+                break;
+            }
+            now += Duration::from_secs(4);
+            sent.push(email);
+        }
+
+        // The third send started within the deadline and was finished
+        assert_eq!(sent, vec![0, 1, 2]);
+        assert_eq!(deadline.remaining(now), Some(Duration::ZERO));
+        assert_eq!(
+            deadline.remaining(started + Duration::from_secs(3)),
+            Some(Duration::from_secs(7))
+        );
+
+        let unbounded = Deadline::new(started, None);
+        assert!(!unbounded.is_exceeded(started + Duration::from_secs(86400)));
+        assert_eq!(unbounded.remaining(started), None);
+    }
+
+    #[test]
+    fn test_deadline_keeps_pages_together() {
+        let started = Instant::now();
+        let deadline = Deadline::new(started, Some(Duration::from_secs(10)));
+
+        let page = |number| Some(Page { number, count: 3 });
+        let emails = [None, page(1), page(2), page(3), None];
+
+        // Every send takes 4 seconds of a simulated clock, the deadline passes after the first page
+        let mut now = started;
+        let mut sent = Vec::new();
+        for (index, page) in emails.into_iter().enumerate() {
+            if deadline.stops_before(now, page) {
+                break;
+            }
+            now += Duration::from_secs(4);
+            sent.push(index);
+        }
+
+        // The follow-up pages are sent past the deadline, the next E-mail is not
+        assert_eq!(sent, vec![0, 1, 2, 3]);
+        assert!(deadline.stops_before(now, page(1)));
+        assert!(!Deadline::new(started, None).stops_before(now, None));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("600").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(
+            parse_duration(" 1h30m ").unwrap(),
+            Duration::from_secs(5400)
+        );
+        assert_eq!(parse_duration("2m15s").unwrap(), Duration::from_secs(135));
+
+        for invalid in ["", "m", "10x", "1h30", "-5m", "1.5h"] {
+            assert!(parse_duration(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_shutdown_interrupts_sleep() {
+        let shutdown = Arc::new(Shutdown::default());
+
+        let handler = {
+            let shutdown = shutdown.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                shutdown.request();
+            })
+        };
+
+        let started = Instant::now();
+        assert!(!shutdown.sleep(Duration::from_secs(30)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(shutdown.is_requested());
+
+        handler.join().unwrap();
+
+        // Once requested, later sleeps return at once
+        assert!(!shutdown.sleep(Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,443 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::entries::{self, Entry, EntryFormat};
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum SchemaError {
+    #[error("Unknown schema format \"{0}\"")]
+    UnknownFormat(String),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum SchemaFormat {
+    #[default]
+    JsonSchema,
+    Markdown,
+}
+
+impl fmt::Display for SchemaFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaFormat::JsonSchema => write!(f, "json-schema"),
+            SchemaFormat::Markdown => write!(f, "markdown"),
+        }
+    }
+}
+
+impl FromStr for SchemaFormat {
+    type Err = SchemaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let res = match s.trim().to_lowercase().as_str() {
+            "json-schema" => SchemaFormat::JsonSchema,
+            "markdown" => SchemaFormat::Markdown,
+            _ => return Err(SchemaError::UnknownFormat(s.to_string())),
+        };
+
+        Ok(res)
+    }
+}
+
+/// How the `+key` values of the context are accumulated, which a schema can't express.
+const ACCUMULATION: &str = "\
+Entries whose `email` objects are identical, key for key, make up a single E-mail. Their contexts are merged in the
+order of their `utc` time: a key keeps the value of the first entry that has it, and nested objects are merged the
+same way.
+
+A key prefixed with `+`, e.g. `\"+rows\": { \"hostname\": \"db-1\" }`, is accumulated instead: the E-mail gets a `rows`
+array with an `{ order, checksum, value }` item per entry, `order` counting from 1 and `checksum` the CRC32 of the
+value. Templates loop over `rows_values`, the plain values in the same order. Any `+` key makes the entries a batch,
+sent as a single E-mail; without one, every entry is sent on its own.";
+
+/// The JSON Schema of the entry files, as generated from the [`Entry`] definitions.
+pub(crate) fn entry_schema() -> Value {
+    serde_json::to_value(schemars::schema_for!(Entry)).expect("A schema is always valid JSON")
+}
+
+/// The JSON Schema of the entry files, with its keys sorted at every level, so producers can vendor it and
+/// diff it on upgrades.
+pub(crate) fn to_json(schema: &Value) -> String {
+    entries::pretty_json(&entries::canonicalize(schema.clone()))
+}
+
+/// The definition a local `$ref`, e.g. `#/$defs/Email`, points to.
+fn resolve<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    root.pointer(reference.strip_prefix('#')?)
+}
+
+fn ref_name(reference: &str) -> &str {
+    reference.rsplit('/').next().unwrap_or(reference)
+}
+
+/// The type of a property, as written in the field reference, with links to the definitions, or in a violation.
+fn describe_type(schema: &Value, links: bool) -> String {
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        let name = ref_name(reference);
+        return if links {
+            format!("[`{name}`](#{})", name.to_lowercase())
+        } else {
+            format!("`{name}`")
+        };
+    }
+
+    if let Some(any_of) = schema.get("anyOf").and_then(Value::as_array) {
+        return any_of
+            .iter()
+            .map(|alternative| describe_type(alternative, links))
+            .collect::<Vec<_>>()
+            .join(" or ");
+    }
+
+    let types: Vec<&str> = match schema.get("type") {
+        Some(Value::String(name)) => vec![name],
+        Some(Value::Array(names)) => names.iter().filter_map(Value::as_str).collect(),
+        _ => return "any".to_owned(),
+    };
+
+    types
+        .into_iter()
+        .map(|name| match name {
+            "array" => {
+                let items = schema
+                    .get("items")
+                    .map_or("any".to_owned(), |items| describe_type(items, links));
+                format!("array of {items}")
+            }
+            "object" => match schema.get("additionalProperties") {
+                Some(Value::Object(_)) => {
+                    format!(
+                        "object of {}",
+                        describe_type(&schema["additionalProperties"], links)
+                    )
+                }
+                _ => "object".to_owned(),
+            },
+            "string" => match schema.get("format").and_then(Value::as_str) {
+                Some(format) => format!("string ({format})"),
+                None => "string".to_owned(),
+            },
+            name => name.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join(" or ")
+}
+
+/// The field table of an object definition.
+fn field_table(markdown: &mut String, definition: &Value) {
+    let required: Vec<&str> = definition
+        .get("required")
+        .and_then(Value::as_array)
+        .map(|required| required.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+
+    markdown.push_str("| Field | Type | Required | Description |\n");
+    markdown.push_str("|---|---|---|---|\n");
+
+    let properties = definition.get("properties").and_then(Value::as_object);
+
+    for (name, property) in properties.into_iter().flatten() {
+        let mut description = property
+            .get("description")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .replace('\n', " ");
+
+        if let Some(default) = property.get("default") {
+            if !description.is_empty() && !description.ends_with('.') {
+                description.push('.');
+            }
+            description.push_str(&format!(" Defaults to `{default}`."));
+        }
+
+        markdown.push_str(&format!(
+            "| `{name}` | {} | {} | {} |\n",
+            describe_type(property, true),
+            if required.contains(&name.as_str()) {
+                "yes"
+            } else {
+                "no"
+            },
+            description.trim().replace('|', "\\|")
+        ));
+    }
+}
+
+/// The field reference of the entry files, a table per definition of the schema, and the accumulation semantics.
+pub(crate) fn to_markdown(schema: &Value) -> String {
+    let mut markdown = String::from("# Entry Format\n\n");
+
+    let mut definitions = vec![("Entry", schema)];
+    definitions.extend(
+        schema
+            .get("$defs")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+            .map(|(name, definition)| (name.as_str(), definition)),
+    );
+
+    for (name, definition) in definitions {
+        markdown.push_str(&format!("## {name}\n\n"));
+
+        if let Some(description) = definition.get("description").and_then(Value::as_str) {
+            markdown.push_str(&format!("{}\n\n", description.replace('\n', " ")));
+        }
+
+        field_table(&mut markdown, definition);
+        markdown.push('\n');
+    }
+
+    markdown.push_str(&format!("## Accumulation\n\n{ACCUMULATION}\n"));
+    markdown
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_i64() || number.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    let actual = type_name(value);
+    actual == name || (name == "number" && actual == "integer")
+}
+
+/// Checks the value against a schema, for the subset of JSON Schema the entry schema is generated with:
+/// `$ref`, `anyOf`, `type`, `format: date-time`, `required`, `properties`, `additionalProperties` and `items`.
+fn check(root: &Value, schema: &Value, value: &Value, path: &str, violations: &mut Vec<String>) {
+    let location = if path.is_empty() { "/" } else { path };
+
+    let schema = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => {
+            violations.push(format!("{location}: not allowed"));
+            return;
+        }
+        schema => schema,
+    };
+
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        match resolve(root, reference) {
+            Some(definition) => check(root, definition, value, path, violations),
+            None => violations.push(format!("{location}: unknown definition `{reference}`")),
+        }
+    }
+
+    if let Some(any_of) = schema.get("anyOf").and_then(Value::as_array) {
+        let mut alternatives_violations = Vec::new();
+
+        for alternative in any_of {
+            let mut alternative_violations = Vec::new();
+            check(root, alternative, value, path, &mut alternative_violations);
+
+            if alternative_violations.is_empty() {
+                alternatives_violations.clear();
+                break;
+            }
+            alternatives_violations.push(alternative_violations);
+        }
+
+        if !alternatives_violations.is_empty() {
+            // The alternative of the same type tells what is wrong within the value
+            let type_mismatch = format!("{location}: expected ");
+            let within = alternatives_violations
+                .into_iter()
+                .find(|alternative_violations| {
+                    !alternative_violations
+                        .iter()
+                        .any(|violation| violation.starts_with(&type_mismatch))
+                });
+
+            match within {
+                Some(within) => violations.extend(within),
+                None => violations.push(format!(
+                    "{location}: expected {}, found {}",
+                    describe_type(schema, false),
+                    type_name(value)
+                )),
+            }
+            return;
+        }
+    }
+
+    let allowed = match schema.get("type") {
+        Some(Value::String(name)) => Some(vec![name.as_str()]),
+        Some(Value::Array(names)) => Some(names.iter().filter_map(Value::as_str).collect()),
+        _ => None,
+    };
+
+    if let Some(allowed) = allowed {
+        if !allowed.iter().any(|name| is_type(value, name)) {
+            violations.push(format!(
+                "{location}: expected {}, found {}",
+                allowed.join(" or "),
+                type_name(value)
+            ));
+            return;
+        }
+    }
+
+    match value {
+        Value::String(text)
+            if schema.get("format").and_then(Value::as_str) == Some("date-time")
+                && chrono::DateTime::parse_from_rfc3339(text).is_err() =>
+        {
+            violations.push(format!(
+                "{location}: \"{text}\" is not an RFC 3339 date-time"
+            ));
+        }
+        Value::Array(items) => {
+            if let Some(items_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    check(root, items_schema, item, &format!("{path}/{i}"), violations);
+                }
+            }
+        }
+        Value::Object(object) => {
+            for name in schema
+                .get("required")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+            {
+                if !object.contains_key(name) {
+                    violations.push(format!("{location}: missing the required `{name}`"));
+                }
+            }
+
+            let properties = schema.get("properties").and_then(Value::as_object);
+
+            for (name, field) in object {
+                let field_path = format!("{path}/{name}");
+
+                match properties.and_then(|properties| properties.get(name)) {
+                    Some(property) => check(root, property, field, &field_path, violations),
+                    None => {
+                        if let Some(additional) = schema.get("additionalProperties") {
+                            check(root, additional, field, &field_path, violations);
+                        }
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The violations of the schema by an entry, each with the JSON pointer of the offending value.
+pub(crate) fn validate(schema: &Value, entry: &Value) -> Vec<String> {
+    let mut violations = Vec::new();
+    check(schema, schema, entry, "", &mut violations);
+    violations
+}
+
+/// The violations of the schema by an entry file.
+/// ## Error
+/// Fails if the file can't be read or is not JSON, or YAML for a `.yaml` or `.yml` extension.
+pub(crate) fn validate_file(schema: &Value, path: &Path) -> Result<Vec<String>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read entry file \"{}\"", path.display()))?;
+    let entry: Value = EntryFormat::of(path)
+        .unwrap_or(EntryFormat::Json)
+        .parse(&content)
+        .with_context(|| format!("The entry file \"{}\" is malformed", path.display()))?;
+
+    Ok(validate(schema, &entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+    /// The schema shipped to producers, kept in sync with the generated one.
+    const VENDORED_SCHEMA: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/mail_producer/entry.schema.json"
+    );
+
+    #[test]
+    fn test_schema_validates_fixtures() {
+        let schema = entry_schema();
+
+        let mut cases: Vec<PathBuf> = fs::read_dir(Path::new(FIXTURES_DIR).join("compose"))
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|path| path.to_string_lossy().ends_with(".entries.json"))
+            .collect();
+        cases.sort();
+        assert!(!cases.is_empty());
+
+        for case in cases {
+            let entries: Vec<Value> =
+                serde_json::from_str(&fs::read_to_string(&case).unwrap()).unwrap();
+
+            for entry in &entries {
+                // Whatever the schema accepts, the mailer parses
+                assert_eq!(validate(&schema, entry), Vec::<String>::new(), "{case:?}");
+                serde_json::from_value::<Entry>(entry.clone()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_schema_rejects_invalid_entry() {
+        let schema = entry_schema();
+        let path = Path::new(FIXTURES_DIR).join("schema/invalid.entry.json");
+
+        assert_eq!(
+            validate_file(&schema, &path).unwrap(),
+            vec![
+                "/utc: \"yesterday\" is not an RFC 3339 date-time",
+                "/email: missing the required `subject`",
+                "/email/to: expected array, found string",
+                "/email/headers/X-Priority: expected string, found integer",
+                "/email/charset: expected `CharsetConfig` or null, found string",
+                "/email/alternative/generate_text: expected boolean or null, found string",
+                "/context: expected object, found array",
+            ]
+        );
+
+        let entry: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(serde_json::from_value::<Entry>(entry).is_err());
+    }
+
+    #[test]
+    fn test_vendored_schema_is_current() {
+        let generated = to_json(&entry_schema());
+
+        // Regenerated with `UPDATE_FIXTURES=1 cargo test`, as the golden files
+        if std::env::var_os("UPDATE_FIXTURES").is_some() {
+            fs::write(VENDORED_SCHEMA, &generated).unwrap();
+            return;
+        }
+
+        assert_eq!(
+            fs::read_to_string(VENDORED_SCHEMA).unwrap(),
+            generated,
+            "The entry schema changed, regenerate \"mail_producer/entry.schema.json\""
+        );
+
+        let markdown = to_markdown(&entry_schema());
+        assert!(markdown.contains(
+            "| `to` | array of string | yes | The recipients, as addresses, `Name <address>` or `@alias` groups |"
+        ));
+        assert!(markdown.contains("| `charset` | [`CharsetConfig`](#charsetconfig) or null | no |"));
+        assert!(markdown.contains("## Accumulation"));
+    }
+}
@@ -0,0 +1,176 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// The log of sent E-mails, one JSON record per line, placed next to the outbox.
+pub(crate) const SENT_LOG_FILE: &str = "sent_log.jsonl";
+
+/// How long records are kept in the sent-log.
+pub(crate) const SENT_LOG_RETENTION: Duration = Duration::hours(24);
+
+/// A record of a JSON lines log kept next to the outbox, such as the sent-log.
+pub(crate) trait LogRecord: Serialize + DeserializeOwned {
+    /// When the E-mail of the record was sent, which decides how long the record is kept.
+    fn sent_at(&self) -> DateTime<Utc>;
+}
+
+/// A single E-mail that was accepted by the mail relay.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct SentRecord {
+    pub(crate) sent_at: DateTime<Utc>,
+    pub(crate) email_id: u32,
+    pub(crate) template: String,
+    pub(crate) content_hash: String,
+
+    /// The reply token of the E-mail, to correlate the tickets created from its replies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reply_token: Option<String>,
+}
+
+impl LogRecord for SentRecord {
+    #[inline]
+    fn sent_at(&self) -> DateTime<Utc> {
+        self.sent_at
+    }
+}
+
+/// Loads the records sent at or after `since`. A missing sent-log has no records.
+/// Lines that can't be parsed are skipped, as a partially written line must not stop the mailer.
+pub(crate) fn load_since<R: LogRecord, P: AsRef<Path>>(
+    path: P,
+    since: DateTime<Utc>,
+) -> Result<Vec<R>> {
+    let path = path.as_ref();
+
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read sent-log \"{}\"", path.display()))?;
+
+    Ok(contents
+        .lines()
+        .filter_map(|line| serde_json::from_str::<R>(line).ok())
+        .filter(|record| record.sent_at() >= since)
+        .collect())
+}
+
+/// Appends a record to the sent-log, creating it if needed.
+pub(crate) fn append<R: LogRecord, P: AsRef<Path>>(path: P, record: &R) -> Result<()> {
+    let path = path.as_ref();
+    let line = serde_json::to_string(record).expect("Log record is always valid JSON");
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Unable to open sent-log \"{}\"", path.display()))?;
+
+    writeln!(file, "{line}")
+        .with_context(|| format!("Unable to write sent-log \"{}\"", path.display()))
+}
+
+/// Drops the records sent before `since` from the sent-log.
+pub(crate) fn compact<R: LogRecord, P: AsRef<Path>>(path: P, since: DateTime<Utc>) -> Result<()> {
+    let path = path.as_ref();
+
+    if !path.is_file() {
+        return Ok(());
+    }
+
+    let mut contents = String::new();
+    for record in load_since::<R, _>(path, since)? {
+        contents
+            .push_str(&serde_json::to_string(&record).expect("Log record is always valid JSON"));
+        contents.push('\n');
+    }
+
+    replace(path, &contents)
+        .with_context(|| format!("Unable to write sent-log \"{}\"", path.display()))
+}
+
+/// Replaces the contents of a log through a temporary file in the same directory, renamed over the log,
+/// so a compaction stopped midway leaves the log whole rather than truncated.
+pub(crate) fn replace(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let write = || -> std::io::Result<()> {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    };
+
+    write().inspect_err(|_| {
+        let _ = fs::remove_file(&temp_path);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sent_log_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SENT_LOG_FILE);
+        let now = Utc::now();
+
+        let record = |sent_at, content_hash: &str| SentRecord {
+            sent_at,
+            email_id: 1,
+            template: "ops_department".to_owned(),
+            content_hash: content_hash.to_owned(),
+            reply_token: None,
+        };
+
+        assert!(load_since::<SentRecord, _>(&path, now).unwrap().is_empty());
+
+        append(&path, &record(now - Duration::hours(30), "old")).unwrap();
+        append(&path, &record(now, "new")).unwrap();
+        fs::write(
+            &path,
+            fs::read_to_string(&path).unwrap() + "{ partially written",
+        )
+        .unwrap();
+
+        let since = now - SENT_LOG_RETENTION;
+        assert_eq!(
+            load_since::<SentRecord, _>(&path, since).unwrap(),
+            vec![record(now, "new")]
+        );
+
+        compact::<SentRecord, _>(&path, since).unwrap();
+        assert_eq!(
+            load_since::<SentRecord, _>(&path, now - Duration::days(365)).unwrap(),
+            vec![record(now, "new")]
+        );
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_replace_through_temporary_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SENT_LOG_FILE);
+        let temp_path = dir.path().join(format!("{SENT_LOG_FILE}.tmp"));
+
+        // Left over by a compaction stopped before its rename
+        fs::write(&path, "whole\n").unwrap();
+        fs::write(&temp_path, "partial").unwrap();
+
+        replace(&path, "compacted\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "compacted\n");
+        assert!(!temp_path.exists());
+
+        // A log that can't be replaced is left as it was
+        fs::create_dir(&temp_path).unwrap();
+        assert!(replace(&path, "lost\n").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "compacted\n");
+    }
+}
@@ -0,0 +1,308 @@
+use anyhow::{Context, Result};
+use ed25519_dalek::{Signature, VerifyingKey};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::entries;
+
+/// The field of an entry holding its signature, left out of the signed content.
+pub(crate) const SIGNATURE_FIELD: &str = "signature";
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub(crate) enum SignatureError {
+    #[error("The entry is not signed, and `--unsigned-entries` is `reject`")]
+    Unsigned,
+
+    #[error("Unknown producer key `{0}`, see `--producer-keys`")]
+    UnknownKey(String),
+
+    #[error("The signature of producer key `{0}` is not 64 hex-encoded bytes")]
+    Malformed(String),
+
+    #[error("The signature of producer key `{0}` doesn't match the entry, which was altered or signed with another key")]
+    Mismatch(String),
+
+    #[error("Unknown unsigned entries policy \"{0}\"")]
+    UnknownPolicy(String),
+}
+
+/// The Ed25519 signature of an entry by a producer, over its canonical JSON without the `signature` field.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub(crate) struct EntrySignature {
+    /// The producer key of `--producer-keys` the entry is signed with
+    pub(crate) key_id: String,
+
+    /// The hex-encoded Ed25519 signature of the canonical JSON of the entry: its keys sorted at every level,
+    /// without whitespace, and without the `signature` field
+    pub(crate) value: String,
+}
+
+/// What to do with the entries without a signature.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum UnsignedPolicy {
+    #[default]
+    Accept,
+    Reject,
+}
+
+impl fmt::Display for UnsignedPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnsignedPolicy::Accept => write!(f, "accept"),
+            UnsignedPolicy::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+impl FromStr for UnsignedPolicy {
+    type Err = SignatureError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let res = match s.trim().to_lowercase().as_str() {
+            "accept" => UnsignedPolicy::Accept,
+            "reject" => UnsignedPolicy::Reject,
+            _ => return Err(SignatureError::UnknownPolicy(s.to_string())),
+        };
+
+        Ok(res)
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// The content an entry is signed over: its canonical JSON, see [`EntrySignature::value`].
+pub(crate) fn signed_content(entry: &serde_json::Value) -> String {
+    let mut entry = entry.clone();
+    if let serde_json::Value::Object(object) = &mut entry {
+        object.remove(SIGNATURE_FIELD);
+    }
+
+    serde_json::to_string(&entries::canonicalize(entry)).expect("A JSON value is always valid JSON")
+}
+
+/// Verifies the signatures of the entries against the public keys of their producers.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Verifier {
+    keys: BTreeMap<String, VerifyingKey>,
+    unsigned: UnsignedPolicy,
+}
+
+impl Verifier {
+    pub(crate) fn new(keys: BTreeMap<String, VerifyingKey>, unsigned: UnsignedPolicy) -> Self {
+        Self { keys, unsigned }
+    }
+
+    /// The producer key an entry is signed with, or None for an unsigned entry the policy accepts.
+    /// The signature is checked against the entry as written, `content`.
+    pub(crate) fn verify(
+        &self,
+        signature: Option<&EntrySignature>,
+        content: &str,
+    ) -> Result<Option<String>, SignatureError> {
+        let Some(signature) = signature else {
+            return match self.unsigned {
+                UnsignedPolicy::Accept => Ok(None),
+                UnsignedPolicy::Reject => Err(SignatureError::Unsigned),
+            };
+        };
+
+        let key_id = &signature.key_id;
+
+        let key = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| SignatureError::UnknownKey(key_id.clone()))?;
+
+        let bytes: [u8; Signature::BYTE_SIZE] = decode_hex(&signature.value)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| SignatureError::Malformed(key_id.clone()))?;
+
+        let entry: serde_json::Value =
+            serde_json::from_str(content).map_err(|_| SignatureError::Mismatch(key_id.clone()))?;
+
+        key.verify_strict(
+            signed_content(&entry).as_bytes(),
+            &Signature::from_bytes(&bytes),
+        )
+        .map_err(|_| SignatureError::Mismatch(key_id.clone()))?;
+
+        Ok(Some(key_id.clone()))
+    }
+}
+
+/// Loads the public keys of the producers from a TOML file, or a JSON file for a `.json` extension, mapping each
+/// key ID to its hex-encoded Ed25519 public key, e.g. `backup-prod = "3d4017c3e843895a92b70aa74d1b7ebc..."`.
+/// ## Error
+/// Fails if the file can't be read or parsed, or if a key is not a valid Ed25519 public key.
+pub(crate) fn load_keys<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, VerifyingKey>> {
+    let path = path.as_ref();
+
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read producer keys file \"{}\"", path.display()))?;
+
+    let keys: BTreeMap<String, String> = match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("json") => serde_json::from_str(&contents)
+            .with_context(|| {
+                format!("Unable to parse producer keys file \"{}\"", path.display())
+            })?,
+        _ => toml::from_str(&contents).with_context(|| {
+            format!("Unable to parse producer keys file \"{}\"", path.display())
+        })?,
+    };
+
+    keys.into_iter()
+        .map(|(key_id, hex)| {
+            let key = decode_hex(hex.trim())
+                .and_then(|bytes| bytes.try_into().ok())
+                .and_then(|bytes: [u8; 32]| VerifyingKey::from_bytes(&bytes).ok())
+                .with_context(|| {
+                    format!(
+                        "The producer key `{key_id}` of \"{}\" is not a hex-encoded Ed25519 public key",
+                        path.display()
+                    )
+                })?;
+
+            Ok((key_id, key))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+    use serde_json::json;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    /// An entry signed by the key, with keys in another order than signed.
+    fn signed_entry(key: &SigningKey, key_id: &str) -> serde_json::Value {
+        let mut entry = json!({
+            "utc": "2023-01-01T10:00:00+00:00",
+            "id": "1",
+            "notify_error": [],
+            "email": { "to": ["ops@example.com"], "subject": "Daily report" },
+            "context": { "+rows": { "host": "db-1" } },
+        });
+
+        let signature = key.sign(signed_content(&entry).as_bytes());
+        entry[SIGNATURE_FIELD] = json!({ "key_id": key_id, "value": hex(&signature.to_bytes()) });
+        entry
+    }
+
+    fn verifier(key: &SigningKey, unsigned: UnsignedPolicy) -> Verifier {
+        Verifier::new(
+            BTreeMap::from([("backup".to_owned(), key.verifying_key())]),
+            unsigned,
+        )
+    }
+
+    fn verify(
+        verifier: &Verifier,
+        entry: &serde_json::Value,
+    ) -> Result<Option<String>, SignatureError> {
+        let signature = entry
+            .get(SIGNATURE_FIELD)
+            .map(|signature| serde_json::from_value(signature.clone()).unwrap());
+        verifier.verify(signature.as_ref(), &entry.to_string())
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let verifier = verifier(&key, UnsignedPolicy::Reject);
+
+        let entry = signed_entry(&key, "backup");
+        assert_eq!(verify(&verifier, &entry), Ok(Some("backup".to_owned())));
+
+        // Whitespace and key order are not part of the signed content
+        let pretty = serde_json::to_string_pretty(&entry).unwrap();
+        let signature = serde_json::from_value(entry[SIGNATURE_FIELD].clone()).unwrap();
+        assert_eq!(
+            verifier.verify(Some(&signature), &pretty),
+            Ok(Some("backup".to_owned()))
+        );
+
+        let mut tampered = entry.clone();
+        tampered["email"]["to"] = json!(["attacker@example.com"]);
+        assert_eq!(
+            verify(&verifier, &tampered),
+            Err(SignatureError::Mismatch("backup".to_owned()))
+        );
+
+        let mut malformed = entry.clone();
+        malformed[SIGNATURE_FIELD]["value"] = json!("not hex");
+        assert_eq!(
+            verify(&verifier, &malformed),
+            Err(SignatureError::Malformed("backup".to_owned()))
+        );
+
+        // Signed with an unknown key, or with a key claiming another producer
+        let other = SigningKey::from_bytes(&[9; 32]);
+        assert_eq!(
+            verify(&verifier, &signed_entry(&other, "billing")),
+            Err(SignatureError::UnknownKey("billing".to_owned()))
+        );
+        assert_eq!(
+            verify(&verifier, &signed_entry(&other, "backup")),
+            Err(SignatureError::Mismatch("backup".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_unsigned_policy() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let unsigned = json!({ "id": "1" });
+
+        assert_eq!(
+            verify(&verifier(&key, UnsignedPolicy::Accept), &unsigned),
+            Ok(None)
+        );
+        assert_eq!(
+            verify(&verifier(&key, UnsignedPolicy::Reject), &unsigned),
+            Err(SignatureError::Unsigned)
+        );
+
+        // Signed entries are verified whatever the policy
+        let entry = signed_entry(&key, "backup");
+        assert_eq!(
+            verify(&verifier(&key, UnsignedPolicy::Accept), &entry),
+            Ok(Some("backup".to_owned()))
+        );
+        assert_eq!("reject".parse(), Ok(UnsignedPolicy::Reject));
+    }
+
+    #[test]
+    fn test_load_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = SigningKey::from_bytes(&[7; 32]);
+
+        let path = dir.path().join("producer_keys.toml");
+        fs::write(
+            &path,
+            format!("backup = \"{}\"\n", hex(key.verifying_key().as_bytes())),
+        )
+        .unwrap();
+        assert_eq!(load_keys(&path).unwrap()["backup"], key.verifying_key());
+
+        fs::write(&path, "backup = \"abcd\"\n").unwrap();
+        let error = load_keys(&path).unwrap_err();
+        assert!(error.to_string().contains("`backup`"), "{error}");
+    }
+}
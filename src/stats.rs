@@ -0,0 +1,371 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::sent_log::{self, LogRecord};
+
+/// The cumulative volume of sent mail, one JSON record per template and run, kept next to the sent-log.
+/// Unlike the sent-log, it's never compacted.
+pub(crate) const STATS_FILE: &str = "stats.jsonl";
+
+/// The template counted for the E-mails of `html_body` entries, as they have none.
+pub(crate) const RAW_TEMPLATE: &str = "(html_body)";
+
+/// The template of the row summing every template, without `--by-template`.
+const ALL_TEMPLATES: &str = "all";
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum StatsError {
+    #[error("Unknown stats format \"{0}\"")]
+    UnknownFormat(String),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum StatsFormat {
+    #[default]
+    Table,
+    Csv,
+}
+
+impl fmt::Display for StatsFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatsFormat::Table => write!(f, "table"),
+            StatsFormat::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+impl FromStr for StatsFormat {
+    type Err = StatsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let res = match s.trim().to_lowercase().as_str() {
+            "table" => StatsFormat::Table,
+            "csv" => StatsFormat::Csv,
+            _ => return Err(StatsError::UnknownFormat(s.to_string())),
+        };
+
+        Ok(res)
+    }
+}
+
+/// The mail volume of a template, for a run or summed over a month.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TemplateStats {
+    pub(crate) sent_at: DateTime<Utc>,
+    pub(crate) template: String,
+    pub(crate) messages: u64,
+
+    /// The length of the formatted messages, as sent
+    pub(crate) encoded_bytes: u64,
+
+    /// The encoded size of the attached files and inline images, part of `encoded_bytes`
+    pub(crate) attachment_bytes: u64,
+
+    /// The distinct envelope recipients of the run. Summed over several runs, a recipient of each is counted
+    pub(crate) recipients: u64,
+}
+
+impl LogRecord for TemplateStats {
+    #[inline]
+    fn sent_at(&self) -> DateTime<Utc> {
+        self.sent_at
+    }
+}
+
+impl TemplateStats {
+    fn add(&mut self, other: &TemplateStats) {
+        self.messages += other.messages;
+        self.encoded_bytes += other.encoded_bytes;
+        self.attachment_bytes += other.attachment_bytes;
+        self.recipients += other.recipients;
+    }
+}
+
+/// The mail volume of the current run, accumulated per template as E-mails are sent.
+#[derive(Debug, Default)]
+pub(crate) struct RunStats {
+    templates: BTreeMap<String, (TemplateStats, HashSet<String>)>,
+}
+
+impl RunStats {
+    /// Counts a sent message, measured by the length of its formatted form.
+    pub(crate) fn record(
+        &mut self,
+        template: &str,
+        encoded_bytes: usize,
+        attachment_bytes: usize,
+        recipients: &[String],
+    ) {
+        let (stats, unique_recipients) = self.templates.entry(template.to_owned()).or_default();
+
+        stats.messages += 1;
+        stats.encoded_bytes += encoded_bytes as u64;
+        stats.attachment_bytes += attachment_bytes as u64;
+        unique_recipients.extend(recipients.iter().map(|recipient| recipient.to_lowercase()));
+    }
+
+    /// Appends the volume of every template of the run to the stats store.
+    pub(crate) fn save<P: AsRef<Path>>(&self, path: P, now: DateTime<Utc>) -> Result<()> {
+        for (template, (stats, unique_recipients)) in &self.templates {
+            let record = TemplateStats {
+                sent_at: now,
+                template: template.clone(),
+                recipients: unique_recipients.len() as u64,
+                ..stats.clone()
+            };
+
+            sent_log::append(&path, &record)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A calendar month, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Month {
+    start: NaiveDate,
+}
+
+impl Month {
+    /// The month of a time.
+    pub(crate) fn of(time: DateTime<Utc>) -> Self {
+        Self {
+            start: time
+                .date_naive()
+                .with_day(1)
+                .expect("Every month has a first day"),
+        }
+    }
+
+    fn start(&self) -> DateTime<Utc> {
+        self.start
+            .and_hms_opt(0, 0, 0)
+            .unwrap_or_default()
+            .and_utc()
+    }
+
+    fn end(&self) -> DateTime<Utc> {
+        let next = self
+            .start
+            .checked_add_months(chrono::Months::new(1))
+            .unwrap_or(NaiveDate::MAX);
+
+        next.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc()
+    }
+}
+
+impl FromStr for Month {
+    type Err = anyhow::Error;
+
+    /// Parses a `YYYY-MM` month.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let start = NaiveDate::parse_from_str(&format!("{}-01", s.trim()), "%Y-%m-%d")
+            .map_err(|_| anyhow!("Invalid month `{s}`, expected `YYYY-MM`"))?;
+
+        Ok(Self { start })
+    }
+}
+
+impl fmt::Display for Month {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.start.format("%Y-%m"))
+    }
+}
+
+/// The mail volume of a month, per template or summed over all of them, ordered by template.
+pub(crate) fn monthly<P: AsRef<Path>>(
+    path: P,
+    month: Month,
+    by_template: bool,
+) -> Result<Vec<TemplateStats>> {
+    let mut summary: BTreeMap<String, TemplateStats> = BTreeMap::new();
+
+    for record in sent_log::load_since::<TemplateStats, _>(path, month.start())?
+        .into_iter()
+        .filter(|record| record.sent_at < month.end())
+    {
+        let template = if by_template {
+            record.template.clone()
+        } else {
+            ALL_TEMPLATES.to_owned()
+        };
+
+        summary
+            .entry(template.clone())
+            .or_insert_with(|| TemplateStats {
+                sent_at: month.start(),
+                template,
+                ..Default::default()
+            })
+            .add(&record);
+    }
+
+    Ok(summary.into_values().collect())
+}
+
+const COLUMNS: [&str; 5] = [
+    "template",
+    "messages",
+    "encoded_bytes",
+    "attachment_bytes",
+    "recipients",
+];
+
+fn row(stats: &TemplateStats) -> [String; 5] {
+    [
+        stats.template.clone(),
+        stats.messages.to_string(),
+        stats.encoded_bytes.to_string(),
+        stats.attachment_bytes.to_string(),
+        stats.recipients.to_string(),
+    ]
+}
+
+/// Formats the summary as an aligned table, or as CSV with a header line.
+pub(crate) fn render(summary: &[TemplateStats], format: StatsFormat) -> String {
+    let rows: Vec<[String; 5]> = summary.iter().map(row).collect();
+
+    match format {
+        StatsFormat::Csv => {
+            let mut output = COLUMNS.join(",") + "\n";
+
+            for row in rows {
+                let template = &row[0];
+                let template = if template.contains([',', '"', '\n']) {
+                    format!("\"{}\"", template.replace('"', "\"\""))
+                } else {
+                    template.clone()
+                };
+
+                output.push_str(&template);
+                for value in &row[1..] {
+                    output.push(',');
+                    output.push_str(value);
+                }
+                output.push('\n');
+            }
+
+            output
+        }
+        StatsFormat::Table => {
+            let mut widths = COLUMNS.map(str::len);
+            for row in &rows {
+                for (width, value) in widths.iter_mut().zip(row) {
+                    *width = (*width).max(value.len());
+                }
+            }
+
+            let line = |values: [&str; 5]| {
+                let mut line = format!("{:<width$}", values[0], width = widths[0]);
+                for (value, width) in values[1..].iter().zip(&widths[1..]) {
+                    line.push_str(&format!("  {value:>width$}"));
+                }
+                line + "\n"
+            };
+
+            let mut output = line(COLUMNS);
+            for row in &rows {
+                output.push_str(&line([&row[0], &row[1], &row[2], &row[3], &row[4]]));
+            }
+
+            output
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::send::MessageBuilder;
+    use std::fs;
+
+    /// Builds a message of the template with an attachment of `size` bytes, returning its formatted
+    /// length and attachment size, as measured at send time.
+    fn sent_message(dir: &Path, template: &str, size: usize) -> (usize, usize) {
+        let attachment = dir.join(format!("{template}-{size}.bin"));
+        fs::write(&attachment, vec![0x42; size]).unwrap();
+        let attachments = attachment.display().to_string();
+
+        let message = MessageBuilder::new()
+            .from("mailer@x.com")
+            .to_addresses("a@x.com, B@x.com")
+            .subject(template)
+            .content("<p>Report</p>", None)
+            .attachments(&attachments)
+            .build()
+            .unwrap();
+        let attachments_size = message.attachments_size();
+        let message: lettre::Message = message.try_into().unwrap();
+
+        (message.formatted().len(), attachments_size)
+    }
+
+    #[test]
+    fn test_monthly_stats_by_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(STATS_FILE);
+        let recipients = ["a@x.com".to_owned(), "b@x.com".to_owned()];
+        let june: DateTime<Utc> = "2024-06-15T10:00:00Z".parse().unwrap();
+
+        let invoice = sent_message(dir.path(), "invoice", 40_000);
+        let digest = sent_message(dir.path(), "digest", 1_000);
+        assert!(invoice.1 > digest.1 && invoice.0 > invoice.1);
+
+        // Two runs in June, one in July
+        let mut run = RunStats::default();
+        run.record("invoice", invoice.0, invoice.1, &recipients);
+        run.record("invoice", invoice.0, invoice.1, &recipients[..1]);
+        run.record("digest", digest.0, digest.1, &recipients);
+        run.save(&path, june).unwrap();
+
+        let mut run = RunStats::default();
+        run.record("digest", digest.0, digest.1, &["B@X.com".to_owned()]);
+        run.save(&path, june + chrono::Duration::days(1)).unwrap();
+
+        let mut run = RunStats::default();
+        run.record("invoice", invoice.0, invoice.1, &recipients);
+        run.save(&path, "2024-07-01T00:00:00Z".parse().unwrap())
+            .unwrap();
+
+        let month: Month = "2024-06".parse().unwrap();
+        let summary = monthly(&path, month, true).unwrap();
+        let csv = render(&summary, StatsFormat::Csv);
+        assert_eq!(
+            csv,
+            format!(
+                "template,messages,encoded_bytes,attachment_bytes,recipients\n\
+                digest,2,{},{},3\n\
+                invoice,2,{},{},2\n",
+                digest.0 * 2,
+                digest.1 * 2,
+                invoice.0 * 2,
+                invoice.1 * 2,
+            )
+        );
+
+        let total = monthly(&path, month, false).unwrap();
+        assert_eq!(total.len(), 1);
+        assert_eq!(total[0].template, ALL_TEMPLATES);
+        assert_eq!(total[0].messages, 4);
+        assert_eq!(
+            total[0].encoded_bytes,
+            (invoice.0 * 2 + digest.0 * 2) as u64
+        );
+
+        let table = render(&summary, StatsFormat::Table);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("template  messages"), "{table}");
+        assert!(lines[2].starts_with("invoice          2"), "{table}");
+
+        assert!("2024-13".parse::<Month>().is_err());
+        assert_eq!(Month::of(june).to_string(), "2024-06");
+    }
+}
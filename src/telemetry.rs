@@ -0,0 +1,279 @@
+use std::fmt;
+
+#[cfg(feature = "otel")]
+use opentelemetry::{
+    trace::{Span as _, SpanKind, Status, TraceContextExt, Tracer as _, TracerProvider as _},
+    Context, KeyValue,
+};
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider, SpanExporter};
+
+#[cfg(feature = "otel")]
+const SERVICE_NAME: &str = "osa-mailer";
+
+/// A span attribute value.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum AttributeValue {
+    Int(i64),
+    String(String),
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        AttributeValue::Int(value)
+    }
+}
+
+impl From<usize> for AttributeValue {
+    fn from(value: usize) -> Self {
+        AttributeValue::Int(value as i64)
+    }
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::String(value.to_owned())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue::String(value)
+    }
+}
+
+#[cfg(feature = "otel")]
+impl From<AttributeValue> for opentelemetry::Value {
+    fn from(value: AttributeValue) -> Self {
+        match value {
+            AttributeValue::Int(value) => value.into(),
+            AttributeValue::String(value) => value.into(),
+        }
+    }
+}
+
+/// The tracing pipeline of a run. Without an OTLP endpoint, or without the `otel` feature,
+/// every span is a no-op. The spans are flushed when dropped.
+#[derive(Debug, Default)]
+pub(crate) struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    /// Exports the spans to an OTLP/HTTP collector, e.g. `http://localhost:4318/v1/traces`.
+    /// An exporter that can't be created is logged, and tracing disabled. An unreachable collector
+    /// only loses the spans.
+    #[cfg(feature = "otel")]
+    pub(crate) fn init(endpoint: Option<&str>) -> Self {
+        use opentelemetry_otlp::WithExportConfig;
+
+        let Some(endpoint) = endpoint else {
+            return Self::default();
+        };
+
+        match opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+        {
+            Ok(exporter) => Self::with_provider(
+                SdkTracerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(
+                        opentelemetry_sdk::Resource::builder()
+                            .with_service_name(SERVICE_NAME)
+                            .build(),
+                    )
+                    .build(),
+            ),
+            Err(e) => {
+                log::warn!("Unable to export traces to `{endpoint}`, tracing is disabled: {e}");
+                Self::default()
+            }
+        }
+    }
+
+    /// Sends the spans to the given exporter as they end.
+    #[cfg(feature = "otel")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn with_exporter<E: SpanExporter + 'static>(exporter: E) -> Self {
+        Self::with_provider(
+            SdkTracerProvider::builder()
+                .with_simple_exporter(exporter)
+                .build(),
+        )
+    }
+
+    #[cfg(feature = "otel")]
+    fn with_provider(provider: SdkTracerProvider) -> Self {
+        Self {
+            provider: Some(provider),
+        }
+    }
+
+    /// Starts a root span.
+    pub(crate) fn span(&self, name: &'static str) -> Span {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = &self.provider {
+            return Span::start(provider.tracer(SERVICE_NAME), name, &Context::new());
+        }
+
+        let _ = name;
+        Span::default()
+    }
+
+    /// Flushes the spans that ended, before the process exits.
+    pub(crate) fn shutdown(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                log::warn!("Unable to flush the traces: {e}");
+            }
+        }
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// A pipeline stage, ended when dropped.
+#[derive(Debug, Default)]
+pub(crate) struct Span {
+    #[cfg(feature = "otel")]
+    inner: Option<(SdkTracer, opentelemetry_sdk::trace::Span)>,
+}
+
+impl Span {
+    #[cfg(feature = "otel")]
+    fn start(tracer: SdkTracer, name: &'static str, parent: &Context) -> Self {
+        let span = tracer
+            .span_builder(name)
+            .with_kind(SpanKind::Internal)
+            .start_with_context(&tracer, parent);
+
+        Self {
+            inner: Some((tracer, span)),
+        }
+    }
+
+    /// Starts a span within this one.
+    pub(crate) fn child(&self, name: &'static str) -> Span {
+        #[cfg(feature = "otel")]
+        if let Some((tracer, span)) = &self.inner {
+            let parent = Context::new().with_remote_span_context(span.span_context().clone());
+            return Span::start(tracer.clone(), name, &parent);
+        }
+
+        let _ = name;
+        Span::default()
+    }
+
+    pub(crate) fn set_attribute(&mut self, key: &'static str, value: impl Into<AttributeValue>) {
+        #[cfg(feature = "otel")]
+        if let Some((_, span)) = &mut self.inner {
+            span.set_attribute(KeyValue::new(key, value.into()));
+        }
+
+        #[cfg(not(feature = "otel"))]
+        let _ = (key, value);
+    }
+
+    /// Ends the span, as dropping it does.
+    #[inline]
+    pub(crate) fn end(self) {}
+
+    /// Records an error as an `exception` event, and fails the span.
+    pub(crate) fn record_error(&mut self, error: &dyn fmt::Display) {
+        #[cfg(feature = "otel")]
+        if let Some((_, span)) = &mut self.inner {
+            let message = error.to_string();
+            span.add_event(
+                "exception",
+                vec![KeyValue::new("exception.message", message.clone())],
+            );
+            span.set_status(Status::error(message));
+        }
+
+        #[cfg(not(feature = "otel"))]
+        let _ = error;
+    }
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use super::*;
+    use opentelemetry_sdk::error::OTelSdkResult;
+    use opentelemetry_sdk::trace::SpanData;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Clone, Default)]
+    struct MemoryExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for MemoryExporter {
+        fn export(
+            &self,
+            batch: Vec<SpanData>,
+        ) -> impl std::future::Future<Output = OTelSdkResult> + Send {
+            self.0.lock().unwrap().extend(batch);
+            std::future::ready(Ok(()))
+        }
+    }
+
+    fn attribute(span: &SpanData, key: &str) -> Option<opentelemetry::Value> {
+        span.attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == key)
+            .map(|attribute| attribute.value.clone())
+    }
+
+    #[test]
+    fn test_stage_spans_are_exported() {
+        let exporter = MemoryExporter::default();
+        let mut telemetry = Telemetry::with_exporter(exporter.clone());
+
+        {
+            let run = telemetry.span("run");
+            let mut email = run.child("email");
+            email.set_attribute("email.id", "00000abc");
+            email.set_attribute("email.recipients", 3usize);
+
+            let mut send = email.child("send");
+            send.record_error(&"Connection refused");
+        }
+        telemetry.shutdown();
+
+        let spans = exporter.0.lock().unwrap();
+        let names: Vec<&str> = spans.iter().map(|span| span.name.as_ref()).collect();
+        assert_eq!(names, vec!["send", "email", "run"]);
+
+        let (send, email, run) = (&spans[0], &spans[1], &spans[2]);
+        assert_eq!(send.parent_span_id, email.span_context.span_id());
+        assert_eq!(email.parent_span_id, run.span_context.span_id());
+        assert_eq!(
+            send.span_context.trace_id(),
+            run.span_context.trace_id(),
+            "Every stage belongs to the trace of the run"
+        );
+
+        assert_eq!(attribute(email, "email.id"), Some("00000abc".into()));
+        assert_eq!(attribute(email, "email.recipients"), Some(3i64.into()));
+
+        assert_eq!(send.events.len(), 1);
+        assert_eq!(send.events[0].name, "exception");
+        assert!(matches!(send.status, Status::Error { .. }));
+    }
+
+    #[test]
+    fn test_disabled_without_endpoint() {
+        let telemetry = Telemetry::init(None);
+        assert!(telemetry.provider.is_none());
+
+        let mut span = telemetry.span("run");
+        span.set_attribute("entries", 1usize);
+        assert!(span.inner.is_none());
+    }
+}
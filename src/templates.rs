@@ -0,0 +1,883 @@
+use anyhow::{anyhow, Context, Result};
+use path_slash::PathExt;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::context_html::ContextHtmlPolicy;
+use crate::dark_mode::DarkMode;
+use crate::dedup::DedupConfig;
+use crate::entries::ComposedEmail;
+use crate::fallback::RenderFailurePolicy;
+use crate::identity::IdentityConfig;
+use crate::large_files::LargeAttachmentPolicy;
+use crate::localtime::LocalTimeConfig;
+use crate::policy::PolicyConfig;
+use crate::render::{self, EngineExtensions};
+use crate::reply_token::ReplyTokenConfig;
+use crate::send::{self, BodyCharset, CharsetError};
+
+/// The main template file within a template directory.
+pub(crate) const TEMPLATE_FILE: &str = "template.html";
+
+/// The name of the main template file within a template directory, before its extension.
+const TEMPLATE_FILE_STEM: &str = "template";
+
+/// The main template file of a template directory: a `template` file with an engine extension, e.g.
+/// `template.liquid` or `template.tera.html`, the first by name when there are several, otherwise `template.html`.
+pub(crate) fn template_file(template_dir: &Path, extensions: &EngineExtensions) -> PathBuf {
+    let mut template_files: Vec<PathBuf> = fs::read_dir(template_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(TEMPLATE_FILE_STEM))
+                .and_then(|extension| extension.strip_prefix('.'))
+                .is_some_and(|extension| extensions.contains(extension))
+        })
+        .filter(|path| path.is_file())
+        .collect();
+    template_files.sort();
+
+    template_files
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| template_dir.join(TEMPLATE_FILE))
+}
+
+/// The main template file of a template directory, with the extensions of its configuration, see
+/// [`template_file`]. An invalid configuration falls back to the default extensions, it's reported by rendering.
+pub(crate) fn find_template_file(template_dir: &Path) -> PathBuf {
+    let extensions = TemplateConfig::load(template_dir)
+        .and_then(|config| config.engine_extensions())
+        .unwrap_or_default();
+
+    template_file(template_dir, &extensions)
+}
+
+/// The directory next to the template directories holding the layouts they share, e.g. `base.html`.
+pub(crate) const SHARED_TEMPLATE_DIR: &str = "shared";
+
+/// The layout shared by the templates of `new-template`, within the shared directory.
+pub(crate) const LAYOUT_FILE: &str = "base.html";
+
+const STARTER_LAYOUT: &str = include_str!("../templates/shared/base.html");
+
+const STARTER_TEMPLATE: &str = r#"<!--TEMPLATE tera-->
+{% extends "base.html" %}
+
+{% block content %}
+<p>{{ message }}</p>
+{% endblock content %}
+"#;
+
+/// The optional per-template configuration file, placed next to `template.html`.
+pub(crate) const TEMPLATE_CONFIG_FILE: &str = "template.toml";
+
+/// Template level settings loaded from `template.toml`.
+/// A template without a configuration file simply uses the defaults.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub(crate) struct TemplateConfig {
+    /// Maximum number of rows per E-mail for the named accumulation keys.
+    /// Nested keys are addressed with a dotted path, e.g. `table.entries`.
+    pub(crate) page_size: HashMap<String, usize>,
+
+    /// Charsets of the text parts, for recipients that can't read UTF-8.
+    pub(crate) charset: CharsetConfig,
+
+    /// Additional template file extensions and the engine they map to, e.g. `html = "tera"`.
+    pub(crate) extensions: HashMap<String, String>,
+
+    /// Render the address fields of the entries (`from`, `to`, `cc`, `bcc` and `reply_to`) with the template engine,
+    /// e.g. `from = "{{ team }}@example.com"`.
+    pub(crate) render_addresses: bool,
+
+    /// Park the rendered E-mails in the approval directory until an operator runs `approve`,
+    /// e.g. for customer-wide announcements.
+    pub(crate) requires_approval: bool,
+
+    /// The content policy checked on the rendered HTML before it's sent, or with `lint`.
+    pub(crate) policy: PolicyConfig,
+
+    /// Upload the attached files over `large_attachment_threshold` and link them instead, with `link`.
+    pub(crate) large_attachment_policy: LargeAttachmentPolicy,
+
+    /// The size in bytes over which an attached file is linked, 10 MiB when not set.
+    pub(crate) large_attachment_threshold: Option<u64>,
+
+    /// How long the download links are valid, 7 days when not set.
+    pub(crate) large_attachment_expiry_hours: Option<u64>,
+
+    /// The preview text and the generation of the plain-text alternative.
+    pub(crate) alternative: AlternativeConfig,
+
+    /// The dark mode support injected into the rendered HTML, `off` when not set.
+    pub(crate) dark_mode: DarkMode,
+
+    /// The light colors of the template and their dark counterpart, for `dark_mode = "auto"`,
+    /// e.g. `"#ffffff" = "#1e1e1e"`.
+    pub(crate) dark_colors: BTreeMap<String, String>,
+
+    /// How the engine treats the HTML of the context values, `escape` when not set.
+    pub(crate) context_html_policy: ContextHtmlPolicy,
+
+    /// The suppression of near-identical E-mails within a window.
+    pub(crate) dedup: DedupConfig,
+
+    /// The token threading the replies into a ticketing system, added to the reply addresses.
+    pub(crate) reply_token: ReplyTokenConfig,
+
+    /// The display timezone, format and strictness of the `localtime` filter.
+    pub(crate) localtime: LocalTimeConfig,
+
+    /// What happens to an E-mail whose template fails to render, `fail` when not set.
+    pub(crate) on_render_failure: RenderFailurePolicy,
+
+    /// Which entries are batched into a single E-mail: `identity_fields` and `identity_conflict`.
+    #[serde(flatten)]
+    pub(crate) identity: IdentityConfig,
+
+    /// Who to turn to when the template fails: `owner`, `slack_channel` and `escalation_email`.
+    #[serde(flatten)]
+    pub(crate) ownership: Ownership,
+}
+
+/// The team owning a template, surfaced in its failure reports, e.g. `owner = "team-db"`.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub(crate) struct Ownership {
+    pub(crate) owner: Option<String>,
+    pub(crate) slack_channel: Option<String>,
+
+    /// Notified of the failures of the template instead of the `notify_error` addresses of its entries
+    pub(crate) escalation_email: Option<String>,
+}
+
+impl Ownership {
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.owner.is_none() && self.slack_channel.is_none() && self.escalation_email.is_none()
+    }
+}
+
+/// The declared fields, e.g. `owner: team-db, slack: #db-oncall, escalation: db-oncall@example.com`.
+impl fmt::Display for Ownership {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields: Vec<String> = [
+            ("owner", &self.owner),
+            ("slack", &self.slack_channel),
+            ("escalation", &self.escalation_email),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_ref().map(|value| format!("{name}: {value}")))
+        .collect();
+
+        write!(f, "{}", fields.join(", "))
+    }
+}
+
+/// The charsets the rendered content is transcoded to, e.g. `text = "ISO-8859-1"`. UTF-8 when not set.
+/// Set in `template.toml` under `[charset]`, or per entry as `email.charset`, which takes precedence.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub(crate) struct CharsetConfig {
+    /// Charset of the plain-text alternative
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) text: Option<String>,
+
+    /// Charset of the HTML content
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) html: Option<String>,
+
+    /// Replace the characters a charset can't represent with `?` instead of failing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) lenient: Option<bool>,
+}
+
+/// The preview text clients show after the subject, and whether the plain-text alternative is generated from the HTML,
+/// e.g. `preview_text = "{{ alerts | length }} new alerts"`.
+/// Set in `template.toml` under `[alternative]`, or per entry as `email.alternative`, which takes precedence.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub(crate) struct AlternativeConfig {
+    /// The preview text, rendered with the template engine against the context
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) preview_text: Option<String>,
+
+    /// Generate the plain-text alternative from the HTML when the entry has none, on when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) generate_text: Option<bool>,
+}
+
+impl AlternativeConfig {
+    /// Fills the settings missing here from `fallback`.
+    pub(crate) fn or(&self, fallback: &AlternativeConfig) -> AlternativeConfig {
+        AlternativeConfig {
+            preview_text: self
+                .preview_text
+                .clone()
+                .or_else(|| fallback.preview_text.clone()),
+            generate_text: self.generate_text.or(fallback.generate_text),
+        }
+    }
+}
+
+impl CharsetConfig {
+    /// Fills the settings missing here from `fallback`.
+    pub(crate) fn or(&self, fallback: &CharsetConfig) -> CharsetConfig {
+        CharsetConfig {
+            text: self.text.clone().or_else(|| fallback.text.clone()),
+            html: self.html.clone().or_else(|| fallback.html.clone()),
+            lenient: self.lenient.or(fallback.lenient),
+        }
+    }
+
+    /// Resolves the configured charsets of the plain-text and HTML parts.
+    pub(crate) fn resolve(
+        &self,
+    ) -> Result<(Option<BodyCharset>, Option<BodyCharset>), CharsetError> {
+        let lenient = self.lenient.unwrap_or(false);
+        let resolve = |label: &Option<String>| {
+            label
+                .as_deref()
+                .map(|label| BodyCharset::new(label, lenient))
+                .transpose()
+        };
+
+        Ok((resolve(&self.text)?, resolve(&self.html)?))
+    }
+}
+
+impl TemplateConfig {
+    /// Loads the `template.toml` file from the given template directory.
+    /// ## Error
+    /// Fails if the file exists but can't be read or parsed.
+    pub(crate) fn load<P: AsRef<Path>>(template_dir: P) -> Result<Self> {
+        let config_path = template_dir.as_ref().join(TEMPLATE_CONFIG_FILE);
+
+        if !config_path.is_file() {
+            return Ok(Self::default());
+        }
+
+        let contents = fs::read_to_string(&config_path).with_context(|| {
+            format!(
+                "Unable to read template configuration \"{}\"",
+                config_path.display()
+            )
+        })?;
+
+        toml::from_str(&contents).with_context(|| {
+            format!(
+                "Unable to parse template configuration \"{}\"",
+                config_path.display()
+            )
+        })
+    }
+
+    /// The default engine extensions, extended with the configured ones.
+    /// ## Error
+    /// Fails if an extension maps to an unknown engine.
+    pub(crate) fn engine_extensions(&self) -> Result<EngineExtensions> {
+        let mut engine_extensions = EngineExtensions::default();
+
+        for (extension, engine) in &self.extensions {
+            let engine = engine.parse().with_context(|| {
+                format!("Unknown engine `{engine}` for extension `{extension}`")
+            })?;
+            engine_extensions.insert(extension, engine);
+        }
+
+        Ok(engine_extensions)
+    }
+}
+
+/// Creates the directory of a new template, extending the shared layout, which is created when
+/// missing. Returns the created files.
+/// ## Error
+/// Fails if the name isn't a single directory name, or the template already exists.
+pub(crate) fn scaffold(templates_dir: &Path, name: &str) -> Result<Vec<PathBuf>> {
+    let mut components = Path::new(name).components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) || name == SHARED_TEMPLATE_DIR
+    {
+        return Err(anyhow!("Invalid template name `{name}`"));
+    }
+
+    let template_dir = templates_dir.join(name);
+    if template_dir.exists() {
+        return Err(anyhow!(
+            "The template \"{}\" already exists",
+            template_dir.display()
+        ));
+    }
+
+    let mut created = Vec::new();
+    let mut write = |path: PathBuf, contents: &str| -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Unable to create directory \"{}\"", dir.display()))?;
+        }
+        fs::write(&path, contents)
+            .with_context(|| format!("Unable to write \"{}\"", path.display()))?;
+        created.push(path);
+        Ok(())
+    };
+
+    let layout_path = templates_dir.join(SHARED_TEMPLATE_DIR).join(LAYOUT_FILE);
+    if !layout_path.is_file() {
+        write(layout_path, STARTER_LAYOUT)?;
+    }
+
+    write(template_dir.join(TEMPLATE_FILE), STARTER_TEMPLATE)?;
+
+    Ok(created)
+}
+
+/// Checks that the templates directory exists when any of the E-mails is rendered with a template.
+/// Raw E-mails bring their own body, so an outbox of raw E-mails needs no templates directory.
+/// ## Error
+/// Fails once for all the E-mails, listing their templates, when the directory is missing.
+pub(crate) fn require_root(templates_dir: &Path, emails: &[ComposedEmail]) -> Result<()> {
+    if templates_dir.is_dir() {
+        return Ok(());
+    }
+
+    let mut templates: Vec<&str> = Vec::new();
+    for email in emails.iter().filter(|email| !email.header.is_raw()) {
+        if !templates.contains(&email.header.template.as_str()) {
+            templates.push(&email.header.template);
+        }
+    }
+
+    if templates.is_empty() {
+        return Ok(());
+    }
+
+    Err(anyhow!(
+        "The templates directory \"{}\" is missing, required by the template(s) {}",
+        templates_dir.display(),
+        templates
+            .iter()
+            .map(|template| format!("`{template}`"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))
+}
+
+/// The extensions of the image files of a template directory, which must be referenced by its template.
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "svg", "webp", "bmp", "ico"];
+
+/// A finding of [`check_assets`] about the images of a template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum AssetFinding {
+    /// Referenced by the template, but not in its directory
+    Missing(String),
+
+    /// In the template directory, but never referenced
+    Unused(String),
+
+    /// Built by a template expression, which is only known once rendered
+    Dynamic(String),
+}
+
+impl fmt::Display for AssetFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssetFinding::Missing(reference) => write!(f, "Missing image \"{reference}\""),
+            AssetFinding::Unused(file) => write!(f, "Unused image \"{file}\""),
+            AssetFinding::Dynamic(reference) => {
+                write!(f, "Skipped the dynamic image reference \"{reference}\"")
+            }
+        }
+    }
+}
+
+/// Checks the static image references of the template of a directory against the image files of the directory,
+/// reporting the missing images and the unused ones, so a renamed image is caught before sending.
+/// ## Error
+/// Fails if the template file can't be read.
+pub(crate) fn check_assets(template_dir: &Path) -> Result<Vec<AssetFinding>> {
+    let template_path = find_template_file(template_dir);
+    let contents = fs::read_to_string(&template_path).with_context(|| {
+        format!(
+            "Unable to read template file \"{}\"",
+            template_path.display()
+        )
+    })?;
+
+    let mut findings = Vec::new();
+    let mut referenced = HashSet::new();
+    let mut checked = HashSet::new();
+
+    let references = send::image_references(&contents).with_context(|| {
+        format!(
+            "Unable to parse template file \"{}\"",
+            template_path.display()
+        )
+    })?;
+    for reference in references {
+        if !checked.insert(reference) {
+            continue;
+        }
+
+        if send::is_templated(reference) {
+            findings.push(AssetFinding::Dynamic(reference.to_owned()));
+            continue;
+        }
+
+        let image_path = send::get_path(reference, Some(template_dir))
+            .and_then(|image_path| fs::canonicalize::<&Path>(image_path.as_ref()));
+
+        match image_path {
+            Ok(image_path) => {
+                referenced.insert(image_path);
+            }
+            Err(_) => findings.push(AssetFinding::Missing(reference.to_owned())),
+        }
+    }
+
+    let mut images: Vec<PathBuf> = walkdir::WalkDir::new(template_dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .filter(|path| {
+            path.extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| {
+                    IMAGE_EXTENSIONS
+                        .iter()
+                        .any(|image| image.eq_ignore_ascii_case(extension))
+                })
+        })
+        .collect();
+    images.sort();
+
+    for image in images {
+        let is_referenced = fs::canonicalize(&image).is_ok_and(|path| referenced.contains(&path));
+
+        if !is_referenced {
+            let file = image.strip_prefix(template_dir).unwrap_or(&image);
+            findings.push(AssetFinding::Unused(file.to_slash_lossy().into_owned()));
+        }
+    }
+
+    Ok(findings)
+}
+
+/// The findings of [`check_assets`] for every template of the templates directory, by template name.
+/// The shared layouts are not templates of their own.
+/// ## Error
+/// Fails if the templates directory or a template file can't be read.
+pub(crate) fn check_all_assets(templates_dir: &Path) -> Result<Vec<(String, Vec<AssetFinding>)>> {
+    template_dirs(templates_dir)?
+        .into_iter()
+        .map(|(name, template_dir)| Ok((name, check_assets(&template_dir)?)))
+        .collect()
+}
+
+/// A finding of [`check_source`] about the bytes of a template file, which the engines never get, see
+/// [`render::normalize_source`], but which the author should fix in the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SourceFinding {
+    /// The file starts with a UTF-8 byte order mark
+    ByteOrderMark,
+
+    /// The file has both CRLF and LF line endings
+    MixedLineEndings { crlf: usize, lf: usize },
+}
+
+impl fmt::Display for SourceFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceFinding::ByteOrderMark => write!(f, "Starts with a UTF-8 byte order mark"),
+            SourceFinding::MixedLineEndings { crlf, lf } => {
+                write!(f, "Mixed line endings: {crlf} CRLF and {lf} LF")
+            }
+        }
+    }
+}
+
+/// Checks the contents of a template file for a byte order mark and mixed line endings.
+pub(crate) fn check_source(contents: &str) -> Vec<SourceFinding> {
+    let mut findings = Vec::new();
+
+    if contents.starts_with(render::BOM) {
+        findings.push(SourceFinding::ByteOrderMark);
+    }
+
+    let crlf = contents.matches("\r\n").count();
+    let lf = contents.matches('\n').count() - crlf;
+    if crlf > 0 && lf > 0 {
+        findings.push(SourceFinding::MixedLineEndings { crlf, lf });
+    }
+
+    findings
+}
+
+/// The findings of [`check_source`] for every `.html` file of the template directories and the shared layouts,
+/// by file path relative to the templates directory. Files without findings are left out.
+/// ## Error
+/// Fails if the templates directory or a template file can't be read.
+pub(crate) fn check_all_sources(templates_dir: &Path) -> Result<Vec<(String, Vec<SourceFinding>)>> {
+    let mut dirs: Vec<PathBuf> = template_dirs(templates_dir)?
+        .into_iter()
+        .map(|(_, template_dir)| template_dir)
+        .collect();
+    dirs.push(templates_dir.join(SHARED_TEMPLATE_DIR));
+
+    let mut all_findings = Vec::new();
+
+    for dir in dirs {
+        let mut files: Vec<PathBuf> = walkdir::WalkDir::new(&dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "html")
+            })
+            .collect();
+        files.sort();
+
+        for file in files {
+            let contents = fs::read_to_string(&file)
+                .with_context(|| format!("Unable to read template file \"{}\"", file.display()))?;
+
+            let findings = check_source(&contents);
+            if !findings.is_empty() {
+                let name = file.strip_prefix(templates_dir).unwrap_or(&file);
+                all_findings.push((name.to_slash_lossy().into_owned(), findings));
+            }
+        }
+    }
+
+    Ok(all_findings)
+}
+
+/// The template directories holding a template file, by name, without the shared layouts.
+/// ## Error
+/// Fails if the templates directory can't be read.
+fn template_dirs(templates_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut template_dirs: Vec<PathBuf> = fs::read_dir(templates_dir)
+        .with_context(|| {
+            format!(
+                "Unable to read templates directory \"{}\"",
+                templates_dir.display()
+            )
+        })?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| find_template_file(path).is_file())
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name != SHARED_TEMPLATE_DIR)
+        })
+        .collect();
+    template_dirs.sort();
+
+    Ok(template_dirs
+        .into_iter()
+        .map(|template_dir| {
+            let name = template_dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+
+            (name, template_dir)
+        })
+        .collect())
+}
+
+/// The templates without an `owner` or an `escalation_email`, which on-call can't route a failure of.
+/// A template whose configuration can't be loaded is left to the other checks.
+/// ## Error
+/// Fails if the templates directory can't be read.
+pub(crate) fn unowned_templates(templates_dir: &Path) -> Result<Vec<String>> {
+    Ok(template_dirs(templates_dir)?
+        .into_iter()
+        .filter(|(_, template_dir)| {
+            TemplateConfig::load(template_dir).is_ok_and(|config| {
+                config.ownership.owner.is_none() && config.ownership.escalation_email.is_none()
+            })
+        })
+        .map(|(name, _)| name)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_size_config() {
+        let config: TemplateConfig = toml::from_str(
+            r#"
+            [page_size]
+            events = 50
+            "table.entries" = 10
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.page_size.get("events"), Some(&50));
+        assert_eq!(config.page_size.get("table.entries"), Some(&10));
+    }
+
+    #[test]
+    fn test_missing_config_is_default() {
+        let config = TemplateConfig::load("this/template/does/not/exist").unwrap();
+        assert!(config.page_size.is_empty());
+    }
+
+    #[test]
+    fn test_charset_config_precedence() {
+        let config: TemplateConfig = toml::from_str(
+            r#"
+            [charset]
+            text = "ISO-8859-1"
+            lenient = true
+            "#,
+        )
+        .unwrap();
+
+        let entry_charset = CharsetConfig {
+            text: Some("windows-1252".to_owned()),
+            html: Some("ISO-8859-1".to_owned()),
+            lenient: None,
+        };
+
+        assert_eq!(
+            entry_charset.or(&config.charset),
+            CharsetConfig {
+                text: Some("windows-1252".to_owned()),
+                html: Some("ISO-8859-1".to_owned()),
+                lenient: Some(true),
+            }
+        );
+    }
+
+    #[test]
+    fn test_engine_extensions_config() {
+        let config: TemplateConfig = toml::from_str(
+            r#"
+            [extensions]
+            html = "tera"
+            "#,
+        )
+        .unwrap();
+
+        let extensions = config.engine_extensions().unwrap();
+        assert_eq!(
+            extensions.engine_for("template.html"),
+            Some(crate::render::TemplateEngine::Tera)
+        );
+
+        let config: TemplateConfig = toml::from_str("extensions = { html = \"jinja\" }").unwrap();
+        assert!(config.engine_extensions().is_err());
+    }
+
+    #[test]
+    fn test_template_file() {
+        use crate::render::{
+            AbsolutePath, ContextData, DetectionMethod, TemplateData, TemplateEngine,
+            TemplateExtension,
+        };
+        use std::rc::Rc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let template_dir = dir.path().join("ops_department");
+        fs::create_dir_all(&template_dir).unwrap();
+
+        assert_eq!(
+            find_template_file(&template_dir),
+            template_dir.join(TEMPLATE_FILE)
+        );
+
+        // Rendered by the engine of its extension, without a magic comment
+        fs::write(
+            template_dir.join("template.liquid"),
+            "<p>{{ message | upcase }}</p>",
+        )
+        .unwrap();
+        fs::write(template_dir.join("template.txt"), "").unwrap();
+        let template_path = find_template_file(&template_dir);
+        assert_eq!(template_path, template_dir.join("template.liquid"));
+
+        let file_path: AbsolutePath = template_path.clone().into();
+        let template_data = TemplateData {
+            contents: Rc::new(render::read_template(&template_path).unwrap()),
+            file_path: Some(&file_path),
+            extensions: None,
+        };
+        let context_data = ContextData {
+            context: serde_json::json!({ "message": "hi" }),
+            file_path: None,
+            trusted: false,
+            local_time: Default::default(),
+        };
+        assert_eq!(
+            render::detect_engine(&template_data),
+            TemplateEngine::Liquid
+        );
+        let html = render::render(
+            &template_data,
+            &context_data,
+            DetectionMethod::Auto,
+            TemplateExtension::Auto,
+        )
+        .unwrap()
+        .0;
+        assert_eq!(html.to_string(), "<p>HI</p>");
+
+        // An extension of the configuration
+        fs::remove_file(template_dir.join("template.liquid")).unwrap();
+        fs::write(template_dir.join("template.mustache"), "").unwrap();
+        assert_eq!(
+            find_template_file(&template_dir),
+            template_dir.join(TEMPLATE_FILE)
+        );
+
+        fs::write(
+            template_dir.join(TEMPLATE_CONFIG_FILE),
+            "extensions = { mustache = \"handlebars\" }",
+        )
+        .unwrap();
+        assert_eq!(
+            find_template_file(&template_dir),
+            template_dir.join("template.mustache")
+        );
+    }
+
+    #[test]
+    fn test_require_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("templates");
+
+        let email = |template: &str, html_body: Option<&str>| ComposedEmail {
+            header: crate::entries::Email {
+                template: template.to_owned(),
+                html_body: html_body.map(str::to_owned),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // Raw E-mails need no templates directory
+        let raw = vec![email("", Some("<p>Raw</p>")), email("", Some("<p>Raw</p>"))];
+        require_root(&missing, &raw).unwrap();
+
+        let mixed = vec![
+            email("ops_department", None),
+            email("", Some("<p>Raw</p>")),
+            email("ops_department", None),
+            email("daily", None),
+        ];
+        assert_eq!(
+            require_root(&missing, &mixed).unwrap_err().to_string(),
+            format!(
+                "The templates directory \"{}\" is missing, required by the template(s) `ops_department`, `daily`",
+                missing.display()
+            )
+        );
+
+        fs::create_dir_all(&missing).unwrap();
+        require_root(&missing, &mixed).unwrap();
+    }
+
+    #[test]
+    fn test_check_assets() {
+        let dir = tempfile::tempdir().unwrap();
+        let template_dir = dir.path().join("ops_department");
+        fs::create_dir_all(template_dir.join("images")).unwrap();
+
+        fs::write(
+            template_dir.join(TEMPLATE_FILE),
+            r#"<img src="images/logo.png"><img src="images/header.png">
+            <div style="background: url('images/logo.png')"></div>
+            <img src="{{ banner }}"><img src="https://cdn.x.com/remote.png">"#,
+        )
+        .unwrap();
+        fs::write(template_dir.join("images").join("logo.png"), b"png").unwrap();
+        fs::write(template_dir.join("images").join("header_v2.png"), b"png").unwrap();
+        fs::write(template_dir.join(TEMPLATE_CONFIG_FILE), "").unwrap();
+
+        let findings = check_assets(&template_dir).unwrap();
+        assert_eq!(
+            findings,
+            vec![
+                AssetFinding::Missing("images/header.png".to_owned()),
+                AssetFinding::Dynamic("{{ banner }}".to_owned()),
+                AssetFinding::Unused("images/header_v2.png".to_owned()),
+            ]
+        );
+
+        // The dynamic reference is only informational
+        assert_eq!(
+            findings
+                .iter()
+                .filter(|finding| !matches!(finding, AssetFinding::Dynamic(_)))
+                .count(),
+            2
+        );
+
+        fs::create_dir_all(dir.path().join(SHARED_TEMPLATE_DIR)).unwrap();
+        fs::write(
+            dir.path().join(SHARED_TEMPLATE_DIR).join(TEMPLATE_FILE),
+            r#"<img src="missing.png">"#,
+        )
+        .unwrap();
+
+        let all = check_all_assets(dir.path()).unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].0, "ops_department");
+    }
+
+    #[test]
+    fn test_source_findings() {
+        let dir = tempfile::tempdir().unwrap();
+        let template_dir = dir.path().join("ops_department");
+        fs::create_dir_all(&template_dir).unwrap();
+        fs::write(
+            template_dir.join(TEMPLATE_FILE),
+            "\u{feff}<!--TEMPLATE tera-->\r\n<p>{{ a }}</p>\r\n<p>{{ b }}</p>\n",
+        )
+        .unwrap();
+
+        // Consistent CRLF line endings are not a finding
+        let shared_dir = dir.path().join(SHARED_TEMPLATE_DIR);
+        fs::create_dir_all(&shared_dir).unwrap();
+        fs::write(shared_dir.join(LAYOUT_FILE), "<html>\r\n</html>\r\n").unwrap();
+        fs::write(shared_dir.join("footer.html"), "\u{feff}<footer></footer>").unwrap();
+
+        assert_eq!(
+            check_all_sources(dir.path()).unwrap(),
+            vec![
+                (
+                    "ops_department/template.html".to_owned(),
+                    vec![
+                        SourceFinding::ByteOrderMark,
+                        SourceFinding::MixedLineEndings { crlf: 2, lf: 1 }
+                    ]
+                ),
+                (
+                    "shared/footer.html".to_owned(),
+                    vec![SourceFinding::ByteOrderMark]
+                ),
+            ]
+        );
+        assert_eq!(
+            SourceFinding::MixedLineEndings { crlf: 2, lf: 1 }.to_string(),
+            "Mixed line endings: 2 CRLF and 1 LF"
+        );
+        assert!(check_source("<p>\n</p>\n").is_empty());
+    }
+}
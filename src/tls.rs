@@ -0,0 +1,37 @@
+use std::net::TcpStream;
+use std::sync::Arc;
+
+/// The TLS settings of the clients, verifying servers against the bundled web PKI roots, as with SMTP.
+/// ## Error
+/// Fails with the reason when the protocol versions aren't supported.
+pub(crate) fn client_config() -> Result<rustls::ClientConfig, String> {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+
+    Ok(rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|e| e.to_string())?
+    .with_root_certificates(roots)
+    .with_no_client_auth())
+}
+
+/// Opens a TLS session over the given connection, see [`client_config`].
+/// ## Error
+/// Fails with the reason when the host isn't a valid server name or the session can't be set up.
+pub(crate) fn client_stream(
+    host: &str,
+    tcp: TcpStream,
+) -> Result<rustls::StreamOwned<rustls::ClientConnection, TcpStream>, String> {
+    let config = client_config()?;
+
+    let server_name =
+        rustls::pki_types::ServerName::try_from(host.to_owned()).map_err(|e| e.to_string())?;
+
+    let connection =
+        rustls::ClientConnection::new(Arc::new(config), server_name).map_err(|e| e.to_string())?;
+
+    Ok(rustls::StreamOwned::new(connection, tcp))
+}
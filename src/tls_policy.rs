@@ -0,0 +1,196 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub(crate) enum TlsPolicyError {
+    #[error("The TLS policy of `{domain}` requires TLS, but the mail relay connection is `noauth` (plaintext)")]
+    PlaintextRefused { domain: String },
+}
+
+/// Whether E-mails to a recipient domain may travel over a plaintext mail relay connection,
+/// after the modes of MTA-STS.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DomainPolicy {
+    /// E-mails fail over a plaintext connection
+    Require,
+
+    /// E-mails are sent over a plaintext connection, with a warning
+    Testing,
+
+    /// E-mails are sent over any connection
+    AllowPlaintext,
+}
+
+/// Where the TLS policies of the recipient domains come from, such as a static table.
+/// An MTA-STS fetcher would be another source.
+pub(crate) trait PolicySource {
+    /// The policy of a lowercase recipient domain, None when the domain has no policy.
+    fn policy(&self, domain: &str) -> Option<DomainPolicy>;
+}
+
+/// The TLS policies of `--tls-policy-file`, by recipient domain.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PolicyTable {
+    policies: BTreeMap<String, DomainPolicy>,
+}
+
+impl PolicyTable {
+    /// The domains may be written in any case.
+    pub(crate) fn new(policies: BTreeMap<String, DomainPolicy>) -> Self {
+        Self {
+            policies: policies
+                .into_iter()
+                .map(|(domain, policy)| (domain.to_lowercase(), policy))
+                .collect(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+}
+
+impl PolicySource for PolicyTable {
+    fn policy(&self, domain: &str) -> Option<DomainPolicy> {
+        self.policies.get(domain).copied()
+    }
+}
+
+/// Loads the TLS policies of a TOML file, or a JSON file for a `.json` extension, mapping each recipient domain to
+/// its policy, e.g. `"example.com" = "require"`.
+/// ## Error
+/// Fails if the file can't be read or parsed, or if a policy is unknown.
+pub(crate) fn load<P: AsRef<Path>>(path: P) -> Result<PolicyTable> {
+    let path = path.as_ref();
+
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read TLS policy file \"{}\"", path.display()))?;
+
+    let policies: BTreeMap<String, DomainPolicy> = match path
+        .extension()
+        .and_then(|ext| ext.to_str())
+    {
+        Some(ext) if ext.eq_ignore_ascii_case("json") => serde_json::from_str(&contents)
+            .with_context(|| format!("Unable to parse TLS policy file \"{}\"", path.display()))?,
+        _ => toml::from_str(&contents)
+            .with_context(|| format!("Unable to parse TLS policy file \"{}\"", path.display()))?,
+    };
+
+    Ok(PolicyTable::new(policies))
+}
+
+/// Checks the recipient domains of an E-mail against their TLS policy, for a mail relay connection that is
+/// `encrypted` (TLS or STARTTLS) or plaintext. Returns the domains sent in plaintext under a `testing` policy,
+/// to warn about.
+/// ## Error
+/// Fails with the first domain whose `require` policy refuses a plaintext connection.
+pub(crate) fn check<'a>(
+    source: &dyn PolicySource,
+    domains: impl IntoIterator<Item = &'a str>,
+    encrypted: bool,
+) -> Result<Vec<String>, TlsPolicyError> {
+    let mut warnings = Vec::new();
+
+    if encrypted {
+        return Ok(warnings);
+    }
+
+    for domain in domains {
+        let domain = domain.to_lowercase();
+
+        match source.policy(&domain) {
+            Some(DomainPolicy::Require) => return Err(TlsPolicyError::PlaintextRefused { domain }),
+            Some(DomainPolicy::Testing) if !warnings.contains(&domain) => warnings.push(domain),
+            _ => {}
+        }
+    }
+
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::send::Authentication;
+
+    fn table() -> PolicyTable {
+        PolicyTable::new(BTreeMap::from([
+            ("Example.com".to_owned(), DomainPolicy::Require),
+            (
+                "legacy-partner.net".to_owned(),
+                DomainPolicy::AllowPlaintext,
+            ),
+            ("new-partner.org".to_owned(), DomainPolicy::Testing),
+        ]))
+    }
+
+    #[test]
+    fn test_require_and_allow_by_connection() {
+        let table = table();
+        let plaintext = Authentication::NoAuth.is_encrypted();
+
+        // Refused over plaintext, naming the domain
+        assert_eq!(
+            check(&table, ["legacy-partner.net", "EXAMPLE.com"], plaintext),
+            Err(TlsPolicyError::PlaintextRefused {
+                domain: "example.com".to_owned()
+            })
+        );
+        assert!(check(&table, ["example.com"], plaintext)
+            .unwrap_err()
+            .to_string()
+            .contains("`example.com`"));
+
+        // Allowed, or unknown to the policy
+        assert_eq!(
+            check(&table, ["legacy-partner.net", "other.com"], plaintext),
+            Ok(Vec::new())
+        );
+
+        // Warned about under testing, once per domain
+        assert_eq!(
+            check(&table, ["new-partner.org", "new-partner.org"], plaintext),
+            Ok(vec!["new-partner.org".to_owned()])
+        );
+
+        // Every policy is met over TLS and STARTTLS
+        for auth in [Authentication::Tls, Authentication::Starttls] {
+            assert_eq!(
+                check(
+                    &table,
+                    ["example.com", "legacy-partner.net", "new-partner.org"],
+                    auth.is_encrypted()
+                ),
+                Ok(Vec::new()),
+                "{auth}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_policy_file() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let path = dir.path().join("tls_policy.toml");
+        fs::write(
+            &path,
+            "\"example.com\" = \"require\"\n\"legacy-partner.net\" = \"allow-plaintext\"\n",
+        )
+        .unwrap();
+        let table = load(&path).unwrap();
+        assert_eq!(table.policy("example.com"), Some(DomainPolicy::Require));
+        assert_eq!(
+            table.policy("legacy-partner.net"),
+            Some(DomainPolicy::AllowPlaintext)
+        );
+
+        let path = dir.path().join("tls_policy.json");
+        fs::write(&path, r#"{ "example.com": "enforce" }"#).unwrap();
+        assert!(load(&path).is_err());
+    }
+}